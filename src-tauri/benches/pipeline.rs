@@ -0,0 +1,61 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use lightbeam_lib::utils::{boxs_posision, find_edges_pos, get_crop_area, rotate_array, U16Array};
+use ndarray::Array;
+
+// synthetic test-tool: 100 pixel/cm (ypoints[2] - ypoints[1] = 7 cm)
+const H: usize = 2400;
+const W: usize = 3000;
+const XPOINTS: [usize; 3] = [600, 1500, 2400];
+const YPOINTS: [usize; 3] = [500, 1200, 1900];
+// light field edges 15 pixel outside the tool lines
+const FIELD: [usize; 4] = [485, 1915, 585, 2415];
+const THETA: f64 = 0.01;
+
+fn synthetic_field() -> U16Array {
+    let on_line = |p: usize, lines: &[usize; 3]| lines.iter().any(|&l| p + 2 >= l && p <= l + 1);
+    Array::from_shape_fn((H, W), |(r, c)| {
+        if on_line(c, &XPOINTS) || on_line(r, &YPOINTS) {
+            500
+        } else if FIELD[0] <= r && r < FIELD[1] && FIELD[2] <= c && c < FIELD[3] {
+            3000
+        } else {
+            1000
+        }
+    })
+}
+
+fn bench_rotate_fields(c: &mut Criterion) {
+    let large = synthetic_field();
+    let small = synthetic_field();
+    let mut group = c.benchmark_group("rotate both fields");
+    group.sample_size(10);
+    group.bench_function("sequential", |b| {
+        b.iter(|| (rotate_array(THETA, large.view()), rotate_array(THETA, small.view())))
+    });
+    group.bench_function("rayon::join", |b| {
+        b.iter(|| rayon::join(|| rotate_array(THETA, large.view()), || rotate_array(THETA, small.view())))
+    });
+    group.finish();
+}
+
+fn bench_find_edges(c: &mut Criterion) {
+    let arr = synthetic_field();
+    let xpoints: Vec<i32> = XPOINTS.iter().map(|&x| x as i32).collect();
+    let ypoints: Vec<i32> = YPOINTS.iter().map(|&y| y as i32).collect();
+    let boxs_pos = boxs_posision(&xpoints, &ypoints, arr.view());
+    let xypoints = [xpoints[0], xpoints[0], xpoints[2], xpoints[2], ypoints[0], ypoints[0], ypoints[2], ypoints[2]];
+    let run = || {
+        let crop_areas = get_crop_area(&boxs_pos, arr.view());
+        find_edges_pos(&crop_areas, &boxs_pos, xypoints, &ypoints)
+    };
+
+    // same code, one worker thread vs the global pool
+    let single = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+    let mut group = c.benchmark_group("find edges (8 crops)");
+    group.bench_function("1 thread", |b| b.iter(|| single.install(|| black_box(run()))));
+    group.bench_function("rayon", |b| b.iter(|| black_box(run())));
+    group.finish();
+}
+
+criterion_group!(benches, bench_rotate_fields, bench_find_edges);
+criterion_main!(benches);
@@ -0,0 +1,45 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use lightbeam_lib::rotation::{rotate, rotate_roi, Interpolation, Roi};
+use lightbeam_lib::utils::U16Array;
+use ndarray::Array;
+
+const H: usize = 3000;
+const W: usize = 3000;
+const THETA: f64 = 0.01;
+
+fn detector_image() -> U16Array {
+    Array::from_shape_fn((H, W), |(r, c)| ((r * 7 + c * 13) % 4096) as u16)
+}
+
+fn bench_interpolation(c: &mut Criterion) {
+    let arr = detector_image();
+    let mut group = c.benchmark_group("rotate 3000x3000");
+    group.sample_size(10);
+    for interpolation in [Interpolation::Nearest, Interpolation::Bilinear, Interpolation::Bicubic] {
+        group.bench_with_input(BenchmarkId::from_parameter(format!("{:?}", interpolation)), &interpolation, |b, &interpolation| {
+            b.iter(|| rotate(arr.view(), THETA, interpolation, 0))
+        });
+    }
+    group.finish();
+}
+
+fn bench_roi(c: &mut Criterion) {
+    let arr = detector_image();
+    // size of one edge box (0.3w x 0.16h)
+    let roi = Roi { row: 400, col: 150, nrows: 480, ncols: 900 };
+    let mut group = c.benchmark_group("edge box");
+    group.sample_size(10);
+    group.bench_function("rotate whole + crop", |b| {
+        b.iter(|| {
+            let rotated = rotate(arr.view(), THETA, Interpolation::Bilinear, 0);
+            black_box(rotated.slice(ndarray::s![roi.row..roi.row + roi.nrows, roi.col..roi.col + roi.ncols]).to_owned())
+        })
+    });
+    group.bench_function("rotate_roi", |b| {
+        b.iter(|| rotate_roi(arr.view(), THETA, roi, Interpolation::Bilinear, 0))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_interpolation, bench_roi);
+criterion_main!(benches);
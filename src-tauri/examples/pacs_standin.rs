@@ -0,0 +1,277 @@
+//! Orthanc-style query/retrieve stand-in, serving a folder of DICOM files.
+//!
+//!     cargo run --example pacs_standin -- <folder> [--port 4242] [--ae ORTHANC] [--dest LIGHTBEAM=127.0.0.1:11112]...
+//!
+//! Answers C-ECHO, study root C-FIND (STUDY/SERIES/IMAGE, `*`/`?` wildcards and
+//! date ranges) and C-MOVE to the destinations given with `--dest`.
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::fs;
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use dicom::core::VR;
+use dicom::dictionary_std::tags;
+use dicom::object::{InMemDicomObject, OpenFileOptions, Tag};
+use dicom::ul::association::ServerAssociationOptions;
+use lightbeam_lib::dimse::{self, get_str, Association, Message};
+use lightbeam_lib::storescu::store_files;
+
+// keys answered at each level (study root)
+const STUDY_KEYS: [(Tag, VR); 3] = [
+    (tags::STUDY_INSTANCE_UID, VR::UI),
+    (tags::STUDY_DATE, VR::DA),
+    (tags::MODALITIES_IN_STUDY, VR::CS),
+];
+const SERIES_KEYS: [(Tag, VR); 3] = [
+    (tags::SERIES_INSTANCE_UID, VR::UI),
+    (tags::MODALITY, VR::CS),
+    (tags::STATION_NAME, VR::SH),
+];
+const IMAGE_KEYS: [(Tag, VR); 4] = [
+    (tags::SOP_INSTANCE_UID, VR::UI),
+    (tags::DETECTOR_ID, VR::SH),
+    (tags::ACQUISITION_DATE, VR::DA),
+    (tags::ACQUISITION_TIME, VR::TM),
+];
+
+struct Record {
+    path: String,
+    values: HashMap<Tag, String>,
+}
+
+impl Record {
+    fn get(&self, tag: Tag) -> &str {
+        self.values.get(&tag).map(String::as_str).unwrap_or("")
+    }
+}
+
+struct Pacs {
+    ae_title: String,
+    records: Vec<Record>,
+    /// move destinations: AE title -> host:port
+    destinations: HashMap<String, String>,
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let Some(folder) = args.first() else {
+        eprintln!("usage: pacs_standin <folder> [--port 4242] [--ae ORTHANC] [--dest AE=host:port]...");
+        process::exit(2);
+    };
+    let mut port = 4242;
+    let mut ae_title = "ORTHANC".to_string();
+    let mut destinations = HashMap::new();
+    let mut i = 1;
+    while i + 1 < args.len() {
+        match args[i].as_str() {
+            "--port" => port = args[i + 1].parse().expect("invalid port"),
+            "--ae" => ae_title = args[i + 1].clone(),
+            "--dest" => {
+                let (ae, addr) = args[i + 1].split_once('=').expect("--dest AE=host:port");
+                destinations.insert(ae.to_string(), addr.to_string());
+            }
+            other => {
+                eprintln!("unknown option {}", other);
+                process::exit(2);
+            }
+        }
+        i += 2;
+    }
+
+    let mut records = Vec::new();
+    index(Path::new(folder), &mut records);
+    println!("PACS: {} instances from {}", records.len(), folder);
+    let pacs = Arc::new(Pacs { ae_title, records, destinations });
+
+    let listener = TcpListener::bind(("0.0.0.0", port)).expect("cannot listen");
+    println!("PACS: {} listening on port {}", pacs.ae_title, port);
+    for stream in listener.incoming().flatten() {
+        let pacs = pacs.clone();
+        thread::spawn(move || {
+            if let Err(err) = serve(stream, &pacs) {
+                println!("PACS: ERR {}", err);
+            }
+        });
+    }
+}
+
+fn index(dir: &Path, records: &mut Vec<Record>) {
+    let Ok(entries) = fs::read_dir(dir) else { return };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            index(&path, records);
+            continue;
+        }
+        let Ok(obj) = OpenFileOptions::new().read_until(tags::PIXEL_DATA).open_file(&path) else { continue };
+        let mut values = HashMap::new();
+        for (tag, _) in STUDY_KEYS.iter().chain(&SERIES_KEYS).chain(&IMAGE_KEYS) {
+            values.insert(*tag, get_str(&obj, *tag));
+        }
+        records.push(Record { path: path.to_string_lossy().to_string(), values });
+    }
+}
+
+fn serve(stream: TcpStream, pacs: &Pacs) -> Result<(), String> {
+    let mut assoc = ServerAssociationOptions::new()
+        .accept_any()
+        .ae_title(pacs.ae_title.as_str())
+        .with_abstract_syntax(dimse::VERIFICATION)
+        .with_abstract_syntax(dimse::STUDY_ROOT_FIND)
+        .with_abstract_syntax(dimse::STUDY_ROOT_MOVE)
+        .with_transfer_syntax(dimse::IMPLICIT_VR_LE)
+        .with_transfer_syntax(dimse::EXPLICIT_VR_LE)
+        .establish(stream)
+        .map_err(|err| err.to_string())?;
+
+    while let Some(msg) = dimse::receive_message(&mut assoc)? {
+        let ts = assoc.transfer_syntax(msg.pc_id).unwrap_or_else(|| dimse::IMPLICIT_VR_LE.to_string());
+        let identifier = msg.data.as_ref().and_then(|data| dimse::read_dataset(data, &ts));
+        match (msg.command_field(), identifier) {
+            (dimse::C_ECHO_RQ, _) => {
+                let rsp = response(&msg, dimse::C_ECHO_RSP, dimse::STATUS_SUCCESS, false, &[]);
+                dimse::send_message(&mut assoc, msg.pc_id, &rsp, None)?;
+            }
+            (dimse::C_FIND_RQ, Some(identifier)) => {
+                for found in find(pacs, &identifier) {
+                    let rsp = response(&msg, dimse::C_FIND_RSP, dimse::STATUS_PENDING, true, &[]);
+                    let data = dimse::write_dataset(&found, &ts).ok_or("cannot encode match")?;
+                    dimse::send_message(&mut assoc, msg.pc_id, &rsp, Some(&data))?;
+                }
+                let rsp = response(&msg, dimse::C_FIND_RSP, dimse::STATUS_SUCCESS, false, &[]);
+                dimse::send_message(&mut assoc, msg.pc_id, &rsp, None)?;
+            }
+            (dimse::C_MOVE_RQ, Some(identifier)) => {
+                let rsp = c_move(pacs, &msg, &identifier);
+                dimse::send_message(&mut assoc, msg.pc_id, &rsp, None)?;
+            }
+            (other, _) => println!("PACS: unsupported command 0x{:04x}", other),
+        }
+    }
+    Ok(())
+}
+
+/// matches of a C-FIND identifier, one per unique key of the level
+fn find(pacs: &Pacs, identifier: &InMemDicomObject) -> Vec<InMemDicomObject> {
+    let level = get_str(identifier, tags::QUERY_RETRIEVE_LEVEL);
+    let (unique, keys): (Tag, Vec<(Tag, VR)>) = match level.as_str() {
+        "STUDY" => (tags::STUDY_INSTANCE_UID, STUDY_KEYS.to_vec()),
+        "SERIES" => (tags::SERIES_INSTANCE_UID, [&STUDY_KEYS[..1], &SERIES_KEYS[..]].concat()),
+        _ => (tags::SOP_INSTANCE_UID, [&STUDY_KEYS[..1], &SERIES_KEYS[..], &IMAGE_KEYS[..]].concat()),
+    };
+
+    let mut seen = BTreeSet::new();
+    let mut matches = Vec::new();
+    for record in &pacs.records {
+        let study_modalities = modalities_in_study(pacs, record.get(tags::STUDY_INSTANCE_UID));
+        let is_match = keys.iter().all(|(tag, vr)| {
+            let Ok(element) = identifier.element(*tag) else { return true };
+            let query = element.to_str().map(|s| s.trim_end_matches('\0').trim().to_string()).unwrap_or_default();
+            match *tag {
+                tags::MODALITIES_IN_STUDY => query.is_empty() || query.split('\\').any(|m| study_modalities.contains(m)),
+                _ => matches_value(&query, record.get(*tag), *vr),
+            }
+        });
+        if !is_match || !seen.insert(record.get(unique).to_string()) {
+            continue;
+        }
+        // requested keys with the values of the record
+        let mut elements = vec![dimse::element_str(tags::QUERY_RETRIEVE_LEVEL, VR::CS, &level)];
+        for (tag, vr) in &keys {
+            if identifier.element(*tag).is_err() {
+                continue;
+            }
+            let value = match *tag {
+                tags::MODALITIES_IN_STUDY => study_modalities.iter().cloned().collect::<Vec<_>>().join("\\"),
+                _ => record.get(*tag).to_string(),
+            };
+            elements.push(dimse::element_str(*tag, *vr, &value));
+        }
+        matches.push(InMemDicomObject::from_element_iter(elements));
+    }
+    matches
+}
+
+fn modalities_in_study(pacs: &Pacs, study_uid: &str) -> BTreeSet<String> {
+    pacs.records.iter()
+        .filter(|r| r.get(tags::STUDY_INSTANCE_UID) == study_uid)
+        .map(|r| r.get(tags::MODALITY).to_string())
+        .collect()
+}
+
+/// single value matching: empty matches all, date ranges for DA, wildcards otherwise
+fn matches_value(query: &str, value: &str, vr: VR) -> bool {
+    if query.is_empty() || query == "*" {
+        return true;
+    }
+    if vr == VR::DA {
+        if let Some((from, to)) = query.split_once('-') {
+            return (from.is_empty() || value >= from) && (to.is_empty() || value <= to);
+        }
+    }
+    wildcard(query.as_bytes(), value.as_bytes())
+}
+
+fn wildcard(pattern: &[u8], value: &[u8]) -> bool {
+    match (pattern.first(), value.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => wildcard(&pattern[1..], value) || (!value.is_empty() && wildcard(pattern, &value[1..])),
+        (Some(b'?'), Some(_)) => wildcard(&pattern[1..], &value[1..]),
+        (Some(p), Some(v)) if p == v => wildcard(&pattern[1..], &value[1..]),
+        _ => false,
+    }
+}
+
+fn c_move(pacs: &Pacs, msg: &Message, identifier: &InMemDicomObject) -> Vec<u8> {
+    let destination = msg.command_str(tags::MOVE_DESTINATION);
+    let Some(addr) = pacs.destinations.get(&destination) else {
+        println!("PACS: unknown move destination {}", destination);
+        // move destination unknown
+        return response(msg, dimse::C_MOVE_RSP, 0xA801, false, &[]);
+    };
+    let files: Vec<String> = pacs.records.iter()
+        .filter(|r| [tags::STUDY_INSTANCE_UID, tags::SERIES_INSTANCE_UID, tags::SOP_INSTANCE_UID].iter().all(|tag| {
+            let query = get_str(identifier, *tag);
+            query.is_empty() || query.split('\\').any(|uid| uid == r.get(*tag))
+        }))
+        .map(|r| r.path.clone())
+        .collect();
+
+    let (completed, failed) = match store_files(addr, &pacs.ae_title, &destination, &files) {
+        Ok(statuses) => {
+            let completed = statuses.iter().filter(|s| **s == dimse::STATUS_SUCCESS).count() as u16;
+            (completed, statuses.len() as u16 - completed)
+        }
+        Err(err) => {
+            println!("PACS: C-STORE to {} ERR {}", destination, err);
+            (0, files.len() as u16)
+        }
+    };
+    println!("PACS: moved {}/{} to {}", completed, files.len(), destination);
+    // sub-operations complete, one or more failures
+    let status = if failed == 0 { dimse::STATUS_SUCCESS } else { 0xB000 };
+    response(msg, dimse::C_MOVE_RSP, status, false, &[
+        (tags::NUMBER_OF_REMAINING_SUBOPERATIONS, 0),
+        (tags::NUMBER_OF_COMPLETED_SUBOPERATIONS, completed),
+        (tags::NUMBER_OF_FAILED_SUBOPERATIONS, failed),
+        (tags::NUMBER_OF_WARNING_SUBOPERATIONS, 0),
+    ])
+}
+
+fn response(msg: &Message, command_field: u16, status: u16, with_data: bool, counts: &[(Tag, u16)]) -> Vec<u8> {
+    let msg_id = msg.command.element(tags::MESSAGE_ID).ok().and_then(|e| e.to_int::<u16>().ok()).unwrap_or(0);
+    let mut elements = vec![
+        dimse::element_u16(tags::COMMAND_FIELD, command_field),
+        dimse::element_u16(tags::MESSAGE_ID_BEING_RESPONDED_TO, msg_id),
+        dimse::element_u16(tags::COMMAND_DATA_SET_TYPE, if with_data { dimse::DATA_SET } else { dimse::NO_DATA_SET }),
+        dimse::element_u16(tags::STATUS, status),
+        dimse::element_str(tags::AFFECTED_SOP_CLASS_UID, VR::UI, &msg.command_str(tags::AFFECTED_SOP_CLASS_UID)),
+    ];
+    for (tag, count) in counts {
+        elements.push(dimse::element_u16(*tag, *count));
+    }
+    dimse::command(elements)
+}
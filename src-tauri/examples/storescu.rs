@@ -0,0 +1,67 @@
+//! Minimal C-STORE SCU, a stand-in for the X-ray system when testing the receiver.
+//!
+//!     cargo run --example storescu -- <host:port> <called AE> <file>...
+//!     cargo run --example storescu -- --local <file>...
+//!
+//! `--local` starts the receiver on a free port (inbox in the temp folder),
+//! sends the files to it and prints the received files and the pairs.
+use std::env;
+use std::process;
+use std::sync::{Arc, Mutex};
+use lightbeam_lib::pairing::{read_qa_image, Pair, Pairer};
+use lightbeam_lib::storescp::{ScpConfig, StoreScp};
+use lightbeam_lib::storescu::store_files;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("--local") {
+        local(&args[1..]);
+    } else if args.len() >= 3 {
+        if let Err(err) = send(&args[0], &args[1], &args[2..]) {
+            eprintln!("storescu: {}", err);
+            process::exit(1);
+        }
+    } else {
+        eprintln!("usage: storescu <host:port> <called AE> <file>...\n       storescu --local <file>...");
+        process::exit(2);
+    }
+}
+
+fn local(files: &[String]) {
+    let config = ScpConfig {
+        ae_title: "LIGHTBEAM".to_string(),
+        port: 0,
+        inbox: env::temp_dir().join("lightbeam-storescu"),
+    };
+    let pairs: Arc<Mutex<Vec<Pair>>> = Arc::default();
+    let pairer = Mutex::new(Pairer::default());
+    let scp = {
+        let pairs = pairs.clone();
+        StoreScp::start(config.clone(), move |file| {
+            println!("received {} from {}", file.path, file.calling_ae);
+            if let Some(pair) = read_qa_image(&file.path).and_then(|image| pairer.lock().unwrap().push(image)) {
+                pairs.lock().unwrap().push(pair);
+            }
+        })
+        .expect("cannot start the receiver")
+    };
+
+    let addr = format!("127.0.0.1:{}", scp.port());
+    let res = send(&addr, &config.ae_title, files);
+    scp.stop();
+    if let Err(err) = res {
+        eprintln!("storescu: {}", err);
+        process::exit(1);
+    }
+    for pair in pairs.lock().unwrap().iter() {
+        println!("pair [{}]: large {} small {}", pair.large.detector, pair.large.path, pair.small.path);
+    }
+}
+
+fn send(addr: &str, called_ae: &str, files: &[String]) -> Result<(), String> {
+    let statuses = store_files(addr, "STORESCU", called_ae, files)?;
+    for (file, status) in files.iter().zip(statuses) {
+        println!("sent {} status 0x{:04x}", file, status);
+    }
+    Ok(())
+}
@@ -0,0 +1,250 @@
+use std::fs;
+use dicom::dictionary_std::tags;
+use dicom::object::Tag;
+use ndarray::s;
+use serde::{Deserialize, Serialize};
+use crate::analysis::{detector_details, AnalysisError};
+use crate::cache::ImageCache;
+use crate::history::{History, HistoryEntry};
+use crate::pairing::detector_name;
+use crate::plot::{grid_step, save_plot};
+use crate::tolerance::{all_passed, Check, ToleranceProfile};
+use crate::utils::{get_detail, get_pixel_spacing, get_rescale, get_value, rescaled, DcmObj};
+
+// central ROI side (mm), or this fraction of the image if the pixel spacing is unknown
+const ROI_SIZE_MM: f64 = 20.0;
+const ROI_FRACTION: f64 = 0.1;
+// Exposure in µAs (0018,1153)
+const EXPOSURE_IN_UAS: Tag = Tag(0x0018, 0x1153);
+// plotted range (% of the series mean)
+const PLOT_MAX: f64 = 200.0;
+
+/// central ROI and exposure parameters of one AEC exposure
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AecExposure {
+    pub file_path: String,
+    /// phantom thickness as entered (e.g. mm PMMA)
+    pub thickness: f64,
+    pub kvp: Option<f64>,
+    pub mas: Option<f64>,
+    /// rescaled pixel values of the central ROI
+    pub mean: f64,
+    pub sd: f64,
+    pub snr: f64,
+    pub exposure_index: Option<f64>,
+}
+
+/// detector signal of AEC exposures over phantom thicknesses / kV
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AecResult {
+    pub details: Vec<String>,
+    /// station name (detector id if missing), key of the QA history
+    pub room: String,
+    pub roi_size_px: usize,
+    /// ordered by thickness
+    pub exposures: Vec<AecExposure>,
+    /// max deviation from the series mean (%)
+    pub mean_deviation: f64,
+    pub snr_deviation: f64,
+    /// None if not every image has an Exposure Index
+    pub ei_deviation: Option<f64>,
+    /// coefficient of variation of the ROI means (%)
+    pub mean_cv: f64,
+    pub profile: String,
+    pub checks: Vec<Check>,
+    pub passed: bool,
+}
+
+/// AEC consistency: the detector signal behind the phantom should stay
+/// constant over thicknesses and kV; the result is added to the room history
+/// of `history`
+///
+/// csv_path: one row per exposure; plot_path: mean (blue), SNR (red) and EI
+/// (green) in % of the series mean against the thickness
+pub fn run_aec(file_paths: &[String], thickness: &[f64], csv_path: &str, plot_path: &str, cache: &ImageCache, profile: &ToleranceProfile, history: &History) -> Result<AecResult, AnalysisError> {
+    if file_paths.len() != thickness.len() {
+        return Err(AnalysisError::Measurement("one phantom thickness per image is needed".to_string()));
+    }
+    if file_paths.len() < 2 {
+        return Err(AnalysisError::Measurement("at least two exposures are needed".to_string()));
+    }
+    let mut exposures = Vec::with_capacity(file_paths.len());
+    let mut first = None;
+    let mut roi_size_px = 0;
+    for (file_path, &thickness) in file_paths.iter().zip(thickness) {
+        let image = cache.get(file_path).ok_or_else(|| AnalysisError::Load(file_path.to_owned()))?;
+        let obj = &image.obj;
+        let (h, w) = image.arr.dim();
+        let roi = match get_pixel_spacing(obj) {
+            Some(spacing) => (ROI_SIZE_MM / spacing).round() as usize,
+            None => (h.min(w) as f64 * ROI_FRACTION) as usize,
+        };
+        roi_size_px = roi.clamp(8, h.min(w));
+        let (top, left) = ((h - roi_size_px) / 2, (w - roi_size_px) / 2);
+        let block = rescaled(image.arr.slice(s![top..top + roi_size_px, left..left + roi_size_px]), get_rescale(obj));
+        let mean = block.mean().unwrap_or(0.0);
+        let sd = block.std(1.0);
+        exposures.push(AecExposure {
+            file_path: file_path.to_owned(),
+            thickness,
+            kvp: get_value(obj, tags::KVP),
+            mas: exposure_mas(obj),
+            mean,
+            sd,
+            snr: if sd > 0.0 { mean / sd } else { 0.0 },
+            exposure_index: get_value(obj, tags::EXPOSURE_INDEX),
+        });
+        first.get_or_insert(image.clone());
+    }
+    let image = first.ok_or_else(|| AnalysisError::Measurement("no images".to_string()))?;
+    let obj = &image.obj;
+    exposures.sort_by(|a, b| a.thickness.total_cmp(&b.thickness));
+
+    let means: Vec<f64> = exposures.iter().map(|e| e.mean).collect();
+    let snrs: Vec<f64> = exposures.iter().map(|e| e.snr).collect();
+    let eis: Option<Vec<f64>> = exposures.iter().map(|e| e.exposure_index).collect();
+    let mean_deviation = max_deviation(&means);
+    let snr_deviation = max_deviation(&snrs);
+    let ei_deviation = eis.as_deref().map(max_deviation);
+    let average = means.iter().sum::<f64>() / means.len() as f64;
+    let variance = means.iter().map(|m| (m - average).powi(2)).sum::<f64>() / (means.len() - 1) as f64;
+    let mean_cv = if average != 0.0 { variance.sqrt() / average.abs() * 100.0 } else { 0.0 };
+
+    let mut checks = vec![
+        profile.check("aec.mean_deviation", mean_deviation),
+        profile.check("aec.snr_deviation", snr_deviation),
+    ];
+    if let Some(ei_deviation) = ei_deviation {
+        checks.push(profile.check("aec.ei_deviation", ei_deviation));
+    }
+    let passed = all_passed(&checks);
+
+    let room = room_name(obj);
+    let mut entry = HistoryEntry::for_image("aec", obj);
+    // keyed by the room, in the room store
+    entry.detector = room.clone();
+    for check in &checks {
+        entry.values.insert(check.key.trim_start_matches("aec.").to_string(), check.value);
+    }
+    entry.values.insert("mean_cv".to_string(), mean_cv);
+    entry.passed = Some(passed);
+    if let Err(err) = history.rooms().append(&entry) {
+        println!("HISTORY: ERR {}", err);
+    }
+
+    let result = AecResult {
+        details: detector_details(obj),
+        room,
+        roi_size_px,
+        exposures,
+        mean_deviation,
+        snr_deviation,
+        ei_deviation,
+        mean_cv,
+        profile: profile.name.clone(),
+        checks,
+        passed,
+    };
+    save_exposures(&result, csv_path, plot_path)?;
+    println!("AEC: mean deviation {:.2}%, SNR deviation {:.2}%", mean_deviation, snr_deviation);
+    Ok(result)
+}
+
+/// mAs from Exposure in µAs, Exposure (mAs) or tube current x exposure time
+fn exposure_mas(obj: &DcmObj) -> Option<f64> {
+    get_value(obj, EXPOSURE_IN_UAS).map(|uas| uas / 1000.0)
+        .or_else(|| get_value(obj, tags::EXPOSURE))
+        .or_else(|| Some(get_value(obj, tags::X_RAY_TUBE_CURRENT)? * get_value(obj, tags::EXPOSURE_TIME)? / 1000.0))
+}
+
+/// station name, the detector id if missing
+fn room_name(obj: &DcmObj) -> String {
+    let station = get_detail(obj, tags::STATION_NAME);
+    if station == " - " || station.trim().is_empty() {
+        detector_name(obj)
+    } else {
+        station.trim().to_string()
+    }
+}
+
+/// max |value - mean| / mean (%)
+fn max_deviation(values: &[f64]) -> f64 {
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    if mean == 0.0 {
+        return 0.0;
+    }
+    values.iter().map(|v| (v - mean).abs() / mean.abs() * 100.0).fold(0.0, f64::max)
+}
+
+fn save_exposures(result: &AecResult, csv_path: &str, plot_path: &str) -> Result<(), AnalysisError> {
+    let optional = |v: Option<f64>| v.map(|v| format!("{:.2}", v)).unwrap_or_default();
+    let mut csv = String::from("file,thickness,kvp,mas,mean,sd,snr,ei\n");
+    for e in &result.exposures {
+        csv.push_str(&format!("\"{}\",{},{},{},{:.2},{:.3},{:.2},{}\n",
+            e.file_path, e.thickness, optional(e.kvp), optional(e.mas), e.mean, e.sd, e.snr, optional(e.exposure_index)));
+    }
+    fs::write(csv_path, csv).map_err(|err| AnalysisError::Measurement(err.to_string()))?;
+
+    let relative = |values: Vec<Option<f64>>| -> Vec<(f64, f64)> {
+        let present: Vec<f64> = values.iter().flatten().copied().collect();
+        let mean = present.iter().sum::<f64>() / present.len().max(1) as f64;
+        if mean == 0.0 {
+            return vec![];
+        }
+        result.exposures.iter().zip(values)
+            .filter_map(|(e, v)| Some((e.thickness, v? / mean * 100.0)))
+            .collect()
+    };
+    let curves = [
+        relative(result.exposures.iter().map(|e| Some(e.mean)).collect()),
+        relative(result.exposures.iter().map(|e| Some(e.snr)).collect()),
+        relative(result.exposures.iter().map(|e| e.exposure_index).collect()),
+    ];
+    let x_max = result.exposures.iter().map(|e| e.thickness).fold(0.0, f64::max).max(f64::EPSILON) * 1.1;
+    save_plot(plot_path, &curves, x_max, PLOT_MAX, grid_step(x_max), 20.0)
+        .map_err(AnalysisError::Measurement)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dicom::core::VR;
+    use crate::utils::test_header;
+
+    #[test]
+    fn deviation_from_the_series_mean() {
+        assert_eq!(max_deviation(&[100.0, 100.0, 100.0]), 0.0);
+        // mean 100: 90 is 10% off
+        assert!((max_deviation(&[90.0, 100.0, 110.0]) - 10.0).abs() < 1e-9);
+        assert!((max_deviation(&[-90.0, -110.0]) - 10.0).abs() < 1e-9);
+        assert_eq!(max_deviation(&[-1.0, 1.0]), 0.0);
+    }
+
+    #[test]
+    fn mas_from_uas() {
+        let obj = test_header(&[(EXPOSURE_IN_UAS, VR::IS, "2500"), (tags::EXPOSURE, VR::IS, "3")]);
+        assert_eq!(exposure_mas(&obj), Some(2.5));
+    }
+
+    #[test]
+    fn mas_from_exposure() {
+        let obj = test_header(&[(tags::EXPOSURE, VR::IS, "4"), (tags::X_RAY_TUBE_CURRENT, VR::IS, "200")]);
+        assert_eq!(exposure_mas(&obj), Some(4.0));
+    }
+
+    #[test]
+    fn mas_from_current_and_time() {
+        // 250 mA x 20 ms
+        let obj = test_header(&[(tags::X_RAY_TUBE_CURRENT, VR::IS, "250"), (tags::EXPOSURE_TIME, VR::IS, "20")]);
+        assert_eq!(exposure_mas(&obj), Some(5.0));
+        assert_eq!(exposure_mas(&test_header(&[(tags::X_RAY_TUBE_CURRENT, VR::IS, "250")])), None);
+    }
+
+    #[test]
+    fn room_falls_back_to_the_detector() {
+        let station = test_header(&[(tags::STATION_NAME, VR::SH, "ROOM1 "), (tags::DETECTOR_ID, VR::SH, "DR-0042")]);
+        assert_eq!(room_name(&station), "ROOM1");
+        let detector = test_header(&[(tags::DETECTOR_ID, VR::SH, "DR-0042")]);
+        assert_eq!(room_name(&detector), "DR-0042");
+    }
+}
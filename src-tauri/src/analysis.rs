@@ -0,0 +1,395 @@
+use std::collections::HashMap;
+use std::fmt;
+use dicom::dictionary_std::tags;
+use ndarray::{s, Array};
+use ndarray_stats::QuantileExt;
+use serde::{Deserialize, Serialize};
+use crate::rotation::{rotate, rotate_roi, Interpolation, Roi};
+use crate::cache::ImageCache;
+use crate::defects::{mask_defects, DefectMask};
+use crate::flatness::{field_flatness, save_flatness_plot, Flatness};
+use crate::utils::{save_to_image, save_to_image_u8, get_detail, get_pixel_spacing, argmax, inv_lut, find_center_line, rotate_array, fint_horizontal_line, find_vertical_line, boxs_posision, get_rotated_crop_area, find_edges_pos, rectangle_edge_points, length_line, circle_area, split_q_circle, farthest_q, center_point, pixel2cm, distance_pixel, calculate_angle, find_edge_tool, find_mean, mean_profile, tool_search_scale};
+use crate::utils::{U8Array, U16View};
+
+/// stages of the collimator analysis, in order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Load,
+    ToolDetection,
+    Rotation,
+    LineDetection,
+    EdgeDetection,
+    CircleAnalysis,
+    Rendering,
+}
+
+impl Stage {
+    /// progress(%) when the stage starts
+    pub fn percent(&self) -> u8 {
+        match self {
+            Stage::Load => 0,
+            Stage::ToolDetection => 20,
+            Stage::Rotation => 35,
+            Stage::LineDetection => 50,
+            Stage::EdgeDetection => 60,
+            Stage::CircleAnalysis => 75,
+            Stage::Rendering => 85,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AnalysisError {
+    /// file could not be opened or decoded
+    Load(String),
+    /// image loaded but the measurement is not possible (e.g. no edge found)
+    Measurement(String),
+    Cancelled,
+}
+
+impl fmt::Display for AnalysisError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AnalysisError::Load(path) => write!(f, "cannot load DICOM file: {}", path),
+            AnalysisError::Measurement(reason) => write!(f, "cannot analyse image: {}", reason),
+            AnalysisError::Cancelled => write!(f, "analysis cancelled"),
+        }
+    }
+}
+
+/// collimator and beam alignment result (positions in the rotated image)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CollimatorResult {
+    /// beam center(x, y) and circle center(xc, yc) in the circle image
+    pub circle_points: [usize; 4],
+    /// beam alignment [distance(cm), angle(degree)]
+    pub beam_alignment: [f32; 2],
+    /// edge corners: top-left, top-right, bottom-left, bottom-right [x, y]
+    pub points: [[i32; 2]; 4],
+    /// left, right, top, bottom [[length, error], [0, 0]] (cm)
+    pub lengths: Vec<[[f32; 2]; 2]>,
+    /// most error position of each edge (e.g. "top-left")
+    pub most_error: Vec<String>,
+    pub xpoints: Vec<i32>,
+    pub ypoints: Vec<i32>,
+    /// hospital, machine, address, acquisition date, detector type,
+    /// detector id, pixel size, matrix size, bit depth
+    pub details: Vec<String>,
+    /// heel effect and flatness of the large field, None if not measurable
+    #[serde(default)]
+    pub flatness: Option<Flatness>,
+}
+
+/// beam alignment tolerance (degree)
+pub const BEAM_ANGLE_LIMIT: f32 = 3.0;
+/// edge names of `CollimatorResult::lengths`
+pub const EDGE_NAMES: [&str; 4] = ["X1", "X2", "Y1", "Y2"];
+
+/// one collimator edge against the light field
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EdgeEvaluation {
+    pub name: String,
+    pub length_cm: f32,
+    pub error_cm: f32,
+    /// error in % of SID
+    pub error_percent: f32,
+    pub most_error: String,
+    pub passed: bool,
+}
+
+/// pass/fail of a result for a SID and criteria, as in the result screen
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Evaluation {
+    pub sid_cm: f32,
+    /// edge error tolerance in % of SID
+    pub criteria: f32,
+    pub edges: Vec<EdgeEvaluation>,
+    /// field size (X1 + X2, Y1 + Y2) in cm
+    pub field_size_cm: [f32; 2],
+    pub collimator_passed: bool,
+    pub beam_distance_cm: f32,
+    pub beam_angle: f32,
+    pub beam_passed: bool,
+}
+
+/// evaluate edge errors against `criteria` % of `sid_cm`, beam angle against 3 degree
+pub fn evaluate(result: &CollimatorResult, sid_cm: f32, criteria: f32) -> Evaluation {
+    let edges: Vec<EdgeEvaluation> = result.lengths.iter()
+        .zip(EDGE_NAMES)
+        .zip(&result.most_error)
+        .map(|((length, name), most_error)| {
+            let [length_cm, error_cm] = length[0];
+            let error_percent = error_cm.abs() / sid_cm * 100.0;
+            EdgeEvaluation {
+                name: name.to_string(),
+                length_cm,
+                error_cm,
+                error_percent,
+                most_error: most_error.to_owned(),
+                passed: error_percent <= criteria,
+            }
+        })
+        .collect();
+    let length = |i: usize| edges.get(i).map(|e| e.length_cm).unwrap_or(0.0);
+    let [beam_distance_cm, beam_angle] = result.beam_alignment;
+    Evaluation {
+        sid_cm,
+        criteria,
+        field_size_cm: [length(0) + length(1), length(2) + length(3)],
+        collimator_passed: edges.iter().all(|e| e.passed),
+        edges,
+        beam_distance_cm,
+        beam_angle,
+        beam_passed: beam_angle <= BEAM_ANGLE_LIMIT,
+    }
+}
+
+/// run the collimator test on [large field, small field]
+///
+/// `on_stage` is called when each stage starts, return false to cancel.
+/// save_path: [overlay image, circle image, (optional) flatness plot], images
+/// are read through `cache`.
+/// With `defects`, bad pixels and lines of a flat-field image are masked in the tool area first.
+pub fn run_collimator(file_paths: &[String], save_path: &[String], cache: &ImageCache, defects: Option<&DefectMask>, on_stage: &mut dyn FnMut(Stage) -> bool) -> Result<CollimatorResult, AnalysisError> {
+    let [large_path, small_path, ..] = file_paths else {
+        return Err(AnalysisError::Measurement("a large and a small field image are needed".to_string()));
+    };
+    let [overlay_path, circle_path, ..] = save_path else {
+        return Err(AnalysisError::Measurement("an overlay and a circle image path are needed".to_string()));
+    };
+    let mut stage = |s: Stage| if on_stage(s) { Ok(()) } else { Err(AnalysisError::Cancelled) };
+
+    stage(Stage::Load)?;
+    // decode both fields at the same time (or take them from the cache)
+    let (large, small) = rayon::join(
+        || cache.get(large_path),
+        || cache.get(small_path),
+    );
+    let large = large.ok_or_else(|| AnalysisError::Load(large_path.to_owned()))?;
+    let small = small.ok_or_else(|| AnalysisError::Load(small_path.to_owned()))?;
+    let obj = &large.obj;
+
+    stage(Stage::ToolDetection)?;
+    // Large field: find pattern of test-tool
+    let details = detector_details(obj);
+
+    // Find Test-Tool
+    let [row1, row2, col1, col2] = arr_correction(large.arr.view(), get_pixel_spacing(obj));
+    // copy only the tool area, the cached images stay untouched
+    // small field uses the large field area
+    let mut arr = large.arr.slice(s![row1..row2, col1..col2]).to_owned();
+    let mut arr2 = small.arr.slice(s![row1..row2, col1..col2]).to_owned();
+    if let Some(defects) = defects {
+        // defects come from a flat field, edges and tool marks are never taken for defects
+        let map = defects.load(cache, large.arr.dim())?.crop(row1..row2, col1..col2);
+        rayon::join(|| mask_defects(&mut arr, &map), || mask_defects(&mut arr2, &map));
+    }
+    let mut h = arr.nrows();
+    let mut w = arr.ncols();
+    // check is rotate
+    let is_rotate = (row2-row1) > (col2-col1);
+    if is_rotate {
+        (arr, arr2) = rayon::join(
+            || rotate_array(3.14/2.0, arr.view()),
+            || rotate_array(3.14/2.0, arr2.view()),
+        );
+        h = arr.nrows();
+        w = arr.ncols();
+    }
+    // check is inv
+    let hp = (0.2*(h as f32)) as usize;
+    let wp = (0.06*(w as f32)) as usize;
+    let focus_l = arr.slice(s![hp..h-hp, wp*2..wp*3]);
+    let values = argmax(focus_l, 0);
+    let mut counts: HashMap<usize, u16> = HashMap::new();
+    for n in &values {
+        let count = counts.entry(*n).or_insert(0);
+        *count += 1;
+    }
+    let max_val = counts.values().copied().max().unwrap_or(0);
+    let is_inv = (max_val as f32/focus_l.ncols() as f32) < 0.3;
+    if is_inv {
+        (arr, arr2) = rayon::join(
+            || inv_lut(arr.view()),
+            || inv_lut(arr2.view()),
+        );
+    }
+    // heel effect once the signal rises with the dose
+    let flatness = field_flatness(arr.view(), get_pixel_spacing(obj));
+
+    stage(Stage::Rotation)?;
+    // Find Center Line
+    let (_, _, _, _, theta_r) = find_center_line(arr.view());
+    // // Adjust angle
+    // small field: whole image only for the overlay (nearest),
+    // measured areas are resampled bilinear from arr2 below
+    let fill2 = *arr2.max().unwrap();
+    let (rotated_arr, rotated_arr2) = rayon::join(
+        || rotate_array(theta_r, arr.view()),
+        || rotate(arr2.view(), theta_r, Interpolation::Nearest, fill2),
+    );
+
+    stage(Stage::LineDetection)?;
+    // Fine Lines in Rotated array
+    let (ypoints, xpoints) = rayon::join(
+        || fint_horizontal_line(rotated_arr.view()),
+        || find_vertical_line(rotated_arr.view()),
+    );
+
+    stage(Stage::EdgeDetection)?;
+    // // Small field
+    // save_to_image(rotated_arr2.view(), "c:/Users/alant/Desktop/arr2.jpg".to_string());
+
+    // Find the Edges
+    // boxs_position(area for crop)
+    let boxs_pos = boxs_posision(&xpoints, &ypoints, rotated_arr2.view());
+    // get crop area (rotate only the areas)
+    let crop_areas = get_rotated_crop_area(&boxs_pos, arr2.view(), theta_r, fill2);
+    let crop_areas: Vec<U16View> = crop_areas.iter().map(|a| a.view()).collect();
+    // // edges positions
+    let xypoints = [xpoints[0], xpoints[0], xpoints[2], xpoints[2], ypoints[0], ypoints[0], ypoints[2], ypoints[2]];
+    let edges_pos = find_edges_pos(&crop_areas, &boxs_pos, xypoints, &ypoints);
+    // find 4 points(top-left[x, y], top-right, bottom_left, bottom-right) of the edges
+    let (points, mbs) = rectangle_edge_points(boxs_pos, edges_pos);
+    // // Result: left, right, top, bottom [x1, y1, x2, y2, length]
+    let (lengths, most_error) = length_line(points, mbs, &xpoints, &ypoints);
+
+    stage(Stage::CircleAnalysis)?;
+    // Fine the circles
+    let cir_box = circle_area(&xpoints, &ypoints);
+    let cir_arr = rotate_roi(arr2.view(), theta_r, Roi::from_box(&cir_box), Interpolation::Bilinear, fill2);
+    let (q_arr, white_ts, (xc, yc)) = split_q_circle(cir_arr.view());
+    let (farthest_q, farthest_point) = farthest_q(q_arr, white_ts);
+    let (x, y) = center_point(farthest_point, farthest_q, xc, yc);
+    let cir_distance = pixel2cm(&ypoints, distance_pixel(x, y, xc as usize, yc as usize));
+    let cir_angle = calculate_angle(cir_distance);
+
+    stage(Stage::Rendering)?;
+    let add_arr = add_arrays(rotated_arr.view(), rotated_arr2.view());
+    // save_to_image_u8(add_arr, "c:/Users/alant/Downloads/result.png".to_string());
+    save_to_image_u8(add_arr, overlay_path.to_owned());
+    save_to_image(cir_arr.view(), circle_path.to_owned());
+    if let (Some(flatness), Some(path)) = (&flatness, save_path.get(2)) {
+        if let Err(err) = save_flatness_plot(flatness, path) {
+            println!("FLATNESS: ERR {}", err);
+        }
+    }
+
+    Ok(CollimatorResult {
+        circle_points: [x, y, xc as usize, yc as usize],
+        beam_alignment: [cir_distance, cir_angle],
+        points,
+        lengths,
+        most_error,
+        xpoints,
+        ypoints,
+        details,
+        flatness,
+    })
+}
+
+/// indices of `detector_details` blanked by the de-identification
+pub const DETAIL_HOSPITAL: usize = 0;
+pub const DETAIL_ADDRESS: usize = 2;
+pub const DETAIL_DETECTOR_ID: usize = 5;
+
+/// header details shown with a result, see `CollimatorResult::details`
+pub fn detector_details(obj: &dicom::object::DefaultDicomObject) -> Vec<String> {
+    let hospital = get_detail(obj, tags::INSTITUTION_NAME);
+    let manufacturer = get_detail(obj, tags::MANUFACTURER);
+    let acquisition_date = get_detail(obj, tags::ACQUISITION_DATE);
+    let detector_type = get_detail(obj, tags::DETECTOR_TYPE);
+    let detector_id = get_detail(obj, tags::DETECTOR_ID);
+    let modality = get_detail(obj, tags::MODALITY);
+    let mut machine = " - ".to_string();
+    if manufacturer != " - ".to_string() {
+        machine = format!("{} [{}]", manufacturer, modality);
+    }
+    let address = get_detail(obj, tags::INSTITUTION_ADDRESS);
+    let spatial_resolution = get_detail(obj, tags::SPATIAL_RESOLUTION);
+    let mut pixel_size = " - ".to_string();
+    if spatial_resolution != " - ".to_string() {
+        pixel_size = format!("{}x{} mm", spatial_resolution, spatial_resolution);
+    }
+    let rows_ = get_detail(obj, tags::ROWS);
+    let cols_ = get_detail(obj, tags::COLUMNS);
+    let mut matrix_size = format!("");
+    if (rows_ != " - ".to_string()) && (cols_ != " - ".to_string()) {
+        matrix_size = format!("{}x{}", rows_, cols_);
+    }
+    let bit_depth = get_detail(obj, tags::BITS_STORED);
+    vec![hospital, machine, address, acquisition_date, detector_type, detector_id, pixel_size, matrix_size, bit_depth]
+}
+
+fn arr_correction(arr: U16View, pixel_spacing: Option<f64>) -> [usize; 4] {
+    // crop array as expect.
+    // Find Test-Tool
+    let shape = arr.shape();
+    let h = shape[0];
+    let w = shape[1];
+    let search = tool_search_scale(h, w, pixel_spacing);
+    let offset = search.offset;
+    // find x-axis
+    let focus_x1_avg = mean_profile(arr.slice(s![
+        (h/2)-offset..h/2, offset..w/2
+    ]), 0);
+    let ts = find_mean(&focus_x1_avg);
+    let x1 = find_edge_tool(&focus_x1_avg, offset, ts, search.edge_run) + offset;
+
+    let focus_y1_avg = mean_profile(arr.slice(s![
+        offset..h/2, w/3..(w/3)+offset
+    ]), 1);
+    let ts = find_mean(&focus_y1_avg);
+    let y1 = find_edge_tool(&focus_y1_avg, offset, ts, search.edge_run) + offset;
+
+    let mut focus_x2_avg = mean_profile(arr.slice(s![
+        h/2-offset..h/2, w/2..w-offset
+    ]), 0);
+    focus_x2_avg.reverse();
+    let n = focus_x2_avg.len();
+    let ts = find_mean(&focus_x2_avg);
+    let x2 = n - find_edge_tool(&focus_x2_avg, offset, ts, search.edge_run) + w/2;
+
+    let mut focus_y2_avg = mean_profile(arr.slice(s![
+        h/2..h-offset, w/3..(w/3)+offset
+    ]), 1);
+    focus_y2_avg.reverse();
+    let n = focus_y2_avg.len();
+    let ts = find_mean(&focus_y2_avg);
+    let y2 = n - find_edge_tool(&focus_y2_avg, offset, ts, search.edge_run) + h/2;
+
+    [y1, y2, x1, x2]
+}
+
+/// add 2 array
+fn add_arrays(arr1: U16View, arr2: U16View) -> U8Array {
+    let nrows = arr1.nrows();
+    let ncols = arr1.ncols();
+    let max_v = *arr1.max().unwrap() as f32 * 2.0;
+    let mut add_arr = vec![];
+    for r in 0..nrows {
+        for c in 0..ncols {
+            let add_v = arr1[(r, c)] as u32 + arr2[(r, c)] as u32;
+            let v_u8 = ((add_v as f32/max_v) * 255.0) as u8;
+            add_arr.push(v_u8);
+        }
+    }
+    Array::from_shape_vec((nrows, ncols), add_arr).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collimator_needs_two_images_and_paths() {
+        let cache = ImageCache::default();
+        let paths = |n: usize| -> Vec<String> { (0..n).map(|i| format!("{}.png", i)).collect() };
+        let mut run = |files: &[String], save: &[String]| run_collimator(files, save, &cache, None, &mut |_| true);
+        assert!(matches!(run(&paths(1), &paths(2)), Err(AnalysisError::Measurement(_))));
+        assert!(matches!(run(&paths(2), &paths(1)), Err(AnalysisError::Measurement(_))));
+        // lengths are fine, the files do not exist
+        assert!(matches!(run(&paths(2), &paths(2)), Err(AnalysisError::Load(_))));
+    }
+}
@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use serde::Serialize;
+use serde_json::json;
+use tiny_http::{Header, Method, Request, Response, Server};
+use crate::analysis::{evaluate, CollimatorResult, Stage};
+use crate::cache::ImageCache;
+use crate::deident::{deidentify_result, DeidentOptions};
+use crate::jobs::{JobEvent, JobRegistry};
+use crate::report::collimator_report;
+
+// how often the request loop checks the stop flag
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+// largest accepted DICOM upload
+const MAX_UPLOAD: u64 = 256 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiStatus {
+    WaitingForFiles,
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+/// analysis submitted over HTTP, files live in `dir`
+#[derive(Debug, Clone, Serialize)]
+struct ApiAnalysis {
+    id: u64,
+    status: ApiStatus,
+    stage: Option<Stage>,
+    percent: u8,
+    error: Option<String>,
+    #[serde(skip)]
+    dir: PathBuf,
+    #[serde(skip)]
+    job_id: Option<u64>,
+    #[serde(skip)]
+    result: Option<CollimatorResult>,
+    sid_cm: f32,
+    criteria: f32,
+}
+
+type Analyses = Arc<Mutex<HashMap<u64, ApiAnalysis>>>;
+
+/// local HTTP/JSON API, runs analyses through the same jobs as the GUI
+///
+/// POST   /analyses                    new analysis, returns its id
+/// PUT    /analyses/{id}/large|small   upload a DICOM file (request body)
+/// POST   /analyses/{id}/start         start, optional ?sid=100&criteria=1
+/// GET    /analyses/{id}               status, stage and percent
+/// GET    /analyses/{id}/result        result and evaluation
+/// GET    /analyses/{id}/overlay.png   overlay image
+/// GET    /analyses/{id}/report.pdf    PDF report
+/// DELETE /analyses/{id}               cancel and remove
+///
+/// every request is handled on its own thread, a slow upload does not hold up the status polls
+pub struct ApiServer {
+    pub port: u16,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ApiServer {
+    /// listen on `addr` (e.g. "127.0.0.1:8787"), files are kept in `work_dir`
+    pub fn start(addr: &str, work_dir: PathBuf, cache: ImageCache) -> Result<ApiServer, String> {
+        fs::create_dir_all(&work_dir).map_err(|err| err.to_string())?;
+        let server = Server::http(addr).map_err(|err| format!("cannot listen on {}: {}", addr, err))?;
+        let port = server.server_addr().to_ip().map(|a| a.port()).unwrap_or(0);
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let next_id = AtomicU64::new(first_free_id(&work_dir));
+                let api = Arc::new(Api { work_dir, cache, jobs: JobRegistry::default(), analyses: Analyses::default(), next_id });
+                while !stop.load(Ordering::Relaxed) {
+                    match server.recv_timeout(POLL_INTERVAL) {
+                        Ok(Some(request)) => {
+                            let api = api.clone();
+                            thread::spawn(move || api.handle(request));
+                        }
+                        Ok(None) => {}
+                        Err(err) => println!("API: ERR {}", err),
+                    }
+                }
+            })
+        };
+        println!("API: listening on {}", addr);
+        Ok(ApiServer { port, stop, handle: Some(handle) })
+    }
+
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for ApiServer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+struct Api {
+    work_dir: PathBuf,
+    cache: ImageCache,
+    jobs: JobRegistry,
+    analyses: Analyses,
+    // never reused, the directories of earlier ids may still hold their files
+    next_id: AtomicU64,
+}
+
+// (status code, content type, body)
+type Reply = (u16, &'static str, Vec<u8>);
+
+impl Api {
+    fn handle(&self, mut request: Request) {
+        let url = request.url().to_string();
+        let (path, query) = url.split_once('?').unwrap_or((url.as_str(), ""));
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        let method = request.method().clone();
+
+        let reply = match (&method, segments.as_slice()) {
+            (Method::Post, ["analyses"]) => self.create(),
+            (Method::Put, ["analyses", id, field @ ("large" | "small")]) => self.upload(id, field, &mut request),
+            (Method::Post, ["analyses", id, "start"]) => self.start(id, query),
+            (Method::Get, ["analyses", id]) => self.with(id, |a| json_reply(200, &a)),
+            (Method::Get, ["analyses", id, "result"]) => self.result(id),
+            (Method::Get, ["analyses", id, "overlay.png"]) => self.file(id, "overlay.png", "image/png"),
+            (Method::Get, ["analyses", id, "report.pdf"]) => self.report(id),
+            (Method::Delete, ["analyses", id]) => self.delete(id),
+            _ => error_reply(404, "not found"),
+        };
+
+        let (code, content_type, body) = reply;
+        let header = Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes()).unwrap();
+        let response = Response::from_data(body).with_status_code(code).with_header(header);
+        if let Err(err) = request.respond(response) {
+            println!("API: ERR {}", err);
+        }
+    }
+
+    fn create(&self) -> Reply {
+        let mut analyses = self.analyses.lock().unwrap();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let dir = self.work_dir.join(id.to_string());
+        if let Err(err) = fs::create_dir_all(&dir) {
+            return error_reply(500, &err.to_string());
+        }
+        let analysis = ApiAnalysis {
+            id,
+            status: ApiStatus::WaitingForFiles,
+            stage: None,
+            percent: 0,
+            error: None,
+            dir,
+            job_id: None,
+            result: None,
+            sid_cm: 100.0,
+            criteria: 1.0,
+        };
+        let reply = json_reply(201, &analysis);
+        analyses.insert(id, analysis);
+        reply
+    }
+
+    /// clone of the analysis `id`
+    fn get(&self, id: &str) -> Option<ApiAnalysis> {
+        let id: u64 = id.parse().ok()?;
+        self.analyses.lock().unwrap().get(&id).cloned()
+    }
+
+    fn with(&self, id: &str, f: impl FnOnce(ApiAnalysis) -> Reply) -> Reply {
+        match self.get(id) {
+            Some(analysis) => f(analysis),
+            None => error_reply(404, "unknown analysis"),
+        }
+    }
+
+    fn upload(&self, id: &str, field: &str, request: &mut Request) -> Reply {
+        self.with(id, |analysis| {
+            if analysis.status != ApiStatus::WaitingForFiles {
+                return error_reply(409, "analysis already started");
+            }
+            if request.body_length().is_some_and(|len| len as u64 > MAX_UPLOAD) {
+                return error_reply(413, "file too large");
+            }
+            let mut data = Vec::new();
+            // one byte more than allowed tells an oversized body from a full one
+            if let Err(err) = request.as_reader().take(MAX_UPLOAD + 1).read_to_end(&mut data) {
+                return error_reply(400, &err.to_string());
+            }
+            if data.len() as u64 > MAX_UPLOAD {
+                return error_reply(413, "file too large");
+            }
+            if data.len() < 132 || &data[128..132] != b"DICM" {
+                return error_reply(415, "not a DICOM file (Part 10)");
+            }
+            // written under another name first, a concurrent start only sees whole files
+            let path = analysis.dir.join(format!("{}.dcm", field));
+            let part = path.with_extension("part");
+            match fs::write(&part, data).and_then(|_| fs::rename(&part, &path)) {
+                Ok(_) => json_reply(200, &analysis),
+                Err(err) => error_reply(500, &err.to_string()),
+            }
+        })
+    }
+
+    fn start(&self, id: &str, query: &str) -> Reply {
+        self.with(id, |analysis| {
+            if analysis.status != ApiStatus::WaitingForFiles {
+                return error_reply(409, "analysis already started");
+            }
+            let file_paths: Vec<String> = ["large.dcm", "small.dcm"].iter()
+                .map(|name| analysis.dir.join(name).to_string_lossy().to_string())
+                .collect();
+            if !file_paths.iter().all(|p| PathBuf::from(p).exists()) {
+                return error_reply(409, "upload the large and small field first");
+            }
+            let save_path = vec![
+                analysis.dir.join("overlay.png").to_string_lossy().to_string(),
+                analysis.dir.join("circle.png").to_string_lossy().to_string(),
+                analysis.dir.join("flatness.png").to_string_lossy().to_string(),
+            ];
+            let params = query_params(query);
+            let sid_cm = params.get("sid").and_then(|v| v.parse().ok()).unwrap_or(100.0);
+            let criteria = params.get("criteria").and_then(|v| v.parse().ok()).unwrap_or(1.0);
+
+            let analyses = self.analyses.clone();
+            let analysis_id = analysis.id;
+            let job_id = self.jobs.start(file_paths, save_path, self.cache.clone(), None, move |event| {
+                let mut analyses = analyses.lock().unwrap();
+                let Some(a) = analyses.get_mut(&analysis_id) else { return };
+                match event {
+                    JobEvent::Progress { stage, percent, .. } => {
+                        a.stage = Some(stage);
+                        a.percent = percent;
+                    }
+                    JobEvent::Done { result, .. } => {
+                        a.status = ApiStatus::Done;
+                        a.percent = 100;
+                        a.result = Some(result);
+                    }
+                    JobEvent::Failed { error, .. } => {
+                        a.status = ApiStatus::Failed;
+                        a.error = Some(error);
+                    }
+                    JobEvent::Cancelled { .. } => a.status = ApiStatus::Cancelled,
+                }
+            });
+
+            let mut analyses = self.analyses.lock().unwrap();
+            let Some(a) = analyses.get_mut(&analysis.id) else { return error_reply(404, "unknown analysis") };
+            // the job may already have finished
+            if a.status == ApiStatus::WaitingForFiles {
+                a.status = ApiStatus::Running;
+            }
+            a.job_id = Some(job_id);
+            a.sid_cm = sid_cm;
+            a.criteria = criteria;
+            json_reply(202, &*a)
+        })
+    }
+
+    fn result(&self, id: &str) -> Reply {
+        self.with(id, |analysis| match &analysis.result {
+            Some(result) => json_reply(200, &json!({
+                "result": deidentify_result(result, &DeidentOptions::default()),
+                "evaluation": evaluate(result, analysis.sid_cm, analysis.criteria),
+            })),
+            None => error_reply(409, "analysis not done"),
+        })
+    }
+
+    fn file(&self, id: &str, name: &str, content_type: &'static str) -> Reply {
+        self.with(id, |analysis| {
+            if analysis.status != ApiStatus::Done {
+                return error_reply(409, "analysis not done");
+            }
+            match fs::read(analysis.dir.join(name)) {
+                Ok(data) => (200, content_type, data),
+                Err(err) => error_reply(500, &err.to_string()),
+            }
+        })
+    }
+
+    fn report(&self, id: &str) -> Reply {
+        self.with(id, |analysis| {
+            let Some(result) = &analysis.result else { return error_reply(409, "analysis not done") };
+            let result = deidentify_result(result, &DeidentOptions::default());
+            let evaluation = evaluate(&result, analysis.sid_cm, analysis.criteria);
+            let report = collimator_report(&result, &evaluation, &analysis.dir.join("overlay.png"), &analysis.dir.join("circle.png"), &analysis.dir.join("flatness.png"));
+            (200, "application/pdf", report.to_pdf())
+        })
+    }
+
+    fn delete(&self, id: &str) -> Reply {
+        self.with(id, |analysis| {
+            if let Some(job_id) = analysis.job_id {
+                self.jobs.cancel(job_id);
+            }
+            self.analyses.lock().unwrap().remove(&analysis.id);
+            let _ = fs::remove_dir_all(&analysis.dir);
+            json_reply(200, &json!({ "id": analysis.id, "deleted": true }))
+        })
+    }
+}
+
+/// id after the numbered directories left in `work_dir` by earlier sessions
+fn first_free_id(work_dir: &Path) -> u64 {
+    let used = fs::read_dir(work_dir).into_iter().flatten().flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<u64>().ok())
+        .max();
+    used.map_or(1, |id| id + 1)
+}
+
+fn query_params(query: &str) -> HashMap<&str, &str> {
+    query.split('&').filter_map(|pair| pair.split_once('=')).collect()
+}
+
+fn json_reply<T: Serialize>(code: u16, value: &T) -> Reply {
+    (code, "application/json", serde_json::to_vec(value).unwrap_or_default())
+}
+
+fn error_reply(code: u16, message: &str) -> Reply {
+    json_reply(code, &json!({ "error": message }))
+}
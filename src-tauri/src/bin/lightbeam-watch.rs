@@ -0,0 +1,67 @@
+//! Watch-folder daemon: analyses the collimator test pairs exported to a folder.
+//!
+//!     lightbeam-watch <input folder> <output folder> [--archive <folder>] [--small-first] [--no-device-identity] [--sid <cm>] [--criteria <cm>]
+use std::env;
+use std::path::PathBuf;
+use std::process;
+use lightbeam_lib::cache::ImageCache;
+use lightbeam_lib::deident::DeidentOptions;
+use lightbeam_lib::watch::{FolderWatcher, WatchConfig, WatchEvent};
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() < 2 {
+        eprintln!("usage: lightbeam-watch <input folder> <output folder> [--archive <folder>] [--small-first] [--no-device-identity] [--sid <cm>] [--criteria <cm>]");
+        process::exit(2);
+    }
+    let mut config = WatchConfig {
+        input: PathBuf::from(&args[0]),
+        output: PathBuf::from(&args[1]),
+        archive: None,
+        large_first: true,
+        deident: DeidentOptions::default(),
+        sid_cm: 100.0,
+        criteria: 1.0,
+    };
+    let mut rest = args[2..].iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--archive" => config.archive = rest.next().map(PathBuf::from),
+            "--small-first" => config.large_first = false,
+            "--no-device-identity" => config.deident.retain_device_identity = false,
+            "--sid" => config.sid_cm = number(rest.next()),
+            "--criteria" => config.criteria = number(rest.next()),
+            other => {
+                eprintln!("unknown option {}", other);
+                process::exit(2);
+            }
+        }
+    }
+
+    let watcher = FolderWatcher::start(config, ImageCache::default(), |event| match event {
+        WatchEvent::Analysed { pair, output, .. } => {
+            println!("OK   {} + {} -> {}", pair.large.path, pair.small.path, output)
+        }
+        WatchEvent::Failed { pair, error } => {
+            println!("FAIL {} + {}: {}", pair.large.path, pair.small.path, error)
+        }
+    });
+    match watcher {
+        // runs until the process is killed
+        Ok(watcher) => watcher.join(),
+        Err(err) => {
+            eprintln!("lightbeam-watch: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
+fn number(arg: Option<&String>) -> f32 {
+    match arg.and_then(|v| v.parse().ok()) {
+        Some(v) => v,
+        None => {
+            eprintln!("--sid and --criteria need a number");
+            process::exit(2);
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use dicom::dictionary_std::tags;
+use crate::utils::{open_dcm_array, DcmObj, U16Array};
+
+// decoded images kept per session (a detector image is ~20 MB)
+const CACHE_CAPACITY: usize = 8;
+
+/// decoded dicom file: header (pixel data element removed) and first frame
+pub struct DcmImage {
+    pub obj: DcmObj,
+    pub arr: U16Array,
+}
+
+struct Entry {
+    mtime: SystemTime,
+    last_used: u64,
+    image: Arc<DcmImage>,
+}
+
+#[derive(Default)]
+struct Entries {
+    files: HashMap<String, Entry>,
+    clock: u64,
+}
+
+/// session image cache (tauri state), keyed by path + modified time
+///
+/// cloning shares the same cache, so worker threads can use it
+#[derive(Clone, Default)]
+pub struct ImageCache {
+    entries: Arc<Mutex<Entries>>,
+}
+
+impl ImageCache {
+    /// decoded image of `file_path`, decoded again only if the file changed
+    pub fn get(&self, file_path: &str) -> Option<Arc<DcmImage>> {
+        self.get_or_decode(file_path, |path| {
+            let (mut obj, arr) = open_dcm_array(path.to_string())?;
+            obj.remove_element(tags::PIXEL_DATA);
+            Some(DcmImage { obj, arr })
+        })
+    }
+
+    fn get_or_decode(&self, file_path: &str, decode: impl FnOnce(&str) -> Option<DcmImage>) -> Option<Arc<DcmImage>> {
+        let mtime = fs::metadata(file_path).and_then(|m| m.modified()).ok()?;
+        {
+            let mut entries = self.entries.lock().unwrap();
+            entries.clock += 1;
+            let clock = entries.clock;
+            if let Some(entry) = entries.files.get_mut(file_path) {
+                if entry.mtime == mtime {
+                    entry.last_used = clock;
+                    return Some(entry.image.clone());
+                }
+            }
+        }
+
+        // decode without holding the lock, files can be decoded in parallel
+        let image = Arc::new(decode(file_path)?);
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.clock += 1;
+        let last_used = entries.clock;
+        entries.files.insert(file_path.to_string(), Entry { mtime, last_used, image: image.clone() });
+        // drop the least recently used image
+        if entries.files.len() > CACHE_CAPACITY {
+            let oldest = entries.files.iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(path, _)| path.clone());
+            if let Some(path) = oldest {
+                entries.files.remove(&path);
+            }
+        }
+        Some(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::fs::File;
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use ndarray::Array2;
+    use crate::utils::test_header;
+
+    fn files(name: &str, n: usize) -> (PathBuf, Vec<String>) {
+        let dir = std::env::temp_dir().join(format!("lightbeam-cache-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let paths = (0..n)
+            .map(|i| {
+                let path = dir.join(format!("{}.dcm", i));
+                fs::write(&path, b"DICM").unwrap();
+                path.to_string_lossy().to_string()
+            })
+            .collect();
+        (dir, paths)
+    }
+
+    /// `cache.get` with a stand-in decoder that counts the decodes
+    fn get(cache: &ImageCache, path: &str, decodes: &Cell<usize>) -> Arc<DcmImage> {
+        cache.get_or_decode(path, |_| {
+            decodes.set(decodes.get() + 1);
+            Some(DcmImage { obj: test_header(&[]), arr: Array2::zeros((1, 1)) })
+        }).unwrap()
+    }
+
+    #[test]
+    fn changed_file_is_decoded_again() {
+        let (dir, paths) = files("mtime", 1);
+        let (cache, decodes) = (ImageCache::default(), Cell::new(0));
+        let first = get(&cache, &paths[0], &decodes);
+        assert!(Arc::ptr_eq(&first, &get(&cache, &paths[0], &decodes)));
+        assert_eq!(decodes.get(), 1);
+
+        let later = fs::metadata(&paths[0]).unwrap().modified().unwrap() + Duration::from_secs(5);
+        File::options().write(true).open(&paths[0]).unwrap().set_modified(later).unwrap();
+        assert!(!Arc::ptr_eq(&first, &get(&cache, &paths[0], &decodes)));
+        assert_eq!(decodes.get(), 2);
+        // a missing file is never served from the cache
+        fs::remove_file(&paths[0]).unwrap();
+        assert!(cache.get(&paths[0]).is_none());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn least_recently_used_is_dropped() {
+        let (dir, paths) = files("lru", CACHE_CAPACITY + 1);
+        let (cache, decodes) = (ImageCache::default(), Cell::new(0));
+        for path in &paths[..CACHE_CAPACITY] {
+            get(&cache, path, &decodes);
+        }
+        // use the first again, the second is now the oldest
+        get(&cache, &paths[0], &decodes);
+        get(&cache, &paths[CACHE_CAPACITY], &decodes);
+        assert_eq!(decodes.get(), CACHE_CAPACITY + 1);
+        get(&cache, &paths[0], &decodes);
+        assert_eq!(decodes.get(), CACHE_CAPACITY + 1);
+        get(&cache, &paths[1], &decodes);
+        assert_eq!(decodes.get(), CACHE_CAPACITY + 2);
+        assert_eq!(cache.entries.lock().unwrap().files.len(), CACHE_CAPACITY);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn clones_share_the_entries() {
+        let (dir, paths) = files("clone", 1);
+        let (cache, decodes) = (ImageCache::default(), Cell::new(0));
+        let worker = cache.clone();
+        let image = get(&worker, &paths[0], &decodes);
+        assert!(Arc::ptr_eq(&image, &get(&cache, &paths[0], &decodes)));
+        assert_eq!(decodes.get(), 1);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
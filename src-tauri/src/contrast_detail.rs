@@ -0,0 +1,458 @@
+use std::f64::consts::PI;
+use std::fs;
+use std::path::Path;
+use image::{Rgb, RgbImage};
+use ndarray::{s, Array2};
+use serde::{Deserialize, Serialize};
+use crate::analysis::{detector_details, AnalysisError};
+use crate::cache::ImageCache;
+use crate::plot::{grid_step, save_plot};
+use crate::tolerance::{all_passed, Check, ToleranceProfile};
+use crate::utils::{find_theta, get_pixel_spacing, get_rescale, rescaled, rotate_array, U16View};
+
+// registration: search of the offset (mm) as (range, step), coarse to fine
+const SEARCH: [(f64, f64); 3] = [(20.0, 2.0), (2.0, 0.5), (0.5, 0.1)];
+// tilt: the two strongest details of a row are searched within this many radii
+const LOCATE_RADII: f64 = 1.5;
+// disc measured inside 0.8 r, background in the annulus 1.3 r .. 2 r
+const DISC_RADIUS: f64 = 0.8;
+const BACKGROUND_RADII: (f64, f64) = (1.3, 2.0);
+// longest side of the overlay image
+const OVERLAY_SIZE: usize = 1024;
+
+/// one disc of the phantom, position from the phantom centre (x right, y down)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Detail {
+    pub x_mm: f64,
+    pub y_mm: f64,
+    pub diameter_mm: f64,
+    /// nominal contrast (%)
+    pub contrast: f64,
+}
+
+/// contrast-detail phantom layout
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContrastDetailPhantom {
+    pub name: String,
+    pub details: Vec<Detail>,
+    /// layout scale in the detector plane
+    #[serde(default = "unit")]
+    pub magnification: f64,
+    /// Rose criterion: a detail is visible if |CNR| x sqrt(disc pixels) reaches it
+    pub threshold: f64,
+}
+
+fn unit() -> f64 {
+    1.0
+}
+
+impl Default for ContrastDetailPhantom {
+    /// example 4 x 3 grid (diameters by row, contrasts by column), to be
+    /// replaced with the layout of the phantom in use
+    fn default() -> Self {
+        let mut details = Vec::new();
+        for (row, diameter_mm) in [8.0, 4.0, 2.0].into_iter().enumerate() {
+            for (col, contrast) in [4.0, 2.0, 1.0, 0.5].into_iter().enumerate() {
+                details.push(Detail { x_mm: -45.0 + 30.0 * col as f64, y_mm: -30.0 + 30.0 * row as f64, diameter_mm, contrast });
+            }
+        }
+        ContrastDetailPhantom { name: "Example layout".to_string(), details, magnification: 1.0, threshold: 5.0 }
+    }
+}
+
+/// layouts saved in `path`, the example layout if there is none
+pub fn load_phantoms(path: &Path) -> Vec<ContrastDetailPhantom> {
+    let mut phantoms: Vec<ContrastDetailPhantom> = fs::read_to_string(path).ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default();
+    if phantoms.is_empty() {
+        phantoms.push(ContrastDetailPhantom::default());
+    }
+    phantoms
+}
+
+pub fn save_phantoms(path: &Path, phantoms: &[ContrastDetailPhantom]) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+    }
+    let json = serde_json::to_string_pretty(phantoms).map_err(|err| err.to_string())?;
+    fs::write(path, json).map_err(|err| err.to_string())
+}
+
+/// layout `name` of `path`, the first layout if not found
+pub fn find_phantom(path: &Path, name: &str) -> ContrastDetailPhantom {
+    let mut phantoms = load_phantoms(path);
+    let i = phantoms.iter().position(|p| p.name == name).unwrap_or(0);
+    phantoms.swap_remove(i)
+}
+
+/// measured disc
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DetailMeasurement {
+    pub diameter_mm: f64,
+    pub contrast: f64,
+    /// disc centre in the levelled image (px)
+    pub x_px: f64,
+    pub y_px: f64,
+    pub radius_px: f64,
+    /// (disc mean - background mean) / background sd
+    pub cnr: f64,
+    /// |cnr| x sqrt(disc pixels)
+    pub snr: f64,
+    pub visible: bool,
+}
+
+/// threshold contrast of one diameter, None if no detail of it has signal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThresholdContrast {
+    pub diameter_mm: f64,
+    /// contrast (%) where the snr reaches the phantom threshold
+    pub contrast: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContrastDetailResult {
+    pub details: Vec<String>,
+    pub phantom: String,
+    /// phantom centre from the image centre (mm) and rotation (degree)
+    pub offset_mm: [f64; 2],
+    pub angle: f64,
+    pub measurements: Vec<DetailMeasurement>,
+    pub threshold_contrast: Vec<ThresholdContrast>,
+    pub visible: usize,
+    pub profile: String,
+    pub checks: Vec<Check>,
+    pub passed: bool,
+}
+
+/// register the layout on a contrast-detail phantom image and score every disc
+///
+/// save_path: [details csv, threshold contrast plot, overlay (green visible, red not)]
+pub fn run_contrast_detail(file_path: &str, save_path: &[String], cache: &ImageCache, phantom: &ContrastDetailPhantom, profile: &ToleranceProfile) -> Result<ContrastDetailResult, AnalysisError> {
+    if phantom.details.is_empty() {
+        return Err(AnalysisError::Measurement(format!("phantom {} has no details", phantom.name)));
+    }
+    let image = cache.get(file_path).ok_or_else(|| AnalysisError::Load(file_path.to_owned()))?;
+    let spacing = get_pixel_spacing(&image.obj)
+        .ok_or_else(|| AnalysisError::Measurement("pixel spacing is missing".to_string()))?;
+    let rescale = get_rescale(&image.obj);
+    let layout = Layout::new(phantom, spacing);
+
+    let (angle, centre, arr) = level(&layout, image.arr.view(), rescale);
+    let measurements: Vec<DetailMeasurement> = phantom.details.iter()
+        .zip(layout.positions(centre))
+        .map(|(detail, (x, y))| measure(&arr, detail, x, y, detail.diameter_mm / 2.0 * phantom.magnification / spacing, phantom.threshold))
+        .collect();
+    let threshold_contrast = threshold_contrast(&measurements, phantom.threshold);
+    let visible = measurements.iter().filter(|m| m.visible).count();
+
+    let checks = vec![profile.check("contrast_detail.visible", visible as f64)];
+    // phantom centre in the image as acquired
+    let (h, w) = arr.dim();
+    let image_centre = (w as f64 / 2.0, h as f64 / 2.0);
+    let (x, y) = rotate_point(centre, image_centre, angle);
+    let result = ContrastDetailResult {
+        details: detector_details(&image.obj),
+        phantom: phantom.name.clone(),
+        offset_mm: [(x - image_centre.0) * spacing, (y - image_centre.1) * spacing],
+        angle: angle.to_degrees(),
+        measurements,
+        threshold_contrast,
+        visible,
+        profile: profile.name.clone(),
+        passed: all_passed(&checks),
+        checks,
+    };
+    save_outputs(&result, &arr, save_path)?;
+    println!("CONTRAST DETAIL: {} of {} visible", visible, phantom.details.len());
+    Ok(result)
+}
+
+/// level the phantom as the line-pair test: tilt of a detail row, then rotate_array
+///
+/// Returns: (angle (radian), phantom centre in the levelled image, levelled rescaled image)
+fn level(layout: &Layout, raw: U16View, rescale: (f64, f64)) -> (f64, (f64, f64), Array2<f64>) {
+    let (h, w) = raw.dim();
+    let image_centre = (w as f64 / 2.0, h as f64 / 2.0);
+    let table = SumTable::new(&rescaled(raw, rescale));
+    let coarse = layout.register(&table, &SEARCH[..2], image_centre);
+    let angle = layout.tilt(&table, coarse);
+    let arr = rescaled(rotate_array(angle, raw).view(), rescale);
+    let centre = layout.register(&SumTable::new(&arr), &SEARCH[1..], rotate_point(coarse, image_centre, -angle));
+    (angle, centre, arr)
+}
+
+/// layout in pixels and the details used for the registration
+struct Layout {
+    /// detail positions from the phantom centre (px)
+    points: Vec<(f64, f64)>,
+    /// (detail index, radius (px), nominal contrast)
+    registration: Vec<(usize, f64, f64)>,
+    spacing: f64,
+}
+
+impl Layout {
+    fn new(phantom: &ContrastDetailPhantom, spacing: f64) -> Self {
+        let scale = phantom.magnification / spacing;
+        let points = phantom.details.iter().map(|d| (d.x_mm * scale, d.y_mm * scale)).collect();
+        let registration = phantom.details.iter().enumerate()
+            .map(|(i, d)| (i, d.diameter_mm / 2.0 * scale, d.contrast))
+            .collect();
+        Layout { points, registration, spacing }
+    }
+
+    /// detail positions of the levelled phantom at `centre`
+    fn positions(&self, centre: (f64, f64)) -> Vec<(f64, f64)> {
+        self.points.iter().map(|&(x, y)| (centre.0 + x, centre.1 + y)).collect()
+    }
+
+    /// |Σ contrast x (disc - background)| of the details, any offset lowers it
+    fn score(&self, table: &SumTable, centre: (f64, f64)) -> f64 {
+        let positions = self.positions(centre);
+        let score: f64 = self.registration.iter()
+            .filter_map(|&(i, r, contrast)| Some(contrast * table.disc_contrast(positions[i], r)?))
+            .sum();
+        score.abs()
+    }
+
+    /// phantom centre (px) of the best score, `stages` from `start`
+    fn register(&self, table: &SumTable, stages: &[(f64, f64)], start: (f64, f64)) -> (f64, f64) {
+        let mut best = start;
+        for &shift in stages {
+            let start = best;
+            let mut best_score = f64::MIN;
+            for dy in steps(shift) {
+                for dx in steps(shift) {
+                    let centre = (start.0 + dx / self.spacing, start.1 + dy / self.spacing);
+                    let score = self.score(table, centre);
+                    if score > best_score {
+                        best_score = score;
+                        best = centre;
+                    }
+                }
+            }
+        }
+        best
+    }
+
+    /// tilt (radian) of the row of the strongest detail (contrast x area),
+    /// from it and the strongest other detail of the row, both located
+    /// around their positions at `centre`; 0 if the row has one detail
+    fn tilt(&self, table: &SumTable, centre: (f64, f64)) -> f64 {
+        let strength = |&(_, r, contrast): &(usize, f64, f64)| contrast.abs() * r * r;
+        let mut order = self.registration.clone();
+        order.sort_by(|a, b| strength(b).total_cmp(&strength(a)));
+        let Some(&first) = order.first() else { return 0.0 };
+        let Some(&second) = order.iter().skip(1).find(|d| (self.points[d.0].1 - self.points[first.0].1).abs() < 0.5) else {
+            return 0.0;
+        };
+        let positions = self.positions(centre);
+        let locate = |(i, r, contrast): (usize, f64, f64)| -> Option<(f64, f64)> {
+            let n = (LOCATE_RADII * r).ceil() as i64;
+            let (x0, y0) = positions[i];
+            (-n..=n).flat_map(|dy| (-n..=n).map(move |dx| (x0 + dx as f64, y0 + dy as f64)))
+                .filter_map(|p| Some((p, contrast.signum() * table.disc_contrast(p, r)?)))
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .map(|(p, _)| p)
+        };
+        let (Some(a), Some(b)) = (locate(first), locate(second)) else { return 0.0 };
+        let (left, right) = if self.points[first.0].0 < self.points[second.0].0 { (a, b) } else { (b, a) };
+        find_theta(left.0.round() as i32, right.0.round() as i32, left.1.round() as i32, right.1.round() as i32)
+    }
+}
+
+/// `point` rotated by `angle` around `centre`, as `rotate` maps an output pixel to its source
+fn rotate_point(point: (f64, f64), centre: (f64, f64), angle: f64) -> (f64, f64) {
+    let (sin, cos) = angle.sin_cos();
+    let (x, y) = (point.0 - centre.0, point.1 - centre.1);
+    (centre.0 + x * cos - y * sin, centre.1 + x * sin + y * cos)
+}
+
+/// summed-area table for constant time square means
+struct SumTable {
+    sums: Array2<f64>,
+}
+
+impl SumTable {
+    fn new(arr: &Array2<f64>) -> Self {
+        let (h, w) = arr.dim();
+        let mut sums = Array2::<f64>::zeros((h + 1, w + 1));
+        for y in 0..h {
+            let mut row = 0.0;
+            for x in 0..w {
+                row += arr[[y, x]];
+                sums[[y + 1, x + 1]] = sums[[y, x + 1]] + row;
+            }
+        }
+        SumTable { sums }
+    }
+
+    /// sum and pixel count of the square of half side `half` at (x, y), None if outside
+    fn sum(&self, x: f64, y: f64, half: f64) -> Option<(f64, f64)> {
+        let (h, w) = (self.sums.nrows() - 1, self.sums.ncols() - 1);
+        let (top, left) = ((y - half).round(), (x - half).round());
+        let (bottom, right) = ((y + half).round() + 1.0, (x + half).round() + 1.0);
+        if top < 0.0 || left < 0.0 || bottom > h as f64 || right > w as f64 || bottom <= top || right <= left {
+            return None;
+        }
+        let (t, l, b, r) = (top as usize, left as usize, bottom as usize, right as usize);
+        let sum = self.sums[[b, r]] - self.sums[[t, r]] - self.sums[[b, l]] + self.sums[[t, l]];
+        Some((sum, ((b - t) * (r - l)) as f64))
+    }
+
+    fn mean(&self, x: f64, y: f64, half: f64) -> Option<f64> {
+        self.sum(x, y, half).map(|(sum, n)| sum / n)
+    }
+
+    /// mean of the square inscribed in the disc of radius r at (x, y) minus the
+    /// mean of the square ring r .. 1.5 r around it, both touch the disc edge
+    fn disc_contrast(&self, (x, y): (f64, f64), r: f64) -> Option<f64> {
+        let disc = self.mean(x, y, r / 2f64.sqrt())?;
+        let (inner, inner_n) = self.sum(x, y, r)?;
+        let (outer, outer_n) = self.sum(x, y, 1.5 * r)?;
+        Some(disc - (outer - inner) / (outer_n - inner_n).max(1.0))
+    }
+}
+
+/// -range..=range in `step`s
+fn steps((range, step): (f64, f64)) -> impl Iterator<Item = f64> {
+    let n = (range / step).round() as i64;
+    (-n..=n).map(move |i| i as f64 * step)
+}
+
+fn measure(arr: &Array2<f64>, detail: &Detail, x0: f64, y0: f64, r: f64, threshold: f64) -> DetailMeasurement {
+    let (h, w) = arr.dim();
+    let outer = BACKGROUND_RADII.1 * r;
+    let (top, bottom) = ((y0 - outer).floor().max(0.0) as usize, ((y0 + outer).ceil().max(0.0) as usize + 1).min(h));
+    let (left, right) = ((x0 - outer).floor().max(0.0) as usize, ((x0 + outer).ceil().max(0.0) as usize + 1).min(w));
+    let mut disc = Vec::new();
+    let mut background = Vec::new();
+    if top < bottom && left < right {
+        for ((y, x), &v) in arr.slice(s![top..bottom, left..right]).indexed_iter() {
+            let d = ((left + x) as f64 - x0).hypot((top + y) as f64 - y0);
+            if d <= DISC_RADIUS * r {
+                disc.push(v);
+            } else if d >= BACKGROUND_RADII.0 * r && d <= outer {
+                background.push(v);
+            }
+        }
+    }
+    let mean = |values: &[f64]| values.iter().sum::<f64>() / values.len().max(1) as f64;
+    let bg_mean = mean(&background);
+    let bg_sd = (background.iter().map(|v| (v - bg_mean).powi(2)).sum::<f64>() / (background.len().max(2) - 1) as f64).sqrt();
+    let cnr = if bg_sd > 0.0 && !disc.is_empty() { (mean(&disc) - bg_mean) / bg_sd } else { 0.0 };
+    let snr = cnr.abs() * (disc.len() as f64).sqrt();
+    DetailMeasurement {
+        diameter_mm: detail.diameter_mm,
+        contrast: detail.contrast,
+        x_px: x0,
+        y_px: y0,
+        radius_px: r,
+        cnr,
+        snr,
+        visible: snr >= threshold,
+    }
+}
+
+/// per diameter: threshold x median(contrast / snr), snr taken as proportional to contrast
+fn threshold_contrast(measurements: &[DetailMeasurement], threshold: f64) -> Vec<ThresholdContrast> {
+    let mut diameters: Vec<f64> = measurements.iter().map(|m| m.diameter_mm).collect();
+    diameters.sort_by(f64::total_cmp);
+    diameters.dedup();
+    diameters.into_iter()
+        .map(|diameter_mm| {
+            let mut ratios: Vec<f64> = measurements.iter()
+                .filter(|m| m.diameter_mm == diameter_mm && m.snr > 0.0)
+                .map(|m| m.contrast.abs() / m.snr)
+                .collect();
+            ratios.sort_by(f64::total_cmp);
+            let contrast = (!ratios.is_empty()).then(|| threshold * ratios[ratios.len() / 2]);
+            ThresholdContrast { diameter_mm, contrast }
+        })
+        .collect()
+}
+
+fn save_outputs(result: &ContrastDetailResult, arr: &Array2<f64>, save_path: &[String]) -> Result<(), AnalysisError> {
+    let [csv_path, plot_path, overlay_path] = save_path else {
+        return Err(AnalysisError::Measurement("csv, plot and overlay paths are needed".to_string()));
+    };
+    let mut csv = String::from("diameter_mm,contrast,x_px,y_px,cnr,snr,visible\n");
+    for m in &result.measurements {
+        csv.push_str(&format!("{},{},{:.1},{:.1},{:.3},{:.2},{}\n", m.diameter_mm, m.contrast, m.x_px, m.y_px, m.cnr, m.snr, m.visible));
+    }
+    fs::write(csv_path, csv).map_err(|err| AnalysisError::Measurement(err.to_string()))?;
+
+    let curve: Vec<(f64, f64)> = result.threshold_contrast.iter().filter_map(|t| Some((t.diameter_mm, t.contrast?))).collect();
+    let x_max = result.threshold_contrast.iter().map(|t| t.diameter_mm).fold(0.0, f64::max);
+    let y_max = curve.iter().map(|p| p.1).fold(0.0, f64::max).max(f64::EPSILON);
+    save_plot(plot_path, &[curve], x_max, y_max, grid_step(x_max), grid_step(y_max)).map_err(AnalysisError::Measurement)?;
+
+    save_overlay(result, arr, overlay_path).map_err(AnalysisError::Measurement)
+}
+
+/// downscaled image (1-99% window) with the measured discs
+fn save_overlay(result: &ContrastDetailResult, arr: &Array2<f64>, path: &str) -> Result<(), String> {
+    let (h, w) = arr.dim();
+    let scale = ((h.max(w) + OVERLAY_SIZE - 1) / OVERLAY_SIZE).max(1);
+    let small = arr.slice(s![..;scale, ..;scale]);
+    let mut sorted: Vec<f64> = small.iter().copied().collect();
+    sorted.sort_by(f64::total_cmp);
+    let low = sorted[sorted.len() / 100];
+    let high = sorted[sorted.len() * 99 / 100].max(low + f64::EPSILON);
+    let mut img = RgbImage::new(small.ncols() as u32, small.nrows() as u32);
+    for ((y, x), &v) in small.indexed_iter() {
+        let level = ((v - low) / (high - low) * 255.0).clamp(0.0, 255.0) as u8;
+        img.put_pixel(x as u32, y as u32, Rgb([level, level, level]));
+    }
+    for m in &result.measurements {
+        let color = if m.visible { Rgb([0, 200, 0]) } else { Rgb([230, 0, 0]) };
+        let scale = scale as f64;
+        draw_circle(&mut img, m.x_px / scale, m.y_px / scale, (m.radius_px / scale).max(2.0), color);
+    }
+    img.save(path).map_err(|err| err.to_string())
+}
+
+fn draw_circle(img: &mut RgbImage, cx: f64, cy: f64, r: f64, color: Rgb<u8>) {
+    let n = (2.0 * PI * r).ceil().max(8.0) as usize;
+    for i in 0..n {
+        let t = 2.0 * PI * i as f64 / n as f64;
+        let (x, y) = ((cx + r * t.cos()).round(), (cy + r * t.sin()).round());
+        if x >= 0.0 && y >= 0.0 && (x as u32) < img.width() && (y as u32) < img.height() {
+            img.put_pixel(x as u32, y as u32, color);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// example layout at `centre` (px) tilted by `angle`, 0.3 mm pixels, background 1000 with noise
+    fn phantom_image(centre: (f64, f64), angle: f64) -> Array2<u16> {
+        let phantom = ContrastDetailPhantom::default();
+        let (sin, cos) = angle.sin_cos();
+        Array2::from_shape_fn((600, 600), |(y, x)| {
+            // layout coordinates (mm) of the pixel
+            let (dx, dy) = (x as f64 - centre.0, y as f64 - centre.1);
+            let (u, v) = ((dx * cos + dy * sin) * 0.3, (-dx * sin + dy * cos) * 0.3);
+            let signal = phantom.details.iter()
+                .find(|d| (u - d.x_mm).hypot(v - d.y_mm) < d.diameter_mm / 2.0)
+                .map_or(0.0, |d| d.contrast * 10.0);
+            let noise = ((x * 7919 + y * 104729) % 7) as f64 - 3.0;
+            (1000.0 + signal + noise) as u16
+        })
+    }
+
+    #[test]
+    fn tilted_phantom_is_levelled() {
+        let layout = Layout::new(&ContrastDetailPhantom::default(), 0.3);
+        let (centre, angle) = ((310.0, 290.0), 2f64.to_radians());
+        let (found, levelled, arr) = level(&layout, phantom_image(centre, angle).view(), (1.0, 0.0));
+        assert!((found - angle).abs().to_degrees() < 0.3, "angle {}", found.to_degrees());
+        let (x, y) = rotate_point(levelled, (300.0, 300.0), found);
+        assert!((x - centre.0).hypot(y - centre.1) < 2.0, "centre {:?}", (x, y));
+        // the largest, strongest disc sits on its layout position in the levelled image
+        let (px, py) = layout.positions(levelled)[0];
+        assert!(arr[[py.round() as usize, px.round() as usize]] > 1030.0);
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+use image::{Rgb, RgbImage};
+use ndarray::{s, Array2, Axis};
+use serde::{Deserialize, Serialize};
+use crate::analysis::{detector_details, AnalysisError};
+use crate::cache::ImageCache;
+use crate::tolerance::{all_passed, Check, ToleranceProfile};
+use crate::utils::{U16Array, U16View};
+
+// median window (px), features of half the window or wider are kept
+const MEDIAN_WINDOW: usize = 5;
+
+/// deviations from the local median flagged as defects (fraction of the median)
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct DefectThresholds {
+    /// single pixel
+    pub pixel: f64,
+    /// median deviation along a whole row/column
+    pub line: f64,
+}
+
+impl Default for DefectThresholds {
+    fn default() -> Self {
+        DefectThresholds { pixel: 0.2, line: 0.05 }
+    }
+}
+
+/// defects of the flat-field image `flat_field`, masked in the other
+/// images of the same detector
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DefectMask {
+    pub flat_field: String,
+    pub thresholds: DefectThresholds,
+}
+
+impl DefectMask {
+    /// defect map of the flat field, it must have the size of the masked images
+    pub fn load(&self, cache: &ImageCache, dim: (usize, usize)) -> Result<DefectMap, AnalysisError> {
+        let image = cache.get(&self.flat_field).ok_or_else(|| AnalysisError::Load(self.flat_field.clone()))?;
+        if image.arr.dim() != dim {
+            return Err(AnalysisError::Measurement("the flat-field image for the defect map has another size".to_string()));
+        }
+        Ok(detect_defects(image.arr.view(), &self.thresholds))
+    }
+}
+
+/// defective pixels, rows and columns of an image
+#[derive(Debug, Clone)]
+pub struct DefectMap {
+    pub pixels: Array2<bool>,
+    pub rows: Vec<usize>,
+    pub columns: Vec<usize>,
+}
+
+impl DefectMap {
+    fn is_line(&self, y: usize, x: usize) -> bool {
+        self.rows.binary_search(&y).is_ok() || self.columns.binary_search(&x).is_ok()
+    }
+
+    /// pixel or line defect
+    pub fn is_defect(&self, y: usize, x: usize) -> bool {
+        self.pixels[[y, x]] || self.is_line(y, x)
+    }
+
+    /// map of the region rows x columns of the image
+    pub fn crop(&self, rows: Range<usize>, columns: Range<usize>) -> DefectMap {
+        let inside = |lines: &[usize], range: &Range<usize>| -> Vec<usize> {
+            lines.iter().filter(|i| range.contains(i)).map(|i| i - range.start).collect()
+        };
+        DefectMap {
+            rows: inside(&self.rows, &rows),
+            columns: inside(&self.columns, &columns),
+            pixels: self.pixels.slice(s![rows, columns]).to_owned(),
+        }
+    }
+
+    /// number of pixel defect clusters (8-connected, line defects left out) by size
+    pub fn clusters(&self) -> BTreeMap<usize, usize> {
+        let (h, w) = self.pixels.dim();
+        let mut seen = Array2::<bool>::from_elem((h, w), false);
+        let mut counts = BTreeMap::new();
+        for ((y, x), &defect) in self.pixels.indexed_iter() {
+            if !defect || seen[[y, x]] || self.is_line(y, x) {
+                continue;
+            }
+            seen[[y, x]] = true;
+            let mut stack = vec![(y, x)];
+            let mut size = 0;
+            while let Some((cy, cx)) = stack.pop() {
+                size += 1;
+                for ny in cy.saturating_sub(1)..(cy + 2).min(h) {
+                    for nx in cx.saturating_sub(1)..(cx + 2).min(w) {
+                        if self.pixels[[ny, nx]] && !seen[[ny, nx]] && !self.is_line(ny, nx) {
+                            seen[[ny, nx]] = true;
+                            stack.push((ny, nx));
+                        }
+                    }
+                }
+            }
+            *counts.entry(size).or_insert(0) += 1;
+        }
+        counts
+    }
+}
+
+/// pixels deviating from the 5 x 5 (separable) median and rows/columns whose
+/// median deviation from the neighbouring lines exceeds the thresholds
+pub fn detect_defects(arr: U16View, thresholds: &DefectThresholds) -> DefectMap {
+    let horizontal = median_filter(arr, Axis(1));
+    let vertical = median_filter(arr, Axis(0));
+    let local = median_filter(horizontal.view(), Axis(0));
+
+    let relative = |value: u16, median: u16| {
+        let median = median.max(1) as f64;
+        (value as f64 - median) / median
+    };
+    let pixels = ndarray::Zip::from(&arr).and(&local)
+        .map_collect(|&v, &m| relative(v, m).abs() > thresholds.pixel);
+    // a line is compared with the lines next to it: rows against the vertical median
+    let lines = |axis: Axis, reference: &U16Array| -> Vec<usize> {
+        arr.axis_iter(axis).zip(reference.axis_iter(axis)).enumerate()
+            .filter_map(|(i, (line, median))| {
+                let mut deviations: Vec<f64> = line.iter().zip(median.iter()).map(|(&v, &m)| relative(v, m)).collect();
+                let mid = deviations.len() / 2;
+                let (_, deviation, _) = deviations.select_nth_unstable_by(mid, f64::total_cmp);
+                (deviation.abs() > thresholds.line).then_some(i)
+            })
+            .collect()
+    };
+    DefectMap {
+        rows: lines(Axis(0), &vertical),
+        columns: lines(Axis(1), &horizontal),
+        pixels,
+    }
+}
+
+/// running median along `axis`, the window is cut at the image border
+fn median_filter(arr: U16View, axis: Axis) -> U16Array {
+    let half = MEDIAN_WINDOW / 2;
+    let mut out = arr.to_owned();
+    let mut window = Vec::with_capacity(MEDIAN_WINDOW);
+    for (line, mut out_line) in arr.lanes(axis).into_iter().zip(out.lanes_mut(axis)) {
+        let n = line.len();
+        for i in 0..n {
+            window.clear();
+            window.extend((i.saturating_sub(half)..(i + half + 1).min(n)).map(|k| line[k]));
+            let mid = window.len() / 2;
+            out_line[i] = *window.select_nth_unstable(mid).1;
+        }
+    }
+    out
+}
+
+/// replace defects by the median of the good pixels around them
+///
+/// line defects take the pixels of the nearest good lines
+pub fn mask_defects(arr: &mut U16Array, map: &DefectMap) {
+    let (h, w) = arr.dim();
+    let source = arr.clone();
+    let mut values = Vec::new();
+    for y in 0..h {
+        for x in 0..w {
+            if !map.is_defect(y, x) {
+                continue;
+            }
+            // grow the window until good pixels are found
+            for half in [MEDIAN_WINDOW / 2, MEDIAN_WINDOW, 4 * MEDIAN_WINDOW] {
+                values.clear();
+                for ny in y.saturating_sub(half)..(y + half + 1).min(h) {
+                    for nx in x.saturating_sub(half)..(x + half + 1).min(w) {
+                        if !map.is_defect(ny, nx) {
+                            values.push(source[[ny, nx]]);
+                        }
+                    }
+                }
+                if !values.is_empty() {
+                    let mid = values.len() / 2;
+                    arr[[y, x]] = *values.select_nth_unstable(mid).1;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// pixels of a defect cluster size
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClusterCount {
+    pub size: usize,
+    pub count: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DefectResult {
+    pub details: Vec<String>,
+    pub thresholds: DefectThresholds,
+    /// defective pixels outside the defect lines
+    pub pixel_count: usize,
+    pub rows: Vec<usize>,
+    pub columns: Vec<usize>,
+    pub clusters: Vec<ClusterCount>,
+    pub largest_cluster: usize,
+    pub profile: String,
+    pub checks: Vec<Check>,
+    pub passed: bool,
+}
+
+/// defect map of a flat-field (or open field) image
+///
+/// map_path: png, defect pixels black and defect lines red on white
+pub fn run_defects(file_path: &str, map_path: &str, cache: &ImageCache, thresholds: &DefectThresholds, profile: &ToleranceProfile) -> Result<DefectResult, AnalysisError> {
+    let image = cache.get(file_path).ok_or_else(|| AnalysisError::Load(file_path.to_owned()))?;
+    let map = detect_defects(image.arr.view(), thresholds);
+    let clusters: Vec<ClusterCount> = map.clusters().into_iter().map(|(size, count)| ClusterCount { size, count }).collect();
+    let pixel_count = clusters.iter().map(|c| c.size * c.count).sum();
+    let largest_cluster = clusters.last().map(|c| c.size).unwrap_or(0);
+
+    let checks = vec![
+        profile.check("defects.pixels", pixel_count as f64),
+        profile.check("defects.lines", (map.rows.len() + map.columns.len()) as f64),
+        profile.check("defects.largest_cluster", largest_cluster as f64),
+    ];
+    save_map(&map, map_path).map_err(AnalysisError::Measurement)?;
+    println!("DEFECTS: {} pixels, {} rows, {} columns", pixel_count, map.rows.len(), map.columns.len());
+    Ok(DefectResult {
+        details: detector_details(&image.obj),
+        thresholds: *thresholds,
+        pixel_count,
+        rows: map.rows,
+        columns: map.columns,
+        clusters,
+        largest_cluster,
+        profile: profile.name.clone(),
+        passed: all_passed(&checks),
+        checks,
+    })
+}
+
+fn save_map(map: &DefectMap, path: &str) -> Result<(), String> {
+    let (h, w) = map.pixels.dim();
+    let img = RgbImage::from_fn(w as u32, h as u32, |x, y| {
+        let (y, x) = (y as usize, x as usize);
+        if map.is_line(y, x) {
+            Rgb([230, 0, 0])
+        } else if map.pixels[[y, x]] {
+            Rgb([0, 0, 0])
+        } else {
+            Rgb([255, 255, 255])
+        }
+    });
+    img.save(path).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cropped_map_keeps_the_defects() {
+        let mut flat = U16Array::from_elem((40, 50), 1000);
+        flat[[12, 30]] = 2000;
+        flat.column_mut(7).fill(1200);
+        let map = detect_defects(flat.view(), &DefectThresholds::default());
+        assert_eq!(map.columns, vec![7]);
+        assert!(map.rows.is_empty());
+        assert!(map.pixels[[12, 30]]);
+
+        let crop = map.crop(10..30, 5..35);
+        assert_eq!(crop.pixels.dim(), (20, 30));
+        assert_eq!(crop.columns, vec![2]);
+        assert!(crop.is_defect(2, 25));
+        assert!(!crop.is_defect(3, 25));
+        assert!(map.crop(20..30, 10..20).columns.is_empty());
+    }
+}
@@ -0,0 +1,308 @@
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use sha1_smol::Sha1;
+use dicom::core::value::Value;
+use dicom::core::{Length, PrimitiveValue, Tag, VR};
+use dicom::dictionary_std::tags;
+use dicom::object::mem::InMemElement;
+use dicom::object::{open_file, FileMetaTableBuilder, InMemDicomObject};
+use crate::analysis::{CollimatorResult, DETAIL_ADDRESS, DETAIL_DETECTOR_ID, DETAIL_HOSPITAL};
+use crate::dimse::get_str;
+
+/// de-identification settings (DICOM PS3.15 Annex E)
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeidentOptions {
+    /// Retain Device Identity Option: keep Detector ID, Station Name, serial numbers
+    pub retain_device_identity: bool,
+}
+
+impl Default for DeidentOptions {
+    fn default() -> Self {
+        DeidentOptions { retain_device_identity: true }
+    }
+}
+
+// Basic Profile "Z": type 2, kept with an empty value
+const EMPTY: [(Tag, VR); 11] = [
+    (tags::PATIENT_NAME, VR::PN),
+    (tags::PATIENT_ID, VR::LO),
+    (tags::PATIENT_BIRTH_DATE, VR::DA),
+    (tags::PATIENT_SEX, VR::CS),
+    (tags::REFERRING_PHYSICIAN_NAME, VR::PN),
+    (tags::STUDY_ID, VR::SH),
+    (tags::ACCESSION_NUMBER, VR::SH),
+    (tags::STUDY_DATE, VR::DA),
+    (tags::STUDY_TIME, VR::TM),
+    (tags::CONTENT_DATE, VR::DA),
+    (tags::CONTENT_TIME, VR::TM),
+];
+
+// Basic Profile "X": removed
+const REMOVE: [Tag; 44] = [
+    tags::INSTITUTION_NAME,
+    tags::INSTITUTION_ADDRESS,
+    tags::INSTITUTION_CODE_SEQUENCE,
+    tags::INSTITUTIONAL_DEPARTMENT_NAME,
+    tags::PHYSICIANS_OF_RECORD,
+    tags::PERFORMING_PHYSICIAN_NAME,
+    tags::NAME_OF_PHYSICIANS_READING_STUDY,
+    tags::OPERATORS_NAME,
+    tags::REQUESTING_PHYSICIAN,
+    tags::ADMITTING_DIAGNOSES_DESCRIPTION,
+    tags::STUDY_DESCRIPTION,
+    tags::SERIES_DESCRIPTION,
+    tags::REQUESTED_PROCEDURE_DESCRIPTION,
+    tags::REQUESTED_PROCEDURE_ID,
+    tags::REQUEST_ATTRIBUTES_SEQUENCE,
+    tags::REFERENCED_STUDY_SEQUENCE,
+    tags::REFERENCED_PATIENT_SEQUENCE,
+    tags::REFERENCED_PERFORMED_PROCEDURE_STEP_SEQUENCE,
+    tags::PERFORMED_PROCEDURE_STEP_ID,
+    tags::PERFORMED_PROCEDURE_STEP_START_DATE,
+    tags::PERFORMED_PROCEDURE_STEP_START_TIME,
+    tags::PERFORMED_PROCEDURE_STEP_DESCRIPTION,
+    tags::PERFORMED_LOCATION,
+    tags::PATIENT_BIRTH_TIME,
+    tags::OTHER_PATIENT_I_DS,
+    tags::OTHER_PATIENT_NAMES,
+    tags::PATIENT_AGE,
+    tags::PATIENT_SIZE,
+    tags::PATIENT_WEIGHT,
+    tags::PATIENT_ADDRESS,
+    tags::ETHNIC_GROUP,
+    tags::OCCUPATION,
+    tags::ADDITIONAL_PATIENT_HISTORY,
+    tags::PATIENT_COMMENTS,
+    tags::MEDICAL_RECORD_LOCATOR,
+    tags::SERIES_DATE,
+    tags::SERIES_TIME,
+    tags::ACQUISITION_DATE,
+    tags::ACQUISITION_TIME,
+    tags::ACQUISITION_DATE_TIME,
+    tags::INSTANCE_CREATION_DATE,
+    tags::INSTANCE_CREATION_TIME,
+    tags::IMAGE_COMMENTS,
+    tags::DERIVATION_DESCRIPTION,
+];
+
+// removed unless the device identity is retained
+const DEVICE: [Tag; 8] = [
+    tags::DEVICE_UID,
+    tags::STATION_NAME,
+    tags::PERFORMED_STATION_NAME,
+    tags::DETECTOR_ID,
+    tags::DEVICE_SERIAL_NUMBER,
+    tags::PLATE_ID,
+    tags::CASSETTE_ID,
+    tags::GANTRY_ID,
+];
+
+// Basic Profile "U": replaced by a new UID, the same original gives the same UID,
+// so references (e.g. Referenced SOP Instance UID in sequence items) stay consistent
+const UIDS: [Tag; 12] = [
+    tags::STUDY_INSTANCE_UID,
+    tags::SERIES_INSTANCE_UID,
+    tags::SOP_INSTANCE_UID,
+    tags::REFERENCED_SOP_INSTANCE_UID,
+    tags::FRAME_OF_REFERENCE_UID,
+    tags::REFERENCED_FRAME_OF_REFERENCE_UID,
+    tags::SYNCHRONIZATION_FRAME_OF_REFERENCE_UID,
+    tags::IRRADIATION_EVENT_UID,
+    tags::CONCATENATION_UID,
+    tags::DIMENSION_ORGANIZATION_UID,
+    tags::INSTANCE_CREATOR_UID,
+    tags::STORAGE_MEDIA_FILE_SET_UID,
+];
+
+// namespace of the replaced UIDs (name-based UUID, version 5)
+const UID_NAMESPACE: &str = "lightbeam-deident";
+
+/// apply the Basic Application Level Confidentiality Profile to `obj`
+pub fn deidentify(obj: &mut InMemDicomObject, options: &DeidentOptions) {
+    deidentify_dataset(obj, options);
+
+    let mut method = "PS3.15 Basic Profile".to_string();
+    if options.retain_device_identity {
+        method.push_str(", Retain Device Identity Option");
+    }
+    obj.put(InMemElement::new(tags::PATIENT_IDENTITY_REMOVED, VR::CS, PrimitiveValue::from("YES")));
+    obj.put(InMemElement::new(tags::DEIDENTIFICATION_METHOD, VR::LO, PrimitiveValue::from(method)));
+}
+
+/// profile of one data set, then of the items of its sequences
+fn deidentify_dataset(obj: &mut InMemDicomObject, options: &DeidentOptions) {
+    for (tag, vr) in EMPTY {
+        if obj.element(tag).is_ok() {
+            obj.put(InMemElement::new(tag, vr, PrimitiveValue::Empty));
+        }
+    }
+    for tag in REMOVE {
+        obj.remove_element(tag);
+    }
+    if !options.retain_device_identity {
+        for tag in DEVICE {
+            obj.remove_element(tag);
+        }
+    }
+    for tag in UIDS {
+        let uid = get_str(obj, tag);
+        if !uid.is_empty() {
+            obj.put(InMemElement::new(tag, VR::UI, PrimitiveValue::from(replace_uid(&uid))));
+        }
+    }
+
+    // private attributes (odd groups)
+    let private: Vec<Tag> = (&*obj).into_iter()
+        .map(|e| e.header().tag)
+        .filter(|tag| tag.0 % 2 == 1)
+        .collect();
+    for tag in private {
+        obj.remove_element(tag);
+    }
+
+    // identifiers inside sequence items (e.g. names in referenced/request sequences)
+    let sequences: Vec<Tag> = (&*obj).into_iter()
+        .filter(|e| e.items().is_some())
+        .map(|e| e.header().tag)
+        .collect();
+    for tag in sequences {
+        let Some(mut items) = obj.element(tag).ok().and_then(|e| e.items()).map(|items| items.to_vec()) else { continue };
+        for item in &mut items {
+            deidentify_dataset(item, options);
+        }
+        obj.put(InMemElement::new(tag, VR::SQ, Value::Sequence { items: items.into(), size: Length::UNDEFINED }));
+    }
+}
+
+/// write a de-identified copy of `src` into `out_dir` as <new SOP Instance UID>.dcm
+///
+/// Returns: path of the copy
+pub fn deidentify_file(src: &str, out_dir: &Path, options: &DeidentOptions) -> Result<PathBuf, String> {
+    let file = open_file(src).map_err(|err| format!("{}: {}", src, err))?;
+    let sop_class_uid = file.meta().media_storage_sop_class_uid.trim_end_matches('\0').to_string();
+    let ts = file.meta().transfer_syntax().trim_end_matches('\0').to_string();
+    let mut obj = file.into_inner();
+    deidentify(&mut obj, options);
+
+    let sop_instance_uid = get_str(&obj, tags::SOP_INSTANCE_UID);
+    let meta = FileMetaTableBuilder::new()
+        .media_storage_sop_class_uid(sop_class_uid.as_str())
+        .media_storage_sop_instance_uid(sop_instance_uid.as_str())
+        .transfer_syntax(ts.as_str())
+        .build()
+        .map_err(|err| err.to_string())?;
+    let path = out_dir.join(format!("{}.dcm", sop_instance_uid));
+    obj.with_exact_meta(meta).write_to_file(&path).map_err(|err| err.to_string())?;
+    Ok(path)
+}
+
+/// result with institution name and address (and detector id) removed from `details`,
+/// used before a result leaves the application
+pub fn deidentify_result(result: &CollimatorResult, options: &DeidentOptions) -> CollimatorResult {
+    let mut result = result.clone();
+    deidentify_details(&mut result.details, options);
+    result
+}
+
+/// blank hospital, address and detector id of `detector_details`
+pub fn deidentify_details(details: &mut [String], options: &DeidentOptions) {
+    let mut hidden = vec![DETAIL_HOSPITAL, DETAIL_ADDRESS];
+    if !options.retain_device_identity {
+        hidden.push(DETAIL_DETECTOR_ID);
+    }
+    for i in hidden {
+        if let Some(detail) = details.get_mut(i) {
+            *detail = " - ".to_string();
+        }
+    }
+}
+
+/// "2.25." UID of the name-based (SHA-1, version 5) UUID of `uid`, the same
+/// on every platform and Rust release
+fn replace_uid(uid: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(UID_NAMESPACE.as_bytes());
+    hasher.update(uid.as_bytes());
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&hasher.digest().bytes()[..16]);
+    bytes[6] = (bytes[6] & 0x0f) | 0x50;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    format!("2.25.{}", u128::from_be_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaced_uid_is_fixed() {
+        // SHA-1 name-based UUID: must not change between builds
+        assert_eq!(replace_uid("1.2.840.10008.1.2.3"), "2.25.7751106769184975003754781258753801784");
+        assert_ne!(replace_uid("1.2.3"), replace_uid("1.2.4"));
+        assert!(replace_uid("1.2.3").len() <= 64);
+    }
+
+    #[test]
+    fn sequence_items_are_deidentified() {
+        let item = InMemDicomObject::from_element_iter([
+            InMemElement::new(tags::PATIENT_NAME, VR::PN, PrimitiveValue::from("Doe^John")),
+            InMemElement::new(tags::OPERATORS_NAME, VR::PN, PrimitiveValue::from("Tech^A")),
+            InMemElement::new(Tag(0x0009, 0x1001), VR::LO, PrimitiveValue::from("private")),
+        ]);
+        let mut obj = InMemDicomObject::from_element_iter([
+            InMemElement::new(tags::PATIENT_NAME, VR::PN, PrimitiveValue::from("Doe^John")),
+            InMemElement::new(tags::REFERENCED_IMAGE_SEQUENCE, VR::SQ, Value::Sequence { items: vec![item].into(), size: Length::UNDEFINED }),
+        ]);
+        deidentify(&mut obj, &DeidentOptions::default());
+
+        let items = obj.element(tags::REFERENCED_IMAGE_SEQUENCE).unwrap().items().unwrap();
+        assert_eq!(get_str(&items[0], tags::PATIENT_NAME), "");
+        assert!(items[0].element(tags::OPERATORS_NAME).is_err());
+        assert!(items[0].element(Tag(0x0009, 0x1001)).is_err());
+        assert_eq!(get_str(&obj, tags::PATIENT_NAME), "");
+    }
+
+    #[test]
+    fn references_follow_the_replaced_uids() {
+        let item = InMemDicomObject::from_element_iter([
+            InMemElement::new(tags::REFERENCED_SOP_INSTANCE_UID, VR::UI, PrimitiveValue::from("1.2.3.4.5")),
+        ]);
+        let mut obj = InMemDicomObject::from_element_iter([
+            InMemElement::new(tags::SOP_INSTANCE_UID, VR::UI, PrimitiveValue::from("1.2.3.4.5")),
+            InMemElement::new(tags::REFERENCED_IMAGE_SEQUENCE, VR::SQ, Value::Sequence { items: vec![item].into(), size: Length::UNDEFINED }),
+        ]);
+        deidentify(&mut obj, &DeidentOptions::default());
+
+        let items = obj.element(tags::REFERENCED_IMAGE_SEQUENCE).unwrap().items().unwrap();
+        assert_eq!(get_str(&obj, tags::SOP_INSTANCE_UID), replace_uid("1.2.3.4.5"));
+        assert_eq!(get_str(&items[0], tags::REFERENCED_SOP_INSTANCE_UID), replace_uid("1.2.3.4.5"));
+    }
+
+    #[test]
+    fn device_identity_removed() {
+        let options = DeidentOptions { retain_device_identity: false };
+        let mut obj = InMemDicomObject::from_element_iter([
+            InMemElement::new(tags::DETECTOR_ID, VR::SH, PrimitiveValue::from("DR-0042")),
+            InMemElement::new(tags::STATION_NAME, VR::SH, PrimitiveValue::from("ROOM1")),
+        ]);
+        deidentify(&mut obj, &options);
+        assert!(obj.element(tags::DETECTOR_ID).is_err());
+        assert!(obj.element(tags::STATION_NAME).is_err());
+    }
+
+    #[test]
+    fn details_are_blanked() {
+        let details = || -> Vec<String> { (0..9).map(|i| format!("detail {}", i)).collect() };
+        let mut retained = details();
+        deidentify_details(&mut retained, &DeidentOptions::default());
+        assert_eq!(retained[DETAIL_HOSPITAL], " - ");
+        assert_eq!(retained[DETAIL_ADDRESS], " - ");
+        assert_eq!(retained[DETAIL_DETECTOR_ID], "detail 5");
+
+        let mut removed = details();
+        deidentify_details(&mut removed, &DeidentOptions { retain_device_identity: false });
+        assert_eq!(removed[5], " - ");
+        assert_eq!(removed.iter().filter(|d| *d == " - ").count(), 3);
+    }
+}
@@ -0,0 +1,249 @@
+use dicom::core::{DataElement, PrimitiveValue, VR};
+use dicom::dictionary_std::tags;
+use dicom::object::mem::InMemElement;
+use dicom::object::InMemDicomObject;
+use dicom::transfer_syntax::entries::IMPLICIT_VR_LITTLE_ENDIAN;
+use dicom::transfer_syntax::{TransferSyntaxIndex, TransferSyntaxRegistry};
+use dicom::ul::association::{ClientAssociation, ServerAssociation};
+use dicom::ul::pdu::{PDataValue, PDataValueType, Pdu};
+
+// command field values (PS3.7 E.1)
+pub const C_STORE_RQ: u16 = 0x0001;
+pub const C_STORE_RSP: u16 = 0x8001;
+pub const C_FIND_RQ: u16 = 0x0020;
+pub const C_FIND_RSP: u16 = 0x8020;
+pub const C_MOVE_RQ: u16 = 0x0021;
+pub const C_MOVE_RSP: u16 = 0x8021;
+pub const C_ECHO_RQ: u16 = 0x0030;
+pub const C_ECHO_RSP: u16 = 0x8030;
+/// command data set type: no data set follows
+pub const NO_DATA_SET: u16 = 0x0101;
+pub const DATA_SET: u16 = 0x0000;
+
+pub const STATUS_SUCCESS: u16 = 0x0000;
+pub const STATUS_PENDING: u16 = 0xFF00;
+/// pending, some optional keys not supported
+pub const STATUS_PENDING_WARNING: u16 = 0xFF01;
+/// out of resources / cannot understand
+pub const STATUS_STORE_FAILED: u16 = 0xA700;
+/// unrecognized operation (PS3.7 C.5.3)
+pub const STATUS_UNRECOGNIZED_OPERATION: u16 = 0x0211;
+/// response bit of the command field
+pub const RESPONSE: u16 = 0x8000;
+
+pub const IMPLICIT_VR_LE: &str = "1.2.840.10008.1.2";
+pub const EXPLICIT_VR_LE: &str = "1.2.840.10008.1.2.1";
+
+pub const VERIFICATION: &str = "1.2.840.10008.1.1";
+pub const CR_IMAGE_STORAGE: &str = "1.2.840.10008.5.1.4.1.1.1";
+pub const DX_PRESENTATION_STORAGE: &str = "1.2.840.10008.5.1.4.1.1.1.1";
+pub const DX_PROCESSING_STORAGE: &str = "1.2.840.10008.5.1.4.1.1.1.1.1";
+pub const STUDY_ROOT_FIND: &str = "1.2.840.10008.5.1.4.1.2.2.1";
+pub const STUDY_ROOT_MOVE: &str = "1.2.840.10008.5.1.4.1.2.2.2";
+/// storage classes of the QA images
+pub const STORAGE_CLASSES: [&str; 3] = [CR_IMAGE_STORAGE, DX_PRESENTATION_STORAGE, DX_PROCESSING_STORAGE];
+
+// PDV header within a P-DATA PDU (item length, context id, message control header)
+const PDV_HEADER_LEN: usize = 6;
+// PDV payload for the default maximum PDU length, if the peer sets no limit
+const DEFAULT_MAX_PDV_LEN: usize = 16384 - PDV_HEADER_LEN;
+
+/// DIMSE message: command set and the encoded data set (if any)
+pub struct Message {
+    pub pc_id: u8,
+    pub command: InMemDicomObject,
+    pub data: Option<Vec<u8>>,
+}
+
+impl Message {
+    pub fn command_field(&self) -> u16 {
+        command_u16(&self.command, tags::COMMAND_FIELD).unwrap_or(0)
+    }
+
+    pub fn status(&self) -> u16 {
+        command_u16(&self.command, tags::STATUS).unwrap_or(0)
+    }
+
+    pub fn command_str(&self, tag: dicom::object::Tag) -> String {
+        get_str(&self.command, tag)
+    }
+
+    pub fn is_pending(&self) -> bool {
+        matches!(self.status(), STATUS_PENDING | STATUS_PENDING_WARNING)
+    }
+}
+
+/// trimmed string value, empty if the element is missing
+pub fn get_str(obj: &InMemDicomObject, tag: dicom::object::Tag) -> String {
+    match obj.element(tag) {
+        Ok(e) => e.to_str().map(|s| s.trim_end_matches('\0').trim().to_string()).unwrap_or_default(),
+        Err(_) => String::new(),
+    }
+}
+
+fn command_u16(obj: &InMemDicomObject, tag: dicom::object::Tag) -> Option<u16> {
+    obj.element(tag).ok()?.to_int::<u16>().ok()
+}
+
+/// server and client associations, so messages are sent the same way on both
+pub trait Association {
+    fn send_pdu(&mut self, pdu: &Pdu) -> Result<(), String>;
+    fn receive_pdu(&mut self) -> Result<Pdu, String>;
+    /// negotiated transfer syntax of a presentation context
+    fn transfer_syntax(&self, pc_id: u8) -> Option<String>;
+    /// largest PDV payload the peer accepts
+    fn max_pdv_len(&self) -> usize;
+}
+
+/// PDV payload within the maximum PDU length negotiated by the peer (0: no limit)
+fn pdv_len(max_pdu_length: u32) -> usize {
+    match max_pdu_length as usize {
+        0 => DEFAULT_MAX_PDV_LEN,
+        n => n.saturating_sub(PDV_HEADER_LEN).max(1),
+    }
+}
+
+impl Association for ServerAssociation {
+    fn send_pdu(&mut self, pdu: &Pdu) -> Result<(), String> {
+        self.send(pdu).map_err(|err| err.to_string())
+    }
+
+    fn receive_pdu(&mut self) -> Result<Pdu, String> {
+        self.receive().map_err(|err| err.to_string())
+    }
+
+    fn transfer_syntax(&self, pc_id: u8) -> Option<String> {
+        self.presentation_contexts().iter()
+            .find(|pc| pc.id == pc_id)
+            .map(|pc| pc.transfer_syntax.trim_end_matches('\0').to_string())
+    }
+
+    fn max_pdv_len(&self) -> usize {
+        pdv_len(self.requestor_max_pdu_length())
+    }
+}
+
+impl Association for ClientAssociation {
+    fn send_pdu(&mut self, pdu: &Pdu) -> Result<(), String> {
+        self.send(pdu).map_err(|err| err.to_string())
+    }
+
+    fn receive_pdu(&mut self) -> Result<Pdu, String> {
+        self.receive().map_err(|err| err.to_string())
+    }
+
+    fn transfer_syntax(&self, pc_id: u8) -> Option<String> {
+        self.presentation_contexts().iter()
+            .find(|pc| pc.id == pc_id)
+            .map(|pc| pc.transfer_syntax.trim_end_matches('\0').to_string())
+    }
+
+    fn max_pdv_len(&self) -> usize {
+        pdv_len(self.acceptor_max_pdu_length())
+    }
+}
+
+/// command set with the group length, encoded in implicit VR little endian
+pub fn command(elements: Vec<InMemElement>) -> Vec<u8> {
+    let obj = InMemDicomObject::command_from_element_iter(elements);
+    let mut bytes = Vec::new();
+    obj.write_dataset_with_ts(&mut bytes, &IMPLICIT_VR_LITTLE_ENDIAN.erased())
+        .expect("command set can always be encoded");
+    bytes
+}
+
+pub fn element_u16(tag: dicom::object::Tag, value: u16) -> InMemElement {
+    DataElement::new(tag, VR::US, PrimitiveValue::from(value))
+}
+
+pub fn element_str(tag: dicom::object::Tag, vr: VR, value: &str) -> InMemElement {
+    DataElement::new(tag, vr, PrimitiveValue::from(value))
+}
+
+/// decode a data set received in transfer syntax `ts_uid`
+pub fn read_dataset(bytes: &[u8], ts_uid: &str) -> Option<InMemDicomObject> {
+    let ts = TransferSyntaxRegistry.get(ts_uid.trim_end_matches('\0'))?;
+    InMemDicomObject::read_dataset_with_ts(bytes, ts).ok()
+}
+
+/// encode a data set in transfer syntax `ts_uid`
+pub fn write_dataset(obj: &InMemDicomObject, ts_uid: &str) -> Option<Vec<u8>> {
+    let ts = TransferSyntaxRegistry.get(ts_uid.trim_end_matches('\0'))?;
+    let mut bytes = Vec::new();
+    obj.write_dataset_with_ts(&mut bytes, ts).ok()?;
+    Some(bytes)
+}
+
+/// send a command (and data set) as P-DATA, split into PDVs
+pub fn send_message(assoc: &mut dyn Association, pc_id: u8, command: &[u8], data: Option<&[u8]>) -> Result<(), String> {
+    send_fragments(assoc, pc_id, PDataValueType::Command, command)?;
+    if let Some(data) = data {
+        send_fragments(assoc, pc_id, PDataValueType::Data, data)?;
+    }
+    Ok(())
+}
+
+fn send_fragments(assoc: &mut dyn Association, pc_id: u8, value_type: PDataValueType, bytes: &[u8]) -> Result<(), String> {
+    let max_len = assoc.max_pdv_len();
+    let n = bytes.len().div_ceil(max_len).max(1);
+    for i in 0..n {
+        let chunk = &bytes[i * max_len..((i + 1) * max_len).min(bytes.len())];
+        let pdu = Pdu::PData {
+            data: vec![PDataValue {
+                presentation_context_id: pc_id,
+                value_type,
+                is_last: i == n - 1,
+                data: chunk.to_vec(),
+            }],
+        };
+        assoc.send_pdu(&pdu)?;
+    }
+    Ok(())
+}
+
+/// receive the next complete message
+///
+/// Returns: None when the peer releases the association
+pub fn receive_message(assoc: &mut dyn Association) -> Result<Option<Message>, String> {
+    let mut command_bytes = Vec::new();
+    let mut command: Option<InMemDicomObject> = None;
+    let mut data_bytes = Vec::new();
+    loop {
+        match assoc.receive_pdu()? {
+            Pdu::PData { data } => {
+                for pdv in data {
+                    let pc_id = pdv.presentation_context_id;
+                    match pdv.value_type {
+                        PDataValueType::Command => {
+                            command_bytes.extend_from_slice(&pdv.data);
+                            if !pdv.is_last {
+                                continue;
+                            }
+                            let obj = read_dataset(&command_bytes, IMPLICIT_VR_LE)
+                                .ok_or("cannot decode DIMSE command")?;
+                            if command_u16(&obj, tags::COMMAND_DATA_SET_TYPE) == Some(NO_DATA_SET) {
+                                return Ok(Some(Message { pc_id, command: obj, data: None }));
+                            }
+                            command = Some(obj);
+                        }
+                        PDataValueType::Data => {
+                            data_bytes.extend_from_slice(&pdv.data);
+                            if !pdv.is_last {
+                                continue;
+                            }
+                            let command = command.take().ok_or("data set before command")?;
+                            return Ok(Some(Message { pc_id, command, data: Some(data_bytes) }));
+                        }
+                    }
+                }
+            }
+            Pdu::ReleaseRQ => {
+                assoc.send_pdu(&Pdu::ReleaseRP)?;
+                return Ok(None);
+            }
+            Pdu::ReleaseRP => return Ok(None),
+            Pdu::AbortRQ { .. } => return Err("association aborted by peer".to_string()),
+            _ => return Err("unexpected PDU".to_string()),
+        }
+    }
+}
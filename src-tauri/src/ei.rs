@@ -0,0 +1,204 @@
+use std::fs;
+use dicom::dictionary_std::tags;
+use dicom::object::OpenFileOptions;
+use serde::{Deserialize, Serialize};
+use crate::analysis::{detector_details, AnalysisError};
+use crate::history::{History, HistoryEntry};
+use crate::plot::{grid_step, save_plot};
+use crate::tolerance::{all_passed, Check, ToleranceProfile};
+use crate::utils::{get_value, DcmObj};
+
+// IEC 62494-1 calibration: EI = 100 x air kerma (µGy) at RQA5
+const EI_PER_UGY: f64 = 100.0;
+
+/// dose indicators of one flat exposure at a known air kerma
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DoseIndicator {
+    pub file_path: String,
+    pub kerma_ugy: f64,
+    /// Exposure Index (0018,1411)
+    pub exposure_index: f64,
+    /// Target Exposure Index (0018,1412)
+    pub target_exposure_index: Option<f64>,
+    /// Deviation Index (0018,1413) as stored
+    pub deviation_index: Option<f64>,
+    /// 10 log10(EI / EI_T)
+    pub expected_deviation_index: Option<f64>,
+    /// EI against 100 x kerma (%)
+    pub calibration_error: f64,
+}
+
+/// detector dose indicator test (IEC 62494-1)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EiResult {
+    pub details: Vec<String>,
+    pub exposures: Vec<DoseIndicator>,
+    /// least squares EI = slope x kerma + intercept
+    pub slope: f64,
+    pub intercept: f64,
+    pub r2: f64,
+    /// max EI deviation from the fit (%)
+    pub linearity_error: f64,
+    /// max |calibration_error| (%)
+    pub calibration_error: f64,
+    /// max |DI - expected DI|, None if no image has DI and EI_T
+    pub di_error: Option<f64>,
+    pub profile: String,
+    pub checks: Vec<Check>,
+    pub passed: bool,
+}
+
+/// EI/DI of flat exposures at known air kerma (µGy at the detector), the
+/// result is added to `history`
+///
+/// csv_path: one row per exposure; plot_path: EI (blue), fit (red), 100 x kerma (green)
+pub fn run_ei(file_paths: &[String], kerma_ugy: &[f64], csv_path: &str, plot_path: &str, profile: &ToleranceProfile, history: &History) -> Result<EiResult, AnalysisError> {
+    if file_paths.len() != kerma_ugy.len() {
+        return Err(AnalysisError::Measurement("one air kerma value per image is needed".to_string()));
+    }
+    let mut exposures = Vec::with_capacity(file_paths.len());
+    let mut first: Option<DcmObj> = None;
+    for (file_path, &kerma) in file_paths.iter().zip(kerma_ugy) {
+        if kerma <= 0.0 {
+            return Err(AnalysisError::Measurement(format!("air kerma of {} must be positive", file_path)));
+        }
+        // header only, the pixel data is not needed
+        let obj = OpenFileOptions::new()
+            .read_until(tags::PIXEL_DATA)
+            .open_file(file_path)
+            .map_err(|_| AnalysisError::Load(file_path.to_owned()))?;
+        let exposure_index = get_value(&obj, tags::EXPOSURE_INDEX)
+            .ok_or_else(|| AnalysisError::Measurement(format!("no Exposure Index in {}", file_path)))?;
+        let target_exposure_index = get_value(&obj, tags::TARGET_EXPOSURE_INDEX);
+        let expected_deviation_index = target_exposure_index
+            .filter(|&t| t > 0.0 && exposure_index > 0.0)
+            .map(|t| 10.0 * (exposure_index / t).log10());
+        exposures.push(DoseIndicator {
+            file_path: file_path.to_owned(),
+            kerma_ugy: kerma,
+            exposure_index,
+            target_exposure_index,
+            deviation_index: get_value(&obj, tags::DEVIATION_INDEX),
+            expected_deviation_index,
+            calibration_error: (exposure_index - EI_PER_UGY * kerma) / (EI_PER_UGY * kerma) * 100.0,
+        });
+        first.get_or_insert(obj);
+    }
+    let obj = first.ok_or_else(|| AnalysisError::Measurement("no images".to_string()))?;
+
+    let xs: Vec<f64> = exposures.iter().map(|e| e.kerma_ugy).collect();
+    let ys: Vec<f64> = exposures.iter().map(|e| e.exposure_index).collect();
+    let (slope, intercept, r2) = fit_line(&xs, &ys)
+        .ok_or_else(|| AnalysisError::Measurement("at least two different air kerma values are needed".to_string()))?;
+    let linearity_error = exposures.iter()
+        .map(|e| {
+            let fit = slope * e.kerma_ugy + intercept;
+            if fit != 0.0 { (e.exposure_index - fit).abs() / fit.abs() * 100.0 } else { 0.0 }
+        })
+        .fold(0.0, f64::max);
+    let calibration_error = exposures.iter().map(|e| e.calibration_error.abs()).fold(0.0, f64::max);
+    let di_error = exposures.iter()
+        .filter_map(|e| Some((e.deviation_index? - e.expected_deviation_index?).abs()))
+        .reduce(f64::max);
+
+    let mut checks = vec![
+        profile.check("ei.linearity_r2", r2),
+        profile.check("ei.linearity_error", linearity_error),
+        profile.check("ei.calibration_error", calibration_error),
+    ];
+    if let Some(di_error) = di_error {
+        checks.push(profile.check("ei.di_error", di_error));
+    }
+    let passed = all_passed(&checks);
+
+    let mut entry = HistoryEntry::for_image("ei", &obj);
+    for check in &checks {
+        entry.values.insert(check.key.trim_start_matches("ei.").to_string(), check.value);
+    }
+    entry.values.insert("slope".to_string(), slope);
+    entry.passed = Some(passed);
+    if let Err(err) = history.append(&entry) {
+        println!("HISTORY: ERR {}", err);
+    }
+
+    let result = EiResult {
+        details: detector_details(&obj),
+        exposures,
+        slope,
+        intercept,
+        r2,
+        linearity_error,
+        calibration_error,
+        di_error,
+        profile: profile.name.clone(),
+        checks,
+        passed,
+    };
+    save_exposures(&result, csv_path, plot_path)?;
+    println!("EI: slope {:.1} r2 {:.4}", slope, r2);
+    Ok(result)
+}
+
+/// least squares y = slope * x + intercept
+///
+/// Returns: slope, intercept, r², None if all x are equal
+fn fit_line(xs: &[f64], ys: &[f64]) -> Option<(f64, f64, f64)> {
+    let n = xs.len() as f64;
+    let mx = xs.iter().sum::<f64>() / n;
+    let my = ys.iter().sum::<f64>() / n;
+    let sxx: f64 = xs.iter().map(|x| (x - mx).powi(2)).sum();
+    let syy: f64 = ys.iter().map(|y| (y - my).powi(2)).sum();
+    let sxy: f64 = xs.iter().zip(ys).map(|(x, y)| (x - mx) * (y - my)).sum();
+    if xs.len() < 2 || sxx <= 0.0 {
+        return None;
+    }
+    let slope = sxy / sxx;
+    let r2 = if syy > 0.0 { sxy * sxy / (sxx * syy) } else { 1.0 };
+    Some((slope, my - slope * mx, r2))
+}
+
+fn save_exposures(result: &EiResult, csv_path: &str, plot_path: &str) -> Result<(), AnalysisError> {
+    let optional = |v: Option<f64>| v.map(|v| format!("{:.2}", v)).unwrap_or_default();
+    let mut csv = String::from("file,kerma_ugy,ei,target_ei,di,expected_di,calibration_error\n");
+    for e in &result.exposures {
+        csv.push_str(&format!("\"{}\",{:.3},{:.1},{},{},{},{:.2}\n",
+            e.file_path, e.kerma_ugy, e.exposure_index, optional(e.target_exposure_index),
+            optional(e.deviation_index), optional(e.expected_deviation_index), e.calibration_error));
+    }
+    fs::write(csv_path, csv).map_err(|err| AnalysisError::Measurement(err.to_string()))?;
+
+    let mut measured: Vec<(f64, f64)> = result.exposures.iter().map(|e| (e.kerma_ugy, e.exposure_index)).collect();
+    measured.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let x_max = measured.last().map(|p| p.0).unwrap_or(1.0) * 1.1;
+    let fit = vec![(0.0, result.intercept), (x_max, result.slope * x_max + result.intercept)];
+    let nominal = vec![(0.0, 0.0), (x_max, EI_PER_UGY * x_max)];
+    let y_max = measured.iter().map(|p| p.1).chain([fit[1].1, nominal[1].1]).fold(0.0, f64::max);
+    save_plot(plot_path, &[measured, fit, nominal], x_max, y_max, grid_step(x_max), grid_step(y_max))
+        .map_err(AnalysisError::Measurement)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_line() {
+        // EI = 100 x kerma, as a calibrated detector
+        let (slope, intercept, r2) = fit_line(&[1.0, 2.5, 5.0, 10.0], &[100.0, 250.0, 500.0, 1000.0]).unwrap();
+        assert!((slope - 100.0).abs() < 1e-9 && intercept.abs() < 1e-9 && (r2 - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn scattered_points() {
+        // y = 2x + 1 with residuals +1 -1 -1 +1: r² = 1 - 4 / (Σ(y - ȳ)²)
+        let (slope, intercept, r2) = fit_line(&[0.0, 1.0, 2.0, 3.0], &[2.0, 2.0, 4.0, 8.0]).unwrap();
+        assert!((slope - 2.0).abs() < 1e-9 && (intercept - 1.0).abs() < 1e-9, "{} {}", slope, intercept);
+        assert!((r2 - (1.0 - 4.0 / 24.0)).abs() < 1e-9, "r2 {}", r2);
+    }
+
+    #[test]
+    fn one_exposure_level() {
+        assert!(fit_line(&[2.0, 2.0], &[190.0, 210.0]).is_none());
+        assert!(fit_line(&[2.0], &[200.0]).is_none());
+    }
+}
@@ -0,0 +1,101 @@
+use std::f64::consts::PI;
+use ndarray::{Array2, Axis};
+
+/// in-place radix-2 FFT, `re` and `im` length must be a power of two
+pub fn fft(re: &mut [f64], im: &mut [f64]) {
+    let n = re.len();
+    assert!(n.is_power_of_two() && im.len() == n);
+    // bit reversal permutation
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f64;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f64).sin_cos();
+                let a = start + k;
+                let b = a + len / 2;
+                let tr = re[b] * cos - im[b] * sin;
+                let ti = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - tr;
+                im[b] = im[a] - ti;
+                re[a] += tr;
+                im[a] += ti;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+/// |FFT| of a real signal, zero padded to the next power of two
+///
+/// Returns: magnitude of the first n/2 + 1 frequencies and the padded length
+pub fn magnitude(signal: &[f64]) -> (Vec<f64>, usize) {
+    let n = signal.len().next_power_of_two();
+    let mut re = signal.to_vec();
+    re.resize(n, 0.0);
+    let mut im = vec![0.0; n];
+    fft(&mut re, &mut im);
+    let mag = re.iter().zip(&im).take(n / 2 + 1).map(|(r, i)| r.hypot(*i)).collect();
+    (mag, n)
+}
+
+/// |FFT|² of a real 2D array (sides must be powers of two)
+pub fn power_spectrum_2d(arr: &Array2<f64>) -> Array2<f64> {
+    let mut re = arr.clone();
+    let mut im = Array2::<f64>::zeros(arr.dim());
+    for axis in [Axis(1), Axis(0)] {
+        for (mut r, mut i) in re.lanes_mut(axis).into_iter().zip(im.lanes_mut(axis)) {
+            let mut lr = r.to_vec();
+            let mut li = i.to_vec();
+            fft(&mut lr, &mut li);
+            r.assign(&ndarray::ArrayView1::from(&lr));
+            i.assign(&ndarray::ArrayView1::from(&li));
+        }
+    }
+    re.mapv(|v| v * v) + im.mapv(|v| v * v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_dft() {
+        let n = 16;
+        let signal: Vec<f64> = (0..n).map(|i| ((i * 7 + 3) % 11) as f64 - 5.0).collect();
+        let mut re = signal.clone();
+        let mut im = vec![0.0; n];
+        fft(&mut re, &mut im);
+        for k in 0..n {
+            let (mut dr, mut di) = (0.0, 0.0);
+            for (j, x) in signal.iter().enumerate() {
+                let angle = -2.0 * PI * (j * k) as f64 / n as f64;
+                dr += x * angle.cos();
+                di += x * angle.sin();
+            }
+            assert!((re[k] - dr).abs() < 1e-9 && (im[k] - di).abs() < 1e-9, "bin {}", k);
+        }
+    }
+
+    #[test]
+    fn power_spectrum_of_a_cosine() {
+        // cos along x at 2 cycles per 8 pixels: two peaks of (N² / 2)²
+        let arr = Array2::from_shape_fn((8, 8), |(_, x)| (2.0 * PI * 2.0 * x as f64 / 8.0).cos());
+        let power = power_spectrum_2d(&arr);
+        assert!((power[[0, 2]] - 1024.0).abs() < 1e-6 && (power[[0, 6]] - 1024.0).abs() < 1e-6);
+        assert!((power.sum() - 2048.0).abs() < 1e-6);
+    }
+}
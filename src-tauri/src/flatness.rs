@@ -0,0 +1,154 @@
+use ndarray::{s, ArrayView1, Axis};
+use serde::{Deserialize, Serialize};
+use crate::plot::{grid_step, save_plot};
+use crate::utils::U16View;
+
+// central part of the other axis whose median gives the profile
+const BAND: f64 = 0.3;
+// running median along the profile (fraction of its length), removes the tool marks
+const MARK_WINDOW: f64 = 0.04;
+// evaluated central part of the profile, the field edges are left out
+const EVALUATED: f64 = 0.8;
+// centre value: mean of this central part
+const CENTRE: f64 = 0.1;
+// plotted range (% of the centre value)
+const PLOT_RANGE: (f64, f64) = (80.0, 120.0);
+
+/// intensity profile of the open field along one axis
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FieldProfile {
+    /// position (cm, px if the pixel spacing is unknown) from the first evaluated point
+    pub positions: Vec<f64>,
+    /// % of the centre value
+    pub values: Vec<f64>,
+    /// fitted change over the evaluated length (% of the centre value),
+    /// positive if the intensity grows along the axis
+    pub gradient_percent: f64,
+    /// (max - min) / (max + min) (%)
+    pub flatness_percent: f64,
+    /// max difference of mirrored points (% of the centre value)
+    pub symmetry_percent: f64,
+}
+
+/// heel effect and flatness of the large field (pixel values as stored)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Flatness {
+    /// along the image columns (x) and rows (y) of the rotated tool area
+    pub x: FieldProfile,
+    pub y: FieldProfile,
+    /// "x" or "y": axis with the larger gradient, taken as the anode-cathode axis
+    pub heel_axis: String,
+}
+
+impl Flatness {
+    pub fn heel(&self) -> &FieldProfile {
+        if self.heel_axis == "x" { &self.x } else { &self.y }
+    }
+
+    pub fn transverse(&self) -> &FieldProfile {
+        if self.heel_axis == "x" { &self.y } else { &self.x }
+    }
+}
+
+/// profiles along x and y through the field centre, None if the area is too small
+pub fn field_flatness(arr: U16View, pixel_spacing: Option<f64>) -> Option<Flatness> {
+    let (h, w) = arr.dim();
+    let band = |n: usize| {
+        let half = ((n as f64 * BAND / 2.0) as usize).max(1);
+        (n / 2).saturating_sub(half)..(n / 2 + half).min(n)
+    };
+    let x = field_profile(arr.slice(s![band(h), ..]), Axis(0), pixel_spacing)?;
+    let y = field_profile(arr.slice(s![.., band(w)]), Axis(1), pixel_spacing)?;
+    let heel_axis = if x.gradient_percent.abs() >= y.gradient_percent.abs() { "x" } else { "y" };
+    Some(Flatness { x, y, heel_axis: heel_axis.to_string() })
+}
+
+/// median over `axis` of the band, then a running median along the profile
+fn field_profile(band: U16View, axis: Axis, pixel_spacing: Option<f64>) -> Option<FieldProfile> {
+    let raw: Vec<f64> = band.axis_iter(Axis(1 - axis.index()))
+        .map(|line| median(line.iter().map(|&v| v as f64).collect()))
+        .collect();
+    let n = raw.len();
+    let half = ((n as f64 * MARK_WINDOW) as usize / 2).max(1);
+    let smooth: Vec<f64> = (0..n)
+        .map(|i| median(raw[i.saturating_sub(half)..(i + half + 1).min(n)].to_vec()))
+        .collect();
+
+    let skip = (n as f64 * (1.0 - EVALUATED) / 2.0) as usize;
+    let evaluated = &smooth[skip..n - skip];
+    if evaluated.len() < 16 {
+        return None;
+    }
+    let m = evaluated.len();
+    let centre_half = ((m as f64 * CENTRE / 2.0) as usize).max(1);
+    let centre = ArrayView1::from(&evaluated[m / 2 - centre_half..m / 2 + centre_half]).mean()?;
+    if centre <= 0.0 {
+        return None;
+    }
+    let values: Vec<f64> = evaluated.iter().map(|v| v / centre * 100.0).collect();
+
+    // least squares slope (% per sample) times the evaluated length
+    let mx = (m - 1) as f64 / 2.0;
+    let my = values.iter().sum::<f64>() / m as f64;
+    let sxx: f64 = (0..m).map(|i| (i as f64 - mx).powi(2)).sum();
+    let sxy: f64 = values.iter().enumerate().map(|(i, v)| (i as f64 - mx) * (v - my)).sum();
+    let gradient_percent = sxy / sxx * (m - 1) as f64;
+    let max = values.iter().copied().fold(f64::MIN, f64::max);
+    let min = values.iter().copied().fold(f64::MAX, f64::min);
+    let symmetry_percent = (0..m / 2).map(|i| (values[i] - values[m - 1 - i]).abs()).fold(0.0, f64::max);
+
+    let scale = pixel_spacing.map_or(1.0, |spacing| spacing / 10.0);
+    Some(FieldProfile {
+        positions: (0..m).map(|i| i as f64 * scale).collect(),
+        values,
+        gradient_percent,
+        flatness_percent: (max - min) / (max + min) * 100.0,
+        symmetry_percent,
+    })
+}
+
+fn median(mut values: Vec<f64>) -> f64 {
+    let mid = values.len() / 2;
+    *values.select_nth_unstable_by(mid, f64::total_cmp).1
+}
+
+/// anode-cathode (blue) and transverse (red) profiles, 80 - 120% of the centre value
+pub fn save_flatness_plot(flatness: &Flatness, path: &str) -> Result<(), String> {
+    let curves: Vec<Vec<(f64, f64)>> = [flatness.heel(), flatness.transverse()].iter()
+        .map(|p| p.positions.iter().zip(&p.values).map(|(&x, &v)| (x, v - PLOT_RANGE.0)).collect())
+        .collect();
+    let x_max = curves.iter().flatten().map(|p| p.0).fold(0.0, f64::max).max(f64::EPSILON);
+    save_plot(path, &curves, x_max, PLOT_RANGE.1 - PLOT_RANGE.0, grid_step(x_max), 5.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::Array2;
+
+    #[test]
+    fn ramp_along_x() {
+        // +1 per column: about 400 over the evaluated 80%, centre about 1250
+        let arr = Array2::from_shape_fn((300, 500), |(_, x)| 1000 + x as u16);
+        let flatness = field_flatness(arr.view(), Some(0.2)).unwrap();
+        assert_eq!(flatness.heel_axis, "x");
+        let values = &flatness.x.values;
+        // linear profile: the fitted change is the end to end difference
+        assert!((flatness.x.gradient_percent - (values[values.len() - 1] - values[0])).abs() < 1e-9);
+        assert!((flatness.x.gradient_percent - 32.0).abs() < 0.5, "{}", flatness.x.gradient_percent);
+        assert!(flatness.y.gradient_percent.abs() < 1e-9);
+        assert_eq!(flatness.transverse().gradient_percent, flatness.y.gradient_percent);
+        // 0.2 mm pixels: positions in cm
+        assert!((flatness.x.positions[1] - 0.02).abs() < 1e-12);
+    }
+
+    #[test]
+    fn tool_mark_is_removed() {
+        // 4 px dark line across the field, narrower than the running median
+        let arr = Array2::from_shape_fn((300, 500), |(_, x)| if (260..264).contains(&x) { 200 } else { 1000 });
+        let flatness = field_flatness(arr.view(), None).unwrap();
+        assert!(flatness.x.values.iter().all(|&v| v == 100.0));
+        assert_eq!(flatness.x.flatness_percent, 0.0);
+        assert_eq!(flatness.x.gradient_percent, 0.0);
+    }
+}
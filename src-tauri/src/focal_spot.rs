@@ -0,0 +1,357 @@
+use std::f64::consts::PI;
+use dicom::dictionary_std::tags;
+use ndarray::{s, Array2};
+use serde::{Deserialize, Serialize};
+use crate::analysis::{detector_details, AnalysisError};
+use crate::cache::ImageCache;
+use crate::plot::{grid_step, save_plot};
+use crate::tolerance::{all_passed, Check, ToleranceProfile};
+use crate::utils::{get_pixel_spacing, get_rescale, get_value, rescaled, DcmObj};
+
+// star pattern: block side (px) of the local sd map used to find the star
+const SD_BLOCK: usize = 8;
+// half angle (degree) of the arc sampled around each axis
+const ARC_DEG: f64 = 10.0;
+// radius step (px) of the modulation profile
+const RADIUS_STEP: f64 = 0.5;
+// blurred if the modulation drops below this fraction of the outer modulation
+const BLUR_LEVEL: f64 = 0.25;
+// pinhole: spot width at this fraction of the peak (IEC 60336)
+const PINHOLE_LEVEL: f64 = 0.15;
+
+/// test object of the focal spot image
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FocalSpotMethod {
+    /// star pattern with spokes of `angle` degree
+    Star { angle: f64 },
+    Pinhole,
+}
+
+/// focal spot size along x (image columns) and y (image rows)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FocalSpotResult {
+    pub details: Vec<String>,
+    pub method: FocalSpotMethod,
+    /// SID / SOD of the test object
+    pub magnification: f64,
+    /// "manual", "SID/SOD" or "magnification factor"
+    pub magnification_source: String,
+    /// star or spot centre (x, y) in px
+    pub centre: [f64; 2],
+    /// star: blur diameter along x and y; pinhole: spot width along x and y (mm, detector plane)
+    pub measured_mm: [f64; 2],
+    /// focal spot size along x and y (mm)
+    pub size_mm: [f64; 2],
+    pub nominal_mm: Option<f64>,
+    /// star: modulation against radius (mm) towards +x, +y, -x, -y;
+    /// pinhole: profiles along x and y against position (mm)
+    pub curves: Vec<Vec<(f64, f64)>>,
+    pub profile: String,
+    pub checks: Vec<Check>,
+    pub passed: bool,
+}
+
+/// effective focal spot size from a star pattern or pinhole image
+///
+/// `magnification` overrides SID / SOD of the image header.
+/// plot_path: star modulation (blue +x, red +y, green -x, orange -y) or
+/// pinhole profiles (blue x, red y)
+pub fn run_focal_spot(file_path: &str, plot_path: &str, cache: &ImageCache, method: FocalSpotMethod, magnification: Option<f64>, nominal_mm: Option<f64>, profile: &ToleranceProfile) -> Result<FocalSpotResult, AnalysisError> {
+    let image = cache.get(file_path).ok_or_else(|| AnalysisError::Load(file_path.to_owned()))?;
+    let obj = &image.obj;
+    let spacing = get_pixel_spacing(obj)
+        .ok_or_else(|| AnalysisError::Measurement("pixel spacing is missing".to_string()))?;
+    let (magnification, magnification_source) = match magnification {
+        Some(m) => (m, "manual".to_string()),
+        None => header_magnification(obj)
+            .ok_or_else(|| AnalysisError::Measurement("no SID/SOD in the image, enter the magnification".to_string()))?,
+    };
+    if magnification <= 1.0 {
+        return Err(AnalysisError::Measurement(format!("magnification {:.3} must be above 1", magnification)));
+    }
+    let arr = rescaled(image.arr.view(), get_rescale(obj));
+
+    let (centre, measured_px, curves) = match method {
+        FocalSpotMethod::Star { angle } if angle > 0.0 => star_blur(&arr)?,
+        FocalSpotMethod::Star { .. } => return Err(AnalysisError::Measurement("the star spoke angle must be positive".to_string())),
+        FocalSpotMethod::Pinhole => pinhole_width(&arr)?,
+    };
+    let measured_mm = measured_px.map(|v| v * spacing);
+    let curves: Vec<Vec<(f64, f64)>> = curves.into_iter()
+        .map(|c| c.into_iter().map(|(x, y)| (x * spacing, y)).collect())
+        .collect();
+    let size_mm = focal_spot_size(method, measured_mm, magnification);
+
+    let mut checks = vec![
+        profile.check("focal_spot.size_x", size_mm[0]),
+        profile.check("focal_spot.size_y", size_mm[1]),
+    ];
+    if let Some(nominal) = nominal_mm.filter(|&n| n > 0.0) {
+        checks.push(profile.check("focal_spot.ratio", size_mm[0].max(size_mm[1]) / nominal));
+    }
+    let result = FocalSpotResult {
+        details: detector_details(obj),
+        method,
+        magnification,
+        magnification_source,
+        centre,
+        measured_mm,
+        size_mm,
+        nominal_mm,
+        curves,
+        profile: profile.name.clone(),
+        passed: all_passed(&checks),
+        checks,
+    };
+    save_curves(&result, plot_path)?;
+    println!("FOCAL SPOT: {:.2} x {:.2} mm", size_mm[0], size_mm[1]);
+    Ok(result)
+}
+
+/// focal spot size along x and y (mm) from the measured blur or spot width
+///
+/// star: f = pi theta D / (180 (M - 1)), the blur along one axis comes from
+/// the focal spot extent across it; pinhole: f = width / (M - 1)
+fn focal_spot_size(method: FocalSpotMethod, measured_mm: [f64; 2], magnification: f64) -> [f64; 2] {
+    match method {
+        FocalSpotMethod::Star { angle } => [measured_mm[1], measured_mm[0]].map(|d| PI * angle * d / (180.0 * (magnification - 1.0))),
+        FocalSpotMethod::Pinhole => measured_mm.map(|w| w / (magnification - 1.0)),
+    }
+}
+
+/// SID / SOD, or the estimated radiographic magnification factor
+fn header_magnification(obj: &DcmObj) -> Option<(f64, String)> {
+    let sid = get_value(obj, tags::DISTANCE_SOURCE_TO_DETECTOR);
+    let sod = get_value(obj, tags::DISTANCE_SOURCE_TO_PATIENT);
+    if let (Some(sid), Some(sod)) = (sid, sod) {
+        if sod > 0.0 {
+            return Some((sid / sod, "SID/SOD".to_string()));
+        }
+    }
+    get_value(obj, tags::ESTIMATED_RADIOGRAPHIC_MAGNIFICATION_FACTOR)
+        .map(|m| (m, "magnification factor".to_string()))
+}
+
+fn bilinear(arr: &Array2<f64>, x: f64, y: f64) -> Option<f64> {
+    let (h, w) = arr.dim();
+    if x < 0.0 || y < 0.0 || x >= (w - 1) as f64 || y >= (h - 1) as f64 {
+        return None;
+    }
+    let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+    let (fx, fy) = (x - x0 as f64, y - y0 as f64);
+    let top = arr[[y0, x0]] * (1.0 - fx) + arr[[y0, x0 + 1]] * fx;
+    let bottom = arr[[y0 + 1, x0]] * (1.0 - fx) + arr[[y0 + 1, x0 + 1]] * fx;
+    Some(top * (1.0 - fy) + bottom * fy)
+}
+
+/// star centre and radius (px) from the blocks with a high local sd
+fn find_star(arr: &Array2<f64>) -> Option<([f64; 2], f64)> {
+    let (h, w) = arr.dim();
+    let (rows, cols) = (h / SD_BLOCK, w / SD_BLOCK);
+    let mut blocks = Vec::with_capacity(rows * cols);
+    for row in 0..rows {
+        for col in 0..cols {
+            let block = arr.slice(s![row * SD_BLOCK..(row + 1) * SD_BLOCK, col * SD_BLOCK..(col + 1) * SD_BLOCK]);
+            blocks.push((row, col, block.std(0.0)));
+        }
+    }
+    let mut sds: Vec<f64> = blocks.iter().map(|b| b.2).collect();
+    sds.sort_by(f64::total_cmp);
+    let level = 0.5 * *sds.get(sds.len() * 99 / 100)?;
+    let active: Vec<(f64, f64)> = blocks.iter()
+        .filter(|b| b.2 > level)
+        .map(|&(row, col, _)| ((col as f64 + 0.5) * SD_BLOCK as f64, (row as f64 + 0.5) * SD_BLOCK as f64))
+        .collect();
+    if active.len() < 16 {
+        return None;
+    }
+    let n = active.len() as f64;
+    let centre = [active.iter().map(|p| p.0).sum::<f64>() / n, active.iter().map(|p| p.1).sum::<f64>() / n];
+    let mut distances: Vec<f64> = active.iter().map(|p| (p.0 - centre[0]).hypot(p.1 - centre[1])).collect();
+    distances.sort_by(f64::total_cmp);
+    Some((centre, distances[distances.len() * 98 / 100]))
+}
+
+/// blur diameters (px) of a star pattern along x and y
+///
+/// the modulation (sd / mean) of arcs around each axis half is followed
+/// inwards, the outermost minimum below `BLUR_LEVEL` of the outer modulation
+/// is the blur radius
+fn star_blur(arr: &Array2<f64>) -> Result<([f64; 2], [f64; 2], Vec<Vec<(f64, f64)>>), AnalysisError> {
+    let (centre, radius) = find_star(arr)
+        .ok_or_else(|| AnalysisError::Measurement("no star pattern found".to_string()))?;
+    let mut radii = [0.0; 4];
+    let mut curves = Vec::with_capacity(4);
+    // +x, +y, -x, -y (image rows grow downwards)
+    for (i, direction) in [0.0f64, 90.0, 180.0, 270.0].into_iter().enumerate() {
+        let mut curve = Vec::new();
+        let mut r = 0.95 * radius;
+        while r > 0.05 * radius {
+            let samples = ((2.0 * ARC_DEG.to_radians() * r / 0.25).ceil() as usize).max(64);
+            let values: Vec<f64> = (0..=samples)
+                .filter_map(|k| {
+                    let t = (direction - ARC_DEG + 2.0 * ARC_DEG * k as f64 / samples as f64).to_radians();
+                    bilinear(arr, centre[0] + r * t.cos(), centre[1] + r * t.sin())
+                })
+                .collect();
+            let n = values.len() as f64;
+            let mean = values.iter().sum::<f64>() / n.max(1.0);
+            let sd = (values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n.max(1.0)).sqrt();
+            curve.push((r, if mean != 0.0 { sd / mean.abs() } else { 0.0 }));
+            r -= RADIUS_STEP;
+        }
+        let modulation = smooth(&curve.iter().map(|p| p.1).collect::<Vec<f64>>());
+        let outer: Vec<f64> = curve.iter().zip(&modulation).filter(|(p, _)| p.0 >= 0.75 * radius).map(|(_, &m)| m).collect();
+        let reference = median(&outer);
+        let start = modulation.iter().position(|&m| m < BLUR_LEVEL * reference)
+            .ok_or_else(|| AnalysisError::Measurement("no blur zone in the star pattern, check the magnification".to_string()))?;
+        // follow the drop down to its minimum
+        let mut k = start;
+        while k + 1 < modulation.len() && modulation[k + 1] <= modulation[k] {
+            k += 1;
+        }
+        radii[i] = curve[k].0;
+        curves.push(curve.into_iter().zip(modulation).map(|((r, _), m)| (r, m)).collect());
+    }
+    Ok((centre, [radii[0] + radii[2], radii[1] + radii[3]], curves))
+}
+
+/// spot widths (px) along x and y at `PINHOLE_LEVEL` of the peak
+fn pinhole_width(arr: &Array2<f64>) -> Result<([f64; 2], [f64; 2], Vec<Vec<(f64, f64)>>), AnalysisError> {
+    let mut sorted: Vec<f64> = arr.iter().copied().collect();
+    sorted.sort_by(f64::total_cmp);
+    let background = sorted[sorted.len() / 2];
+    let peak = sorted[sorted.len() - 1];
+    // the spot may be darker than the background (inverted LUT)
+    let low = sorted[0];
+    let bright = peak - background >= background - low;
+    let signal = arr.mapv(|v| if bright { v - background } else { background - v }.max(0.0));
+    let top = if bright { peak - background } else { background - low };
+    if top <= 0.0 {
+        return Err(AnalysisError::Measurement("no pinhole image found".to_string()));
+    }
+
+    // box around the pixels above half the peak, three times its size
+    let half: Vec<(usize, usize)> = signal.indexed_iter().filter(|(_, &v)| v > 0.5 * top).map(|(p, _)| p).collect();
+    let (y0, y1) = (half.iter().map(|p| p.0).min().unwrap_or(0), half.iter().map(|p| p.0).max().unwrap_or(0));
+    let (x0, x1) = (half.iter().map(|p| p.1).min().unwrap_or(0), half.iter().map(|p| p.1).max().unwrap_or(0));
+    let (h, w) = arr.dim();
+    let (dy, dx) = (y1 - y0 + 1, x1 - x0 + 1);
+    let spot = signal.slice(s![y0.saturating_sub(dy)..(y1 + dy + 1).min(h), x0.saturating_sub(dx)..(x1 + dx + 1).min(w)]);
+    let (top_row, left_col) = (y0.saturating_sub(dy), x0.saturating_sub(dx));
+
+    let mut widths = [0.0; 2];
+    let mut centre = [0.0; 2];
+    let mut curves = Vec::with_capacity(2);
+    for (i, axis) in [ndarray::Axis(0), ndarray::Axis(1)].into_iter().enumerate() {
+        // summed over the other axis: x profile sums the rows
+        let profile = spot.sum_axis(axis).to_vec();
+        let max = profile.iter().copied().fold(f64::MIN, f64::max);
+        let level = PINHOLE_LEVEL * max;
+        let first = profile.iter().position(|&v| v >= level).unwrap_or(0);
+        let last = profile.iter().rposition(|&v| v >= level).unwrap_or(0);
+        // crossings interpolated between neighbouring samples
+        let cross = |a: usize, b: usize| {
+            let (va, vb) = (profile[a], profile[b]);
+            if vb != va { a as f64 + (level - va) / (vb - va) * (b as f64 - a as f64) } else { a as f64 }
+        };
+        let start = if first > 0 { cross(first - 1, first) } else { 0.0 };
+        let end = if last + 1 < profile.len() { cross(last, last + 1) } else { last as f64 };
+        widths[i] = end - start;
+        centre[i] = (start + end) / 2.0 + if i == 0 { left_col } else { top_row } as f64;
+        curves.push(profile.iter().enumerate().map(|(k, &v)| (k as f64, v / max)).collect());
+    }
+    Ok((centre, widths, curves))
+}
+
+fn smooth(values: &[f64]) -> Vec<f64> {
+    (0..values.len())
+        .map(|i| {
+            let window = &values[i.saturating_sub(2)..(i + 3).min(values.len())];
+            window.iter().sum::<f64>() / window.len() as f64
+        })
+        .collect()
+}
+
+fn median(values: &[f64]) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    sorted.get(sorted.len() / 2).copied().unwrap_or(0.0)
+}
+
+fn save_curves(result: &FocalSpotResult, plot_path: &str) -> Result<(), AnalysisError> {
+    let x_max = result.curves.iter().flatten().map(|p| p.0).fold(0.0, f64::max);
+    let y_max = result.curves.iter().flatten().map(|p| p.1).fold(0.0, f64::max).max(f64::EPSILON);
+    // radius grows to the right
+    let curves: Vec<Vec<(f64, f64)>> = result.curves.iter()
+        .map(|c| {
+            let mut c = c.clone();
+            c.sort_by(|a, b| a.0.total_cmp(&b.0));
+            c
+        })
+        .collect();
+    save_plot(plot_path, &curves, x_max, y_max, grid_step(x_max), grid_step(y_max)).map_err(AnalysisError::Measurement)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dicom::core::VR;
+    use crate::utils::test_header;
+
+    /// Gaussian spot (sd along x and y in px) at (70, 60) on a flat background
+    fn spot(sd: [f64; 2], background: f64, amplitude: f64) -> Array2<f64> {
+        Array2::from_shape_fn((128, 128), |(y, x)| {
+            let (u, v) = ((x as f64 - 70.0) / sd[0], (y as f64 - 60.0) / sd[1]);
+            background + amplitude * (-(u * u + v * v) / 2.0).exp()
+        })
+    }
+
+    // width of a Gaussian at 15% of its peak: 2 sd sqrt(2 ln(1 / 0.15))
+    fn gaussian_width(sd: f64) -> f64 {
+        2.0 * sd * (2.0 * (1.0 / PINHOLE_LEVEL).ln()).sqrt()
+    }
+
+    #[test]
+    fn pinhole_spot_width() {
+        let (centre, widths, curves) = pinhole_width(&spot([4.0, 6.0], 100.0, 1000.0)).unwrap();
+        assert!((widths[0] - gaussian_width(4.0)).abs() < 0.2, "x {} vs {}", widths[0], gaussian_width(4.0));
+        assert!((widths[1] - gaussian_width(6.0)).abs() < 0.2, "y {} vs {}", widths[1], gaussian_width(6.0));
+        assert!((centre[0] - 70.0).abs() < 0.1 && (centre[1] - 60.0).abs() < 0.1, "{:?}", centre);
+        assert_eq!(curves.len(), 2);
+    }
+
+    #[test]
+    fn inverted_pinhole_spot() {
+        let (_, widths, _) = pinhole_width(&spot([4.0, 6.0], 3000.0, -1000.0)).unwrap();
+        assert!((widths[0] - gaussian_width(4.0)).abs() < 0.2 && (widths[1] - gaussian_width(6.0)).abs() < 0.2, "{:?}", widths);
+        assert!(pinhole_width(&Array2::from_elem((32, 32), 100.0)).is_err());
+    }
+
+    #[test]
+    fn star_size_swaps_the_axes() {
+        // 2° spokes, blur 10 mm along x and 20 mm along y, M = 2:
+        // x size = pi 2 20 / 180 = 0.698 mm from the blur along y
+        let size = focal_spot_size(FocalSpotMethod::Star { angle: 2.0 }, [10.0, 20.0], 2.0);
+        assert!((size[0] - 0.698132).abs() < 1e-6 && (size[1] - 0.349066).abs() < 1e-6, "{:?}", size);
+        let size = focal_spot_size(FocalSpotMethod::Pinhole, [1.2, 0.9], 4.0);
+        assert!((size[0] - 0.4).abs() < 1e-9 && (size[1] - 0.3).abs() < 1e-9, "{:?}", size);
+    }
+
+    #[test]
+    fn magnification_from_the_header() {
+        let distances = test_header(&[
+            (tags::DISTANCE_SOURCE_TO_DETECTOR, VR::DS, "1000"),
+            (tags::DISTANCE_SOURCE_TO_PATIENT, VR::DS, "500"),
+            (tags::ESTIMATED_RADIOGRAPHIC_MAGNIFICATION_FACTOR, VR::DS, "1.8"),
+        ]);
+        assert_eq!(header_magnification(&distances), Some((2.0, "SID/SOD".to_string())));
+        let factor = test_header(&[
+            (tags::DISTANCE_SOURCE_TO_DETECTOR, VR::DS, "1000"),
+            (tags::ESTIMATED_RADIOGRAPHIC_MAGNIFICATION_FACTOR, VR::DS, "1.8"),
+        ]);
+        assert_eq!(header_magnification(&factor), Some((1.8, "magnification factor".to_string())));
+        assert_eq!(header_magnification(&test_header(&[])), None);
+    }
+}
@@ -0,0 +1,300 @@
+use ndarray::{s, ArrayView2};
+use serde::{Deserialize, Serialize};
+use crate::analysis::{detector_details, AnalysisError};
+use crate::cache::ImageCache;
+use crate::fft::magnitude;
+use crate::history::{History, HistoryEntry};
+use crate::plot::{grid_step, save_plot};
+use crate::tolerance::{all_passed, Check, ToleranceProfile};
+use crate::utils::{get_pixel_spacing, get_rescale, median_by_col, rescaled, U16View};
+
+// central part of the image used for the spectra and the profile band
+const AREA: f64 = 0.8;
+// lines of the central area whose spectra are averaged
+const SPECTRUM_LINES: usize = 64;
+// spectrum bins below n / LOW_BINS are field trends, not grid lines
+const LOW_BINS: usize = 32;
+// central part of the other axis whose column medians give the lateral profile
+const BAND: f64 = 0.3;
+// running mean along the profile (fraction of its length)
+const SMOOTH: f64 = 0.02;
+// evaluated central part of the profile, the collimation edges are left out
+const EVALUATED: f64 = 0.9;
+// centre and side values: mean of this part of the evaluated profile
+const PART: f64 = 0.1;
+// plotted profile range (% of the centre value)
+const PLOT_RANGE: (f64, f64) = (50.0, 110.0);
+
+/// grid line peak of the averaged line spectra
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GridSpectrum {
+    /// cycles/mm (lp/mm) up to Nyquist
+    pub frequencies: Vec<f64>,
+    /// mean |FFT| of the mean-free lines
+    pub magnitude: Vec<f64>,
+    /// strongest frequency above the trend bins, aliased if the grid is
+    /// finer than the pixel pitch
+    pub peak_frequency: f64,
+    /// peak / median magnitude
+    pub peak_ratio: f64,
+}
+
+/// grid cut-off and grid line artefacts of a flood exposure with the grid in place
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GridResult {
+    pub details: Vec<String>,
+    pub pixel_spacing_mm: f64,
+    /// FFT of the row profiles (vertical grid lines) and of the column profiles
+    pub rows: GridSpectrum,
+    pub columns: GridSpectrum,
+    /// "vertical" or "horizontal": direction of the grid lines, from the stronger peak
+    pub line_direction: String,
+    /// lateral profile across the grid lines: position (mm) from the first
+    /// evaluated point and % of the centre value
+    pub positions: Vec<f64>,
+    pub values: Vec<f64>,
+    /// drop of the outer parts against the centre (%)
+    pub cutoff_start_percent: f64,
+    pub cutoff_end_percent: f64,
+    /// start - end side value (% of the centre value)
+    pub asymmetry_percent: f64,
+    /// maximum of the fitted parabola from the profile centre (mm),
+    /// None if the profile has no maximum
+    pub decentering_mm: Option<f64>,
+    pub profile: String,
+    pub checks: Vec<Check>,
+    pub passed: bool,
+}
+
+/// grid alignment from a flood exposure, the result is added to `history`
+///
+/// a decentred or tilted focused grid cuts off one side of the field more
+/// than the other; the grid lines show as a peak in the line spectra
+///
+/// save_path: lateral profile plot (50 - 110%), spectra plot (rows blue,
+/// columns red, normalised to the larger peak)
+pub fn run_grid(file_path: &str, save_path: &[String], cache: &ImageCache, profile: &ToleranceProfile, history: &History) -> Result<GridResult, AnalysisError> {
+    let image = cache.get(file_path).ok_or_else(|| AnalysisError::Load(file_path.to_owned()))?;
+    let obj = &image.obj;
+    let spacing = get_pixel_spacing(obj)
+        .ok_or_else(|| AnalysisError::Measurement("pixel spacing is missing".to_string()))?;
+    let (h, w) = image.arr.dim();
+    let (ch, cw) = ((h as f64 * AREA) as usize, (w as f64 * AREA) as usize);
+    let raw = image.arr.slice(s![(h - ch) / 2..(h + ch) / 2, (w - cw) / 2..(w + cw) / 2]);
+    // output values: an intercept would shift the % of the centre value
+    let rescale = get_rescale(obj);
+    let arr = rescaled(raw, rescale);
+    let area = arr.view();
+
+    let rows = line_spectrum(area, spacing)
+        .ok_or_else(|| AnalysisError::Measurement("image too small".to_string()))?;
+    let columns = line_spectrum(area.reversed_axes(), spacing)
+        .ok_or_else(|| AnalysisError::Measurement("image too small".to_string()))?;
+    // lines across the x axis modulate the rows
+    let vertical = rows.peak_ratio >= columns.peak_ratio;
+    let (across, peak) = if vertical { (raw, &rows) } else { (raw.reversed_axes(), &columns) };
+    let (peak_frequency, peak_ratio) = (peak.peak_frequency, peak.peak_ratio);
+
+    let (positions, values) = lateral_profile(across, rescale, spacing, peak_frequency)
+        .ok_or_else(|| AnalysisError::Measurement("no lateral profile (field too small)".to_string()))?;
+    let m = values.len();
+    let part = ((m as f64 * PART) as usize).max(1);
+    let mean = |v: &[f64]| v.iter().sum::<f64>() / v.len() as f64;
+    let (start, end) = (mean(&values[..part]), mean(&values[m - part..]));
+    let decentering_mm = parabola_peak(&positions, &values);
+
+    let checks = vec![
+        profile.check("grid.asymmetry", (start - end).abs()),
+        profile.check("grid.decentering", decentering_mm.map_or(0.0, f64::abs)),
+        profile.check("grid.line_artefact", peak_ratio),
+    ];
+    let passed = all_passed(&checks);
+    let mut entry = HistoryEntry::for_image("grid", obj);
+    entry.values.insert("asymmetry".to_string(), start - end);
+    if let Some(d) = decentering_mm {
+        entry.values.insert("decentering_mm".to_string(), d);
+    }
+    entry.values.insert("peak_ratio".to_string(), peak_ratio);
+    entry.passed = Some(passed);
+    if let Err(err) = history.append(&entry) {
+        println!("HISTORY: ERR {}", err);
+    }
+
+    let result = GridResult {
+        details: detector_details(obj),
+        pixel_spacing_mm: spacing,
+        line_direction: if vertical { "vertical" } else { "horizontal" }.to_string(),
+        rows,
+        columns,
+        positions,
+        values,
+        cutoff_start_percent: 100.0 - start,
+        cutoff_end_percent: 100.0 - end,
+        asymmetry_percent: start - end,
+        decentering_mm,
+        profile: profile.name.clone(),
+        checks,
+        passed,
+    };
+    save_plots(&result, save_path).map_err(AnalysisError::Measurement)?;
+    println!("GRID: asymmetry {:.2}%, peak {:.3} lp/mm ({:.1}x)", result.asymmetry_percent, peak_frequency, peak_ratio);
+    Ok(result)
+}
+
+/// mean spectrum of up to `SPECTRUM_LINES` rows spread over `area`
+fn line_spectrum(area: ArrayView2<f64>, spacing: f64) -> Option<GridSpectrum> {
+    let (h, w) = area.dim();
+    if h == 0 || w < 2 * LOW_BINS {
+        return None;
+    }
+    let step = (h / SPECTRUM_LINES).max(1);
+    let mut sum: Vec<f64> = vec![];
+    let mut n = 0;
+    let mut count = 0;
+    for row in area.rows().into_iter().step_by(step) {
+        let mean = row.sum() / w as f64;
+        let line: Vec<f64> = row.iter().map(|&v| v - mean).collect();
+        let (mag, padded) = magnitude(&line);
+        if sum.is_empty() {
+            sum = vec![0.0; mag.len()];
+        }
+        sum.iter_mut().zip(&mag).for_each(|(s, m)| *s += m);
+        n = padded;
+        count += 1;
+    }
+    let magnitude: Vec<f64> = sum.iter().map(|s| s / count as f64).collect();
+    let low = n / LOW_BINS;
+    let (k, &peak) = magnitude.iter().enumerate().skip(low).max_by(|a, b| a.1.total_cmp(b.1))?;
+    let mut rest = magnitude[low..].to_vec();
+    let mid = rest.len() / 2;
+    let median = *rest.select_nth_unstable_by(mid, f64::total_cmp).1;
+    Some(GridSpectrum {
+        frequencies: (0..magnitude.len()).map(|k| k as f64 / (n as f64 * spacing)).collect(),
+        peak_frequency: k as f64 / (n as f64 * spacing),
+        peak_ratio: if median > 0.0 { peak / median } else { 0.0 },
+        magnitude,
+    })
+}
+
+/// column medians of the central band, running mean over the grid lines,
+/// (mm, % of the centre value) of the evaluated part
+///
+/// the medians of the stored values are rescaled, the linear rescale keeps the median
+fn lateral_profile(area: U16View, (slope, intercept): (f64, f64), spacing: f64, grid_frequency: f64) -> Option<(Vec<f64>, Vec<f64>)> {
+    let (h, w) = area.dim();
+    let half = ((h as f64 * BAND / 2.0) as usize).max(1);
+    let band = area.slice(s![(h / 2).saturating_sub(half)..(h / 2 + half).min(h), ..]);
+    let raw: Vec<f64> = median_by_col(band.rows().into_iter().map(|row| row.to_vec()).collect())
+        .into_iter()
+        .map(|v| v as f64 * slope + intercept)
+        .collect();
+
+    // at least 3 grid periods
+    let period = if grid_frequency > 0.0 { 1.0 / (grid_frequency * spacing) } else { 0.0 };
+    let window = ((w as f64 * SMOOTH) as usize).max((3.0 * period).ceil() as usize).max(1) / 2;
+    let smooth: Vec<f64> = (0..w)
+        .map(|i| {
+            let part = &raw[i.saturating_sub(window)..(i + window + 1).min(w)];
+            part.iter().sum::<f64>() / part.len() as f64
+        })
+        .collect();
+
+    let skip = (w as f64 * (1.0 - EVALUATED) / 2.0) as usize;
+    let evaluated = &smooth[skip..w - skip];
+    let m = evaluated.len();
+    if m < 16 {
+        return None;
+    }
+    let part = ((m as f64 * PART / 2.0) as usize).max(1);
+    let centre = evaluated[m / 2 - part..m / 2 + part].iter().sum::<f64>() / (2 * part) as f64;
+    if centre <= 0.0 {
+        return None;
+    }
+    Some((
+        (0..m).map(|i| i as f64 * spacing).collect(),
+        evaluated.iter().map(|v| v / centre * 100.0).collect(),
+    ))
+}
+
+/// vertex of y = a x² + b x + c from the profile centre, None if a >= 0 or
+/// the vertex is outside the profile
+fn parabola_peak(x: &[f64], y: &[f64]) -> Option<f64> {
+    let n = x.len() as f64;
+    let centre = (x[0] + x[x.len() - 1]) / 2.0;
+    // symmetric positions: the odd sums vanish
+    let (mut s2, mut s4, mut sy, mut sxy, mut sx2y) = (0.0, 0.0, 0.0, 0.0, 0.0);
+    for (&x, &y) in x.iter().zip(y) {
+        let x = x - centre;
+        s2 += x * x;
+        s4 += x * x * x * x;
+        sy += y;
+        sxy += x * y;
+        sx2y += x * x * y;
+    }
+    let b = sxy / s2;
+    let a = (n * sx2y - s2 * sy) / (n * s4 - s2 * s2);
+    if a >= 0.0 {
+        return None;
+    }
+    let vertex = -b / (2.0 * a);
+    (vertex.abs() <= centre).then_some(vertex)
+}
+
+fn save_plots(result: &GridResult, save_path: &[String]) -> Result<(), String> {
+    if let Some(path) = save_path.first() {
+        let curve: Vec<(f64, f64)> = result.positions.iter().zip(&result.values).map(|(&x, &v)| (x, v - PLOT_RANGE.0)).collect();
+        let x_max = result.positions.last().copied().unwrap_or(0.0).max(f64::EPSILON);
+        save_plot(path, &[curve], x_max, PLOT_RANGE.1 - PLOT_RANGE.0, grid_step(x_max), 10.0)?;
+    }
+    if let Some(path) = save_path.get(1) {
+        let peak = |s: &GridSpectrum| s.magnitude.iter().skip(1).copied().fold(0.0, f64::max);
+        let scale = peak(&result.rows).max(peak(&result.columns)).max(f64::EPSILON);
+        let curves: Vec<Vec<(f64, f64)>> = [&result.rows, &result.columns].iter()
+            .map(|s| s.frequencies.iter().zip(&s.magnitude).skip(1).map(|(&f, &m)| (f, m / scale)).collect())
+            .collect();
+        let x_max = 0.5 / result.pixel_spacing_mm;
+        save_plot(path, &curves, x_max, 1.0, grid_step(x_max), 0.1)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+    use ndarray::Array2;
+
+    #[test]
+    fn grid_lines_give_the_spectrum_peak() {
+        // vertical lines at 1.25 lp/mm on 0.1 mm pixels: 8 px period, bin 32 of 256
+        let arr = Array2::from_shape_fn((128, 256), |(_, x)| 1000.0 + 50.0 * (2.0 * PI * x as f64 / 8.0).cos());
+        let rows = line_spectrum(arr.view(), 0.1).unwrap();
+        assert!((rows.peak_frequency - 1.25).abs() < 1e-9);
+        assert!(rows.peak_ratio > 100.0);
+        assert!((rows.frequencies.last().unwrap() - 5.0).abs() < 1e-9);
+        // the columns are flat
+        let columns = line_spectrum(arr.view().reversed_axes(), 0.1).unwrap();
+        assert_eq!(columns.peak_ratio, 0.0);
+    }
+
+    #[test]
+    fn vertex_of_an_exact_parabola() {
+        let x: Vec<f64> = (0..=100).map(|i| i as f64 * 0.5).collect();
+        // vertex at 30 mm, 5 mm right of the profile centre
+        let y: Vec<f64> = x.iter().map(|x| 100.0 - 0.01 * (x - 30.0).powi(2)).collect();
+        assert!((parabola_peak(&x, &y).unwrap() - 5.0).abs() < 1e-9);
+        let valley: Vec<f64> = y.iter().map(|y| 200.0 - y).collect();
+        assert_eq!(parabola_peak(&x, &valley), None);
+    }
+
+    #[test]
+    fn profile_of_a_cut_off_field() {
+        // signal falls linearly 20% from left to right, intercept -1000
+        let arr = Array2::from_shape_fn((64, 200), |(_, x)| (3000.0 - 2.0 * x as f64) as u16);
+        let (positions, values) = lateral_profile(arr.view(), (1.0, -1000.0), 0.1, 0.0).unwrap();
+        assert_eq!(positions.len(), values.len());
+        let m = values.len();
+        assert!((values[m / 2] - 100.0).abs() < 0.5);
+        assert!(values[0] > 108.0 && values[m - 1] < 92.0, "{} {}", values[0], values[m - 1]);
+    }
+}
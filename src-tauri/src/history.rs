@@ -0,0 +1,152 @@
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use dicom::dictionary_std::tags;
+use serde::{Deserialize, Serialize};
+use crate::analysis::{CollimatorResult, Evaluation, EDGE_NAMES};
+use crate::pairing::detector_name;
+use crate::utils::{get_detail, DcmObj};
+
+/// one QA measurement of a detector, only the detector id and values are
+/// stored (no institution or patient data)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// test name, e.g. "nps"
+    pub test: String,
+    pub detector: String,
+    /// acquisition date (YYYYMMDD) of the image
+    pub acquired: String,
+    /// seconds since 1970 when the entry was stored
+    pub recorded: u64,
+    pub values: BTreeMap<String, f64>,
+    pub passed: Option<bool>,
+}
+
+impl HistoryEntry {
+    pub fn new(test: &str, detector: &str, acquired: &str) -> Self {
+        HistoryEntry {
+            test: test.to_string(),
+            detector: detector.trim().to_string(),
+            acquired: acquired.trim().to_string(),
+            recorded: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            values: BTreeMap::new(),
+            passed: None,
+        }
+    }
+
+    /// entry for the detector and acquisition date of `obj`
+    pub fn for_image(test: &str, obj: &DcmObj) -> Self {
+        let mut acquired = get_detail(obj, tags::ACQUISITION_DATE);
+        if acquired == " - " {
+            acquired = get_detail(obj, tags::CONTENT_DATE);
+        }
+        HistoryEntry::new(test, &detector_name(obj), acquired.trim_matches(|c| c == ' ' || c == '-'))
+    }
+}
+
+/// collimator edge errors (cm), field size, beam alignment and heel effect of an evaluated result
+pub fn collimator_entry(obj: &DcmObj, result: &CollimatorResult, evaluation: &Evaluation) -> HistoryEntry {
+    let mut entry = HistoryEntry::for_image("collimator", obj);
+    for (name, edge) in EDGE_NAMES.iter().zip(&evaluation.edges) {
+        entry.values.insert(format!("{}_error_cm", name.to_lowercase()), edge.error_cm as f64);
+    }
+    entry.values.insert("field_x_cm".to_string(), evaluation.field_size_cm[0] as f64);
+    entry.values.insert("field_y_cm".to_string(), evaluation.field_size_cm[1] as f64);
+    entry.values.insert("beam_distance_cm".to_string(), evaluation.beam_distance_cm as f64);
+    entry.values.insert("beam_angle".to_string(), evaluation.beam_angle as f64);
+    if let Some(flatness) = &result.flatness {
+        entry.values.insert("heel_gradient".to_string(), flatness.heel().gradient_percent);
+        entry.values.insert("transverse_symmetry".to_string(), flatness.transverse().symmetry_percent);
+    }
+    entry.passed = Some(evaluation.collimator_passed && evaluation.beam_passed);
+    entry
+}
+
+/// QA history, one JSON-lines file per detector in `dir`
+#[derive(Debug, Clone)]
+pub struct History {
+    dir: PathBuf,
+}
+
+impl History {
+    pub fn new(dir: &Path) -> Self {
+        History { dir: dir.to_path_buf() }
+    }
+
+    /// history per room (AEC), kept apart so rooms never show up as or share
+    /// the file of a detector
+    pub fn rooms(&self) -> History {
+        History::new(&self.dir.join("rooms"))
+    }
+
+    fn path(&self, detector: &str) -> PathBuf {
+        let name: String = detector.trim().chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect();
+        self.dir.join(format!("{}.jsonl", if name.is_empty() { "unknown" } else { &name }))
+    }
+
+    pub fn append(&self, entry: &HistoryEntry) -> Result<(), String> {
+        fs::create_dir_all(&self.dir).map_err(|err| err.to_string())?;
+        let line = serde_json::to_string(entry).map_err(|err| err.to_string())?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(&entry.detector))
+            .map_err(|err| err.to_string())?;
+        writeln!(file, "{}", line).map_err(|err| err.to_string())
+    }
+
+    /// entries of `detector` (all tests if `test` is None), oldest first
+    pub fn entries(&self, detector: &str, test: Option<&str>) -> Vec<HistoryEntry> {
+        let Ok(text) = fs::read_to_string(self.path(detector)) else { return Vec::new() };
+        let mut entries: Vec<HistoryEntry> = text.lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .filter(|e: &HistoryEntry| test.map_or(true, |t| e.test == t))
+            .collect();
+        entries.sort_by(|a, b| (&a.acquired, a.recorded).cmp(&(&b.acquired, b.recorded)));
+        entries
+    }
+
+    /// detectors with a history
+    pub fn detectors(&self) -> Vec<String> {
+        let Ok(files) = fs::read_dir(&self.dir) else { return Vec::new() };
+        let mut detectors: Vec<String> = files.flatten()
+            .filter_map(|f| {
+                let path = f.path();
+                if path.extension()? != "jsonl" {
+                    return None;
+                }
+                // the stored id, the file name is sanitised
+                let text = fs::read_to_string(&path).ok()?;
+                let entry: HistoryEntry = serde_json::from_str(text.lines().next()?).ok()?;
+                Some(entry.detector)
+            })
+            .collect();
+        detectors.sort();
+        detectors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rooms_are_kept_apart() {
+        let dir = std::env::temp_dir().join(format!("lightbeam-history-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let history = History::new(&dir);
+        history.append(&HistoryEntry::new("nps", "DET1", "20240101")).unwrap();
+        // a room named like the detector
+        history.rooms().append(&HistoryEntry::new("aec", "DET1", "20240102")).unwrap();
+
+        assert_eq!(history.detectors(), ["DET1"]);
+        assert_eq!(history.entries("DET1", None).len(), 1);
+        assert_eq!(history.rooms().detectors(), ["DET1"]);
+        assert_eq!(history.rooms().entries("DET1", Some("aec")).len(), 1);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
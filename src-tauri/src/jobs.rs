@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use serde::Serialize;
+use crate::cache::ImageCache;
+use crate::defects::DefectMask;
+use crate::analysis::{run_collimator, AnalysisError, CollimatorResult, Stage};
+
+/// events of an analysis job, sent to the frontend as tauri events
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum JobEvent {
+    Progress { job_id: u64, stage: Stage, percent: u8 },
+    Done { job_id: u64, result: CollimatorResult },
+    Failed { job_id: u64, error: String },
+    Cancelled { job_id: u64 },
+}
+
+impl JobEvent {
+    /// tauri event name
+    pub fn name(&self) -> &'static str {
+        match self {
+            JobEvent::Progress { .. } => "analysis-progress",
+            JobEvent::Done { .. } => "analysis-done",
+            JobEvent::Failed { .. } => "analysis-error",
+            JobEvent::Cancelled { .. } => "analysis-cancelled",
+        }
+    }
+}
+
+/// running analysis jobs (tauri state), each on its own worker thread
+#[derive(Default)]
+pub struct JobRegistry {
+    next_id: AtomicU64,
+    // cancel flag of each running job
+    running: Arc<Mutex<HashMap<u64, Arc<AtomicBool>>>>,
+}
+
+impl JobRegistry {
+    /// start the collimator analysis in the background, masking the
+    /// flat-field defects first if `defects` is set
+    ///
+    /// Returns: job id, the job reports through `on_event`
+    pub fn start<F>(&self, file_paths: Vec<String>, save_path: Vec<String>, cache: ImageCache, defects: Option<DefectMask>, on_event: F) -> u64
+    where
+        F: Fn(JobEvent) + Send + 'static,
+    {
+        let job_id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let cancel = Arc::new(AtomicBool::new(false));
+        self.running.lock().unwrap().insert(job_id, cancel.clone());
+        let running = self.running.clone();
+
+        thread::spawn(move || {
+            let mut on_stage = |stage: Stage| {
+                if cancel.load(Ordering::Relaxed) {
+                    return false;
+                }
+                on_event(JobEvent::Progress { job_id, stage, percent: stage.percent() });
+                true
+            };
+            // a panic in the pipeline must still end the job for the frontend
+            let res = panic::catch_unwind(AssertUnwindSafe(|| {
+                run_collimator(&file_paths, &save_path, &cache, defects.as_ref(), &mut on_stage)
+            }));
+            let event = match res {
+                Ok(Ok(result)) => JobEvent::Done { job_id, result },
+                Ok(Err(AnalysisError::Cancelled)) => JobEvent::Cancelled { job_id },
+                Ok(Err(err)) => JobEvent::Failed { job_id, error: err.to_string() },
+                Err(_) => JobEvent::Failed { job_id, error: "analysis failed: images could not be analysed".to_string() },
+            };
+            running.lock().unwrap().remove(&job_id);
+            on_event(event);
+        });
+
+        job_id
+    }
+
+    /// request cancellation, the job stops at the next stage
+    ///
+    /// Returns: false if the job is not running
+    pub fn cancel(&self, job_id: u64) -> bool {
+        match self.running.lock().unwrap().get(&job_id) {
+            Some(cancel) => {
+                cancel.store(true, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+}
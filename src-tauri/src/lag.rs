@@ -0,0 +1,273 @@
+use image::{Rgb, RgbImage};
+use ndarray::{s, Axis};
+use serde::{Deserialize, Serialize};
+use crate::analysis::{detector_details, AnalysisError};
+use crate::cache::ImageCache;
+use crate::history::{History, HistoryEntry};
+use crate::pairing::{read_qa_image, Pairer, PAIR_WINDOW_S};
+use crate::rotation::Roi;
+use crate::tolerance::{all_passed, Check, ToleranceProfile};
+use crate::utils::{get_rescale, U16View};
+
+// block edge: half way from the image median to the 1st/99th percentile
+const EDGE_LEVEL: f64 = 0.5;
+// rows/columns with at least this fraction of the most covered line are in the block
+const BLOCK_FRACTION: f64 = 0.5;
+// smallest block side (px)
+const MIN_BLOCK: usize = 16;
+// longest side of the overlay image
+const OVERLAY_SIZE: usize = 1024;
+
+/// mean and sd (rescaled) of a ROI in both exposures
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LagRoi {
+    pub roi: Roi,
+    pub block_mean: f64,
+    pub flat_mean: f64,
+    pub flat_sd: f64,
+}
+
+/// ghosting of a block exposure in the following flat exposure
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LagResult {
+    pub details: Vec<String>,
+    /// file paths in exposure order
+    pub block_file: String,
+    pub flat_file: String,
+    /// seconds between the exposures, None if the times are missing
+    pub interval_s: Option<i64>,
+    /// block position in the first exposure
+    pub block: Roi,
+    /// central half of the block
+    pub inside: LagRoi,
+    /// ROIs next to the block
+    pub outside: Vec<LagRoi>,
+    /// (inside - outside) / outside of the flat exposure (%)
+    pub ghost_percent: f64,
+    /// (inside - outside) / outside sd of the flat exposure
+    pub ghost_cnr: f64,
+    /// flat (outside - inside) / block exposure (outside - inside)
+    pub lag_factor: f64,
+    pub profile: String,
+    pub checks: Vec<Check>,
+    pub passed: bool,
+}
+
+/// residual block signal in the exposure after a block exposure, the result
+/// is added to `history`
+///
+/// the two images are paired as the collimator fields (same detector, within
+/// `PAIR_WINDOW_S`), the earlier one is the block exposure
+///
+/// overlay_path: flat exposure (narrow window) with the block (yellow),
+/// inside (red) and outside (green) ROIs
+pub fn run_lag(file_paths: &[String], overlay_path: &str, cache: &ImageCache, profile: &ToleranceProfile, history: &History) -> Result<LagResult, AnalysisError> {
+    let [first, second] = file_paths else {
+        return Err(AnalysisError::Measurement("a block and a flat exposure are needed".to_string()));
+    };
+    let header = |path: &String| read_qa_image(path).ok_or_else(|| AnalysisError::Load(path.to_owned()));
+    let (a, b) = (header(first)?, header(second)?);
+    if a.detector != b.detector {
+        return Err(AnalysisError::Measurement("the images are from different detectors".to_string()));
+    }
+    let interval_s = a.acquired.zip(b.acquired).map(|(a, b)| (b - a).abs());
+    // ordered by acquisition time, as selected if a time is missing
+    let (block_file, flat_file) = if interval_s.is_some() {
+        let mut pairer = Pairer::new(true);
+        pairer.push(a);
+        let pair = pairer.push(b)
+            .ok_or_else(|| AnalysisError::Measurement(format!("the exposures are more than {} s apart", PAIR_WINDOW_S)))?;
+        (pair.large.path, pair.small.path)
+    } else {
+        (a.path, b.path)
+    };
+
+    let block_image = cache.get(&block_file).ok_or_else(|| AnalysisError::Load(block_file.clone()))?;
+    let flat_image = cache.get(&flat_file).ok_or_else(|| AnalysisError::Load(flat_file.clone()))?;
+    if block_image.arr.dim() != flat_image.arr.dim() {
+        return Err(AnalysisError::Measurement("the images have different sizes".to_string()));
+    }
+    let block = find_block(block_image.arr.view())
+        .ok_or_else(|| AnalysisError::Measurement("no attenuator block found in the first exposure".to_string()))?;
+
+    let (h, w) = block_image.arr.dim();
+    let inside = Roi { row: block.row + block.nrows / 4, col: block.col + block.ncols / 4, nrows: block.nrows / 2, ncols: block.ncols / 2 };
+    // same size as the inside ROI, half its size away from the block
+    let (gap_y, gap_x) = ((inside.nrows / 2).max(1), (inside.ncols / 2).max(1));
+    let candidates = [
+        (inside.row as i64, block.col as i64 - gap_x as i64 - inside.ncols as i64),
+        (inside.row as i64, (block.col + block.ncols + gap_x) as i64),
+        (block.row as i64 - gap_y as i64 - inside.nrows as i64, inside.col as i64),
+        ((block.row + block.nrows + gap_y) as i64, inside.col as i64),
+    ];
+    let outside_rois: Vec<Roi> = candidates.iter()
+        .filter(|&&(row, col)| row >= 0 && col >= 0 && row as usize + inside.nrows <= h && col as usize + inside.ncols <= w)
+        .map(|&(row, col)| Roi { row: row as usize, col: col as usize, nrows: inside.nrows, ncols: inside.ncols })
+        .collect();
+    if outside_rois.is_empty() {
+        return Err(AnalysisError::Measurement("no space for a background ROI next to the block".to_string()));
+    }
+
+    let block_rescale = get_rescale(&block_image.obj);
+    let flat_rescale = get_rescale(&flat_image.obj);
+    let measure = |roi: Roi| -> Option<LagRoi> {
+        let (block_mean, _) = stats(roi.view(block_image.arr.view())?, block_rescale);
+        let (flat_mean, flat_sd) = stats(roi.view(flat_image.arr.view())?, flat_rescale);
+        Some(LagRoi { roi, block_mean, flat_mean, flat_sd })
+    };
+    let inside = measure(inside).ok_or_else(|| AnalysisError::Measurement("block ROI outside the image".to_string()))?;
+    let outside: Vec<LagRoi> = outside_rois.into_iter().filter_map(measure).collect();
+    let n = outside.len() as f64;
+    let out_block = outside.iter().map(|r| r.block_mean).sum::<f64>() / n;
+    let out_flat = outside.iter().map(|r| r.flat_mean).sum::<f64>() / n;
+    let out_sd = outside.iter().map(|r| r.flat_sd).sum::<f64>() / n;
+
+    let ghost = inside.flat_mean - out_flat;
+    let ghost_percent = if out_flat != 0.0 { ghost / out_flat * 100.0 } else { 0.0 };
+    let ghost_cnr = if out_sd > 0.0 { ghost / out_sd } else { 0.0 };
+    let contrast = out_block - inside.block_mean;
+    if contrast == 0.0 {
+        return Err(AnalysisError::Measurement("the block has no contrast in the first exposure".to_string()));
+    }
+    let lag_factor = -ghost / contrast;
+
+    let checks = vec![
+        profile.check("lag.ghost_percent", ghost_percent.abs()),
+        profile.check("lag.lag_factor", lag_factor.abs()),
+    ];
+    let passed = all_passed(&checks);
+    let mut entry = HistoryEntry::for_image("lag", &flat_image.obj);
+    entry.values.insert("ghost_percent".to_string(), ghost_percent);
+    entry.values.insert("ghost_cnr".to_string(), ghost_cnr);
+    entry.values.insert("lag_factor".to_string(), lag_factor);
+    entry.passed = Some(passed);
+    if let Err(err) = history.append(&entry) {
+        println!("HISTORY: ERR {}", err);
+    }
+
+    let result = LagResult {
+        details: detector_details(&flat_image.obj),
+        block_file,
+        flat_file,
+        interval_s,
+        block,
+        inside,
+        outside,
+        ghost_percent,
+        ghost_cnr,
+        lag_factor,
+        profile: profile.name.clone(),
+        checks,
+        passed,
+    };
+    save_overlay(&result, flat_image.arr.view(), out_flat, out_sd, flat_rescale, overlay_path).map_err(AnalysisError::Measurement)?;
+    println!("LAG: ghost {:.3}% lag factor {:.4}", ghost_percent, lag_factor);
+    Ok(result)
+}
+
+/// rescaled mean and sd of a ROI
+fn stats(roi: U16View, (slope, intercept): (f64, f64)) -> (f64, f64) {
+    let values = roi.mapv(|v| v as f64);
+    let mean = values.mean().unwrap_or(0.0);
+    (slope * mean + intercept, slope.abs() * values.std(1.0))
+}
+
+/// bounding box of the attenuator (darker or brighter than the open field)
+fn find_block(arr: U16View) -> Option<Roi> {
+    let (h, w) = arr.dim();
+    let mut sample: Vec<u16> = arr.slice(s![..;4, ..;4]).iter().copied().collect();
+    sample.sort_unstable();
+    let at = |q: f64| sample[((sample.len() - 1) as f64 * q) as usize] as f64;
+    let (median, low, high) = (at(0.5), at(0.01), at(0.99));
+    let dark = median - low > high - median;
+    let level = median + EDGE_LEVEL * (if dark { low } else { high } - median);
+    let in_block = |v: u16| if dark { (v as f64) < level } else { (v as f64) > level };
+
+    // covered fraction of each row and column
+    let mask = arr.mapv(|v| in_block(v) as u8 as f64);
+    let rows = mask.mean_axis(Axis(1))?.to_vec();
+    let cols = mask.mean_axis(Axis(0))?.to_vec();
+    let (top, bottom) = block_range(&rows)?;
+    let (left, right) = block_range(&cols)?;
+    let (nrows, ncols) = (bottom - top, right - left);
+    if nrows < MIN_BLOCK || ncols < MIN_BLOCK || nrows * 10 > h * 9 || ncols * 10 > w * 9 {
+        return None;
+    }
+    Some(Roi { row: top, col: left, nrows, ncols })
+}
+
+/// continuous run around the most covered line, [start, end)
+fn block_range(fractions: &[f64]) -> Option<(usize, usize)> {
+    let (peak, &max) = fractions.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1))?;
+    if max <= 0.0 {
+        return None;
+    }
+    let level = max * BLOCK_FRACTION;
+    let start = fractions[..peak].iter().rposition(|&f| f < level).map_or(0, |i| i + 1);
+    let end = fractions[peak..].iter().position(|&f| f < level).map_or(fractions.len(), |i| peak + i);
+    Some((start, end))
+}
+
+fn save_overlay(result: &LagResult, arr: U16View, mean: f64, sd: f64, (slope, intercept): (f64, f64), path: &str) -> Result<(), String> {
+    let (h, w) = arr.dim();
+    let scale = ((h.max(w) + OVERLAY_SIZE - 1) / OVERLAY_SIZE).max(1);
+    let small = arr.slice(s![..;scale, ..;scale]);
+    // ±5 sd around the background, the ghost stands out
+    let (low, high) = (mean - 5.0 * sd, mean + 5.0 * sd.max(f64::EPSILON));
+    let mut img = RgbImage::new(small.ncols() as u32, small.nrows() as u32);
+    for ((y, x), &v) in small.indexed_iter() {
+        let level = ((slope * v as f64 + intercept - low) / (high - low) * 255.0).clamp(0.0, 255.0) as u8;
+        img.put_pixel(x as u32, y as u32, Rgb([level, level, level]));
+    }
+    draw_roi(&mut img, &result.block, scale, Rgb([230, 200, 0]));
+    draw_roi(&mut img, &result.inside.roi, scale, Rgb([230, 0, 0]));
+    for roi in &result.outside {
+        draw_roi(&mut img, &roi.roi, scale, Rgb([0, 200, 0]));
+    }
+    img.save(path).map_err(|err| err.to_string())
+}
+
+fn draw_roi(img: &mut RgbImage, roi: &Roi, scale: usize, color: Rgb<u8>) {
+    let (w, h) = (img.width() as usize, img.height() as usize);
+    let (top, left) = (roi.row / scale, roi.col / scale);
+    let (bottom, right) = (((roi.row + roi.nrows) / scale).min(h - 1), ((roi.col + roi.ncols) / scale).min(w - 1));
+    for x in left..=right {
+        img.put_pixel(x as u32, top as u32, color);
+        img.put_pixel(x as u32, bottom as u32, color);
+    }
+    for y in top..=bottom {
+        img.put_pixel(left as u32, y as u32, color);
+        img.put_pixel(right as u32, y as u32, color);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::Array2;
+
+    /// 200 x 300 open field (with a little structure) and a block at rows 50..110, columns 80..180
+    fn block_image(block: u16) -> Array2<u16> {
+        Array2::from_shape_fn((200, 300), |(y, x)| {
+            if (50..110).contains(&y) && (80..180).contains(&x) { block } else { 2000 + ((x * 31 + y * 17) % 20) as u16 }
+        })
+    }
+
+    const BLOCK: Roi = Roi { row: 50, col: 80, nrows: 60, ncols: 100 };
+
+    #[test]
+    fn finds_an_attenuating_block() {
+        assert_eq!(find_block(block_image(400).view()), Some(BLOCK));
+    }
+
+    #[test]
+    fn finds_a_bright_block() {
+        // inverted display: the attenuator is brighter than the field
+        assert_eq!(find_block(block_image(3800).view()), Some(BLOCK));
+    }
+
+    #[test]
+    fn flat_image_has_no_block() {
+        assert!(find_block(Array2::from_elem((200, 300), 2000).view()).is_none());
+    }
+}
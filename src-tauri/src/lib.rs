@@ -0,0 +1,33 @@
+pub mod aec;
+pub mod analysis;
+pub mod api;
+pub mod cache;
+pub mod contrast_detail;
+pub mod defects;
+pub mod deident;
+pub mod dimse;
+pub mod ei;
+pub mod fft;
+pub mod flatness;
+pub mod grid;
+pub mod focal_spot;
+pub mod history;
+pub mod jobs;
+pub mod lag;
+pub mod linepair;
+pub mod media;
+pub mod mtf;
+pub mod nps;
+pub mod pairing;
+pub mod plot;
+pub mod qatrack;
+pub mod qr;
+pub mod report;
+pub mod rotation;
+pub mod sid;
+pub mod storescp;
+pub mod storescu;
+pub mod tolerance;
+pub mod uniformity;
+pub mod utils;
+pub mod watch;
@@ -0,0 +1,411 @@
+use std::f64::consts::PI;
+use std::fs;
+use std::path::Path;
+use ndarray::s;
+use serde::{Deserialize, Serialize};
+use crate::analysis::{detector_details, AnalysisError};
+use crate::cache::ImageCache;
+use crate::mtf::Direction;
+use crate::plot::{grid_step, save_plot};
+use crate::tolerance::{all_passed, Check, ToleranceProfile};
+use crate::utils::{find_theta, get_pixel_spacing, mean_profile, rotate_array, U16View};
+
+// column blocks for the tilt, and the peak/median row activity of a block with bars
+const TILT_BLOCKS: usize = 16;
+const MIN_BAR_ACTIVITY: f64 = 2.0;
+
+/// bar pattern phantom: group frequencies in the order along the phantom
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinePairPhantom {
+    pub name: String,
+    /// lp/mm, ascending
+    pub groups: Vec<f64>,
+    /// bars per group (at least)
+    pub bars: usize,
+    /// modulation (relative to the first group) of a resolved group
+    pub threshold: f64,
+}
+
+impl Default for LinePairPhantom {
+    fn default() -> Self {
+        LinePairPhantom {
+            name: "Huttner type 18".to_string(),
+            groups: vec![0.6, 0.7, 0.8, 0.9, 1.0, 1.2, 1.4, 1.6, 1.8, 2.0, 2.2, 2.5, 2.8, 3.1, 3.4, 3.7, 4.0, 4.3, 4.6, 5.0],
+            bars: 3,
+            threshold: 0.1,
+        }
+    }
+}
+
+/// phantoms saved in `path`, the default phantom if there is none
+pub fn load_phantoms(path: &Path) -> Vec<LinePairPhantom> {
+    let mut phantoms: Vec<LinePairPhantom> = fs::read_to_string(path).ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default();
+    if phantoms.is_empty() {
+        phantoms.push(LinePairPhantom::default());
+    }
+    phantoms
+}
+
+pub fn save_phantoms(path: &Path, phantoms: &[LinePairPhantom]) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+    }
+    let json = serde_json::to_string_pretty(phantoms).map_err(|err| err.to_string())?;
+    fs::write(path, json).map_err(|err| err.to_string())
+}
+
+/// phantom `name` of `path`, the first phantom if not found
+pub fn find_phantom(path: &Path, name: &str) -> LinePairPhantom {
+    let mut phantoms = load_phantoms(path);
+    let i = phantoms.iter().position(|p| p.name == name).unwrap_or(0);
+    phantoms.swap_remove(i)
+}
+
+/// one bar group found on the profile
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinePairGroup {
+    /// lp/mm
+    pub frequency: f64,
+    /// start along the profile in phantom order (px), None above Nyquist
+    pub position_px: Option<usize>,
+    /// amplitude at the group frequency relative to the first group
+    pub modulation: f64,
+    pub resolved: bool,
+}
+
+/// groups of one phantom image, horizontal is measured with the phantom along the rows
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinePairAxis {
+    pub file_path: String,
+    pub direction: Direction,
+    /// phantom tilt corrected before taking the profile (degree)
+    pub angle: f64,
+    pub groups: Vec<LinePairGroup>,
+    /// highest frequency with all groups up to it resolved (lp/mm)
+    pub limiting_resolution: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinePairResult {
+    pub details: Vec<String>,
+    pub pixel_spacing_mm: f64,
+    pub phantom: String,
+    pub axes: Vec<LinePairAxis>,
+    pub profile: String,
+    pub checks: Vec<Check>,
+    pub passed: bool,
+}
+
+/// limiting resolution of one or two bar phantom images (e.g. along the rows
+/// and along the columns), the phantom must lie (nearly) along an image axis
+///
+/// csv_path: modulation of every group; plot_path: modulation over lp/mm (blue, red)
+pub fn run_linepair(file_paths: &[String], csv_path: &str, plot_path: &str, cache: &ImageCache, phantom: &LinePairPhantom, profile: &ToleranceProfile) -> Result<LinePairResult, AnalysisError> {
+    if phantom.groups.is_empty() || phantom.bars == 0 {
+        return Err(AnalysisError::Measurement(format!("phantom {} has no groups", phantom.name)));
+    }
+    let mut axes = Vec::new();
+    let mut details = Vec::new();
+    let mut pixel_spacing_mm = 0.0;
+    for file_path in file_paths {
+        let image = cache.get(file_path).ok_or_else(|| AnalysisError::Load(file_path.to_owned()))?;
+        let spacing = get_pixel_spacing(&image.obj)
+            .ok_or_else(|| AnalysisError::Measurement("pixel spacing is missing".to_string()))?;
+        if axes.is_empty() {
+            details = detector_details(&image.obj);
+            pixel_spacing_mm = spacing;
+        }
+        let axis = phantom_axis(image.arr.view(), spacing, phantom)
+            .ok_or_else(|| AnalysisError::Measurement(format!("no bar pattern found in {}", file_path)))?;
+        axes.push(LinePairAxis { file_path: file_path.to_owned(), ..axis });
+    }
+
+    let checks: Vec<Check> = axes.iter()
+        .map(|axis| {
+            let check = profile.check("linepair.limiting_resolution", axis.limiting_resolution);
+            Check { key: format!("{} ({})", check.key, axis.direction.name()), ..check }
+        })
+        .collect();
+    save_groups(&axes, csv_path, plot_path, phantom)?;
+    Ok(LinePairResult {
+        details,
+        pixel_spacing_mm,
+        phantom: phantom.name.clone(),
+        axes,
+        profile: profile.name.clone(),
+        passed: all_passed(&checks),
+        checks,
+    })
+}
+
+/// level the phantom, take its profile and locate the groups
+fn phantom_axis(arr: U16View, spacing: f64, phantom: &LinePairPhantom) -> Option<LinePairAxis> {
+    // the phantom runs along the axis with the stronger bar activity
+    let (rows_peak, _) = band(&row_activity(arr))?;
+    let (cols_peak, _) = band(&row_activity(arr.t()))?;
+    let (direction, arr) = if rows_peak >= cols_peak {
+        (Direction::Horizontal, arr)
+    } else {
+        (Direction::Vertical, arr.reversed_axes())
+    };
+
+    // tilt from the band centre of the column blocks with bars
+    let w = arr.ncols();
+    let block = (w / TILT_BLOCKS).max(16);
+    let centres: Vec<(f64, f64)> = (0..w / block)
+        .filter_map(|i| {
+            let (peak, (top, bottom)) = band(&row_activity(arr.slice(s![.., i * block..(i + 1) * block])))?;
+            (peak >= MIN_BAR_ACTIVITY).then(|| ((i * block + block / 2) as f64, (top + bottom) as f64 / 2.0))
+        })
+        .collect();
+    let theta = match (centres.first(), centres.last()) {
+        (Some(first), Some(last)) if last.0 > first.0 => {
+            let (slope, intercept) = fit_line(&centres);
+            let y = |x: f64| (slope * x + intercept).round() as i32;
+            find_theta(first.0 as i32, last.0 as i32, y(first.0), y(last.0))
+        }
+        _ => 0.0,
+    };
+    let rotated = rotate_array(theta, arr);
+    let (_, (top, bottom)) = band(&row_activity(rotated.view()))?;
+    // middle half of the band, away from the bar ends
+    let quarter = (bottom - top) / 4;
+    let profile = mean_profile(rotated.slice(s![top + quarter..bottom - quarter + 1, ..]), 0);
+
+    let reversed: Vec<f64> = profile.iter().rev().copied().collect();
+    let mut groups = match group_segments(&profile, spacing, phantom) {
+        Some((segments, false)) => measure_groups(&profile, &segments, spacing, phantom),
+        Some((segments, true)) => measure_groups(&reversed, &segments, spacing, phantom),
+        None => {
+            // groups not separated: best window of each group, in both directions
+            let forward = search_groups(&profile, spacing, phantom);
+            let backward = search_groups(&reversed, spacing, phantom);
+            let total = |groups: &[LinePairGroup]| groups.iter().map(|g| g.modulation).sum::<f64>();
+            if total(&backward) > total(&forward) { backward } else { forward }
+        }
+    };
+
+    // relative to the first (largest) bars
+    let reference = groups[0].modulation;
+    if reference <= 0.0 {
+        return None;
+    }
+    let mut limiting_resolution = groups[0].frequency;
+    let mut resolved = true;
+    for group in groups.iter_mut() {
+        group.modulation /= reference;
+        resolved = resolved && group.position_px.is_some() && group.modulation >= phantom.threshold;
+        group.resolved = resolved;
+        if resolved {
+            limiting_resolution = group.frequency;
+        }
+    }
+    Some(LinePairAxis {
+        file_path: String::new(),
+        direction,
+        angle: theta.to_degrees(),
+        groups,
+        limiting_resolution,
+    })
+}
+
+/// least squares y = slope * x + intercept of (x, y) points
+fn fit_line(points: &[(f64, f64)]) -> (f64, f64) {
+    let n = points.len() as f64;
+    let mx = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let my = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let sxx: f64 = points.iter().map(|p| (p.0 - mx).powi(2)).sum();
+    let sxy: f64 = points.iter().map(|p| (p.0 - mx) * (p.1 - my)).sum();
+    let slope = if sxx > 0.0 { sxy / sxx } else { 0.0 };
+    (slope, my - slope * mx)
+}
+
+/// mean absolute difference of neighbouring pixels along each row
+fn row_activity(arr: U16View) -> Vec<f64> {
+    arr.rows()
+        .into_iter()
+        .map(|row| {
+            let n = row.len().max(2) - 1;
+            row.windows(2).into_iter().map(|p| (p[1] as f64 - p[0] as f64).abs()).sum::<f64>() / n as f64
+        })
+        .collect()
+}
+
+/// rows of the band around the activity peak above half of (peak - median)
+///
+/// Returns: peak over median, (first, last) row
+fn band(activity: &[f64]) -> Option<(f64, (usize, usize))> {
+    let mut sorted = activity.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let median = *sorted.get(sorted.len() / 2)?;
+    let (peak, max) = activity.iter().enumerate().fold((0, f64::MIN), |best, (i, &v)| if v > best.1 { (i, v) } else { best });
+    if max <= median {
+        return None;
+    }
+    let level = median + (max - median) / 2.0;
+    let top = (0..=peak).rev().take_while(|&i| activity[i] >= level).last()?;
+    let bottom = (peak..activity.len()).take_while(|&i| activity[i] >= level).last()?;
+    Some((max / median.max(f64::EPSILON), (top, bottom)))
+}
+
+/// group extents from the mean level, which stays off the background even
+/// for unresolved bars
+///
+/// Returns: (start, end) of each group in phantom order and whether the
+/// phantom order is reversed (positions on the reversed profile), None if the
+/// number of segments does not match the phantom
+fn group_segments(profile: &[f64], spacing: f64, phantom: &LinePairPhantom) -> Option<(Vec<(usize, usize)>, bool)> {
+    // box filter over the largest bar period merges the bars of a group
+    let lowest = phantom.groups.iter().copied().fold(f64::INFINITY, f64::min);
+    let width = (1.0 / (lowest * spacing)).round().max(1.0) as usize;
+    let smooth: Vec<f64> = (0..profile.len())
+        .map(|i| {
+            let window = &profile[i.saturating_sub(width / 2)..(i + width / 2 + 1).min(profile.len())];
+            window.iter().sum::<f64>() / window.len() as f64
+        })
+        .collect();
+    let mut sorted = smooth.clone();
+    sorted.sort_by(f64::total_cmp);
+    let background = sorted[sorted.len() / 2];
+    let deviation: Vec<f64> = smooth.iter().map(|v| (v - background).abs()).collect();
+    let level = deviation.iter().copied().fold(0.0, f64::max) / 4.0;
+    if level <= 0.0 {
+        return None;
+    }
+
+    let mut segments: Vec<(usize, usize)> = Vec::new();
+    let mut start = None;
+    for (i, &d) in deviation.iter().chain([0.0].iter()).enumerate() {
+        match (start, d > level) {
+            (None, true) => start = Some(i),
+            (Some(s), false) => {
+                if i - s >= 2 {
+                    segments.push((s, i));
+                }
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if segments.len() != phantom.groups.len() {
+        return None;
+    }
+    // the largest bars come first
+    let length = |s: &(usize, usize)| s.1 - s.0;
+    let reversed = length(&segments[0]) < length(&segments[segments.len() - 1]);
+    if reversed {
+        let n = profile.len();
+        segments = segments.iter().rev().map(|&(s, e)| (n - e, n - s)).collect();
+    }
+    Some((segments, reversed))
+}
+
+/// modulation of each group over its segment
+fn measure_groups(profile: &[f64], segments: &[(usize, usize)], spacing: f64, phantom: &LinePairPhantom) -> Vec<LinePairGroup> {
+    let nyquist = 1.0 / (2.0 * spacing);
+    phantom.groups.iter().zip(segments)
+        .map(|(&frequency, &(start, end))| {
+            let below_nyquist = frequency <= nyquist;
+            LinePairGroup {
+                frequency,
+                position_px: below_nyquist.then_some(start),
+                modulation: if below_nyquist { group_modulation(profile, start, end, 1.0 / (frequency * spacing)) } else { 0.0 },
+                resolved: false,
+            }
+        })
+        .collect()
+}
+
+/// amplitude over whole periods in the middle of a segment, after removing
+/// the mean level (box of one period) that steps at the group ends
+fn group_modulation(profile: &[f64], start: usize, end: usize, period: f64) -> f64 {
+    let half = (period / 2.0).round() as usize;
+    let periods = ((end - start) as f64 / period).floor().max(1.0);
+    let len = ((periods * period).round() as usize).min(end - start);
+    let first = start + (end - start - len) / 2;
+    let high_pass: Vec<f64> = (first..first + len)
+        .map(|i| {
+            let window = &profile[i.saturating_sub(half)..(i + half + 1).min(profile.len())];
+            profile[i] - window.iter().sum::<f64>() / window.len() as f64
+        })
+        .collect();
+    amplitude(&high_pass, period)
+}
+
+/// best window for each group in order along the profile
+fn search_groups(profile: &[f64], spacing: f64, phantom: &LinePairPhantom) -> Vec<LinePairGroup> {
+    let nyquist = 1.0 / (2.0 * spacing);
+    let mut start = 0;
+    phantom.groups.iter()
+        .map(|&frequency| {
+            let period = 1.0 / (frequency * spacing);
+            let len = ((phantom.bars as f64 - 0.5) * period).round() as usize;
+            let mut group = LinePairGroup { frequency, position_px: None, modulation: 0.0, resolved: false };
+            if frequency > nyquist || len < 2 || start + len > profile.len() {
+                return group;
+            }
+            let (position, amplitude) = (start..=profile.len() - len)
+                .map(|x| (x, amplitude(&profile[x..x + len], period)))
+                .fold((start, 0.0), |best, (x, a)| if a > best.1 { (x, a) } else { best });
+            group.position_px = Some(position);
+            group.modulation = amplitude;
+            start = position + len;
+            group
+        })
+        .collect()
+}
+
+/// amplitude of the `period` (px) component of a window
+fn amplitude(window: &[f64], period: f64) -> f64 {
+    let mean = window.iter().sum::<f64>() / window.len() as f64;
+    let (re, im) = window.iter().enumerate().fold((0.0, 0.0), |(re, im), (x, v)| {
+        let (sin, cos) = (2.0 * PI * x as f64 / period).sin_cos();
+        (re + (v - mean) * cos, im - (v - mean) * sin)
+    });
+    2.0 * re.hypot(im) / window.len() as f64
+}
+
+fn save_groups(axes: &[LinePairAxis], csv_path: &str, plot_path: &str, phantom: &LinePairPhantom) -> Result<(), AnalysisError> {
+    let mut csv = String::from("direction,frequency,position_px,modulation,resolved\n");
+    for axis in axes {
+        for g in &axis.groups {
+            let position = g.position_px.map(|p| p.to_string()).unwrap_or_default();
+            csv.push_str(&format!("{},{:.2},{},{:.4},{}\n", axis.direction.name(), g.frequency, position, g.modulation, g.resolved));
+        }
+    }
+    fs::write(csv_path, csv).map_err(|err| AnalysisError::Measurement(err.to_string()))?;
+
+    let points: Vec<Vec<(f64, f64)>> = axes.iter()
+        .map(|axis| axis.groups.iter().filter(|g| g.position_px.is_some()).map(|g| (g.frequency, g.modulation)).collect())
+        .collect();
+    let x_max = phantom.groups.iter().copied().fold(0.0, f64::max);
+    save_plot(plot_path, &points, x_max, 1.2, grid_step(x_max), 0.1).map_err(AnalysisError::Measurement)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_through_band_centres() {
+        let points: Vec<(f64, f64)> = (0..10).map(|i| (i as f64 * 10.0, 50.0 + 0.5 * i as f64 * 10.0)).collect();
+        let (slope, intercept) = fit_line(&points);
+        assert!((slope - 0.5).abs() < 1e-9 && (intercept - 50.0).abs() < 1e-9);
+        assert_eq!(fit_line(&[(3.0, 1.0), (3.0, 2.0)]), (0.0, 1.5));
+    }
+
+    #[test]
+    fn band_of_bar_rows() {
+        // bars on rows 10..20 of a flat image
+        let arr = ndarray::Array2::from_shape_fn((40, 32), |(y, x)| if (10..20).contains(&y) && x % 4 < 2 { 900 } else { 100 });
+        let activity = row_activity(arr.view());
+        assert_eq!(activity[0], 0.0);
+        let (ratio, rows) = band(&activity).unwrap();
+        assert_eq!(rows, (10, 19));
+        // flat rows: median 0, the ratio is against EPSILON
+        assert!(ratio > 1e6);
+        assert!(band(&[1.0; 8]).is_none());
+    }
+}
@@ -1,35 +1,49 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
-mod utils;
 use std::fs;
-use tauri::Manager;
-use crate::utils::{open_dcm_file, save_to_image, get_detail, convert_to_u8, save_to_image_u8, find_common_value, find_center_line, find_theta, rotate_array, fint_horizontal_line, find_vertical_line, boxs_posision, find_edges_pos, split_q_circle, farthest_q, center_point};
-use crate::utils::{U8Array, U16Array, pixel2cm, cm2pixel, U128Array, find_edge_tool, find_mean, cast_type_arr, inv_lut, rectangle_edge_points, length_line, distance_pixel, calculate_angle};
-use std::collections::HashMap;
-use ndarray_stats::QuantileExt;
-use dicom::pixeldata::image::{flat, GrayImage};
-use dicom::dictionary_std::tags::{self, FOCAL_DISTANCE, NUMBER_OF_COMPENSATORS, SPHERE_POWER};
-use ndarray::{s, Array, ArrayBase, Axis, Dim, OwnedRepr};
-use dicom::object::{pixeldata, FileDicomObject, InMemDicomObject, Tag};
-use dicom::{object::open_file, pixeldata::PixelDecoder};
-use utils::{argmax, get_crop_area};
-
-// TYPE
-type DcmObj = dicom::object::FileDicomObject<dicom::object::InMemDicomObject>;
-type I32Array = ArrayBase<OwnedRepr<i32>, Dim<[usize; 2]>>;
-type Obj = FileDicomObject<InMemDicomObject>;
-
-#[tauri::command]
-fn preview(file_path: String, save_path: String) -> [String; 4] {
-    match open_dcm_file(file_path) {
-        Some(obj) => {      
-            let pixel_data: dicom::pixeldata::DecodedPixelData<'_> = obj.decode_pixel_data().unwrap();
-            let arr = pixel_data.to_ndarray::<u16>().unwrap().slice(s![0, .., .., 0]).to_owned();
-            let acquisition_time = get_detail(&obj, tags::ACQUISITION_TIME);
-            let acquisition_date = get_detail(&obj, tags::ACQUISITION_DATE);
-            let detector_id = get_detail(&obj, tags::DETECTOR_ID);
-            let address = get_detail(&obj, tags::INSTITUTION_ADDRESS);
-            save_to_image(arr, save_path);
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tauri::{Manager, State, Window};
+use lightbeam_lib::aec::{run_aec, AecResult};
+use lightbeam_lib::analysis::{evaluate, run_collimator, AnalysisError, CollimatorResult};
+use lightbeam_lib::api::ApiServer;
+use lightbeam_lib::cache::ImageCache;
+use lightbeam_lib::contrast_detail::{self, run_contrast_detail, ContrastDetailPhantom, ContrastDetailResult};
+use lightbeam_lib::defects::{run_defects, DefectMask, DefectResult, DefectThresholds};
+use lightbeam_lib::deident::{deidentify_file, DeidentOptions};
+use lightbeam_lib::ei::{run_ei, EiResult};
+use lightbeam_lib::focal_spot::{run_focal_spot, FocalSpotMethod, FocalSpotResult};
+use lightbeam_lib::grid::{run_grid, GridResult};
+use lightbeam_lib::history::{collimator_entry, History, HistoryEntry};
+use lightbeam_lib::jobs::JobRegistry;
+use lightbeam_lib::lag::{run_lag, LagResult};
+use lightbeam_lib::linepair::{self, run_linepair, LinePairPhantom, LinePairResult};
+use lightbeam_lib::media::{self, MediaInstance};
+use lightbeam_lib::mtf::{run_mtf, MtfResult};
+use lightbeam_lib::nps::{run_nps, NpsResult};
+use lightbeam_lib::pairing::{read_qa_image, Pairer};
+use lightbeam_lib::qatrack::{test_list_upload, QatrackConfig};
+use lightbeam_lib::qr::{self, QrConfig, QrInstance, QrQuery};
+use lightbeam_lib::report::focal_spot_report;
+use lightbeam_lib::sid::{check_sid, SidCheck};
+use lightbeam_lib::storescp::{inbox_path, ScpConfig, StoreScp};
+use lightbeam_lib::tolerance::{self, ToleranceProfile};
+use lightbeam_lib::uniformity::{run_uniformity, UniformityResult};
+use lightbeam_lib::watch::{FolderWatcher, WatchConfig};
+use lightbeam_lib::utils::{save_to_image, get_detail, U8Array, U16View};
+use dicom::dictionary_std::tags;
+use ndarray::Array;
+
+#[tauri::command]
+fn preview(cache: State<'_, ImageCache>, file_path: String, save_path: String) -> [String; 4] {
+    match cache.get(&file_path) {
+        Some(image) => {
+            let obj = &image.obj;
+            let acquisition_time = get_detail(obj, tags::ACQUISITION_TIME);
+            let acquisition_date = get_detail(obj, tags::ACQUISITION_DATE);
+            let detector_id = get_detail(obj, tags::DETECTOR_ID);
+            let address = get_detail(obj, tags::INSTITUTION_ADDRESS);
+            save_to_image(image.arr.view(), save_path);
 
             [detector_id, address, acquisition_date, acquisition_time]
         },
@@ -40,220 +54,454 @@ fn preview(file_path: String, save_path: String) -> [String; 4] {
 }
 
 #[tauri::command]
-fn processing(file_paths: Vec<String>, save_path: Vec<String>) -> ([usize; 4], [f32; 2], [[i32; 2]; 4], Vec<[[f32; 2]; 2]>, Vec<[[i32; 2]; 2]>, Vec<String>, Vec<i32>, Vec<i32>, Vec<String>) {
-    dbg!(&file_paths, &save_path);
-    let large_field = file_paths[0].to_owned();
-    let small_field = file_paths[1].to_owned();
-    // let mut arrays = vec![];
-    match open_dcm_file(large_field) {
-        Some(obj) => {
-            // Large field: find pattern of test-tool 
-            let pixel_data: dicom::pixeldata::DecodedPixelData<'_> = obj.decode_pixel_data().unwrap();
-            let arr = pixel_data.to_ndarray::<u16>().unwrap().slice(s![0, .., .., 0]).to_owned();
-            // save_to_image(arr.clone(), save_path);
-            
-            // Detector details
-            let hospital = get_detail(&obj, tags::INSTITUTION_NAME);
-            let manufacturer = get_detail(&obj, tags::MANUFACTURER);
-            let acquisition_date = get_detail(&obj, tags::ACQUISITION_DATE);
-            let detector_type = get_detail(&obj, tags::DETECTOR_TYPE);
-            let detector_id = get_detail(&obj, tags::DETECTOR_ID);
-            let modality = get_detail(&obj, tags::MODALITY);
-            let mut machine = " - ".to_string();
-            if manufacturer != " - ".to_string() {
-                machine = format!("{} [{}]", manufacturer, modality);
-            } 
-            let address = get_detail(&obj, tags::INSTITUTION_ADDRESS);
-            let spatial_resolution = get_detail(&obj, tags::SPATIAL_RESOLUTION);
-            let mut pixel_size = " - ".to_string();
-            if spatial_resolution != " - ".to_string() {
-                pixel_size = format!("{}x{} mm", spatial_resolution, spatial_resolution);
-            }
-            let rows_ = get_detail(&obj, tags::ROWS);
-            let cols_ = get_detail(&obj, tags::COLUMNS);
-            let mut matrix_size = format!("");
-            if (rows_ != " - ".to_string()) && (cols_ != " - ".to_string()) {
-                matrix_size = format!("{}x{}", rows_, cols_);
-            } 
-            let bit_depth = get_detail(&obj, tags::BITS_STORED);
-            let details = vec![hospital, machine, address, acquisition_date, detector_type, detector_id, pixel_size, matrix_size, bit_depth];
-
-            // Find Test-Tool
-            let [row1, row2, col1, col2] = arr_correction(arr.clone());
-            let mut arr = arr.slice(s![
-                row1..row2, col1..col2
-            ]).to_owned();
-            let mut h = arr.nrows();
-            let mut w = arr.ncols();
-            // check is rotate
-            let mut is_rotate = false;
-            if (row2-row1) > (col2-col1) {
-                is_rotate = true;
-                arr = rotate_array(3.14/2.0, arr.clone());
-                h = arr.nrows();
-                w = arr.ncols();
-            }
-            // check is inv
-            let mut is_inv =  false;
-            let hp = (0.2*(h as f32)) as usize;
-            let wp = (0.06*(w as f32)) as usize;
-            let focus_l = arr.slice(s![hp..h-hp, wp*2..wp*3]).to_owned();
-            let values = argmax(focus_l.clone(), 0);
-            let mut counts: HashMap<usize, u16> = HashMap::new();
-            for n in &values {
-                let count = counts.entry(*n).or_insert(0);
-                *count += 1;
-            }
-            let mut max_key = None;
-            let mut max_val = std::u16::MIN;
-            for (k, v) in counts {
-                if v > max_val {
-                    max_key = Some(k);
-                    max_val = v;
-                }
-            }
-            if (max_val as f32/focus_l.ncols() as f32) < 0.3 {
-                is_inv = true; 
-                arr = inv_lut(arr.clone());
-            }
+fn processing(cache: State<'_, ImageCache>, file_paths: Vec<String>, save_path: Vec<String>) -> Result<CollimatorResult, String> {
+    run_collimator(&file_paths, &save_path, &cache, None, &mut |_| true).map_err(|err| err.to_string())
+}
+
+/// start the analysis on a worker thread, masking the defects of a flat-field image if `defects` is set
+/// 
+/// Returns: job id, progress/result come as "analysis-*" events
+#[tauri::command]
+fn start_processing(window: Window, jobs: State<'_, JobRegistry>, cache: State<'_, ImageCache>, file_paths: Vec<String>, save_path: Vec<String>, defects: Option<DefectMask>) -> u64 {
+    jobs.start(file_paths, save_path, cache.inner().clone(), defects, move |event| {
+        if let Err(err) = window.emit(event.name(), &event) {
+            println!("EMIT: ERR {}", err);
+        }
+    })
+}
 
+#[tauri::command]
+fn cancel_processing(jobs: State<'_, JobRegistry>, job_id: u64) -> bool {
+    jobs.cancel(job_id)
+}
 
-            // Find Center Line
-            let (_, _, _, _, theta_r) = find_center_line(arr.clone());
-            // // Adjust angle
-            let rotated_arr = rotate_array(theta_r, arr.clone());
-            // Fine Lines in Rotated array
-            let ypoints = fint_horizontal_line(rotated_arr.clone());
-            let xpoints = find_vertical_line(rotated_arr.clone());
-            
-            // // Small field
-            match open_dcm_file(small_field) {
-                Some(obj) => {
-                    let pixel_data: dicom::pixeldata::DecodedPixelData<'_> = obj.decode_pixel_data().unwrap();
-                    let arr = pixel_data.to_ndarray::<u16>().unwrap().slice(s![0, .., .., 0]).to_owned();
-                    let mut arr = arr.slice(s![
-                        row1..row2, col1..col2
-                    ]).to_owned();
-                    // is_rotate and is_inv
-                    if is_rotate {
-                        arr = rotate_array(3.14/2.0, arr.clone());
-                    }
-                    if is_inv {
-                        arr = inv_lut(arr.clone());
-                    }
-
-                    let rotated_arr2 = rotate_array(theta_r, arr);
-                    
-                    // save_to_image(rotated_arr2.clone(), "c:/Users/alant/Desktop/arr2.jpg".to_string());
-                    
-                    // Find the Edges
-                    // boxs_position(area for crop)
-                    let boxs_pos = boxs_posision(&xpoints, &ypoints, rotated_arr2.clone());
-                    // get crop area
-                    let crop_areas = get_crop_area(boxs_pos.clone(), rotated_arr2.clone());
-                    // // edges positions
-                    let xypoints = [xpoints[0], xpoints[0], xpoints[2], xpoints[2], ypoints[0], ypoints[0], ypoints[2], ypoints[2]];
-                    let edges_pos = find_edges_pos(crop_areas, boxs_pos.clone(), xypoints, &ypoints);
-                    // find 4 points(top-left[x, y], top-right, bottom_left, bottom-right) of the edges
-                    let (points, mbs) = rectangle_edge_points(boxs_pos, edges_pos);
-                    // // Result: left, right, top, bottom [x1, y1, x2, y2, length]
-                    let (results, results_pos_text) = length_line(points, mbs, &xpoints, &ypoints);
-
-                    // Fine the circles
-                    let (cir_arr, q_arr, white_ts, (xc, yc)) = split_q_circle(&xpoints, &ypoints, rotated_arr2.clone());
-                    let (farthest_q, farthest_point) = farthest_q(q_arr.clone(), white_ts);
-                    let (x, y) = center_point(farthest_point, farthest_q, xc, yc);
-                    let cir_distance = pixel2cm(&ypoints, distance_pixel(x, y, xc as usize, yc as usize));
-                    let cir_angle = calculate_angle(cir_distance);
-                    // dbg!(results, results_pos, points);
-                    // dbg!(center_p, res_xy, res_length, res_err);
-                    let add_arr = add_arrays(rotated_arr, rotated_arr2);
-
-                    // save_to_image_u8(add_arr, "c:/Users/alant/Downloads/result.png".to_string());
-
-                    save_to_image_u8(add_arr, save_path[0].to_owned());
-                    save_to_image(cir_arr, save_path[1].to_owned());
-
-                    ([x, y, xc as usize, yc as usize], [cir_distance, cir_angle], points, results, vec![[[0i32; 2]; 2]; 2], results_pos_text, xpoints, ypoints, details)
-                },
-                None => {
-                    ([0; 4], [0f32; 2], [[0; 2]; 4], vec![[[0f32; 2]; 2], [[0f32; 2]; 2], [[0f32; 2]; 2], [[0f32; 2]; 2]], vec![[[0; 2]; 2], [[0; 2]; 2]], vec![String::from("")], vec![0], vec![0], vec![String::from("")])
-                }
-            }
-        }, 
-        None => {
-            ([0; 4], [0f32; 2], [[0; 2]; 4], vec![[[0f32; 2]; 2], [[0f32; 2]; 2], [[0f32; 2]; 2], [[0f32; 2]; 2]], vec![[[0; 2]; 2], [[0; 2]; 2]],  vec![String::from("")], vec![0], vec![0], vec![String::from("")])
+/// running DICOM receiver (tauri state)
+#[derive(Default)]
+struct ScpState(Mutex<Option<StoreScp>>);
+
+/// start the DICOM C-STORE receiver, replacing a running one
+///
+/// every stored file is sent as "scp-received", a complete large/small
+/// field pair as "scp-pair"
+///
+/// Returns: port the receiver listens on
+#[tauri::command]
+fn start_scp(window: Window, scp: State<'_, ScpState>, config: ScpConfig, large_first: bool) -> Result<u16, String> {
+    let mut running = scp.0.lock().unwrap();
+    if let Some(old) = running.take() {
+        old.stop();
+    }
+    let pairer = Mutex::new(Pairer::new(large_first));
+    let receiver = StoreScp::start(config, move |file| {
+        let _ = window.emit("scp-received", &file);
+        let pair = read_qa_image(&file.path).and_then(|image| pairer.lock().unwrap().push(image));
+        if let Some(pair) = pair {
+            let _ = window.emit("scp-pair", &pair);
         }
+    }).map_err(|err| err.to_string())?;
+    let port = receiver.port();
+    *running = Some(receiver);
+    Ok(port)
+}
+
+#[tauri::command]
+fn stop_scp(scp: State<'_, ScpState>) {
+    if let Some(receiver) = scp.0.lock().unwrap().take() {
+        receiver.stop();
     }
 }
 
-fn arr_correction(arr: U16Array) -> [usize; 4] {
-    // crop array as expect.
-    // Find Test-Tool
-    let shape = arr.shape();
-    let h = shape[0];
-    let w = shape[1];
-    let offset = 30;
-    // find x-axis
-    let focus_x1 = arr.slice(s![
-        (h/2)-offset..h/2, offset..w/2
-    ]).to_owned();
-    let focus_x1_u128 = cast_type_arr(focus_x1);
-    let focus_x1_avg = focus_x1_u128.mean_axis(Axis(0)).unwrap().into_raw_vec();
-    let n = focus_x1_avg.len();
-    let ts = find_mean(focus_x1_avg.clone(), n) as u128;
-    let x1 = find_edge_tool(focus_x1_avg, n, offset, ts) + offset;
-
-    let focus_y1 = arr.slice(s![
-        offset..h/2, w/3..(w/3)+offset
-    ]).to_owned();
-    let focus_y1_u128 = cast_type_arr(focus_y1);
-    let focus_y1_avg = focus_y1_u128.mean_axis(Axis(1)).unwrap().into_raw_vec();
-    let n = focus_y1_avg.len();
-    let ts = find_mean(focus_y1_avg.clone(), n) as u128;
-    let y1 = find_edge_tool(focus_y1_avg, n, offset, ts) + offset;
-
-    let focus_x2 = arr.slice(s![
-        h/2-offset..h/2, w/2..w-offset
-    ]).to_owned();
-    let focus_x2_u128 = cast_type_arr(focus_x2);
-    let mut focus_x2_avg = focus_x2_u128.mean_axis(Axis(0)).unwrap().into_raw_vec();
-    focus_x2_avg.reverse();
-    let n = focus_x2_avg.len();
-    let ts = find_mean(focus_x2_avg.clone(), n) as u128;
-    let x2 = n - find_edge_tool(focus_x2_avg, n, offset, ts) + w/2;
-
-    let focus_y2 = arr.slice(s![
-        h/2..h-offset, w/3..(w/3)+offset
-    ]).to_owned();
-    let focus_y2_u128 = cast_type_arr(focus_y2);
-    let mut focus_y2_avg = focus_y2_u128.mean_axis(Axis(1)).unwrap().into_raw_vec();
-    focus_y2_avg.reverse();
-    let n = focus_y2_avg.len();
-    let ts = find_mean(focus_y2_avg.clone(), n) as u128;
-    let y2 = n - find_edge_tool(focus_y2_avg, n, offset, ts) + h/2;
-    
-    [y1, y2, x1, x2]
-}
-
-
-/// add 2 array
-fn add_arrays(arr1: U16Array, arr2: U16Array) -> U8Array {
-    let nrows = arr1.nrows();
-    let ncols = arr1.ncols();
-    let max_v = *arr1.max().unwrap() as f32 * 2.0; 
-    let mut add_arr = vec![];
-    for r in 0..nrows {
-        for c in 0..ncols {
-            let add_v = arr1[(r, c)] as u32 + arr2[(r, c)] as u32;
-            let v_u8 = ((add_v as f32/max_v) * 255.0) as u8;
-            add_arr.push(v_u8);
+/// Returns: settings of the running receiver
+#[tauri::command]
+fn scp_status(scp: State<'_, ScpState>) -> Option<ScpConfig> {
+    scp.0.lock().unwrap().as_ref().map(|receiver| receiver.config.clone())
+}
+
+fn tolerance_path(window: &Window) -> Result<PathBuf, String> {
+    let dir = window.path_resolver().app_data_dir().ok_or("no app data folder")?;
+    Ok(dir.join("tolerances.json"))
+}
+
+#[tauri::command]
+fn tolerance_profiles(window: Window) -> Result<Vec<ToleranceProfile>, String> {
+    Ok(tolerance::load_profiles(&tolerance_path(&window)?))
+}
+
+#[tauri::command]
+fn save_tolerance_profiles(window: Window, profiles: Vec<ToleranceProfile>) -> Result<(), String> {
+    tolerance::save_profiles(&tolerance_path(&window)?, &profiles)
+}
+
+/// detector uniformity/SNR of a flood image, checked against tolerance profile `profile`
+#[tauri::command]
+async fn uniformity(window: Window, cache: State<'_, ImageCache>, file_path: String, save_path: String, profile: String) -> Result<UniformityResult, String> {
+    let profile = tolerance::find_profile(&tolerance_path(&window)?, &profile);
+    let cache = cache.inner().clone();
+    tauri::async_runtime::spawn_blocking(move || run_uniformity(&file_path, &save_path, &cache, &profile))
+        .await
+        .map_err(|err| err.to_string())?
+        .map_err(|err| err.to_string())
+}
+
+/// slanted-edge MTF of one or two edge images
+///
+/// save_path: [curve CSV, curve plot PNG]
+#[tauri::command]
+async fn mtf(window: Window, cache: State<'_, ImageCache>, file_paths: Vec<String>, save_path: Vec<String>, profile: String) -> Result<MtfResult, String> {
+    let profile = tolerance::find_profile(&tolerance_path(&window)?, &profile);
+    let cache = cache.inner().clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        let [csv, plot] = &save_path[..] else {
+            return Err(AnalysisError::Measurement("a csv and a plot path are needed".to_string()));
+        };
+        run_mtf(&file_paths, csv, plot, &cache, &profile)
+    })
+        .await
+        .map_err(|err| err.to_string())?
+        .map_err(|err| err.to_string())
+}
+
+fn phantom_path(window: &Window) -> Result<PathBuf, String> {
+    let dir = window.path_resolver().app_data_dir().ok_or("no app data folder")?;
+    Ok(dir.join("phantoms.json"))
+}
+
+#[tauri::command]
+fn linepair_phantoms(window: Window) -> Result<Vec<LinePairPhantom>, String> {
+    Ok(linepair::load_phantoms(&phantom_path(&window)?))
+}
+
+#[tauri::command]
+fn save_linepair_phantoms(window: Window, phantoms: Vec<LinePairPhantom>) -> Result<(), String> {
+    linepair::save_phantoms(&phantom_path(&window)?, &phantoms)
+}
+
+/// limiting resolution of bar phantom images (one per axis)
+///
+/// save_path: csv and plot (png) of the group modulations
+#[tauri::command]
+async fn linepair(window: Window, cache: State<'_, ImageCache>, file_paths: Vec<String>, save_path: Vec<String>, phantom: String, profile: String) -> Result<LinePairResult, String> {
+    let profile = tolerance::find_profile(&tolerance_path(&window)?, &profile);
+    let phantom = linepair::find_phantom(&phantom_path(&window)?, &phantom);
+    let cache = cache.inner().clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        let [csv, plot] = &save_path[..] else {
+            return Err(AnalysisError::Measurement("a csv and a plot path are needed".to_string()));
+        };
+        run_linepair(&file_paths, csv, plot, &cache, &phantom, &profile)
+    })
+        .await
+        .map_err(|err| err.to_string())?
+        .map_err(|err| err.to_string())
+}
+
+/// ghosting of a block exposure in the following flat exposure, added to the QA history
+#[tauri::command]
+async fn lag(window: Window, cache: State<'_, ImageCache>, file_paths: Vec<String>, save_path: String, profile: String) -> Result<LagResult, String> {
+    let profile = tolerance::find_profile(&tolerance_path(&window)?, &profile);
+    let history = qa_history_store(&window)?;
+    let cache = cache.inner().clone();
+    tauri::async_runtime::spawn_blocking(move || run_lag(&file_paths, &save_path, &cache, &profile, &history))
+        .await
+        .map_err(|err| err.to_string())?
+        .map_err(|err| err.to_string())
+}
+
+/// focal spot size of a star pattern or pinhole image
+///
+/// save_path: plot (png) and PDF report
+#[tauri::command]
+async fn focal_spot(window: Window, cache: State<'_, ImageCache>, file_path: String, save_path: Vec<String>, method: FocalSpotMethod, magnification: Option<f64>, nominal: Option<f64>, profile: String) -> Result<FocalSpotResult, String> {
+    let profile = tolerance::find_profile(&tolerance_path(&window)?, &profile);
+    let cache = cache.inner().clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        let [plot, pdf] = &save_path[..] else {
+            return Err(AnalysisError::Measurement("a plot and a report path are needed".to_string()));
+        };
+        let result = run_focal_spot(&file_path, plot, &cache, method, magnification, nominal, &profile)?;
+        focal_spot_report(&result, Path::new(plot))
+            .save(Path::new(pdf))
+            .map_err(|err| AnalysisError::Measurement(err.to_string()))?;
+        Ok::<_, AnalysisError>(result)
+    })
+        .await
+        .map_err(|err| err.to_string())?
+        .map_err(|err| err.to_string())
+}
+
+/// SID from the magnification of the tool marks against the header and the
+/// typed-in SID, checked before the edge errors are taken in % of the SID
+#[tauri::command]
+fn sid_check(window: Window, cache: State<'_, ImageCache>, file_path: String, result: CollimatorResult, tool_height: f64, sid: f64, profile: String) -> Result<SidCheck, String> {
+    let profile = tolerance::find_profile(&tolerance_path(&window)?, &profile);
+    let image = cache.get(&file_path).ok_or("cannot read the image")?;
+    check_sid(&result, &image.obj, tool_height, sid, &profile).map_err(|err| err.to_string())
+}
+
+/// AEC consistency over phantom thicknesses (one per image), added to the QA
+/// history of the room
+///
+/// save_path: csv and plot (png) of the central ROI against the thickness
+#[tauri::command]
+async fn aec(window: Window, cache: State<'_, ImageCache>, file_paths: Vec<String>, thickness: Vec<f64>, save_path: Vec<String>, profile: String) -> Result<AecResult, String> {
+    let profile = tolerance::find_profile(&tolerance_path(&window)?, &profile);
+    let history = qa_history_store(&window)?;
+    let cache = cache.inner().clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        let [csv, plot] = &save_path[..] else {
+            return Err(AnalysisError::Measurement("a csv and a plot path are needed".to_string()));
+        };
+        run_aec(&file_paths, &thickness, csv, plot, &cache, &profile, &history)
+    })
+        .await
+        .map_err(|err| err.to_string())?
+        .map_err(|err| err.to_string())
+}
+
+/// grid cut-off and grid line artefacts of a flood exposure, added to the QA history
+///
+/// save_path: lateral profile and line spectra plots (png)
+#[tauri::command]
+async fn grid(window: Window, cache: State<'_, ImageCache>, file_path: String, save_path: Vec<String>, profile: String) -> Result<GridResult, String> {
+    let profile = tolerance::find_profile(&tolerance_path(&window)?, &profile);
+    let history = qa_history_store(&window)?;
+    let cache = cache.inner().clone();
+    tauri::async_runtime::spawn_blocking(move || run_grid(&file_path, &save_path, &cache, &profile, &history))
+        .await
+        .map_err(|err| err.to_string())?
+        .map_err(|err| err.to_string())
+}
+
+/// bad pixel/line map of a flat-field image
+#[tauri::command]
+async fn defects(window: Window, cache: State<'_, ImageCache>, file_path: String, save_path: String, thresholds: DefectThresholds, profile: String) -> Result<DefectResult, String> {
+    let profile = tolerance::find_profile(&tolerance_path(&window)?, &profile);
+    let cache = cache.inner().clone();
+    tauri::async_runtime::spawn_blocking(move || run_defects(&file_path, &save_path, &cache, &thresholds, &profile))
+        .await
+        .map_err(|err| err.to_string())?
+        .map_err(|err| err.to_string())
+}
+
+fn contrast_phantom_path(window: &Window) -> Result<PathBuf, String> {
+    let dir = window.path_resolver().app_data_dir().ok_or("no app data folder")?;
+    Ok(dir.join("contrast_phantoms.json"))
+}
+
+#[tauri::command]
+fn contrast_phantoms(window: Window) -> Result<Vec<ContrastDetailPhantom>, String> {
+    Ok(contrast_detail::load_phantoms(&contrast_phantom_path(&window)?))
+}
+
+#[tauri::command]
+fn save_contrast_phantoms(window: Window, phantoms: Vec<ContrastDetailPhantom>) -> Result<(), String> {
+    contrast_detail::save_phantoms(&contrast_phantom_path(&window)?, &phantoms)
+}
+
+/// disc CNR and threshold contrast of a contrast-detail phantom image
+///
+/// save_path: csv of the discs, threshold contrast plot and overlay (png)
+#[tauri::command]
+async fn contrast(window: Window, cache: State<'_, ImageCache>, file_path: String, save_path: Vec<String>, phantom: String, profile: String) -> Result<ContrastDetailResult, String> {
+    let profile = tolerance::find_profile(&tolerance_path(&window)?, &profile);
+    let phantom = contrast_detail::find_phantom(&contrast_phantom_path(&window)?, &phantom);
+    let cache = cache.inner().clone();
+    tauri::async_runtime::spawn_blocking(move || run_contrast_detail(&file_path, &save_path, &cache, &phantom, &profile))
+        .await
+        .map_err(|err| err.to_string())?
+        .map_err(|err| err.to_string())
+}
+
+/// normalised noise power spectrum of a flat-field image, added to the QA history
+///
+/// save_path: csv and plot (png) of the NNPS curves
+#[tauri::command]
+async fn nps(window: Window, cache: State<'_, ImageCache>, file_path: String, save_path: Vec<String>, profile: String) -> Result<NpsResult, String> {
+    let profile = tolerance::find_profile(&tolerance_path(&window)?, &profile);
+    let history = qa_history_store(&window)?;
+    let cache = cache.inner().clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        let [csv, plot] = &save_path[..] else {
+            return Err(AnalysisError::Measurement("a csv and a plot path are needed".to_string()));
+        };
+        run_nps(&file_path, csv, plot, &cache, &profile, &history)
+    })
+        .await
+        .map_err(|err| err.to_string())?
+        .map_err(|err| err.to_string())
+}
+
+/// EI/DI test of flat exposures at known air kerma (µGy), added to the QA history
+///
+/// save_path: csv and plot (png) of EI against air kerma
+#[tauri::command]
+async fn exposure_index(window: Window, file_paths: Vec<String>, kerma: Vec<f64>, save_path: Vec<String>, profile: String) -> Result<EiResult, String> {
+    let profile = tolerance::find_profile(&tolerance_path(&window)?, &profile);
+    let history = qa_history_store(&window)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        let [csv, plot] = &save_path[..] else {
+            return Err(AnalysisError::Measurement("a csv and a plot path are needed".to_string()));
+        };
+        run_ei(&file_paths, &kerma, csv, plot, &profile, &history)
+    })
+        .await
+        .map_err(|err| err.to_string())?
+        .map_err(|err| err.to_string())
+}
+
+/// add a collimator result (evaluated for `sid`/`criteria`) to the QA history
+/// of the detector of `file_path`
+#[tauri::command]
+fn record_collimator(window: Window, cache: State<'_, ImageCache>, file_path: String, result: CollimatorResult, sid: f32, criteria: f32) -> Result<(), String> {
+    let image = cache.get(&file_path).ok_or("cannot read the image")?;
+    qa_history_store(&window)?.append(&collimator_entry(&image.obj, &result, &evaluate(&result, sid, criteria)))
+}
+
+fn qa_history_store(window: &Window) -> Result<History, String> {
+    let dir = window.path_resolver().app_data_dir().ok_or("no app data folder")?;
+    Ok(History::new(&dir.join("history")))
+}
+
+/// history store of `test`: AEC is kept per room, the other tests per detector
+fn test_history_store(window: &Window, test: &str) -> Result<History, String> {
+    let history = qa_history_store(window)?;
+    Ok(if test == "aec" { history.rooms() } else { history })
+}
+
+/// detectors (rooms for AEC) with QA history of `test`
+#[tauri::command]
+fn history_detectors(window: Window, test: String) -> Result<Vec<String>, String> {
+    Ok(test_history_store(&window, &test)?.detectors())
+}
+
+/// QA history of a detector (room for AEC), all tests if `test` is empty
+#[tauri::command]
+fn qa_history(window: Window, detector: String, test: String) -> Result<Vec<HistoryEntry>, String> {
+    let history = test_history_store(&window, &test)?;
+    let test = if test.is_empty() { None } else { Some(test.as_str()) };
+    Ok(history.entries(&detector, test))
+}
+
+/// list the images of a DICOMDIR or folder (CD/USB import)
+#[tauri::command]
+async fn scan_media(path: String) -> Result<Vec<MediaInstance>, String> {
+    tauri::async_runtime::spawn_blocking(move || media::scan_media(Path::new(&path)))
+        .await
+        .map_err(|err| err.to_string())?
+}
+
+/// search the PACS for QA images
+#[tauri::command]
+async fn qr_find(config: QrConfig, query: QrQuery) -> Result<Vec<QrInstance>, String> {
+    tauri::async_runtime::spawn_blocking(move || qr::find(&config, &query))
+        .await
+        .map_err(|err| err.to_string())?
+}
+
+/// retrieve instances from the PACS (C-MOVE to our receiver)
+///
+/// a temporary receiver with `scp_config` is started if none is running
+///
+/// Returns: file paths in the inbox, already decoded into the image cache
+#[tauri::command]
+async fn qr_retrieve(scp: State<'_, ScpState>, cache: State<'_, ImageCache>, config: QrConfig, scp_config: ScpConfig, instances: Vec<QrInstance>) -> Result<Vec<String>, String> {
+    let running = scp.0.lock().unwrap().as_ref().map(|receiver| receiver.config.clone());
+    let cache = cache.inner().clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        let temporary = match running {
+            Some(_) => None,
+            None => Some(StoreScp::start(scp_config.clone(), |_| {}).map_err(|err| err.to_string())?),
+        };
+        let scp_config = running.unwrap_or(scp_config);
+        let res = qr::retrieve(&config, &scp_config.ae_title, &instances);
+        if let Some(receiver) = temporary {
+            receiver.stop();
+        }
+        let (_, failed) = res?;
+
+        let paths: Vec<String> = instances.iter()
+            .map(|instance| inbox_path(&scp_config, &instance.sop_instance_uid))
+            .filter(|path| path.exists())
+            .map(|path| path.to_string_lossy().to_string())
+            .collect();
+        if paths.len() < instances.len() {
+            return Err(format!("{} of {} images not received ({} failed), is {} known to the PACS?",
+                instances.len() - paths.len(), instances.len(), failed, scp_config.ae_title));
         }
+        for path in &paths {
+            cache.get(path);
+        }
+        Ok(paths)
+    })
+    .await
+    .map_err(|err| err.to_string())?
+}
+
+/// running watch-folder service (tauri state)
+#[derive(Default)]
+struct WatchState(Mutex<Option<FolderWatcher>>);
+
+/// watch a folder, each analysed (or failed) pair is sent as "watch-result"
+#[tauri::command]
+fn start_watch(window: Window, watch: State<'_, WatchState>, cache: State<'_, ImageCache>, config: WatchConfig) -> Result<(), String> {
+    let mut running = watch.0.lock().unwrap();
+    if let Some(old) = running.take() {
+        old.stop();
+    }
+    let watcher = FolderWatcher::start(config, cache.inner().clone(), move |event| {
+        let _ = window.emit("watch-result", &event);
+    })?;
+    *running = Some(watcher);
+    Ok(())
+}
+
+#[tauri::command]
+fn stop_watch(watch: State<'_, WatchState>) {
+    if let Some(watcher) = watch.0.lock().unwrap().take() {
+        watcher.stop();
     }
-    Array::from_shape_vec((nrows, ncols), add_arr).unwrap()
 }
 
-fn to_binary_arr(arr: U128Array, cut_off: u128) -> U8Array {
+/// Returns: settings of the running watch-folder service
+#[tauri::command]
+fn watch_status(watch: State<'_, WatchState>) -> Option<WatchConfig> {
+    watch.0.lock().unwrap().as_ref().map(|watcher| watcher.config.clone())
+}
+
+/// running local HTTP API (tauri state)
+#[derive(Default)]
+struct ApiState(Mutex<Option<ApiServer>>);
+
+/// serve the HTTP/JSON API on localhost, uploads are kept in <app data>/api
+///
+/// Returns: port the API listens on
+#[tauri::command]
+fn start_api(window: Window, api: State<'_, ApiState>, cache: State<'_, ImageCache>, port: u16) -> Result<u16, String> {
+    let mut running = api.0.lock().unwrap();
+    if let Some(old) = running.take() {
+        old.stop();
+    }
+    let work_dir = window.path_resolver().app_data_dir().ok_or("no app data folder")?.join("api");
+    let server = ApiServer::start(&format!("127.0.0.1:{}", port), work_dir, cache.inner().clone())?;
+    let port = server.port;
+    *running = Some(server);
+    Ok(port)
+}
+
+#[tauri::command]
+fn stop_api(api: State<'_, ApiState>) {
+    if let Some(server) = api.0.lock().unwrap().take() {
+        server.stop();
+    }
+}
+
+/// Returns: port of the running HTTP API
+#[tauri::command]
+fn api_status(api: State<'_, ApiState>) -> Option<u16> {
+    api.0.lock().unwrap().as_ref().map(|server| server.port)
+}
+
+fn to_binary_arr(arr: U16View, cut_off: u16) -> U8Array {
     let shape = arr.shape();
     let h = shape[0];
     let w = shape[1];
@@ -269,9 +517,34 @@ fn write_csv(save_path: String, content: String) {
     fs::write(save_path, content).unwrap();
 }
 
+/// write the result as a QATrack+ test list upload (JSON)
+#[tauri::command]
+fn export_qatrack(save_path: String, result: CollimatorResult, sid: f32, criteria: f32, config: QatrackConfig, work_started: String) -> Result<(), String> {
+    let evaluation = evaluate(&result, sid, criteria);
+    let upload = test_list_upload(&result, &evaluation, &config, &work_started);
+    let json = serde_json::to_string_pretty(&upload).map_err(|err| err.to_string())?;
+    fs::write(save_path, json).map_err(|err| err.to_string())
+}
+
+/// write de-identified copies of `file_paths` into `out_dir`
+///
+/// Returns: paths of the copies
+#[tauri::command]
+fn export_dicom(file_paths: Vec<String>, out_dir: String, options: DeidentOptions) -> Result<Vec<String>, String> {
+    fs::create_dir_all(&out_dir).map_err(|err| err.to_string())?;
+    file_paths.iter()
+        .map(|path| deidentify_file(path, Path::new(&out_dir), &options).map(|p| p.to_string_lossy().to_string()))
+        .collect()
+}
+
 
 fn main() {
     tauri::Builder::default()
+        .manage(JobRegistry::default())
+        .manage(ImageCache::default())
+        .manage(ScpState::default())
+        .manage(WatchState::default())
+        .manage(ApiState::default())
         .setup(|app| {
             // Get the main window
             let window = app.get_window("main").unwrap();
@@ -303,7 +576,7 @@ fn main() {
 
             Ok(())
         })
-        .invoke_handler(tauri::generate_handler![processing, preview, write_csv])
+        .invoke_handler(tauri::generate_handler![processing, start_processing, cancel_processing, start_scp, stop_scp, scp_status, scan_media, qr_find, qr_retrieve, start_watch, stop_watch, watch_status, start_api, stop_api, api_status, preview, write_csv, export_qatrack, export_dicom, tolerance_profiles, save_tolerance_profiles, uniformity, mtf, nps, exposure_index, linepair, linepair_phantoms, save_linepair_phantoms, contrast, contrast_phantoms, save_contrast_phantoms, defects, lag, focal_spot, sid_check, grid, aec, record_collimator, history_detectors, qa_history])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
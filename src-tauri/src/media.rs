@@ -0,0 +1,125 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use dicom::dictionary_std::tags;
+use dicom::object::{open_file, OpenFileOptions};
+use crate::dimse::get_str;
+use crate::utils::get_detail;
+
+// stop scanning a folder tree after this many files
+const MAX_FILES: usize = 10_000;
+
+/// image found on removable media
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MediaInstance {
+    pub path: String,
+    pub patient_name: String,
+    pub patient_id: String,
+    pub modality: String,
+    pub series_description: String,
+    pub station_name: String,
+    pub detector_id: String,
+    pub acquisition_date: String,
+    pub acquisition_time: String,
+}
+
+/// list the images of a CD/USB folder
+///
+/// root: DICOMDIR file or folder, a DICOMDIR in the folder is used when present,
+/// otherwise the folder is scanned recursively
+///
+/// Returns: instances sorted by detector and acquisition time
+pub fn scan_media(root: &Path) -> Result<Vec<MediaInstance>, String> {
+    let dicomdir = if root.is_file() {
+        Some(root.to_path_buf())
+    } else {
+        ["DICOMDIR", "dicomdir"].iter().map(|name| root.join(name)).find(|p| p.is_file())
+    };
+    let mut paths = dicomdir.and_then(|path| read_dicomdir(&path)).unwrap_or_default();
+    if paths.is_empty() {
+        if !root.is_dir() {
+            return Err(format!("{} is not a folder or DICOMDIR", root.display()));
+        }
+        scan_folder(root, &mut paths);
+    }
+
+    let mut instances: Vec<MediaInstance> = paths.iter().filter_map(|path| read_instance(path)).collect();
+    instances.sort_by(|a, b| {
+        (&a.detector_id, &a.acquisition_date, &a.acquisition_time, &a.path)
+            .cmp(&(&b.detector_id, &b.acquisition_date, &b.acquisition_time, &b.path))
+    });
+    println!("MEDIA: {} images in {}", instances.len(), root.display());
+    Ok(instances)
+}
+
+/// files of the IMAGE records of a DICOMDIR
+fn read_dicomdir(path: &Path) -> Option<Vec<PathBuf>> {
+    let obj = open_file(path).ok()?;
+    let base = path.parent()?;
+    let records = obj.element(tags::DIRECTORY_RECORD_SEQUENCE).ok()?.items()?;
+    let files = records.iter()
+        .filter(|record| get_str(record, tags::DIRECTORY_RECORD_TYPE) == "IMAGE")
+        .filter_map(|record| {
+            let file_id = record.element(tags::REFERENCED_FILE_ID).ok()?.to_multi_str().ok()?;
+            let relative: PathBuf = file_id.iter().map(|c| c.trim()).collect();
+            let path = base.join(&relative);
+            if path.is_file() {
+                return Some(path);
+            }
+            // media mounted with lower case names
+            let lower = base.join(relative.to_string_lossy().to_lowercase());
+            lower.is_file().then_some(lower)
+        })
+        .collect();
+    Some(files)
+}
+
+/// DICOM files (with the "DICM" preamble) below `dir`
+fn scan_folder(dir: &Path, paths: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else { return };
+    let mut entries: Vec<PathBuf> = entries.flatten().map(|e| e.path()).collect();
+    entries.sort();
+    for path in entries {
+        if paths.len() >= MAX_FILES {
+            return;
+        }
+        let hidden = path.file_name().map(|n| n.to_string_lossy().starts_with('.')).unwrap_or(true);
+        if hidden {
+            continue;
+        }
+        if path.is_dir() {
+            scan_folder(&path, paths);
+        } else if is_dicom(&path) {
+            paths.push(path);
+        }
+    }
+}
+
+fn is_dicom(path: &Path) -> bool {
+    let mut head = [0u8; 132];
+    File::open(path).and_then(|mut f| f.read_exact(&mut head)).is_ok() && &head[128..] == b"DICM"
+}
+
+/// header fields shown in the import list, None for files without an image
+fn read_instance(path: &Path) -> Option<MediaInstance> {
+    let obj = OpenFileOptions::new()
+        .read_until(tags::PIXEL_DATA)
+        .open_file(path)
+        .ok()?;
+    if get_detail(&obj, tags::ROWS) == " - " {
+        return None;
+    }
+    let detail = |tag| get_detail(&obj, tag).trim().to_string();
+    Some(MediaInstance {
+        path: path.to_string_lossy().to_string(),
+        patient_name: detail(tags::PATIENT_NAME),
+        patient_id: detail(tags::PATIENT_ID),
+        modality: detail(tags::MODALITY),
+        series_description: detail(tags::SERIES_DESCRIPTION),
+        station_name: detail(tags::STATION_NAME),
+        detector_id: detail(tags::DETECTOR_ID),
+        acquisition_date: detail(tags::ACQUISITION_DATE),
+        acquisition_time: detail(tags::ACQUISITION_TIME),
+    })
+}
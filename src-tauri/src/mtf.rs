@@ -0,0 +1,299 @@
+use std::f64::consts::PI;
+use std::fs;
+use ndarray::{s, ArrayView2, Axis, Slice};
+use serde::{Deserialize, Serialize};
+use crate::analysis::{detector_details, AnalysisError};
+use crate::cache::ImageCache;
+use crate::fft;
+use crate::plot::save_plot;
+use crate::tolerance::{all_passed, Check, ToleranceProfile};
+use crate::utils::{get_pixel_spacing, get_rescale, rescaled};
+
+// square ROI around the image centre, the edge must cross it
+const ROI_SIZE_MM: f64 = 50.0;
+const ROI_SIZE_PX: usize = 256;
+// ESF bins per pixel
+const OVERSAMPLING: usize = 4;
+// half width (px) of the LSF centroid window on each row
+const CENTROID_HALF_WIDTH: usize = 6;
+
+/// MTF direction, horizontal is measured with a (near) vertical edge
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Horizontal,
+    Vertical,
+}
+
+impl Direction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Direction::Horizontal => "horizontal",
+            Direction::Vertical => "vertical",
+        }
+    }
+}
+
+/// pre-sampled MTF of one edge image
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MtfCurve {
+    pub file_path: String,
+    pub direction: Direction,
+    /// edge angle against the pixel rows/columns (degree)
+    pub edge_angle: f64,
+    /// lp/mm, or cycles/pixel without pixel spacing, up to Nyquist
+    pub frequencies: Vec<f64>,
+    pub mtf: Vec<f64>,
+    pub mtf50: Option<f64>,
+    pub mtf10: Option<f64>,
+}
+
+/// IEC 62220-1 style slanted-edge MTF
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MtfResult {
+    pub details: Vec<String>,
+    pub pixel_spacing_mm: Option<f64>,
+    pub curves: Vec<MtfCurve>,
+    pub profile: String,
+    pub checks: Vec<Check>,
+    pub passed: bool,
+}
+
+/// MTF of one or more edge images (e.g. vertical and horizontal edge)
+///
+/// csv_path: curves as direction,frequency,mtf; plot_path: curves (blue, red, ...)
+pub fn run_mtf(file_paths: &[String], csv_path: &str, plot_path: &str, cache: &ImageCache, profile: &ToleranceProfile) -> Result<MtfResult, AnalysisError> {
+    let mut curves = Vec::new();
+    let mut details = Vec::new();
+    let mut pixel_spacing_mm = None;
+    for file_path in file_paths {
+        let image = cache.get(file_path).ok_or_else(|| AnalysisError::Load(file_path.to_owned()))?;
+        let spacing = get_pixel_spacing(&image.obj);
+        if curves.is_empty() {
+            details = detector_details(&image.obj);
+            pixel_spacing_mm = spacing;
+        }
+        let roi_px = spacing.map(|s| (ROI_SIZE_MM / s).round() as usize).unwrap_or(ROI_SIZE_PX);
+        let (h, w) = image.arr.dim();
+        let roi = roi_px.min(h).min(w);
+        let (top, left) = ((h - roi) / 2, (w - roi) / 2);
+        let arr = rescaled(image.arr.slice(s![top..top + roi, left..left + roi]), get_rescale(&image.obj));
+        let curve = edge_mtf(arr.view(), spacing.unwrap_or(1.0))
+            .ok_or_else(|| AnalysisError::Measurement(format!("no edge found in the centre of {}", file_path)))?;
+        curves.push(MtfCurve { file_path: file_path.to_owned(), ..curve });
+    }
+
+    let mut checks = Vec::new();
+    for curve in &curves {
+        let direction = curve.direction.name();
+        let mut push = |key: &str, value: f64| {
+            let check = profile.check(key, value);
+            checks.push(Check { key: format!("{} ({})", key, direction), ..check });
+        };
+        push("mtf.edge_angle", curve.edge_angle);
+        push("mtf.mtf50", curve.mtf50.unwrap_or(0.0));
+        push("mtf.mtf10", curve.mtf10.unwrap_or(0.0));
+    }
+
+    save_curves(&curves, csv_path, plot_path, pixel_spacing_mm.unwrap_or(1.0))?;
+    Ok(MtfResult {
+        details,
+        pixel_spacing_mm,
+        curves,
+        profile: profile.name.clone(),
+        passed: all_passed(&checks),
+        checks,
+    })
+}
+
+/// MTF of an edge ROI, None if no straight edge crosses it
+fn edge_mtf(roi: ArrayView2<f64>, spacing: f64) -> Option<MtfCurve> {
+    // the edge runs along the direction with the smaller gradient
+    let grad = |axis: usize| {
+        let a = roi.view();
+        let n = a.len_of(Axis(axis));
+        (a.slice_axis(Axis(axis), Slice::from(1..n)).to_owned() - a.slice_axis(Axis(axis), Slice::from(0..n - 1))).mapv(f64::abs).sum()
+    };
+    let (direction, arr) = if grad(1) >= grad(0) {
+        (Direction::Horizontal, roi.to_owned())
+    } else {
+        (Direction::Vertical, roi.t().to_owned())
+    };
+
+    // edge position on each row: centroid of the derivative around its peak
+    let (rows, cols) = arr.dim();
+    let mut ys = Vec::new();
+    let mut xs = Vec::new();
+    for (y, row) in arr.axis_iter(Axis(0)).enumerate() {
+        let diff: Vec<f64> = (1..cols - 1).map(|x| (row[x + 1] - row[x - 1]).abs()).collect();
+        let (peak, _) = diff.iter().enumerate().fold((0, 0.0), |best, (i, &d)| if d > best.1 { (i, d) } else { best });
+        let lo = peak.saturating_sub(CENTROID_HALF_WIDTH);
+        let hi = (peak + CENTROID_HALF_WIDTH + 1).min(diff.len());
+        let weight: f64 = diff[lo..hi].iter().sum();
+        if weight > 0.0 {
+            let centroid = diff[lo..hi].iter().enumerate().map(|(i, d)| (lo + i) as f64 * d).sum::<f64>() / weight;
+            ys.push(y as f64);
+            xs.push(centroid + 1.0);
+        }
+    }
+    if ys.len() < rows / 2 {
+        return None;
+    }
+    let (slope, intercept) = fit_line(&ys, &xs);
+    let edge_angle = slope.atan().to_degrees().abs();
+
+    // oversampled ESF from the perpendicular distance of every pixel to the edge
+    let cos = slope.atan().cos();
+    let offset = (cols * OVERSAMPLING) as isize;
+    let mut sum = vec![0.0; 2 * offset as usize];
+    let mut count = vec![0usize; sum.len()];
+    for ((y, x), &v) in arr.indexed_iter() {
+        let d = (x as f64 - (slope * y as f64 + intercept)) * cos;
+        let bin = (d * OVERSAMPLING as f64).floor() as isize + offset;
+        if bin >= 0 && (bin as usize) < sum.len() {
+            sum[bin as usize] += v;
+            count[bin as usize] += 1;
+        }
+    }
+    let first = count.iter().position(|&c| c > 0)?;
+    let last = count.iter().rposition(|&c| c > 0)?;
+    let mut esf: Vec<Option<f64>> = (first..=last)
+        .map(|i| if count[i] > 0 { Some(sum[i] / count[i] as f64) } else { None })
+        .collect();
+    fill_gaps(&mut esf);
+    let esf: Vec<f64> = esf.into_iter().map(|v| v.unwrap_or(0.0)).collect();
+    if esf.len() < 8 {
+        return None;
+    }
+
+    // LSF (central difference) with a Hann window centred on its peak
+    let mut lsf: Vec<f64> = (0..esf.len())
+        .map(|i| (esf[(i + 1).min(esf.len() - 1)] - esf[i.saturating_sub(1)]) / 2.0)
+        .collect();
+    let peak = lsf.iter().enumerate().fold((0, 0.0), |best, (i, v)| if v.abs() > best.1 { (i, v.abs()) } else { best }).0;
+    let width = lsf.len() as f64;
+    for (i, v) in lsf.iter_mut().enumerate() {
+        let t = (i as f64 - peak as f64) / width;
+        *v *= if t.abs() < 0.5 { 0.5 * (1.0 + (2.0 * PI * t).cos()) } else { 0.0 };
+    }
+
+    let (magnitude, n) = fft::magnitude(&lsf);
+    let dc = magnitude[0];
+    if dc <= 0.0 {
+        return None;
+    }
+    let dx = spacing / OVERSAMPLING as f64;
+    let nyquist = 1.0 / (2.0 * spacing);
+    let mut frequencies = Vec::new();
+    let mut mtf = Vec::new();
+    for (k, m) in magnitude.iter().enumerate() {
+        let f = k as f64 / (n as f64 * dx);
+        if f > nyquist + 1e-9 {
+            break;
+        }
+        // central difference transfer function
+        let arg = PI * 2.0 * f * dx;
+        let correction = if arg > 0.0 { arg.sin() / arg } else { 1.0 };
+        frequencies.push(f);
+        mtf.push(m / dc / correction);
+    }
+
+    Some(MtfCurve {
+        file_path: String::new(),
+        direction,
+        edge_angle,
+        mtf50: crossing(&frequencies, &mtf, 0.5),
+        mtf10: crossing(&frequencies, &mtf, 0.1),
+        frequencies,
+        mtf,
+    })
+}
+
+/// least squares x = slope * y + intercept
+fn fit_line(ys: &[f64], xs: &[f64]) -> (f64, f64) {
+    let n = ys.len() as f64;
+    let my = ys.iter().sum::<f64>() / n;
+    let mx = xs.iter().sum::<f64>() / n;
+    let syy: f64 = ys.iter().map(|y| (y - my).powi(2)).sum();
+    let sxy: f64 = ys.iter().zip(xs).map(|(y, x)| (y - my) * (x - mx)).sum();
+    let slope = if syy > 0.0 { sxy / syy } else { 0.0 };
+    (slope, mx - slope * my)
+}
+
+/// linear interpolation of empty ESF bins
+fn fill_gaps(values: &mut [Option<f64>]) {
+    let mut last: Option<(usize, f64)> = None;
+    for i in 0..values.len() {
+        if let Some(v) = values[i] {
+            if let Some((j, prev)) = last {
+                for k in j + 1..i {
+                    values[k] = Some(prev + (v - prev) * (k - j) as f64 / (i - j) as f64);
+                }
+            }
+            last = Some((i, v));
+        }
+    }
+}
+
+/// first frequency where the MTF falls below `level`
+pub fn crossing(frequencies: &[f64], mtf: &[f64], level: f64) -> Option<f64> {
+    let i = mtf.iter().position(|&m| m < level)?;
+    if i == 0 {
+        return Some(frequencies[0]);
+    }
+    let (f0, f1, m0, m1) = (frequencies[i - 1], frequencies[i], mtf[i - 1], mtf[i]);
+    Some(f0 + (m0 - level) / (m0 - m1) * (f1 - f0))
+}
+
+fn save_curves(curves: &[MtfCurve], csv_path: &str, plot_path: &str, spacing: f64) -> Result<(), AnalysisError> {
+    let mut csv = String::from("direction,frequency,mtf\n");
+    for curve in curves {
+        for (f, m) in curve.frequencies.iter().zip(&curve.mtf) {
+            csv.push_str(&format!("{},{:.4},{:.4}\n", curve.direction.name(), f, m));
+        }
+    }
+    fs::write(csv_path, csv).map_err(|err| AnalysisError::Measurement(err.to_string()))?;
+
+    let points: Vec<Vec<(f64, f64)>> = curves.iter()
+        .map(|c| c.frequencies.iter().copied().zip(c.mtf.iter().copied()).collect())
+        .collect();
+    let nyquist = 1.0 / (2.0 * spacing);
+    let x_step = if nyquist > 1.0 { 0.5 } else { 0.1 };
+    save_plot(plot_path, &points, nyquist, 1.0, x_step, 0.1).map_err(AnalysisError::Measurement)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// edge 5° off the columns, every pixel integrates the step over its width
+    fn slanted_edge(size: usize) -> ndarray::Array2<f64> {
+        let slope = 5f64.to_radians().tan();
+        let centre = size as f64 / 2.0;
+        ndarray::Array2::from_shape_fn((size, size), |(y, x)| {
+            let d = x as f64 - (centre + slope * (y as f64 - centre));
+            100.0 + 900.0 * (d + 0.5).clamp(0.0, 1.0)
+        })
+    }
+
+    #[test]
+    fn ideal_edge_gives_the_pixel_aperture() {
+        let curve = edge_mtf(slanted_edge(128).view(), 0.1).unwrap();
+        assert_eq!(curve.direction, Direction::Horizontal);
+        assert!((curve.edge_angle - 5.0).abs() < 0.1, "angle {}", curve.edge_angle);
+        // MTF of a 0.1 mm aperture: sinc(f a), 0.64 at Nyquist, MTF50 beyond it
+        for (f, m) in curve.frequencies.iter().zip(&curve.mtf) {
+            let arg = PI * f * 0.1;
+            let sinc = if arg > 0.0 { arg.sin() / arg } else { 1.0 };
+            assert!((m - sinc).abs() < 0.05, "{:.2} lp/mm: {:.3} vs {:.3}", f, m, sinc);
+        }
+        assert!((curve.frequencies.last().unwrap() - 5.0).abs() < 1e-9);
+        assert!(curve.mtf50.is_none());
+    }
+
+    #[test]
+    fn crossing_interpolates() {
+        assert_eq!(crossing(&[0.0, 1.0, 2.0], &[1.0, 0.6, 0.2], 0.5), Some(1.25));
+        assert_eq!(crossing(&[0.0, 1.0], &[1.0, 0.9], 0.5), None);
+    }
+}
@@ -0,0 +1,296 @@
+use std::fs;
+use ndarray::{s, Array2, ArrayView2};
+use serde::{Deserialize, Serialize};
+use crate::analysis::{detector_details, AnalysisError};
+use crate::cache::ImageCache;
+use crate::fft::power_spectrum_2d;
+use crate::history::{History, HistoryEntry};
+use crate::plot::save_plot;
+use crate::tolerance::{all_passed, Check, ToleranceProfile};
+use crate::utils::{get_pixel_spacing, get_rescale, rescaled};
+
+// central analysis area (IEC 62220-1: 125 x 125 mm)
+const AREA_SIZE_MM: f64 = 125.0;
+// ROI side (power of two), ROIs overlap by half
+const ROI_SIZE: usize = 256;
+const MIN_ROI_SIZE: usize = 64;
+// rows/columns on each side of an axis averaged for the axis cuts (axis excluded)
+const AXIS_LINES: usize = 7;
+// lp/mm of the NNPS values stored in the history
+const REPORT_FREQUENCIES: [f64; 3] = [0.5, 1.0, 2.0];
+
+/// normalised noise power spectrum of a flat-field exposure
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NpsResult {
+    pub details: Vec<String>,
+    pub pixel_spacing_mm: f64,
+    pub roi_size_px: usize,
+    pub roi_count: usize,
+    /// mean (rescaled) pixel value of the analysis area
+    pub mean: f64,
+    /// lp/mm, up to Nyquist
+    pub frequencies: Vec<f64>,
+    /// NNPS (mm²) along u, v and radially averaged
+    pub horizontal: Vec<f64>,
+    pub vertical: Vec<f64>,
+    pub radial: Vec<f64>,
+    /// (lp/mm, radial NNPS) at the report frequencies
+    pub nnps: Vec<(f64, f64)>,
+    pub profile: String,
+    pub checks: Vec<Check>,
+    pub passed: bool,
+}
+
+/// 2D NPS of the central area, averaged over half-overlapping ROIs after
+/// removing a 2nd order polynomial trend; the result is added to `history`
+///
+/// csv_path: frequency,horizontal,vertical,radial; plot_path: log10 NNPS (blue, red, green)
+pub fn run_nps(file_path: &str, csv_path: &str, plot_path: &str, cache: &ImageCache, profile: &ToleranceProfile, history: &History) -> Result<NpsResult, AnalysisError> {
+    let image = cache.get(file_path).ok_or_else(|| AnalysisError::Load(file_path.to_owned()))?;
+    let obj = &image.obj;
+    let spacing = get_pixel_spacing(obj)
+        .ok_or_else(|| AnalysisError::Measurement("pixel spacing is missing".to_string()))?;
+
+    let (h, w) = image.arr.dim();
+    let area = ((AREA_SIZE_MM / spacing).round() as usize).min(h).min(w);
+    let roi = if area >= ROI_SIZE { ROI_SIZE } else { (area + 1).next_power_of_two() / 2 };
+    if roi < MIN_ROI_SIZE {
+        return Err(AnalysisError::Measurement(format!("image too small for a {} px ROI", MIN_ROI_SIZE)));
+    }
+    let (top, left) = ((h - area) / 2, (w - area) / 2);
+    let mut arr = rescaled(image.arr.slice(s![top..top + area, left..left + area]), get_rescale(obj));
+    let mean = arr.mean().unwrap_or(0.0);
+    if mean <= 0.0 {
+        return Err(AnalysisError::Measurement("no signal in the flat-field area".to_string()));
+    }
+    detrend(&mut arr);
+    let (nnps, roi_count) = normalised_nps(arr.view(), roi, spacing, mean);
+
+    let df = 1.0 / (roi as f64 * spacing);
+    let frequencies: Vec<f64> = (0..=roi / 2).map(|k| k as f64 * df).collect();
+    let (horizontal, vertical) = axis_cuts(nnps.view());
+    let radial = radial_average(nnps.view());
+    let report: Vec<(f64, f64)> = REPORT_FREQUENCIES.iter()
+        .filter(|&&f| f < frequencies[frequencies.len() - 1])
+        .map(|&f| (f, interpolate(&frequencies, &radial, f)))
+        .collect();
+
+    let checks: Vec<Check> = report.iter()
+        .map(|(f, v)| profile.check(&format!("nps.nnps_{:.1}", f), *v))
+        .collect();
+    let passed = all_passed(&checks);
+
+    let mut entry = HistoryEntry::for_image("nps", obj);
+    entry.values.insert("mean".to_string(), mean);
+    for (f, v) in &report {
+        entry.values.insert(format!("nnps_{:.1}", f), *v);
+    }
+    entry.passed = Some(passed);
+    if let Err(err) = history.append(&entry) {
+        println!("HISTORY: ERR {}", err);
+    }
+
+    let result = NpsResult {
+        details: detector_details(obj),
+        pixel_spacing_mm: spacing,
+        roi_size_px: roi,
+        roi_count,
+        mean,
+        frequencies,
+        horizontal,
+        vertical,
+        radial,
+        nnps: report,
+        profile: profile.name.clone(),
+        checks,
+        passed,
+    };
+    save_curves(&result, csv_path, plot_path)?;
+    println!("NPS: {} ROIs, mean {:.1}", roi_count, mean);
+    Ok(result)
+}
+
+/// NNPS of the half-overlapping `roi` x `roi` blocks of a square (detrended) area
+///
+/// Returns: (NNPS (mm²), block count)
+fn normalised_nps(arr: ArrayView2<f64>, roi: usize, spacing: f64, mean: f64) -> (Array2<f64>, usize) {
+    let area = arr.nrows().min(arr.ncols());
+    let step = roi / 2;
+    let mut sum = Array2::<f64>::zeros((roi, roi));
+    let mut roi_count = 0;
+    for y in (0..=area - roi).step_by(step) {
+        for x in (0..=area - roi).step_by(step) {
+            let block = arr.slice(s![y..y + roi, x..x + roi]);
+            let block = &block - block.mean().unwrap_or(0.0);
+            sum += &power_spectrum_2d(&block);
+            roi_count += 1;
+        }
+    }
+    // NPS = dx dy / (M N²) Σ |FFT|², normalised by the mean signal²
+    (sum * (spacing * spacing / (roi_count * roi * roi) as f64 / (mean * mean)), roi_count)
+}
+
+/// subtract a least squares 2nd order polynomial surface
+fn detrend(arr: &mut Array2<f64>) {
+    let (h, w) = arr.dim();
+    // coordinates scaled to [-1, 1] to keep the normal equations well conditioned
+    let basis = |y: usize, x: usize| {
+        let u = 2.0 * x as f64 / (w - 1).max(1) as f64 - 1.0;
+        let v = 2.0 * y as f64 / (h - 1).max(1) as f64 - 1.0;
+        [1.0, u, v, u * u, u * v, v * v]
+    };
+    let mut ata = [[0.0; 6]; 6];
+    let mut atb = [0.0; 6];
+    for ((y, x), &value) in arr.indexed_iter() {
+        let b = basis(y, x);
+        for i in 0..6 {
+            for j in 0..6 {
+                ata[i][j] += b[i] * b[j];
+            }
+            atb[i] += b[i] * value;
+        }
+    }
+    let Some(coef) = solve(ata, atb) else { return };
+    for ((y, x), value) in arr.indexed_iter_mut() {
+        let b = basis(y, x);
+        *value -= b.iter().zip(&coef).map(|(b, c)| b * c).sum::<f64>();
+    }
+}
+
+/// Gaussian elimination with partial pivoting, None if singular
+fn solve(mut a: [[f64; 6]; 6], mut b: [f64; 6]) -> Option<[f64; 6]> {
+    for col in 0..6 {
+        let pivot = (col..6).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        for row in col + 1..6 {
+            let factor = a[row][col] / a[col][col];
+            for k in col..6 {
+                a[row][k] -= factor * a[col][k];
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+    let mut x = [0.0; 6];
+    for row in (0..6).rev() {
+        let rest: f64 = (row + 1..6).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - rest) / a[row][row];
+    }
+    Some(x)
+}
+
+/// mean of the lines next to the u and v axes (IEC 62220-1), 0..=Nyquist
+fn axis_cuts(nps: ArrayView2<f64>) -> (Vec<f64>, Vec<f64>) {
+    let n = nps.nrows();
+    let lines: Vec<usize> = (1..=AXIS_LINES.min(n / 2 - 1)).flat_map(|k| [k, n - k]).collect();
+    let cut = |at: &dyn Fn(usize, usize) -> f64| -> Vec<f64> {
+        (0..=n / 2).map(|k| lines.iter().map(|&l| at(l, k)).sum::<f64>() / lines.len() as f64).collect()
+    };
+    (cut(&|l, k| nps[[l, k]]), cut(&|l, k| nps[[k, l]]))
+}
+
+/// mean over rings of one frequency step, 0..=Nyquist
+fn radial_average(nps: ArrayView2<f64>) -> Vec<f64> {
+    let n = nps.nrows();
+    let bins = n / 2 + 1;
+    let mut sum = vec![0.0; bins];
+    let mut count = vec![0usize; bins];
+    let signed = |k: usize| if k <= n / 2 { k as f64 } else { k as f64 - n as f64 };
+    for ((v, u), &value) in nps.indexed_iter() {
+        let bin = signed(u).hypot(signed(v)).round() as usize;
+        if bin < bins && (u, v) != (0, 0) {
+            sum[bin] += value;
+            count[bin] += 1;
+        }
+    }
+    sum.iter().zip(&count).map(|(s, &c)| if c > 0 { s / c as f64 } else { 0.0 }).collect()
+}
+
+fn interpolate(xs: &[f64], ys: &[f64], x: f64) -> f64 {
+    let i = xs.iter().position(|&v| v >= x).unwrap_or(xs.len() - 1).max(1);
+    let (x0, x1, y0, y1) = (xs[i - 1], xs[i], ys[i - 1], ys[i]);
+    if x1 > x0 { y0 + (x - x0) / (x1 - x0) * (y1 - y0) } else { y1 }
+}
+
+fn save_curves(result: &NpsResult, csv_path: &str, plot_path: &str) -> Result<(), AnalysisError> {
+    let mut csv = String::from("frequency,horizontal,vertical,radial\n");
+    for (i, f) in result.frequencies.iter().enumerate() {
+        csv.push_str(&format!("{:.4},{:.4e},{:.4e},{:.4e}\n", f, result.horizontal[i], result.vertical[i], result.radial[i]));
+    }
+    fs::write(csv_path, csv).map_err(|err| AnalysisError::Measurement(err.to_string()))?;
+
+    // log10 scale, one grid line per decade (the zero frequency is left out)
+    let curves = [&result.horizontal, &result.vertical, &result.radial];
+    let logs: Vec<f64> = curves.iter().flat_map(|c| c[1..].iter()).filter(|&&v| v > 0.0).map(|v| v.log10()).collect();
+    let low = logs.iter().copied().fold(f64::INFINITY, f64::min).floor();
+    let high = logs.iter().copied().fold(f64::NEG_INFINITY, f64::max).ceil();
+    if !low.is_finite() || !high.is_finite() {
+        return Err(AnalysisError::Measurement("no noise in the flat-field area".to_string()));
+    }
+    let points: Vec<Vec<(f64, f64)>> = curves.iter()
+        .map(|c| result.frequencies.iter().zip(c.iter()).skip(1)
+            .filter(|(_, &v)| v > 0.0)
+            .map(|(&f, v)| (f, v.log10() - low))
+            .collect())
+        .collect();
+    let nyquist = result.frequencies[result.frequencies.len() - 1];
+    let x_step = if nyquist > 1.0 { 0.5 } else { 0.1 };
+    save_plot(plot_path, &points, nyquist, (high - low).max(1.0), x_step, 1.0).map_err(AnalysisError::Measurement)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// uniform noise in [-0.5, 0.5) x `range` (xorshift), variance range² / 12
+    fn white_noise(size: usize, range: f64) -> Array2<f64> {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        Array2::from_shape_fn((size, size), |_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            ((state >> 11) as f64 / (1u64 << 53) as f64 - 0.5) * range
+        })
+    }
+
+    #[test]
+    fn white_noise_is_flat() {
+        let (spacing, mean, range) = (0.1, 1000.0, 20.0);
+        let (nnps, roi_count) = normalised_nps(white_noise(256, range).view(), 64, spacing, mean);
+        assert_eq!(roi_count, 49);
+        // σ² dx dy / mean²
+        let expected = range * range / 12.0 * spacing * spacing / (mean * mean);
+        let radial = radial_average(nnps.view());
+        for (k, v) in radial.iter().enumerate().skip(2) {
+            assert!((v / expected - 1.0).abs() < 0.15, "bin {}: {:.3e} vs {:.3e}", k, v, expected);
+        }
+    }
+
+    #[test]
+    fn solves_a_known_system() {
+        let mut a = [[0.0; 6]; 6];
+        for (i, row) in a.iter_mut().enumerate() {
+            for (j, v) in row.iter_mut().enumerate() {
+                *v = 1.0 / (i + j + 1) as f64 + if i == j { 1.0 } else { 0.0 };
+            }
+        }
+        let x = [1.0, -2.0, 3.0, -4.0, 5.0, -6.0];
+        let b: Vec<f64> = a.iter().map(|row| row.iter().zip(&x).map(|(a, x)| a * x).sum()).collect();
+        let solved = solve(a, b.try_into().unwrap()).unwrap();
+        assert!(solved.iter().zip(&x).all(|(s, x)| (s - x).abs() < 1e-9), "{:?}", solved);
+        assert!(solve([[1.0; 6]; 6], [1.0; 6]).is_none());
+    }
+
+    #[test]
+    fn detrend_removes_a_quadratic_surface() {
+        let mut arr = Array2::from_shape_fn((40, 60), |(y, x)| {
+            let (x, y) = (x as f64, y as f64);
+            500.0 + 2.0 * x - 3.0 * y + 0.05 * x * x - 0.02 * x * y + 0.1 * y * y
+        });
+        detrend(&mut arr);
+        assert!(arr.iter().all(|v| v.abs() < 1e-6));
+    }
+}
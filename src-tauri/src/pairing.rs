@@ -0,0 +1,230 @@
+use serde::{Deserialize, Serialize};
+use dicom::dictionary_std::tags;
+use dicom::object::OpenFileOptions;
+use crate::utils::{get_detail, DcmObj};
+
+/// exposures further apart than this are not a pair (seconds)
+pub const PAIR_WINDOW_S: i64 = 10 * 60;
+// unpaired images kept while waiting for the other exposure
+const MAX_WAITING: usize = 64;
+
+/// header fields used to pair the large and small field exposures
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QaImage {
+    pub path: String,
+    /// detector id, or station name if the detector id is missing
+    pub detector: String,
+    /// acquisition date/time in seconds (DICOM DA + TM, no time zone)
+    pub acquired: Option<i64>,
+}
+
+/// large and small field of one collimator test
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pair {
+    pub large: QaImage,
+    pub small: QaImage,
+}
+
+/// read the pairing fields from the header only (pixel data is not read)
+pub fn read_qa_image(path: &str) -> Option<QaImage> {
+    let obj = OpenFileOptions::new()
+        .read_until(tags::PIXEL_DATA)
+        .open_file(path)
+        .ok()?;
+    let detector = detector_name(&obj);
+    let mut acquired = dicom_datetime(&get_detail(&obj, tags::ACQUISITION_DATE), &get_detail(&obj, tags::ACQUISITION_TIME));
+    if acquired.is_none() {
+        acquired = dicom_datetime(&get_detail(&obj, tags::CONTENT_DATE), &get_detail(&obj, tags::CONTENT_TIME));
+    }
+    Some(QaImage { path: path.to_string(), detector, acquired })
+}
+
+/// detector id, or station name if the detector id is missing
+pub fn detector_name(obj: &DcmObj) -> String {
+    let mut detector = get_detail(obj, tags::DETECTOR_ID);
+    if detector == " - " {
+        detector = get_detail(obj, tags::STATION_NAME);
+    }
+    detector.trim().to_string()
+}
+
+/// DICOM date (YYYYMMDD) and time (HHMMSS.FFFFFF) to seconds since 1970-01-01
+pub fn dicom_datetime(date: &str, time: &str) -> Option<i64> {
+    let date = date.trim();
+    let time = time.trim();
+    if date.len() < 8 || !date.is_char_boundary(8) {
+        return None;
+    }
+    let y: i64 = date[0..4].parse().ok()?;
+    let m: i64 = date[4..6].parse().ok()?;
+    let d: i64 = date[6..8].parse().ok()?;
+    // HH, HHMM or HHMMSS(.frac)
+    let field = |i: usize| time.get(i..i + 2).and_then(|v| v.parse::<i64>().ok()).unwrap_or(0);
+    let seconds = field(0) * 3600 + field(2) * 60 + field(4);
+
+    // days from civil date (proleptic gregorian)
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (m + if m > 2 { -3 } else { 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    Some(days * 86400 + seconds)
+}
+
+/// pairs images as they arrive
+///
+/// two images of the same detector acquired within `PAIR_WINDOW_S` are a pair,
+/// the earlier exposure is the large field unless `large_first` is false
+#[derive(Debug)]
+pub struct Pairer {
+    pub large_first: bool,
+    waiting: Vec<QaImage>,
+}
+
+impl Default for Pairer {
+    fn default() -> Self {
+        Pairer { large_first: true, waiting: Vec::new() }
+    }
+}
+
+impl Pairer {
+    pub fn new(large_first: bool) -> Pairer {
+        Pairer { large_first, waiting: Vec::new() }
+    }
+
+    /// add an image, returns the pair it completes
+    pub fn push(&mut self, image: QaImage) -> Option<Pair> {
+        self.push_evicting(image).0
+    }
+
+    /// add an image, returns the pair it completes and the oldest waiting
+    /// image if it was dropped to keep `MAX_WAITING`
+    pub fn push_evicting(&mut self, image: QaImage) -> (Option<Pair>, Option<QaImage>) {
+        // same file sent again
+        self.waiting.retain(|w| w.path != image.path);
+        let partner = self.waiting.iter()
+            .enumerate()
+            .filter(|(_, w)| w.detector == image.detector)
+            .filter_map(|(i, w)| Some((i, (w.acquired? - image.acquired?).abs())))
+            .filter(|(_, dt)| *dt <= PAIR_WINDOW_S)
+            .min_by_key(|(_, dt)| *dt)
+            .map(|(i, _)| i);
+
+        match partner {
+            Some(i) => {
+                let other = self.waiting.remove(i);
+                (Some(self.order(other, image)), None)
+            }
+            None => {
+                self.waiting.push(image);
+                let evicted = (self.waiting.len() > MAX_WAITING).then(|| self.waiting.remove(0));
+                (None, evicted)
+            }
+        }
+    }
+
+    /// images still waiting for their pair
+    pub fn waiting(&self) -> &[QaImage] {
+        &self.waiting
+    }
+
+    fn order(&self, a: QaImage, b: QaImage) -> Pair {
+        let (first, second) = if a.acquired <= b.acquired { (a, b) } else { (b, a) };
+        if self.large_first {
+            Pair { large: first, small: second }
+        } else {
+            Pair { large: second, small: first }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(path: &str, detector: &str, acquired: Option<i64>) -> QaImage {
+        QaImage { path: path.to_string(), detector: detector.to_string(), acquired }
+    }
+
+    #[test]
+    fn dicom_times() {
+        let day = dicom_datetime("20240301", "").unwrap();
+        assert_eq!(day, 1_709_251_200);
+        assert_eq!(dicom_datetime("20240301", "14"), Some(day + 14 * 3600));
+        assert_eq!(dicom_datetime("20240301", "1430"), Some(day + 14 * 3600 + 30 * 60));
+        assert_eq!(dicom_datetime("20240301", "143015.250000 "), Some(day + 14 * 3600 + 30 * 60 + 15));
+        // leap day
+        assert_eq!(dicom_datetime("20240229", "000000"), Some(day - 86400));
+    }
+
+    #[test]
+    fn missing_date() {
+        assert_eq!(dicom_datetime("", "120000"), None);
+        assert_eq!(dicom_datetime(" - ", " - "), None);
+        assert_eq!(dicom_datetime("2024", "120000"), None);
+    }
+
+    #[test]
+    fn pair_across_midnight() {
+        let mut pairer = Pairer::default();
+        let before = dicom_datetime("20240301", "235930");
+        let after = dicom_datetime("20240302", "000130");
+        assert!(pairer.push(image("b.dcm", "DR1", after)).is_none());
+        let pair = pairer.push(image("a.dcm", "DR1", before)).unwrap();
+        assert_eq!((pair.large.path.as_str(), pair.small.path.as_str()), ("a.dcm", "b.dcm"));
+        assert!(pairer.waiting().is_empty());
+    }
+
+    #[test]
+    fn pair_window() {
+        let mut pairer = Pairer::default();
+        assert!(pairer.push(image("a.dcm", "DR1", Some(0))).is_none());
+        assert!(pairer.push(image("b.dcm", "DR1", Some(PAIR_WINDOW_S + 1))).is_none());
+        assert_eq!(pairer.waiting().len(), 2);
+        // within the window of b only
+        let pair = pairer.push(image("c.dcm", "DR1", Some(2 * PAIR_WINDOW_S))).unwrap();
+        assert_eq!((pair.large.path.as_str(), pair.small.path.as_str()), ("b.dcm", "c.dcm"));
+        // no acquisition time: never paired
+        assert!(pairer.push(image("d.dcm", "DR1", None)).is_none());
+        assert_eq!(pairer.waiting().len(), 2);
+    }
+
+    #[test]
+    fn detectors_do_not_pair() {
+        let mut pairer = Pairer::default();
+        assert!(pairer.push(image("a.dcm", "DR1", Some(0))).is_none());
+        assert!(pairer.push(image("b.dcm", "DR2", Some(10))).is_none());
+        let pair = pairer.push(image("c.dcm", "DR2", Some(20))).unwrap();
+        assert_eq!(pair.large.path, "b.dcm");
+        assert_eq!(pairer.waiting()[0].path, "a.dcm");
+    }
+
+    #[test]
+    fn small_field_first() {
+        let mut pairer = Pairer::new(false);
+        pairer.push(image("a.dcm", "DR1", Some(0)));
+        let pair = pairer.push(image("b.dcm", "DR1", Some(30))).unwrap();
+        assert_eq!((pair.large.path.as_str(), pair.small.path.as_str()), ("b.dcm", "a.dcm"));
+    }
+
+    #[test]
+    fn same_file_twice() {
+        let mut pairer = Pairer::default();
+        assert!(pairer.push(image("a.dcm", "DR1", Some(0))).is_none());
+        assert!(pairer.push(image("a.dcm", "DR1", Some(0))).is_none());
+        assert_eq!(pairer.waiting().len(), 1);
+    }
+
+    #[test]
+    fn oldest_is_evicted() {
+        let mut pairer = Pairer::default();
+        for i in 0..MAX_WAITING {
+            assert_eq!(pairer.push_evicting(image(&format!("{}.dcm", i), &format!("DR{}", i), Some(0))).1.map(|e| e.path), None);
+        }
+        let (pair, evicted) = pairer.push_evicting(image("last.dcm", "DR-last", Some(0)));
+        assert!(pair.is_none());
+        assert_eq!(evicted.unwrap().path, "0.dcm");
+        assert_eq!(pairer.waiting().len(), MAX_WAITING);
+    }
+}
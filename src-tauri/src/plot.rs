@@ -0,0 +1,83 @@
+use image::{Rgb, RgbImage};
+
+const WIDTH: u32 = 640;
+const HEIGHT: u32 = 400;
+const MARGIN: i32 = 30;
+// line colors of the curves, in order
+const COLORS: [[u8; 3]; 4] = [[0, 0, 255], [255, 0, 0], [0, 160, 0], [200, 120, 0]];
+
+/// curve plot (no text): grid lines every `x_step` / `y_step`, axes from 0
+///
+/// curves: (x, y) points, drawn in blue, red, green, orange
+pub fn save_plot(path: &str, curves: &[Vec<(f64, f64)>], x_max: f64, y_max: f64, x_step: f64, y_step: f64) -> Result<(), String> {
+    let mut img = RgbImage::from_pixel(WIDTH, HEIGHT, Rgb([255, 255, 255]));
+    let to_px = |x: f64, y: f64| {
+        let px = MARGIN as f64 + x / x_max * (WIDTH as i32 - 2 * MARGIN) as f64;
+        let py = (HEIGHT as i32 - MARGIN) as f64 - y / y_max * (HEIGHT as i32 - 2 * MARGIN) as f64;
+        (px.round() as i32, py.round() as i32)
+    };
+
+    let grid = Rgb([220, 220, 220]);
+    let mut x = 0.0;
+    while x_step > 0.0 && x <= x_max + 1e-9 {
+        let (a, b) = (to_px(x, 0.0), to_px(x, y_max));
+        draw_line(&mut img, a, b, grid);
+        x += x_step;
+    }
+    let mut y = 0.0;
+    while y_step > 0.0 && y <= y_max + 1e-9 {
+        let (a, b) = (to_px(0.0, y), to_px(x_max, y));
+        draw_line(&mut img, a, b, grid);
+        y += y_step;
+    }
+    let axis = Rgb([0, 0, 0]);
+    draw_line(&mut img, to_px(0.0, 0.0), to_px(x_max, 0.0), axis);
+    draw_line(&mut img, to_px(0.0, 0.0), to_px(0.0, y_max), axis);
+
+    for (curve, color) in curves.iter().zip(COLORS.iter().cycle()) {
+        for pair in curve.windows(2) {
+            let a = to_px(pair[0].0.min(x_max), pair[0].1.clamp(0.0, y_max));
+            let b = to_px(pair[1].0.min(x_max), pair[1].1.clamp(0.0, y_max));
+            draw_line(&mut img, a, b, Rgb(*color));
+        }
+    }
+    img.save(path).map_err(|err| err.to_string())
+}
+
+/// grid step of 1, 2 or 5 x 10^n giving about 10 lines up to `max`
+pub fn grid_step(max: f64) -> f64 {
+    if max <= 0.0 {
+        return 1.0;
+    }
+    let raw = max / 10.0;
+    let decade = 10f64.powf(raw.log10().floor());
+    let step = [1.0, 2.0, 5.0, 10.0].into_iter().find(|s| s * decade >= raw).unwrap_or(10.0);
+    step * decade
+}
+
+// Bresenham
+fn draw_line(img: &mut RgbImage, a: (i32, i32), b: (i32, i32), color: Rgb<u8>) {
+    let (mut x, mut y) = a;
+    let dx = (b.0 - x).abs();
+    let dy = -(b.1 - y).abs();
+    let sx = if x < b.0 { 1 } else { -1 };
+    let sy = if y < b.1 { 1 } else { -1 };
+    let mut err = dx + dy;
+    loop {
+        if x >= 0 && y >= 0 && (x as u32) < img.width() && (y as u32) < img.height() {
+            img.put_pixel(x as u32, y as u32, color);
+        }
+        if (x, y) == b {
+            break;
+        }
+        let e2 = 2 * err;
+        if e2 >= dy {
+            err += dy;
+            x += sx;
+        }
+        if e2 <= dx {
+            err += dx;
+            y += sy;
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use crate::analysis::{CollimatorResult, Evaluation};
+
+/// QATrack+ test macro names of each value, an empty name leaves the value out
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QatrackMacros {
+    /// edge errors (cm) of X1, X2, Y1, Y2
+    pub edge_error: [String; 4],
+    /// field size X and Y (cm)
+    pub field_size: [String; 2],
+    pub collimator_passed: String,
+    pub beam_distance: String,
+    pub beam_angle: String,
+    pub beam_passed: String,
+}
+
+impl Default for QatrackMacros {
+    fn default() -> Self {
+        QatrackMacros {
+            edge_error: ["x1_error", "x2_error", "y1_error", "y2_error"].map(String::from),
+            field_size: ["field_size_x", "field_size_y"].map(String::from),
+            collimator_passed: "collimator_pass".to_string(),
+            beam_distance: "beam_distance".to_string(),
+            beam_angle: "beam_angle".to_string(),
+            beam_passed: "beam_pass".to_string(),
+        }
+    }
+}
+
+/// site settings of the QATrack+ export
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QatrackConfig {
+    /// unit test collection URL, e.g. https://qatrack/api/qa/unittestcollections/12/
+    pub unit_test_collection: String,
+    pub macros: QatrackMacros,
+}
+
+/// test list instance upload (POST /api/qa/testlistinstances/)
+///
+/// work_started: "YYYY-MM-DD HH:MM"
+pub fn test_list_upload(result: &CollimatorResult, evaluation: &Evaluation, config: &QatrackConfig, work_started: &str) -> Value {
+    let macros = &config.macros;
+    let mut tests = Map::new();
+    let mut add = |name: &str, value: Value| {
+        if !name.trim().is_empty() {
+            tests.insert(name.trim().to_string(), json!({ "value": value }));
+        }
+    };
+    for (name, edge) in macros.edge_error.iter().zip(&evaluation.edges) {
+        add(name, json!(round(edge.error_cm)));
+    }
+    for (name, size) in macros.field_size.iter().zip(evaluation.field_size_cm) {
+        add(name, json!(round(size)));
+    }
+    add(&macros.collimator_passed, json!(evaluation.collimator_passed));
+    add(&macros.beam_distance, json!(round(evaluation.beam_distance_cm)));
+    add(&macros.beam_angle, json!(round(evaluation.beam_angle)));
+    add(&macros.beam_passed, json!(evaluation.beam_passed));
+
+    // acquisition date and detector id
+    let detail = |i: usize| result.details.get(i).map(|s| s.trim()).unwrap_or("-").to_string();
+    let comment = format!(
+        "LightBeamKKU: acquired {}, detector {}, SID {} cm, {}% criteria",
+        detail(3), detail(5), evaluation.sid_cm, evaluation.criteria
+    );
+    json!({
+        "unit_test_collection": config.unit_test_collection,
+        "work_started": work_started,
+        "work_completed": work_started,
+        "comment": comment,
+        "tests": tests,
+    })
+}
+
+// 3 decimals as in the result table
+fn round(value: f32) -> f64 {
+    (value as f64 * 1000.0).round() / 1000.0
+}
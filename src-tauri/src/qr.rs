@@ -0,0 +1,261 @@
+use serde::{Deserialize, Serialize};
+use dicom::core::VR;
+use dicom::dictionary_std::tags;
+use dicom::object::{InMemDicomObject, Tag};
+use dicom::ul::association::{ClientAssociation, ClientAssociationOptions};
+use crate::dimse::{self, get_str, Association};
+
+// presentation context ids, in the proposed order
+const FIND_PC: u8 = 1;
+const MOVE_PC: u8 = 3;
+
+/// PACS connection
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QrConfig {
+    /// our (calling) AE title
+    pub ae_title: String,
+    pub pacs_ae_title: String,
+    pub host: String,
+    pub port: u16,
+}
+
+/// search keys, empty fields match everything
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct QrQuery {
+    /// station name, wildcards (*, ?) allowed
+    pub station_name: String,
+    pub detector_id: String,
+    /// YYYYMMDD
+    pub date_from: String,
+    pub date_to: String,
+    /// DX or CR, empty for both
+    pub modality: String,
+}
+
+/// instance found on the PACS
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QrInstance {
+    pub study_instance_uid: String,
+    pub series_instance_uid: String,
+    pub sop_instance_uid: String,
+    pub modality: String,
+    pub station_name: String,
+    pub detector_id: String,
+    pub acquisition_date: String,
+    pub acquisition_time: String,
+}
+
+/// find QA images, study -> series -> image level (study root, hierarchical)
+pub fn find(config: &QrConfig, query: &QrQuery) -> Result<Vec<QrInstance>, String> {
+    let mut assoc = connect(config)?;
+    let res = find_instances(&mut assoc, query);
+    let _ = assoc.release();
+    res
+}
+
+fn find_instances(assoc: &mut ClientAssociation, query: &QrQuery) -> Result<Vec<QrInstance>, String> {
+    let modalities = match query.modality.as_str() {
+        "" => vec!["DX", "CR"],
+        modality => vec![modality],
+    };
+    let date_range = match (query.date_from.trim(), query.date_to.trim()) {
+        ("", "") => String::new(),
+        (from, to) => format!("{}-{}", from, to),
+    };
+
+    let studies = c_find(assoc, "STUDY", vec![
+        (tags::STUDY_DATE, VR::DA, date_range.as_str()),
+        (tags::MODALITIES_IN_STUDY, VR::CS, modalities.join("\\").as_str()),
+        (tags::STUDY_INSTANCE_UID, VR::UI, ""),
+    ])?;
+
+    let mut instances = Vec::new();
+    for study in studies {
+        let study_uid = get_str(&study, tags::STUDY_INSTANCE_UID);
+        let series = c_find(assoc, "SERIES", vec![
+            (tags::STUDY_INSTANCE_UID, VR::UI, study_uid.as_str()),
+            (tags::SERIES_INSTANCE_UID, VR::UI, ""),
+            (tags::MODALITY, VR::CS, ""),
+            (tags::STATION_NAME, VR::SH, query.station_name.as_str()),
+        ])?;
+        for series in series {
+            // modality is matched here, MODALITIES_IN_STUDY is optional for a PACS
+            let modality = get_str(&series, tags::MODALITY);
+            if !modalities.contains(&modality.as_str()) {
+                continue;
+            }
+            let series_uid = get_str(&series, tags::SERIES_INSTANCE_UID);
+            let images = c_find(assoc, "IMAGE", vec![
+                (tags::STUDY_INSTANCE_UID, VR::UI, study_uid.as_str()),
+                (tags::SERIES_INSTANCE_UID, VR::UI, series_uid.as_str()),
+                (tags::SOP_INSTANCE_UID, VR::UI, ""),
+                (tags::DETECTOR_ID, VR::SH, query.detector_id.as_str()),
+                (tags::ACQUISITION_DATE, VR::DA, ""),
+                (tags::ACQUISITION_TIME, VR::TM, ""),
+                (tags::STATION_NAME, VR::SH, ""),
+            ])?;
+            for image in images {
+                // detector id is not a standard key, not every PACS matches it
+                let detector_id = get_str(&image, tags::DETECTOR_ID);
+                if !query.detector_id.is_empty() && detector_id != query.detector_id {
+                    continue;
+                }
+                // neither is station name at the series level, matched again here
+                let station_name = match get_str(&series, tags::STATION_NAME) {
+                    name if name.is_empty() => get_str(&image, tags::STATION_NAME),
+                    name => name,
+                };
+                if !query.station_name.is_empty() && !wildcard_match(query.station_name.trim(), &station_name) {
+                    continue;
+                }
+                instances.push(QrInstance {
+                    study_instance_uid: study_uid.clone(),
+                    series_instance_uid: series_uid.clone(),
+                    sop_instance_uid: get_str(&image, tags::SOP_INSTANCE_UID),
+                    modality: modality.clone(),
+                    station_name,
+                    detector_id,
+                    acquisition_date: get_str(&image, tags::ACQUISITION_DATE),
+                    acquisition_time: get_str(&image, tags::ACQUISITION_TIME),
+                });
+            }
+        }
+    }
+    Ok(instances)
+}
+
+/// DICOM wildcard matching (PS3.4 C.2.2.2.4): `*` any run of characters, `?` one character
+pub fn wildcard_match(pattern: &str, value: &str) -> bool {
+    let (p, v): (Vec<char>, Vec<char>) = (pattern.chars().collect(), value.chars().collect());
+    let (mut i, mut j) = (0, 0);
+    // last `*` of the pattern and the value position it currently stands for
+    let mut star = None;
+    while j < v.len() {
+        if i < p.len() && (p[i] == '?' || p[i] == v[j]) {
+            i += 1;
+            j += 1;
+        } else if i < p.len() && p[i] == '*' {
+            star = Some((i, j));
+            i += 1;
+        } else if let Some((si, sj)) = star {
+            // let the `*` take one more character
+            star = Some((si, sj + 1));
+            i = si + 1;
+            j = sj + 1;
+        } else {
+            return false;
+        }
+    }
+    p[i..].iter().all(|&c| c == '*')
+}
+
+/// move instances to `destination` (our storage SCP AE title)
+///
+/// Returns: (completed, failed) sub-operations
+pub fn retrieve(config: &QrConfig, destination: &str, instances: &[QrInstance]) -> Result<(u16, u16), String> {
+    let mut assoc = connect(config)?;
+    let mut completed = 0;
+    let mut failed = 0;
+    for (i, instance) in instances.iter().enumerate() {
+        let identifier = identifier("IMAGE", vec![
+            (tags::STUDY_INSTANCE_UID, VR::UI, instance.study_instance_uid.as_str()),
+            (tags::SERIES_INSTANCE_UID, VR::UI, instance.series_instance_uid.as_str()),
+            (tags::SOP_INSTANCE_UID, VR::UI, instance.sop_instance_uid.as_str()),
+        ]);
+        let command = dimse::command(vec![
+            dimse::element_u16(tags::COMMAND_FIELD, dimse::C_MOVE_RQ),
+            dimse::element_u16(tags::MESSAGE_ID, i as u16 + 1),
+            dimse::element_u16(tags::PRIORITY, 0),
+            dimse::element_u16(tags::COMMAND_DATA_SET_TYPE, dimse::DATA_SET),
+            dimse::element_str(tags::AFFECTED_SOP_CLASS_UID, VR::UI, dimse::STUDY_ROOT_MOVE),
+            dimse::element_str(tags::MOVE_DESTINATION, VR::AE, destination),
+        ]);
+        let data = encode(&assoc, MOVE_PC, &identifier)?;
+        dimse::send_message(&mut assoc, MOVE_PC, &command, Some(&data))?;
+
+        // pending responses until the sub-operations are done
+        loop {
+            let rsp = dimse::receive_message(&mut assoc)?.ok_or("association released by PACS")?;
+            if rsp.is_pending() {
+                continue;
+            }
+            let count = |tag: Tag| rsp.command.element(tag).ok().and_then(|e| e.to_int::<u16>().ok()).unwrap_or(0);
+            completed += count(tags::NUMBER_OF_COMPLETED_SUBOPERATIONS);
+            failed += count(tags::NUMBER_OF_FAILED_SUBOPERATIONS);
+            if rsp.status() != dimse::STATUS_SUCCESS {
+                println!("QR: C-MOVE {} status 0x{:04x}", instance.sop_instance_uid, rsp.status());
+            }
+            break;
+        }
+    }
+    let _ = assoc.release();
+    Ok((completed, failed))
+}
+
+fn connect(config: &QrConfig) -> Result<ClientAssociation, String> {
+    let ts = vec![dimse::EXPLICIT_VR_LE, dimse::IMPLICIT_VR_LE];
+    ClientAssociationOptions::new()
+        .calling_ae_title(config.ae_title.as_str())
+        .called_ae_title(config.pacs_ae_title.as_str())
+        .with_presentation_context(dimse::STUDY_ROOT_FIND, ts.clone())
+        .with_presentation_context(dimse::STUDY_ROOT_MOVE, ts)
+        .establish((config.host.as_str(), config.port))
+        .map_err(|err| format!("cannot connect to {}@{}:{}: {}", config.pacs_ae_title, config.host, config.port, err))
+}
+
+fn identifier(level: &str, keys: Vec<(Tag, VR, &str)>) -> InMemDicomObject {
+    let mut elements = vec![dimse::element_str(tags::QUERY_RETRIEVE_LEVEL, VR::CS, level)];
+    for (tag, vr, value) in keys {
+        elements.push(dimse::element_str(tag, vr, value));
+    }
+    InMemDicomObject::from_element_iter(elements)
+}
+
+fn encode(assoc: &ClientAssociation, pc_id: u8, obj: &InMemDicomObject) -> Result<Vec<u8>, String> {
+    let ts = assoc.transfer_syntax(pc_id).ok_or("PACS rejected the query/retrieve service")?;
+    dimse::write_dataset(obj, &ts).ok_or("cannot encode query".to_string())
+}
+
+/// one C-FIND, Returns: the matches
+fn c_find(assoc: &mut ClientAssociation, level: &str, keys: Vec<(Tag, VR, &str)>) -> Result<Vec<InMemDicomObject>, String> {
+    let command = dimse::command(vec![
+        dimse::element_u16(tags::COMMAND_FIELD, dimse::C_FIND_RQ),
+        dimse::element_u16(tags::MESSAGE_ID, 1),
+        dimse::element_u16(tags::PRIORITY, 0),
+        dimse::element_u16(tags::COMMAND_DATA_SET_TYPE, dimse::DATA_SET),
+        dimse::element_str(tags::AFFECTED_SOP_CLASS_UID, VR::UI, dimse::STUDY_ROOT_FIND),
+    ]);
+    let data = encode(assoc, FIND_PC, &identifier(level, keys))?;
+    dimse::send_message(assoc, FIND_PC, &command, Some(&data))?;
+
+    let ts = assoc.transfer_syntax(FIND_PC).unwrap_or_default();
+    let mut matches = Vec::new();
+    loop {
+        let rsp = dimse::receive_message(assoc)?.ok_or("association released by PACS")?;
+        if !rsp.is_pending() {
+            if rsp.status() != dimse::STATUS_SUCCESS {
+                return Err(format!("C-FIND failed, status 0x{:04x}", rsp.status()));
+            }
+            return Ok(matches);
+        }
+        if let Some(obj) = rsp.data.as_ref().and_then(|data| dimse::read_dataset(data, &ts)) {
+            matches.push(obj);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("ROOM1", "ROOM1"));
+        assert!(!wildcard_match("ROOM1", "ROOM10"));
+        assert!(wildcard_match("ROOM1*", "ROOM10"));
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("R?OM*1", "ROOM 1 DR1"));
+        assert!(!wildcard_match("R?OM*1", "ROOM 1 DR2"));
+        assert!(!wildcard_match("room1", "ROOM1"));
+    }
+}
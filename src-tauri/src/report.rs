@@ -0,0 +1,385 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+use image::codecs::jpeg::JpegEncoder;
+use image::ColorType;
+use crate::analysis::{CollimatorResult, Evaluation};
+use crate::deident::{deidentify_details, DeidentOptions};
+use crate::flatness::FieldProfile;
+use crate::focal_spot::{FocalSpotMethod, FocalSpotResult};
+use crate::tolerance::Check;
+
+// A4 in points
+const PAGE_W: f32 = 595.0;
+const PAGE_H: f32 = 842.0;
+const MARGIN: f32 = 50.0;
+const TEXT_SIZE: f32 = 10.0;
+const HEADING_SIZE: f32 = 14.0;
+const TITLE_SIZE: f32 = 18.0;
+const ROW_H: f32 = 16.0;
+// average Helvetica glyph width in em, for wrapping and column fitting
+const CHAR_W: f32 = 0.5;
+
+/// grayscale JPEG for a report
+pub struct ReportImage {
+    jpeg: Vec<u8>,
+    width: u32,
+    height: u32,
+}
+
+impl ReportImage {
+    /// load an image file (as saved by the analysis) for the report
+    pub fn open(path: &Path) -> Option<ReportImage> {
+        let gray = image::open(path).ok()?.to_luma8();
+        let (width, height) = gray.dimensions();
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut jpeg, 85)
+            .encode(gray.as_raw(), width, height, ColorType::L8)
+            .ok()?;
+        Some(ReportImage { jpeg, width, height })
+    }
+}
+
+enum Block {
+    Heading(String),
+    Text(String),
+    Table { headers: Vec<String>, rows: Vec<Vec<String>> },
+    Image { image: ReportImage, max_height: f32 },
+}
+
+/// simple PDF report: headings, text, tables and images, flowed onto A4 pages
+pub struct Report {
+    title: String,
+    blocks: Vec<Block>,
+}
+
+impl Report {
+    pub fn new(title: &str) -> Report {
+        Report { title: title.to_string(), blocks: Vec::new() }
+    }
+
+    pub fn heading(&mut self, text: &str) {
+        self.blocks.push(Block::Heading(text.to_string()));
+    }
+
+    /// paragraph, wrapped to the page width
+    pub fn text(&mut self, text: &str) {
+        self.blocks.push(Block::Text(text.to_string()));
+    }
+
+    pub fn table(&mut self, headers: &[&str], rows: Vec<Vec<String>>) {
+        let headers = headers.iter().map(|h| h.to_string()).collect();
+        self.blocks.push(Block::Table { headers, rows });
+    }
+
+    /// image scaled to the page width and at most `max_height` points
+    pub fn image(&mut self, image: ReportImage, max_height: f32) {
+        self.blocks.push(Block::Image { image, max_height });
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_pdf())
+    }
+
+    pub fn to_pdf(&self) -> Vec<u8> {
+        let mut layout = Layout::new();
+        layout.text_line(&self.title, TITLE_SIZE, true);
+        layout.y -= 6.0;
+        let mut images = Vec::new();
+        for block in &self.blocks {
+            match block {
+                Block::Heading(text) => {
+                    layout.space(HEADING_SIZE + 2.0 * ROW_H);
+                    layout.y -= 8.0;
+                    layout.text_line(text, HEADING_SIZE, true);
+                }
+                Block::Text(text) => {
+                    let max_chars = ((PAGE_W - 2.0 * MARGIN) / (TEXT_SIZE * CHAR_W)) as usize;
+                    for line in wrap(text, max_chars) {
+                        layout.text_line(&line, TEXT_SIZE, false);
+                    }
+                }
+                Block::Table { headers, rows } => layout.table(headers, rows),
+                Block::Image { image, max_height } => {
+                    let name = format!("Im{}", images.len());
+                    layout.image(&name, image, *max_height);
+                    images.push(image);
+                }
+            }
+        }
+        layout.finish();
+        write_pdf(&layout.pages, &images)
+    }
+}
+
+// content streams of the pages, top-down cursor
+struct Layout {
+    pages: Vec<String>,
+    content: String,
+    y: f32,
+}
+
+impl Layout {
+    fn new() -> Layout {
+        Layout { pages: Vec::new(), content: String::new(), y: PAGE_H - MARGIN }
+    }
+
+    /// start a new page unless `height` fits
+    fn space(&mut self, height: f32) {
+        if self.y - height < MARGIN {
+            self.pages.push(std::mem::take(&mut self.content));
+            self.y = PAGE_H - MARGIN;
+        }
+    }
+
+    fn text_at(&mut self, x: f32, text: &str, size: f32, bold: bool) {
+        let font = if bold { "F2" } else { "F1" };
+        let _ = writeln!(self.content, "BT /{} {} Tf {:.1} {:.1} Td ({}) Tj ET", font, size, x, self.y, escape(text));
+    }
+
+    fn text_line(&mut self, text: &str, size: f32, bold: bool) {
+        self.space(size * 1.4);
+        self.y -= size * 1.4;
+        self.text_at(MARGIN, text, size, bold);
+    }
+
+    fn table(&mut self, headers: &[String], rows: &[Vec<String>]) {
+        let n = headers.len().max(1);
+        let col_w = (PAGE_W - 2.0 * MARGIN) / n as f32;
+        let max_chars = (col_w / (TEXT_SIZE * CHAR_W)) as usize - 1;
+        let row_line = |layout: &mut Layout, cells: &[String], bold: bool| {
+            layout.space(ROW_H);
+            layout.y -= ROW_H;
+            for (i, cell) in cells.iter().enumerate() {
+                let cell: String = cell.chars().take(max_chars).collect();
+                layout.text_at(MARGIN + i as f32 * col_w + 2.0, &cell, TEXT_SIZE, bold);
+            }
+            let _ = writeln!(layout.content, "0.7 G {:.1} {:.1} m {:.1} {:.1} l S 0 G", MARGIN, layout.y - 4.0, PAGE_W - MARGIN, layout.y - 4.0);
+        };
+        row_line(self, headers, true);
+        for row in rows {
+            row_line(self, row, false);
+        }
+    }
+
+    fn image(&mut self, name: &str, image: &ReportImage, max_height: f32) {
+        let scale = ((PAGE_W - 2.0 * MARGIN) / image.width as f32).min(max_height / image.height as f32);
+        let w = image.width as f32 * scale;
+        let h = image.height as f32 * scale;
+        self.space(h + 8.0);
+        self.y -= h + 8.0;
+        let _ = writeln!(self.content, "q {:.1} 0 0 {:.1} {:.1} {:.1} cm /{} Do Q", w, h, MARGIN, self.y, name);
+    }
+
+    fn finish(&mut self) {
+        if !self.content.is_empty() || self.pages.is_empty() {
+            self.pages.push(std::mem::take(&mut self.content));
+        }
+    }
+}
+
+/// greedy word wrap to `max_chars`
+fn wrap(text: &str, max_chars: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        if !line.is_empty() && line.chars().count() + 1 + word.chars().count() > max_chars {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+/// PDF string literal in WinAnsi, characters outside Latin-1 become '?'
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '(' | ')' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            ' '..='~' => out.push(c),
+            '\u{a0}'..='\u{ff}' => {
+                let _ = write!(out, "\\{:03o}", c as u32);
+            }
+            _ => out.push('?'),
+        }
+    }
+    out
+}
+
+fn write_pdf(pages: &[String], images: &[&ReportImage]) -> Vec<u8> {
+    // 1 catalog, 2 pages, 3-4 fonts, then images, then page + content per page
+    let first_image = 5;
+    let first_page = first_image + images.len();
+    let page_ids: Vec<usize> = (0..pages.len()).map(|i| first_page + 2 * i).collect();
+
+    let mut pdf: Vec<u8> = b"%PDF-1.4\n".to_vec();
+    let mut offsets = Vec::new();
+    let mut object = |pdf: &mut Vec<u8>, body: &[u8]| {
+        offsets.push(pdf.len());
+        pdf.extend_from_slice(format!("{} 0 obj\n", offsets.len()).as_bytes());
+        pdf.extend_from_slice(body);
+        pdf.extend_from_slice(b"\nendobj\n");
+    };
+
+    object(&mut pdf, b"<< /Type /Catalog /Pages 2 0 R >>");
+    let kids: Vec<String> = page_ids.iter().map(|id| format!("{} 0 R", id)).collect();
+    object(&mut pdf, format!("<< /Type /Pages /Kids [{}] /Count {} >>", kids.join(" "), pages.len()).as_bytes());
+    object(&mut pdf, b"<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding /WinAnsiEncoding >>");
+    object(&mut pdf, b"<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica-Bold /Encoding /WinAnsiEncoding >>");
+    for image in images {
+        let mut body = format!(
+            "<< /Type /XObject /Subtype /Image /Width {} /Height {} /ColorSpace /DeviceGray /BitsPerComponent 8 /Filter /DCTDecode /Length {} >>\nstream\n",
+            image.width, image.height, image.jpeg.len()
+        ).into_bytes();
+        body.extend_from_slice(&image.jpeg);
+        body.extend_from_slice(b"\nendstream");
+        object(&mut pdf, &body);
+    }
+    let xobjects: String = (0..images.len()).map(|i| format!("/Im{} {} 0 R ", i, first_image + i)).collect();
+    for (i, content) in pages.iter().enumerate() {
+        let page = format!(
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] /Resources << /Font << /F1 3 0 R /F2 4 0 R >> /XObject << {}>> >> /Contents {} 0 R >>",
+            PAGE_W, PAGE_H, xobjects, page_ids[i] + 1
+        );
+        object(&mut pdf, page.as_bytes());
+        let stream = format!("<< /Length {} >>\nstream\n{}endstream", content.len(), content);
+        object(&mut pdf, stream.as_bytes());
+    }
+
+    let xref = pdf.len();
+    let mut trailer = format!("xref\n0 {}\n0000000000 65535 f \n", offsets.len() + 1);
+    for offset in &offsets {
+        let _ = writeln!(trailer, "{:010} 00000 n ", offset);
+    }
+    let _ = write!(trailer, "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n", offsets.len() + 1, xref);
+    pdf.extend_from_slice(trailer.as_bytes());
+    pdf
+}
+
+/// collimator test report: details, edge table, beam alignment and images
+pub fn collimator_report(result: &CollimatorResult, evaluation: &Evaluation, overlay: &Path, circle: &Path, flatness: &Path) -> Report {
+    let mut report = Report::new("LightBeamKKU - Collimator and Beam Alignment Test");
+    let labels = ["Hospital", "Manufacturer", "Institution Address", "Acquisition Date", "Detector Type", "Detector ID", "Pixel Size", "Matrix Size", "Bit Depth"];
+    report.heading("Information");
+    for (label, value) in labels.iter().zip(&result.details) {
+        report.text(&format!("{}: {}", label, value));
+    }
+
+    let status = |passed: bool| if passed { "passed" } else { "failed" }.to_string();
+    report.heading(&format!(
+        "Collimator Alignment ({}) - SID {} cm, {}% criteria",
+        status(evaluation.collimator_passed), evaluation.sid_cm, evaluation.criteria
+    ));
+    let rows = evaluation.edges.iter()
+        .map(|e| vec![
+            e.name.clone(),
+            format!("{:.3}", e.length_cm),
+            format!("{:.3}", e.error_cm),
+            format!("{:.3}", e.error_percent),
+            e.most_error.clone(),
+            status(e.passed),
+        ])
+        .collect();
+    report.table(&["Position", "Length (cm)", "Error (cm)", "Error (%)", "Most Error", "Status"], rows);
+    report.text(&format!("Field size: {:.2} x {:.2} cm", evaluation.field_size_cm[0], evaluation.field_size_cm[1]));
+
+    report.heading(&format!("Beam Alignment ({})", status(evaluation.beam_passed)));
+    report.table(&["Length (cm)", "Angle (degree)", "Status"], vec![vec![
+        format!("{:.3}", evaluation.beam_distance_cm),
+        format!("{:.3}", evaluation.beam_angle),
+        status(evaluation.beam_passed),
+    ]]);
+
+    if let Some(image) = ReportImage::open(overlay) {
+        report.heading("Light Field / X-ray Field");
+        report.image(image, 360.0);
+    }
+    if let Some(image) = ReportImage::open(circle) {
+        report.heading("Beam Alignment Circle");
+        report.image(image, 160.0);
+    }
+
+    if let Some(field) = &result.flatness {
+        report.heading("Field Flatness");
+        let row = |name: &str, p: &FieldProfile| vec![
+            name.to_string(),
+            format!("{:.2}", p.gradient_percent),
+            format!("{:.2}", p.flatness_percent),
+            format!("{:.2}", p.symmetry_percent),
+        ];
+        report.table(&["Axis", "Gradient (%)", "Flatness (%)", "Symmetry (%)"], vec![
+            row(&format!("Anode-cathode ({})", field.heel_axis), field.heel()),
+            row("Transverse", field.transverse()),
+        ]);
+        if let Some(image) = ReportImage::open(flatness) {
+            report.text("Anode-cathode and transverse profiles, 80 - 120% of the centre value");
+            report.image(image, 300.0);
+        }
+    }
+    report
+}
+
+/// tolerance checks as a table: value, result, min, max, status
+fn checks_table(report: &mut Report, checks: &[Check]) {
+    let limit = |v: Option<f64>| v.map(|v| format!("{}", v)).unwrap_or_else(|| "-".to_string());
+    let rows = checks.iter()
+        .map(|c| vec![
+            c.key.clone(),
+            format!("{:.3}", c.value),
+            limit(c.tolerance.min),
+            limit(c.tolerance.max),
+            match c.passed {
+                Some(true) => "passed",
+                Some(false) => "failed",
+                None => "-",
+            }.to_string(),
+        ])
+        .collect();
+    report.table(&["Check", "Value", "Min", "Max", "Status"], rows);
+}
+
+pub fn focal_spot_report(result: &FocalSpotResult, plot: &Path) -> Report {
+    let mut report = Report::new("LightBeamKKU - Focal Spot Size");
+    let labels = ["Hospital", "Manufacturer", "Institution Address", "Acquisition Date", "Detector Type", "Detector ID", "Pixel Size", "Matrix Size", "Bit Depth"];
+    report.heading("Information");
+    let mut details = result.details.clone();
+    deidentify_details(&mut details, &DeidentOptions::default());
+    for (label, value) in labels.iter().zip(&details) {
+        report.text(&format!("{}: {}", label, value));
+    }
+
+    let (method, measured) = match result.method {
+        FocalSpotMethod::Star { angle } => (format!("star pattern, {} degree spokes", angle), "Blur diameter (mm)"),
+        FocalSpotMethod::Pinhole => ("pinhole".to_string(), "Spot width (mm)"),
+    };
+    report.heading(&format!("Focal Spot ({})", if result.passed { "passed" } else { "failed" }));
+    report.text(&format!("Method: {}", method));
+    report.text(&format!("Magnification: {:.3} ({})", result.magnification, result.magnification_source));
+    if let Some(nominal) = result.nominal_mm {
+        report.text(&format!("Nominal focal spot: {} mm", nominal));
+    }
+    report.table(&["Axis", measured, "Focal spot (mm)"], ["x", "y"].iter().enumerate()
+        .map(|(i, axis)| vec![axis.to_string(), format!("{:.2}", result.measured_mm[i]), format!("{:.2}", result.size_mm[i])])
+        .collect());
+    checks_table(&mut report, &result.checks);
+
+    if let Some(image) = ReportImage::open(plot) {
+        report.heading(match result.method {
+            FocalSpotMethod::Star { .. } => "Modulation against Radius",
+            FocalSpotMethod::Pinhole => "Spot Profiles",
+        });
+        report.image(image, 300.0);
+    }
+    report
+}
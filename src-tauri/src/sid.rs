@@ -0,0 +1,137 @@
+use dicom::dictionary_std::tags;
+use serde::{Deserialize, Serialize};
+use crate::analysis::{AnalysisError, CollimatorResult};
+use crate::tolerance::{all_passed, Check, ToleranceProfile};
+use crate::utils::{get_value, DcmObj};
+
+/// nominal distance of the tool marks ypoints[2] - ypoints[1] (cm)
+pub const MARK_DISTANCE_CM: f64 = 7.0;
+
+/// source-to-image distance from the magnification of the tool marks
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SidCheck {
+    /// tool marks above the detector (cm)
+    pub tool_height_cm: f64,
+    /// pixel spacing at the detector (mm)
+    pub pixel_spacing_mm: f64,
+    /// measured mark distance at the detector (cm)
+    pub mark_distance_cm: f64,
+    pub magnification: f64,
+    pub measured_sid_cm: f64,
+    /// DistanceSourceToDetector, None if not in the header
+    pub header_sid_cm: Option<f64>,
+    /// SID typed into the result screen
+    pub entered_sid_cm: f64,
+    /// (header or entered - measured) / measured (%)
+    pub header_deviation_percent: Option<f64>,
+    pub entered_deviation_percent: f64,
+    pub profile: String,
+    pub checks: Vec<Check>,
+    pub passed: bool,
+}
+
+/// SID = M h / (M - 1) with M = mark distance at the detector / 7 cm and
+/// h the height of the tool marks above the detector
+///
+/// the pixel spacing is taken at the detector (ImagerPixelSpacing first)
+pub fn check_sid(result: &CollimatorResult, obj: &DcmObj, tool_height_cm: f64, entered_sid_cm: f64, profile: &ToleranceProfile) -> Result<SidCheck, AnalysisError> {
+    if tool_height_cm <= 0.0 {
+        return Err(AnalysisError::Measurement("the tool height must be above 0 cm".to_string()));
+    }
+    let pixel_spacing_mm = detector_pixel_spacing(obj)
+        .ok_or_else(|| AnalysisError::Measurement("no pixel spacing in the header".to_string()))?;
+    let Some(pixels) = result.ypoints.get(1..3).map(|p| (p[1] - p[0]).abs()) else {
+        return Err(AnalysisError::Measurement("the tool marks were not found".to_string()));
+    };
+    let mark_distance_cm = pixels as f64 * pixel_spacing_mm / 10.0;
+    let magnification = mark_distance_cm / MARK_DISTANCE_CM;
+    if magnification <= 1.0 {
+        return Err(AnalysisError::Measurement(format!("no magnification of the tool marks ({:.4})", magnification)));
+    }
+    let measured_sid_cm = magnification * tool_height_cm / (magnification - 1.0);
+
+    let header_sid_cm = get_value(obj, tags::DISTANCE_SOURCE_TO_DETECTOR).map(|mm| mm / 10.0);
+    let deviation = |sid: f64| (sid - measured_sid_cm) / measured_sid_cm * 100.0;
+    let header_deviation_percent = header_sid_cm.map(deviation);
+    let entered_deviation_percent = deviation(entered_sid_cm);
+
+    let mut checks = vec![profile.check("sid.entered_deviation", entered_deviation_percent.abs())];
+    if let Some(d) = header_deviation_percent {
+        checks.push(profile.check("sid.header_deviation", d.abs()));
+    }
+    println!("SID: measured {:.1} cm (M {:.4}), entered {:.1} cm", measured_sid_cm, magnification, entered_sid_cm);
+    Ok(SidCheck {
+        tool_height_cm,
+        pixel_spacing_mm,
+        mark_distance_cm,
+        magnification,
+        measured_sid_cm,
+        header_sid_cm,
+        entered_sid_cm,
+        header_deviation_percent,
+        entered_deviation_percent,
+        profile: profile.name.clone(),
+        passed: all_passed(&checks),
+        checks,
+    })
+}
+
+/// ImagerPixelSpacing (at the detector), else PixelSpacing
+fn detector_pixel_spacing(obj: &DcmObj) -> Option<f64> {
+    [tags::IMAGER_PIXEL_SPACING, tags::PIXEL_SPACING].iter()
+        .filter_map(|&tag| obj.element(tag).ok())
+        .filter_map(|elem| elem.to_multi_float64().ok())
+        .filter_map(|values| values.first().copied())
+        .find(|&spacing| spacing > 0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dicom::core::VR;
+    use crate::utils::test_header;
+
+    /// 0.14 mm detector pixels, SID 100 cm in the header
+    fn header() -> DcmObj {
+        test_header(&[
+            (tags::IMAGER_PIXEL_SPACING, VR::DS, "0.14\\0.14"),
+            (tags::PIXEL_SPACING, VR::DS, "0.1\\0.1"),
+            (tags::DISTANCE_SOURCE_TO_DETECTOR, VR::DS, "1000"),
+        ])
+    }
+
+    fn marks(ypoints: Vec<i32>) -> CollimatorResult {
+        CollimatorResult {
+            circle_points: [0; 4],
+            beam_alignment: [0.0; 2],
+            points: [[0; 2]; 4],
+            lengths: vec![],
+            most_error: vec![],
+            xpoints: vec![],
+            ypoints,
+            details: vec![],
+            flatness: None,
+        }
+    }
+
+    #[test]
+    fn sid_from_a_known_magnification() {
+        // marks 20 cm above the detector at SID 100 cm: M = 100 / 80 = 1.25,
+        // 7 cm x 1.25 = 87.5 mm = 625 pixels of 0.14 mm
+        let check = check_sid(&marks(vec![0, 100, 725, 900]), &header(), 20.0, 105.0, &ToleranceProfile::default()).unwrap();
+        assert_eq!(check.pixel_spacing_mm, 0.14);
+        assert!((check.magnification - 1.25).abs() < 1e-9);
+        assert!((check.measured_sid_cm - 100.0).abs() < 1e-9);
+        assert!(check.header_deviation_percent.unwrap().abs() < 1e-9);
+        assert!((check.entered_deviation_percent - 5.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn marks_must_be_magnified() {
+        let profile = ToleranceProfile::default();
+        assert!(check_sid(&marks(vec![0, 100, 725]), &header(), 0.0, 100.0, &profile).is_err());
+        assert!(check_sid(&marks(vec![0, 100]), &header(), 20.0, 100.0, &profile).is_err());
+        // 7 cm at the detector: the marks lie on it
+        assert!(check_sid(&marks(vec![0, 100, 600]), &header(), 20.0, 100.0, &profile).is_err());
+    }
+}
@@ -0,0 +1,217 @@
+use std::fs;
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use dicom::core::VR;
+use dicom::dictionary_std::tags;
+use dicom::object::FileMetaTableBuilder;
+use dicom::ul::association::ServerAssociationOptions;
+use crate::dimse::{self, Association, Message};
+
+// how often the accept loop checks the stop flag
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// storage SCP settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScpConfig {
+    pub ae_title: String,
+    pub port: u16,
+    /// received files are written here as <SOP Instance UID>.dcm
+    pub inbox: PathBuf,
+}
+
+impl Default for ScpConfig {
+    fn default() -> Self {
+        ScpConfig {
+            ae_title: "LIGHTBEAM".to_string(),
+            port: 11112,
+            inbox: PathBuf::from("inbox"),
+        }
+    }
+}
+
+/// image stored by the SCP
+#[derive(Debug, Clone, Serialize)]
+pub struct ReceivedFile {
+    pub path: String,
+    pub calling_ae: String,
+    pub sop_instance_uid: String,
+}
+
+/// running storage SCP, stopped on `stop` or drop
+pub struct StoreScp {
+    pub config: ScpConfig,
+    port: u16,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl StoreScp {
+    /// listen on `config.port` (0 picks a free port) and store incoming images
+    ///
+    /// `on_file` is called from the association threads for every stored image
+    pub fn start<F>(config: ScpConfig, on_file: F) -> io::Result<StoreScp>
+    where
+        F: Fn(ReceivedFile) + Send + Sync + 'static,
+    {
+        fs::create_dir_all(&config.inbox)?;
+        let listener = TcpListener::bind(("0.0.0.0", config.port))?;
+        listener.set_nonblocking(true)?;
+        let port = listener.local_addr()?.port();
+        let stop = Arc::new(AtomicBool::new(false));
+        let on_file = Arc::new(on_file);
+
+        let handle = {
+            let stop = stop.clone();
+            let config = config.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    match listener.accept() {
+                        Ok((stream, addr)) => {
+                            println!("SCP: association from {}", addr);
+                            let config = config.clone();
+                            let on_file = on_file.clone();
+                            thread::spawn(move || {
+                                if let Err(err) = handle_association(stream, &config, &*on_file) {
+                                    println!("SCP: ERR {}", err);
+                                }
+                            });
+                        }
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+                        Err(err) => println!("SCP: ERR {}", err),
+                    }
+                }
+            })
+        };
+
+        println!("SCP: {} listening on port {}", config.ae_title, port);
+        Ok(StoreScp { config, port, stop, handle: Some(handle) })
+    }
+
+    /// port the SCP listens on
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// stop accepting associations (running transfers finish)
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for StoreScp {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn handle_association(stream: TcpStream, config: &ScpConfig, on_file: &dyn Fn(ReceivedFile)) -> Result<(), String> {
+    // accepted sockets may inherit non-blocking mode from the listener
+    stream.set_nonblocking(false).map_err(|err| err.to_string())?;
+    let mut options = ServerAssociationOptions::new()
+        .accept_any()
+        .ae_title(config.ae_title.as_str())
+        .with_abstract_syntax(dimse::VERIFICATION)
+        .with_transfer_syntax(dimse::IMPLICIT_VR_LE)
+        .with_transfer_syntax(dimse::EXPLICIT_VR_LE);
+    for uid in dimse::STORAGE_CLASSES {
+        options = options.with_abstract_syntax(uid);
+    }
+    let mut assoc = options.establish(stream).map_err(|err| err.to_string())?;
+    let calling_ae = assoc.client_ae_title().trim().to_string();
+
+    while let Some(msg) = dimse::receive_message(&mut assoc)? {
+        match msg.command_field() {
+            dimse::C_ECHO_RQ => {
+                let rsp = response(&msg, dimse::C_ECHO_RSP, dimse::STATUS_SUCCESS);
+                dimse::send_message(&mut assoc, msg.pc_id, &rsp, None)?;
+            }
+            dimse::C_STORE_RQ => {
+                let status = match store(&msg, &assoc, config) {
+                    Ok((path, sop_instance_uid)) => {
+                        on_file(ReceivedFile { path, calling_ae: calling_ae.clone(), sop_instance_uid });
+                        dimse::STATUS_SUCCESS
+                    }
+                    Err(err) => {
+                        println!("SCP: STORE ERR {}", err);
+                        dimse::STATUS_STORE_FAILED
+                    }
+                };
+                let rsp = response(&msg, dimse::C_STORE_RSP, status);
+                dimse::send_message(&mut assoc, msg.pc_id, &rsp, None)?;
+            }
+            other => {
+                println!("SCP: unsupported command 0x{:04x}", other);
+                let rsp = response(&msg, other | dimse::RESPONSE, dimse::STATUS_UNRECOGNIZED_OPERATION);
+                dimse::send_message(&mut assoc, msg.pc_id, &rsp, None)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// write the data set of a C-STORE request into the inbox
+///
+/// Returns: (file path, SOP instance uid)
+fn store(msg: &Message, assoc: &dyn Association, config: &ScpConfig) -> Result<(String, String), String> {
+    let data = msg.data.as_ref().ok_or("C-STORE without data set")?;
+    let ts = assoc.transfer_syntax(msg.pc_id).ok_or("unknown presentation context")?;
+    let sop_class_uid = msg.command_str(tags::AFFECTED_SOP_CLASS_UID);
+    let sop_instance_uid = msg.command_str(tags::AFFECTED_SOP_INSTANCE_UID);
+    let obj = dimse::read_dataset(data, &ts).ok_or("cannot decode data set")?;
+    let meta = FileMetaTableBuilder::new()
+        .media_storage_sop_class_uid(sop_class_uid.as_str())
+        .media_storage_sop_instance_uid(sop_instance_uid.as_str())
+        .transfer_syntax(ts.as_str())
+        .build()
+        .map_err(|err| err.to_string())?;
+
+    // write under a temporary name, so folder watchers never see a partial file
+    let path = inbox_path(config, &sop_instance_uid);
+    let part = path.with_extension("part");
+    obj.with_exact_meta(meta).write_to_file(&part).map_err(|err| err.to_string())?;
+    fs::rename(&part, &path).map_err(|err| err.to_string())?;
+    Ok((path.to_string_lossy().to_string(), sop_instance_uid))
+}
+
+/// file of an instance in the inbox
+pub fn inbox_path(config: &ScpConfig, sop_instance_uid: &str) -> PathBuf {
+    let file_name = sop_instance_uid.replace(|c: char| !(c.is_ascii_alphanumeric() || c == '.'), "_");
+    config.inbox.join(format!("{}.dcm", file_name))
+}
+
+fn response(msg: &Message, command_field: u16, status: u16) -> Vec<u8> {
+    let mut elements = vec![
+        dimse::element_u16(tags::COMMAND_FIELD, command_field),
+        dimse::element_u16(tags::MESSAGE_ID_BEING_RESPONDED_TO, msg_id(msg)),
+        dimse::element_u16(tags::COMMAND_DATA_SET_TYPE, dimse::NO_DATA_SET),
+        dimse::element_u16(tags::STATUS, status),
+    ];
+    let sop_class_uid = msg.command_str(tags::AFFECTED_SOP_CLASS_UID);
+    if !sop_class_uid.is_empty() {
+        elements.push(dimse::element_str(tags::AFFECTED_SOP_CLASS_UID, VR::UI, &sop_class_uid));
+    }
+    let sop_instance_uid = msg.command_str(tags::AFFECTED_SOP_INSTANCE_UID);
+    if !sop_instance_uid.is_empty() {
+        elements.push(dimse::element_str(tags::AFFECTED_SOP_INSTANCE_UID, VR::UI, &sop_instance_uid));
+    }
+    dimse::command(elements)
+}
+
+fn msg_id(msg: &Message) -> u16 {
+    msg.command.element(tags::MESSAGE_ID).ok()
+        .and_then(|e| e.to_int::<u16>().ok())
+        .unwrap_or(0)
+}
@@ -0,0 +1,47 @@
+use dicom::core::VR;
+use dicom::dictionary_std::tags;
+use dicom::object::open_file;
+use dicom::ul::association::ClientAssociationOptions;
+use crate::dimse::{self, Association};
+
+/// send files with C-STORE to `called_ae` at `addr` (host:port)
+///
+/// Returns: C-STORE response status of each file
+pub fn store_files(addr: &str, calling_ae: &str, called_ae: &str, files: &[String]) -> Result<Vec<u16>, String> {
+    let mut options = ClientAssociationOptions::new()
+        .calling_ae_title(calling_ae)
+        .called_ae_title(called_ae);
+    // presentation context ids are 1, 3, 5.. in the proposed order
+    for uid in dimse::STORAGE_CLASSES {
+        options = options.with_presentation_context(uid, vec![dimse::EXPLICIT_VR_LE, dimse::IMPLICIT_VR_LE]);
+    }
+    let mut assoc = options.establish(addr).map_err(|err| format!("cannot connect to {}@{}: {}", called_ae, addr, err))?;
+
+    let mut statuses = Vec::with_capacity(files.len());
+    for (i, file) in files.iter().enumerate() {
+        let obj = open_file(file).map_err(|err| format!("{}: {}", file, err))?;
+        let sop_class_uid = obj.meta().media_storage_sop_class_uid.trim_end_matches('\0').to_string();
+        let sop_instance_uid = obj.meta().media_storage_sop_instance_uid.trim_end_matches('\0').to_string();
+        let pc_id = dimse::STORAGE_CLASSES.iter()
+            .position(|uid| *uid == sop_class_uid)
+            .map(|n| 2 * n as u8 + 1)
+            .ok_or(format!("{}: not a CR/DX image", file))?;
+        let ts = assoc.transfer_syntax(pc_id).ok_or(format!("{}: presentation context rejected", file))?;
+        let data = dimse::write_dataset(&obj, &ts).ok_or(format!("{}: cannot encode in {}", file, ts))?;
+
+        let command = dimse::command(vec![
+            dimse::element_u16(tags::COMMAND_FIELD, dimse::C_STORE_RQ),
+            dimse::element_u16(tags::MESSAGE_ID, i as u16 + 1),
+            dimse::element_u16(tags::PRIORITY, 0),
+            dimse::element_u16(tags::COMMAND_DATA_SET_TYPE, dimse::DATA_SET),
+            dimse::element_str(tags::AFFECTED_SOP_CLASS_UID, VR::UI, &sop_class_uid),
+            dimse::element_str(tags::AFFECTED_SOP_INSTANCE_UID, VR::UI, &sop_instance_uid),
+        ]);
+        dimse::send_message(&mut assoc, pc_id, &command, Some(&data))?;
+        let rsp = dimse::receive_message(&mut assoc)?.ok_or("association released")?;
+        statuses.push(rsp.status());
+    }
+
+    let _ = assoc.release();
+    Ok(statuses)
+}
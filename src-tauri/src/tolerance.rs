@@ -0,0 +1,166 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+
+/// limits of a measured value, a missing limit is not checked
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Tolerance {
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+impl Tolerance {
+    pub const fn max(max: f64) -> Self {
+        Tolerance { min: None, max: Some(max) }
+    }
+
+    pub const fn min(min: f64) -> Self {
+        Tolerance { min: Some(min), max: None }
+    }
+
+    pub const fn range(min: f64, max: f64) -> Self {
+        Tolerance { min: Some(min), max: Some(max) }
+    }
+
+    /// Returns: None if no limit is set
+    pub fn check(&self, value: f64) -> Option<bool> {
+        if self.min.is_none() && self.max.is_none() {
+            return None;
+        }
+        Some(self.min.map_or(true, |min| value >= min) && self.max.map_or(true, |max| value <= max))
+    }
+}
+
+/// measured value against its tolerance
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Check {
+    pub key: String,
+    pub value: f64,
+    pub tolerance: Tolerance,
+    /// None if the value has no tolerance
+    pub passed: Option<bool>,
+}
+
+/// true if no check failed
+pub fn all_passed(checks: &[Check]) -> bool {
+    checks.iter().all(|c| c.passed != Some(false))
+}
+
+// built-in limits, used for keys a profile does not set
+const DEFAULT_LIMITS: &[(&str, Tolerance)] = &[
+    // max ROI mean deviation from the image mean (%)
+    ("uniformity.global", Tolerance::max(10.0)),
+    // max ROI mean deviation from its neighbours (%)
+    ("uniformity.local", Tolerance::max(5.0)),
+    // max ROI SNR deviation from the mean SNR (%)
+    ("uniformity.snr_global", Tolerance::max(20.0)),
+    // slanted edge angle (degree), IEC 62220-1 asks for a few degrees
+    ("mtf.edge_angle", Tolerance::range(1.0, 10.0)),
+    // lp/mm, set from the acceptance baseline of each detector
+    ("mtf.mtf50", Tolerance::min(1.0)),
+    ("mtf.mtf10", Tolerance { min: None, max: None }),
+    // NNPS (mm²) at 0.5, 1 and 2 lp/mm, depends on dose: set per detector and exposure
+    ("nps.nnps_0.5", Tolerance { min: None, max: None }),
+    ("nps.nnps_1.0", Tolerance { min: None, max: None }),
+    ("nps.nnps_2.0", Tolerance { min: None, max: None }),
+    // EI against air kerma: r² and max deviation from the fit (%)
+    ("ei.linearity_r2", Tolerance::min(0.99)),
+    ("ei.linearity_error", Tolerance::max(10.0)),
+    // max deviation from EI = 100 x kerma (µGy) (%)
+    ("ei.calibration_error", Tolerance::max(20.0)),
+    // max difference of the stored DI to 10 log10(EI / EI_T)
+    ("ei.di_error", Tolerance::max(0.5)),
+    // lp/mm, set from the acceptance baseline of each room
+    ("linepair.limiting_resolution", Tolerance::min(2.0)),
+    // number of visible details, depends on the phantom layout
+    ("contrast_detail.visible", Tolerance { min: None, max: None }),
+    // defective pixels (outside defect lines), defect rows + columns, pixels of the largest cluster
+    ("defects.pixels", Tolerance { min: None, max: None }),
+    ("defects.lines", Tolerance::max(0.0)),
+    ("defects.largest_cluster", Tolerance { min: None, max: None }),
+    // |ghost| (%) and |lag factor| of the exposure after a block exposure, set from the acceptance baseline
+    ("lag.ghost_percent", Tolerance { min: None, max: None }),
+    ("lag.lag_factor", Tolerance { min: None, max: None }),
+    // focal spot size (mm) along x and y, and largest size / nominal value
+    // (NEMA XR 5: +50% for nominal values below 0.8 mm)
+    ("focal_spot.size_x", Tolerance { min: None, max: None }),
+    ("focal_spot.size_y", Tolerance { min: None, max: None }),
+    ("focal_spot.ratio", Tolerance::max(1.5)),
+    // |SID in the header / typed in - SID from the tool magnification| (% of the measured SID)
+    ("sid.header_deviation", Tolerance::max(2.0)),
+    ("sid.entered_deviation", Tolerance::max(2.0)),
+    // |start - end| of the lateral profile across the grid lines (% of the centre),
+    // |parabola maximum - field centre| (mm), grid line peak / median of the line spectra
+    ("grid.asymmetry", Tolerance::max(10.0)),
+    ("grid.decentering", Tolerance { min: None, max: None }),
+    ("grid.line_artefact", Tolerance { min: None, max: None }),
+    // max deviation of the central ROI mean, SNR and EI from the series mean (%)
+    ("aec.mean_deviation", Tolerance::max(20.0)),
+    ("aec.snr_deviation", Tolerance { min: None, max: None }),
+    ("aec.ei_deviation", Tolerance::max(20.0)),
+];
+
+/// named set of tolerances (e.g. national protocol or site limits)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToleranceProfile {
+    pub name: String,
+    /// key ("test.value") -> limits
+    #[serde(default)]
+    pub limits: BTreeMap<String, Tolerance>,
+}
+
+impl Default for ToleranceProfile {
+    fn default() -> Self {
+        ToleranceProfile {
+            name: "Default".to_string(),
+            limits: DEFAULT_LIMITS.iter().map(|(key, t)| (key.to_string(), *t)).collect(),
+        }
+    }
+}
+
+impl ToleranceProfile {
+    pub fn tolerance(&self, key: &str) -> Tolerance {
+        self.limits.get(key).copied()
+            .or_else(|| DEFAULT_LIMITS.iter().find(|(k, _)| *k == key).map(|(_, t)| *t))
+            .unwrap_or_default()
+    }
+
+    pub fn check(&self, key: &str, value: f64) -> Check {
+        let tolerance = self.tolerance(key);
+        Check { key: key.to_string(), value, tolerance, passed: tolerance.check(value) }
+    }
+}
+
+/// profiles saved in `path`, the default profile if there is none
+///
+/// keys added in newer versions get their built-in limits
+pub fn load_profiles(path: &Path) -> Vec<ToleranceProfile> {
+    let mut profiles: Vec<ToleranceProfile> = fs::read_to_string(path).ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default();
+    if profiles.is_empty() {
+        profiles.push(ToleranceProfile::default());
+    }
+    for profile in profiles.iter_mut() {
+        for (key, t) in DEFAULT_LIMITS {
+            profile.limits.entry(key.to_string()).or_insert(*t);
+        }
+    }
+    profiles
+}
+
+pub fn save_profiles(path: &Path, profiles: &[ToleranceProfile]) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+    }
+    let json = serde_json::to_string_pretty(profiles).map_err(|err| err.to_string())?;
+    fs::write(path, json).map_err(|err| err.to_string())
+}
+
+/// profile `name` of `path`, the first profile if not found
+pub fn find_profile(path: &Path, name: &str) -> ToleranceProfile {
+    let mut profiles = load_profiles(path);
+    let i = profiles.iter().position(|p| p.name == name).unwrap_or(0);
+    profiles.swap_remove(i)
+}
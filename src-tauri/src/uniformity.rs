@@ -0,0 +1,181 @@
+use ndarray::{s, Array};
+use serde::{Deserialize, Serialize};
+use crate::analysis::{detector_details, AnalysisError};
+use crate::cache::ImageCache;
+use crate::tolerance::{all_passed, Check, ToleranceProfile};
+use crate::utils::{get_pixel_spacing, get_rescale, rescaled, save_to_image_u8, U8Array};
+
+// fraction of the image (centred) covered by the ROI grid
+const CENTRAL_AREA: f64 = 0.8;
+// ROI side (mm), or pixels if the pixel spacing is unknown
+const ROI_SIZE_MM: f64 = 20.0;
+const ROI_SIZE_PX: usize = 128;
+// pixels per ROI in the uniformity map image
+const MAP_SCALE: usize = 16;
+
+/// statistics of one ROI (rescaled pixel values)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoiStats {
+    pub row: usize,
+    pub col: usize,
+    pub mean: f64,
+    pub sd: f64,
+    pub snr: f64,
+}
+
+/// detector uniformity test of a flood (flat-field) exposure
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UniformityResult {
+    pub details: Vec<String>,
+    pub roi_size_px: usize,
+    /// ROI rows, columns
+    pub grid: [usize; 2],
+    pub rois: Vec<RoiStats>,
+    /// mean of the ROI values
+    pub mean: f64,
+    pub sd: f64,
+    pub snr: f64,
+    /// max ROI mean deviation from `mean` (%)
+    pub global_uniformity: f64,
+    /// max ROI mean deviation from the mean of its neighbours (%)
+    pub local_uniformity: f64,
+    /// max ROI SNR deviation from `snr` (%)
+    pub snr_global_uniformity: f64,
+    pub profile: String,
+    pub checks: Vec<Check>,
+    pub passed: bool,
+}
+
+/// ROI grid over the central 80% of a flood image
+///
+/// map_path: uniformity map (ROI mean deviation, mid gray = image mean)
+pub fn run_uniformity(file_path: &str, map_path: &str, cache: &ImageCache, profile: &ToleranceProfile) -> Result<UniformityResult, AnalysisError> {
+    let image = cache.get(file_path).ok_or_else(|| AnalysisError::Load(file_path.to_owned()))?;
+    let obj = &image.obj;
+    let (h, w) = image.arr.dim();
+
+    let roi_size_px = match get_pixel_spacing(obj) {
+        Some(spacing) => (ROI_SIZE_MM / spacing).round() as usize,
+        None => ROI_SIZE_PX,
+    };
+    let area_h = (h as f64 * CENTRAL_AREA) as usize;
+    let area_w = (w as f64 * CENTRAL_AREA) as usize;
+    let roi = roi_size_px.clamp(8, area_h.min(area_w).max(8));
+    let rows = (area_h / roi).max(1);
+    let cols = (area_w / roi).max(1);
+    // grid centred in the image
+    let top = (h - (rows * roi).min(h)) / 2;
+    let left = (w - (cols * roi).min(w)) / 2;
+
+    let arr = rescaled(image.arr.view(), get_rescale(obj));
+    let mut rois = Vec::with_capacity(rows * cols);
+    for row in 0..rows {
+        for col in 0..cols {
+            let y = top + row * roi;
+            let x = left + col * roi;
+            let block = arr.slice(s![y..(y + roi).min(h), x..(x + roi).min(w)]);
+            let mean = block.mean().unwrap_or(0.0);
+            let sd = block.std(1.0);
+            let snr = if sd > 0.0 { mean / sd } else { 0.0 };
+            rois.push(RoiStats { row, col, mean, sd, snr });
+        }
+    }
+
+    let n = rois.len() as f64;
+    let mean = rois.iter().map(|r| r.mean).sum::<f64>() / n;
+    let sd = rois.iter().map(|r| r.sd).sum::<f64>() / n;
+    let snr = rois.iter().map(|r| r.snr).sum::<f64>() / n;
+    let global_uniformity = rois.iter().map(|r| deviation(r.mean, mean)).fold(0.0, f64::max);
+    let snr_global_uniformity = rois.iter().map(|r| deviation(r.snr, snr)).fold(0.0, f64::max);
+
+    let local_uniformity = local_uniformity(&rois, [rows, cols]);
+
+    save_map(&rois, [rows, cols], mean, global_uniformity, map_path);
+
+    let checks = vec![
+        profile.check("uniformity.global", global_uniformity),
+        profile.check("uniformity.local", local_uniformity),
+        profile.check("uniformity.snr_global", snr_global_uniformity),
+    ];
+    println!("UNIFORMITY: global {:.2}% local {:.2}%", global_uniformity, local_uniformity);
+    Ok(UniformityResult {
+        details: detector_details(obj),
+        roi_size_px: roi,
+        grid: [rows, cols],
+        rois,
+        mean,
+        sd,
+        snr,
+        global_uniformity,
+        local_uniformity,
+        snr_global_uniformity,
+        profile: profile.name.clone(),
+        passed: all_passed(&checks),
+        checks,
+    })
+}
+
+/// |value - reference| (% of the reference)
+fn deviation(value: f64, reference: f64) -> f64 {
+    if reference != 0.0 { (value - reference).abs() / reference.abs() * 100.0 } else { 0.0 }
+}
+
+/// max ROI mean deviation from the mean of its (up to 8) neighbours (%),
+/// `rois` row by row
+fn local_uniformity(rois: &[RoiStats], grid: [usize; 2]) -> f64 {
+    let [rows, cols] = grid;
+    let at = |row: usize, col: usize| rois[row * cols + col].mean;
+    let mut local_uniformity: f64 = 0.0;
+    for r in rois {
+        let mut sum = 0.0;
+        let mut count = 0;
+        for row in r.row.saturating_sub(1)..=(r.row + 1).min(rows - 1) {
+            for col in r.col.saturating_sub(1)..=(r.col + 1).min(cols - 1) {
+                if (row, col) != (r.row, r.col) {
+                    sum += at(row, col);
+                    count += 1;
+                }
+            }
+        }
+        if count > 0 {
+            local_uniformity = local_uniformity.max(deviation(r.mean, sum / count as f64));
+        }
+    }
+    local_uniformity
+}
+
+/// ROI mean deviation as gray level, scaled to the largest deviation
+fn save_map(rois: &[RoiStats], grid: [usize; 2], mean: f64, max_deviation: f64, map_path: &str) {
+    let [rows, cols] = grid;
+    let range = (max_deviation / 100.0 * mean.abs()).max(f64::EPSILON);
+    let mut map: U8Array = Array::zeros((rows * MAP_SCALE, cols * MAP_SCALE));
+    for r in rois {
+        let level = (127.5 + (r.mean - mean) / range * 127.5).clamp(0.0, 255.0) as u8;
+        map.slice_mut(s![r.row * MAP_SCALE..(r.row + 1) * MAP_SCALE, r.col * MAP_SCALE..(r.col + 1) * MAP_SCALE])
+            .fill(level);
+    }
+    save_to_image_u8(map, map_path.to_owned());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid(means: &[[f64; 3]]) -> Vec<RoiStats> {
+        means.iter().enumerate()
+            .flat_map(|(row, line)| line.iter().enumerate().map(move |(col, &mean)| RoiStats { row, col, mean, sd: 1.0, snr: mean }))
+            .collect()
+    }
+
+    #[test]
+    fn flat_grid_is_uniform() {
+        assert_eq!(local_uniformity(&grid(&[[100.0; 3]; 3]), [3, 3]), 0.0);
+    }
+
+    #[test]
+    fn hot_roi_against_its_neighbours() {
+        // centre 10% above its 8 neighbours, the corners see it as 1 of 3
+        let rois = grid(&[[100.0; 3], [100.0, 110.0, 100.0], [100.0; 3]]);
+        assert!((local_uniformity(&rois, [3, 3]) - 10.0).abs() < 1e-9);
+    }
+}
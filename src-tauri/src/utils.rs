@@ -4,15 +4,17 @@ use std::collections::HashMap;
 use std::u16;
 use dicom::pixeldata::image::GrayImage;
 use dicom::dictionary_std::tags::{self};
-use ndarray::{s, Array, ArrayBase, Axis, Dim, OwnedRepr};
+use ndarray::{s, Array, Array2, ArrayBase, ArrayView2, Axis, Dim, OwnedRepr};
+use rayon::prelude::*;
 use dicom::object::{FileDicomObject, InMemDicomObject, Tag};
 use dicom::{object::open_file, pixeldata::PixelDecoder};
 use std::cmp::max;
+use crate::rotation::{rotate, rotate_roi, Interpolation, Roi};
 
-type DcmObj = dicom::object::FileDicomObject<dicom::object::InMemDicomObject>;
+pub type DcmObj = dicom::object::FileDicomObject<dicom::object::InMemDicomObject>;
 pub type U16Array = ArrayBase<OwnedRepr<u16>, Dim<[usize; 2]>>;
+pub type U16View<'a> = ArrayView2<'a, u16>;
 pub type U8Array = ArrayBase<OwnedRepr<u8>, Dim<[usize; 2]>>;
-pub type U128Array = ArrayBase<OwnedRepr<u128>, Dim<[usize; 2]>>;
 type I32Array = ArrayBase<OwnedRepr<i32>, Dim<[usize; 2]>>;
 type Obj = FileDicomObject<InMemDicomObject>;
 
@@ -29,6 +31,16 @@ pub fn open_dcm_file(file_path: String) -> Option<DcmObj> {
     }
 }
 
+/// open dicom file and decode the first frame
+/// 
+/// Returns: (dicom object, pixel array)
+pub fn open_dcm_array(file_path: String) -> Option<(DcmObj, U16Array)> {
+    let obj = open_dcm_file(file_path)?;
+    let pixel_data = obj.decode_pixel_data().ok()?;
+    let arr = pixel_data.to_ndarray::<u16>().ok()?.slice(s![0, .., .., 0]).to_owned();
+    Some((obj, arr))
+}
+
 pub fn get_detail(obj: &Obj, tags: Tag) -> String {
     match obj.element(tags) {
             Ok(obj) => {
@@ -44,11 +56,11 @@ pub fn get_detail(obj: &Obj, tags: Tag) -> String {
         }
     }
 
-pub fn save_to_image(array: U16Array, save_path: String) {
+pub fn save_to_image(array: U16View, save_path: String) {
     // save array to image
     let h = array.nrows();
     let w = array.ncols();
-    let u8_gray: Vec<u8> = convert_to_u8(array.clone().into_raw_vec(), array.len());
+    let u8_gray: Vec<u8> = convert_to_u8(array);
     let img = array_to_image(u8_gray, h as u32, w as u32);
     img.save(save_path).unwrap();
 }
@@ -57,7 +69,7 @@ pub fn save_to_image_u8(array: U8Array, save_path: String) {
     // save array to image
     let h = array.nrows();
     let w = array.ncols();
-    let img = array_to_image(array.clone().into_raw_vec(), h as u32, w as u32);
+    let img = array_to_image(array.into_raw_vec(), h as u32, w as u32);
     img.save(save_path).unwrap();
 }
 
@@ -65,17 +77,18 @@ fn array_to_image(pixel_vec: Vec<u8>, h: u32, w: u32) -> GrayImage {
     GrayImage::from_raw(w, h, pixel_vec).unwrap()
 }
 
-pub fn convert_to_u8(pixel_vec: Vec<u16>, size: usize) -> Vec<u8> {
-    let mut res: Vec<u8> = Vec::with_capacity(size);
-    let max_value = *pixel_vec.iter().max().unwrap() as f32;
-    for &value in &pixel_vec {
+pub fn convert_to_u8(pixels: U16View) -> Vec<u8> {
+    // row-major order, also for sliced (non-contiguous) views
+    let mut res: Vec<u8> = Vec::with_capacity(pixels.len());
+    let max_value = *pixels.max().unwrap() as f32;
+    for &value in pixels.iter() {
         let u8_val = ((value as f32 / max_value)* 255.) as u8;
         res.push(u8_val);
     }
     res
 }
 
-pub fn find_common_value(arr: U16Array, axis: u8) -> i32 {
+pub fn find_common_value(arr: U16View, axis: u8) -> i32 {
     // find most common pixel value in specific axis
     // axis 0 = by col, axis 1 = by row
     // return: most common pixel value
@@ -100,7 +113,7 @@ pub fn find_common_value(arr: U16Array, axis: u8) -> i32 {
     max_key.unwrap() as i32
 }
 
-pub fn find_center_line(arr: U16Array) -> (i32, i32, i32, i32, f64) {
+pub fn find_center_line(arr: U16View) -> (i32, i32, i32, i32, f64) {
     // find horizontal center line
     // theta: alignment of the center line
     // return (x1, y1, x2, y2, theta)
@@ -121,13 +134,13 @@ pub fn find_center_line(arr: U16Array) -> (i32, i32, i32, i32, f64) {
     // left point
     let focus_l = arr.slice(s![
         crop[0]..crop[1], crop[2]..crop[3]
-    ]).to_owned();
+    ]);
     // add hp (not start at 0)
     let y1 = find_common_value(focus_l, 0) + hp;
     // right point
     let focus_r = arr.slice(s![
         crop[0]..crop[1], w as i32 -crop[3]..w as i32 -crop[2]
-    ]).to_owned();
+    ]);
     let y2 = find_common_value(focus_r, 0) + hp;
 
     // theta
@@ -148,58 +161,17 @@ pub fn find_theta(x1: i32, x2: i32, y1: i32, y2: i32) -> f64 {
 }
 
 /// rotate array CCW by theta in radius 
-pub fn rotate_array(theta_r: f64, array: U16Array) -> U16Array {
-    let h = array.nrows();
-    let w = array.ncols();
-    let max_v = array.max().unwrap().clone() as u16;
-    // let mut rotated = ndarray::Array::zeros((h as usize, w as usize));
-    let mut rotated = Array::from_elem((h as usize, w as usize), max_v);
-    let center_x = w as f64 / 2.;
-    let center_y = h as f64 / 2.;
-
-    for i in 0..h {
-        for j in 0..w {
-            let x = j as f64 - center_x;
-            let y = i as f64 - center_y;
-
-            let new_x = x * theta_r.cos() - y * theta_r.sin() + center_x;
-            let new_y = x * theta_r.sin() + y * theta_r.cos() + center_y;
-
-            // Calculate the four surrounding pixel indices
-            let x0 = new_x.floor() as isize;
-            let x1 = x0 + 1;
-            let y0 = new_y.floor() as isize;
-            let y1 = y0 + 1;
-
-            // Interpolate only if all indices are within bounds
-            if x0 >= 0 && x1 < w as isize && y0 >= 0 && y1 < h as isize {
-                let fx = new_x - x0 as f64;
-                let fy = new_y - y0 as f64;
-
-                let v00 = array[(y0 as usize, x0 as usize)] as f64;
-                let v10 = array[(y0 as usize, x1 as usize)] as f64;
-                let v01 = array[(y1 as usize, x0 as usize)] as f64;
-                let v11 = array[(y1 as usize, x1 as usize)] as f64;
-
-                // Bilinear interpolation formula
-                let value = (1.0 - fx) * (1.0 - fy) * v00
-                          + fx * (1.0 - fy) * v10
-                          + (1.0 - fx) * fy * v01
-                          + fx * fy * v11;
-
-                rotated[(i, j)] = value.round() as u16;
-            }
-        }
-    }
-
-    rotated
+pub fn rotate_array(theta_r: f64, array: U16View) -> U16Array {
+    // blank area filled with max value
+    let max_v = *array.max().unwrap();
+    rotate(array, theta_r, Interpolation::Bilinear, max_v)
 }
 
 
 /// find horizontal lines[y-axis]
 /// 
 /// Returns: (top(y_avg), center(y_avg), bottom(y_avg)) points
-pub fn fint_horizontal_line(arr: U16Array) -> Vec<i32> {
+pub fn fint_horizontal_line(arr: U16View) -> Vec<i32> {
     let mut ypoints = vec![];
     let shape = arr.shape();
     let h = shape[0];
@@ -212,29 +184,29 @@ pub fn fint_horizontal_line(arr: U16Array) -> Vec<i32> {
     let crop = [offset, hp, wp*2, wp*3];
     let focus_l = arr.slice(s![
         crop[0]..crop[1], crop[2]..crop[3]
-    ]).to_owned();
+    ]);
     let y1 = find_common_value(focus_l, 0) + crop[0];
     let focus_r= arr.slice(s![
         crop[0]..crop[1], w as i32 - crop[3]..w as i32 - crop[2]
-    ]).to_owned();
+    ]);
     let y2 = find_common_value(focus_r, 0) + crop[0];
     // average y1 and y2 (may be it not the same)
     ypoints.push((y1 + y2)/2);
 
     // center line
-    let (_, y1, _, y2, _) = find_center_line(arr.clone());
+    let (_, y1, _, y2, _) = find_center_line(arr);
     ypoints.push((y1 + y2)/2);
     
     // bottom line    
     let crop = [h as i32 - hp, h as i32 - offset, wp*4, wp*5];
     let focus_l = arr.slice(s![
         crop[0]..crop[1],crop[2]..crop[3]
-    ]).to_owned();
+    ]);
     // start at  h as i32 - hp (not 0)
     let y1 = find_common_value(focus_l, 0) + crop[0];
     let focus_r = arr.slice(s![
         crop[0]..crop[1],w as i32 - wp*3..w as i32 - wp*2
-    ]).to_owned();
+    ]);
     let y2 = find_common_value(focus_r, 0) + crop[0];
     ypoints.push((y1 + y2)/2);
 
@@ -244,7 +216,7 @@ pub fn fint_horizontal_line(arr: U16Array) -> Vec<i32> {
 /// find vertical lines[x-axis]
 /// 
 /// Returns: (left(x_avg), center(x_avg), right(x_avg)) points
-pub fn find_vertical_line(arr: U16Array) -> Vec<i32> {
+pub fn find_vertical_line(arr: U16View) -> Vec<i32> {
     let mut xpoints = vec![];
     let shape = arr.shape();
     let h = shape[0];
@@ -257,22 +229,22 @@ pub fn find_vertical_line(arr: U16Array) -> Vec<i32> {
     let crop = [hp*3, hp*7, wp+offset, wp*6];
     let focus_t = arr.slice(s![
         crop[0]..crop[1], crop[2]..crop[3]
-    ]).to_owned();
+    ]);
     let x1 = find_common_value(focus_t, 1) + crop[2];
     let focus_b = arr.slice(s![
         h as i32 - crop[1]..h as i32 - crop[0], crop[2]..crop[3]
-    ]).to_owned();
+    ]);
     let x2 = find_common_value(focus_b, 1) + crop[2];
     xpoints.push((x1+x2)/2);
 
     // right line
     let focus_t = arr.slice(s![
         crop[0]..crop[1], w as i32 - crop[3]..w as i32 - crop[2]
-    ]).to_owned();
+    ]);
     let x1 = find_common_value(focus_t, 1) + w as i32 - crop[3];
     let focus_b = arr.slice(s![
         h as i32 - crop[1]..h as i32 - crop[0], w as i32 - crop[3]..w as i32 - crop[2]
-    ]).to_owned();
+    ]);
     let x2 = find_common_value(focus_b, 1) + w as i32 - crop[3];
     xpoints.push((x1+x2)/2);
 
@@ -283,11 +255,11 @@ pub fn find_vertical_line(arr: U16Array) -> Vec<i32> {
     let crop = [hp, hp*2, wp*2, wp*3];
     let focus_t = arr.slice(s![
         crop[0]..crop[1], crop[2]..crop[3]
-    ]).to_owned();
+    ]);
     let x1 = find_common_value(focus_t, 1) + crop[2];
     let focus_b = arr.slice(s![
         h as i32 - crop[1].. h as i32 - crop[0], crop[2]..crop[3]
-    ]).to_owned();
+    ]);
     let x2 = find_common_value(focus_b, 1) + crop[2];
     xpoints.push((x1+x2)/2);
     
@@ -298,7 +270,7 @@ pub fn find_vertical_line(arr: U16Array) -> Vec<i32> {
     xpoints
 }
 
-pub fn argmin(arr: U16Array, axis: u8) -> Vec<usize> {
+pub fn argmin(arr: U16View, axis: u8) -> Vec<usize> {
     // argmin of each column(axis=0), row(axis=1)
     // return position that has minimum pixel value 
     let rows = arr.nrows();
@@ -335,7 +307,7 @@ pub fn argmin(arr: U16Array, axis: u8) -> Vec<usize> {
     argmins 
 }
 
-pub fn argmax(arr: U16Array, axis: u8) -> Vec<usize> {
+pub fn argmax(arr: U16View, axis: u8) -> Vec<usize> {
     // argmax of each column(axis=0), row(axis=1)
     // return position that has minimum pixel value 
     let rows = arr.nrows();
@@ -373,14 +345,12 @@ pub fn argmax(arr: U16Array, axis: u8) -> Vec<usize> {
     argmax 
 }
 
-fn argmax_1d(arr: U16Array) -> i32 {
-    let arr = arr.into_raw_vec();
-    let n = arr.len();
+fn argmax_1d(arr: U16View) -> i32 {
     let mut max_v = 0;
     let mut argmax = 0;
-    for i in 0..n {
-        if arr[i] > max_v {
-            max_v = arr[i];
+    for (i, &v) in arr.iter().enumerate() {
+        if v > max_v {
+            max_v = v;
             argmax = i
         }
     }
@@ -418,7 +388,7 @@ pub fn cm2pixel(ypoints: &Vec<i32>, cm: f32) -> i32 {
 
 
 //// find box of each xs, ys for croping edges area
-pub fn boxs_posision(xpoints: &Vec<i32>, ypoints: &Vec<i32>, arr: U16Array) -> Vec<[[i32; 2]; 2]> {
+pub fn boxs_posision(xpoints: &Vec<i32>, ypoints: &Vec<i32>, arr: U16View) -> Vec<[[i32; 2]; 2]> {
     // return box position(top-left(x, y), bottom-right(x, y))
     // Left, Right, Top, Bottom
     let shape = arr.shape();
@@ -505,46 +475,56 @@ pub fn boxs_posision(xpoints: &Vec<i32>, ypoints: &Vec<i32>, arr: U16Array) -> V
     pos
 }
 
-pub fn get_crop_area(positions: Vec<[[i32; 2]; 2]>, arr: U16Array) -> [U16Array; 8]{
+pub fn get_crop_area<'a>(positions: &[[[i32; 2]; 2]], arr: U16View<'a>) -> [U16View<'a>; 8]{
     // for left, right, top, bottom
     // get crop area pixels from the top_left_point, bottom_right_point
+    // (views into arr, nothing is copied)
     
     let focuses: Vec<_> = positions.iter()
         .map(|[top_left_point, bottom_right_point]| {
-            arr.slice(s![
+            arr.slice_move(s![
                 top_left_point[1]..bottom_right_point[1],
                 top_left_point[0]..bottom_right_point[0]
             ])
-            .to_owned()
         })
         .collect();
 
     focuses.try_into().unwrap()
 }
 
-pub fn find_edges_pos(crop_areas: [U16Array; 8], boxs_pos: Vec<[[i32; 2]; 2]>, xypoints: [i32; 8], ypoints: &Vec<i32>) -> Vec<i32> {
-    let mut edges_pos = vec![];
-    let mut by_x;
-    for (q, crop_area) in crop_areas.into_iter().enumerate() {
-        by_x = q <= 3; 
-        // adjust to the image
-        let [top_lefts, _] = boxs_pos[q];
-        let mut top_left;
-        if by_x {
-            top_left = top_lefts[0];
-        } else {
-            top_left = top_lefts[1];
-        }
-        // central diff
-        let mut edge_pos = central_diff(crop_area, top_left, xypoints[q], by_x, ypoints) as i32;
-        // x-axis: add x
-        edge_pos = edge_pos + top_left;
-        edges_pos.push(edge_pos);
-    }
-    edges_pos
+/// crop areas of the array rotated by theta, without rotating the whole array
+/// 
+/// Returns: same pixels as `get_crop_area(positions, rotate_array(theta_r, arr))`
+pub fn get_rotated_crop_area(positions: &[[[i32; 2]; 2]], arr: U16View, theta_r: f64, fill: u16) -> Vec<U16Array> {
+    positions.par_iter()
+        .map(|pos| rotate_roi(arr, theta_r, Roi::from_box(pos), Interpolation::Bilinear, fill))
+        .collect()
 }
 
-fn central_diff(pixels: U16Array, top_left: i32, xypoint: i32, by_x: bool, ypoints: &Vec<i32>) -> usize {
+pub fn find_edges_pos(crop_areas: &[U16View], boxs_pos: &[[[i32; 2]; 2]], xypoints: [i32; 8], ypoints: &Vec<i32>) -> Vec<i32> {
+    // each crop area is independent: run the 8 central diffs in parallel
+    // (collect keeps the left, right, top, bottom order)
+    crop_areas.par_iter()
+        .enumerate()
+        .map(|(q, crop_area)| {
+            let by_x = q <= 3; 
+            // adjust to the image
+            let [top_lefts, _] = boxs_pos[q];
+            let top_left;
+            if by_x {
+                top_left = top_lefts[0];
+            } else {
+                top_left = top_lefts[1];
+            }
+            // central diff
+            let edge_pos = central_diff(*crop_area, top_left, xypoints[q], by_x, ypoints) as i32;
+            // x-axis: add x
+            edge_pos + top_left
+        })
+        .collect()
+}
+
+fn central_diff(pixels: U16View, top_left: i32, xypoint: i32, by_x: bool, ypoints: &Vec<i32>) -> usize {
     // find most difference position
     // by_x(True, False) = (x, y)
     let nrows = pixels.nrows();
@@ -638,7 +618,8 @@ fn median_of_column(column: &mut Vec<u16>) -> f32 {
     }
 }
 
-fn median_by_col(arr: Vec<Vec<u16>>) -> Vec<f32> {
+/// median of each column of the rows
+pub fn median_by_col(arr: Vec<Vec<u16>>) -> Vec<f32> {
     let ncols = arr[0].len();
     let mut medians = vec![];
     for col in 0..ncols {
@@ -700,43 +681,45 @@ fn percentile(arr: &Vec<f32>, q: f32) -> f32 {
 }
 
 
-pub fn split_q_circle(xpoints: &Vec<i32>, ypoints: &Vec<i32>, arr: U16Array) -> (U16Array, [U16Array; 4], f32, (i32, i32)) {
-    // split the circle into 4q 
+/// area of the circle around the center point
+/// 
+/// Returns: box position(top-left(x, y), bottom-right(x, y))
+pub fn circle_area(xpoints: &Vec<i32>, ypoints: &Vec<i32>) -> [[i32; 2]; 2] {
     let one_cm_pixel = cm2pixel(ypoints, 0.9);
-    let xx = [xpoints[1]-one_cm_pixel, xpoints[1]+one_cm_pixel];
-    let yy = [ypoints[1]-one_cm_pixel, ypoints[1]+one_cm_pixel];
-    let circle_arr = arr.slice(s![
-        yy[0]..yy[1], xx[0]..xx[1]
-    ]).to_owned();
-    let inner_r = cm2pixel(ypoints, 0.41);
-    let outter_r = cm2pixel(ypoints, 0.71);
-    
+    [
+        [xpoints[1]-one_cm_pixel, ypoints[1]-one_cm_pixel],
+        [xpoints[1]+one_cm_pixel, ypoints[1]+one_cm_pixel],
+    ]
+}
+
+pub fn split_q_circle<'a>(circle_arr: U16View<'a>) -> ([U16View<'a>; 4], f32, (i32, i32)) {
+    // split the circle(crop of `circle_area`) into 4q 
+
     // DEBUG
     // let circle_arr = rotate_array(3.14, circle_arr);
 
     // split 4q
-    let mut cir_f32 = vec![];
-    for v in circle_arr.clone().into_raw_vec() {cir_f32.push(v as f32);}
+    let cir_f32: Vec<f32> = circle_arr.iter().map(|&v| v as f32).collect();
     let white_ts = percentile(&cir_f32, 99.0);
 
-    let [xc, yc] = find_center_circle_line(circle_arr.clone());
-    let top_left = circle_arr.slice(s![
+    let [xc, yc] = find_center_circle_line(circle_arr);
+    let top_left = circle_arr.slice_move(s![
         ..yc, ..xc
-    ]).to_owned();
-    let top_right = circle_arr.slice(s![
+    ]);
+    let top_right = circle_arr.slice_move(s![
         ..yc, xc+1.. 
-    ]).to_owned();
-    let bottom_left = circle_arr.slice(s![
+    ]);
+    let bottom_left = circle_arr.slice_move(s![
         yc+1.., ..xc
-    ]).to_owned();
-    let bottom_right = circle_arr.slice(s![
+    ]);
+    let bottom_right = circle_arr.slice_move(s![
         yc+1.., xc+1..
-    ]).to_owned();
+    ]);
     
-    (circle_arr, [top_left, top_right, bottom_left, bottom_right], white_ts, (xc, yc))
+    ([top_left, top_right, bottom_left, bottom_right], white_ts, (xc, yc))
 }
 
-fn find_center_circle_line(arr: U16Array) -> [i32; 2] {
+fn find_center_circle_line(arr: U16View) -> [i32; 2] {
     let shape = arr.shape();
     let h = shape[0] as i32;
     let w = shape[1] as i32;
@@ -746,31 +729,31 @@ fn find_center_circle_line(arr: U16Array) -> [i32; 2] {
     // left
     let focus_l = arr.slice(s![
         hp..h-hp, wp..wp*4
-    ]).to_owned();
+    ]);
     let y1 = find_common_value(focus_l, 0) + hp;
     // right
     let focus_r = arr.slice(s![
         hp..h-hp, w-(wp*2)..w
-    ]).to_owned();
+    ]);
     let y2 = find_common_value(focus_r, 0) + hp;
     let y = ((y1 as f32 + y2 as f32)/2.0).ceil() as i32;
 
     // top
     let focus_t = arr.slice(s![
         wp..wp*3, hp..h-hp
-    ]).to_owned();
+    ]);
     let x1 = find_common_value(focus_t, 1) + hp;
     // bottom
     let focus_b = arr.slice(s![
         w-(wp*2)..w, hp..h-hp
-    ]).to_owned();
+    ]);
     let x2 = find_common_value(focus_b, 1) + hp;
     let x = ((x1 as f32 + x2 as f32)/2.0).ceil() as i32;
 
     [x, y]
 }
 
-pub fn farthest_q(q_arr: [U16Array; 4], white_ts: f32) -> (usize, [[usize; 2]; 2]) {
+pub fn farthest_q(q_arr: [U16View; 4], white_ts: f32) -> (usize, [[usize; 2]; 2]) {
     // q_array = [top_left, top_right, bottom_left, bottom_right]
     // fartest point quadrate 
     // return fartest quadrate, [row, col]
@@ -785,8 +768,8 @@ pub fn farthest_q(q_arr: [U16Array; 4], white_ts: f32) -> (usize, [[usize; 2]; 2
         (true, false),
         (true, true)
     ];
-    for (i, q) in q_arr.clone().into_iter().enumerate() {
-        let (x, y) = find_farthest_white(q.clone(), config[i].0, config[i].1, white_ts);
+    for (i, q) in q_arr.iter().enumerate() {
+        let (x, y) = find_farthest_white(*q, config[i].0, config[i].1, white_ts);
         let d = ((x.pow(2) + y.pow(2)) as f64).sqrt();
         if d >= farthest {
             farthest = d;
@@ -803,42 +786,42 @@ pub fn farthest_q(q_arr: [U16Array; 4], white_ts: f32) -> (usize, [[usize; 2]; 2
     match farthest_q {
         0 => {
             // max_col for row
-            let slice_row = q_arr[farthest_q].slice(s![nrows - row_idx - 1, (ncols - col_idx - 1)..]).to_owned();
+            let slice_row = q_arr[farthest_q].slice(s![nrows - row_idx - 1, (ncols - col_idx - 1)..]);
             let reversed_slice_row: Vec<u16> = slice_row.iter().rev().cloned().collect();
             farthest_point[0][1] = reversed_slice_row.iter().enumerate().max_by(|a, b| a.1.cmp(b.1)).map(|(i, _)| i).unwrap_or(0);
 
             // max_row for col
-            let slice_col = q_arr[farthest_q].slice(s![(nrows - row_idx - 1).., ncols - col_idx - 1]).to_owned();
+            let slice_col = q_arr[farthest_q].slice(s![(nrows - row_idx - 1).., ncols - col_idx - 1]);
             let reversed_slice_col: Vec<u16> = slice_col.iter().rev().cloned().collect();
             farthest_point[1][0] = reversed_slice_col.iter().enumerate().max_by(|a, b| a.1.cmp(b.1)).map(|(i, _)| i).unwrap_or(0);
         }
         1 => {
             // max_col for row
-            let slice_row = q_arr[farthest_q].slice(s![nrows - row_idx - 1, ..=col_idx]).to_owned();
+            let slice_row = q_arr[farthest_q].slice(s![nrows - row_idx - 1, ..=col_idx]);
             farthest_point[0][1] = slice_row.iter().enumerate().max_by(|a, b| a.1.cmp(b.1)).map(|(i, _)| i).unwrap_or(0);
 
             // max_row for col
-            let slice_col = q_arr[farthest_q].slice(s![(nrows - row_idx - 1).., col_idx]).to_owned();
+            let slice_col = q_arr[farthest_q].slice(s![(nrows - row_idx - 1).., col_idx]);
             let reversed_slice_col: Vec<u16> = slice_col.iter().rev().cloned().collect();
             farthest_point[1][0] = reversed_slice_col.iter().enumerate().max_by(|a, b| a.1.cmp(b.1)).map(|(i, _)| i).unwrap_or(0);
         }
         2 => {
             // max_col for row
-            let slice_row = q_arr[farthest_q].slice(s![row_idx, (ncols - col_idx - 1)..]).to_owned();
+            let slice_row = q_arr[farthest_q].slice(s![row_idx, (ncols - col_idx - 1)..]);
             let reversed_slice_row: Vec<u16> = slice_row.iter().rev().cloned().collect();
             farthest_point[0][1] = reversed_slice_row.iter().enumerate().max_by(|a, b| a.1.cmp(b.1)).map(|(i, _)| i).unwrap_or(0);
 
             // max_row for col
-            let slice_col = q_arr[farthest_q].slice(s![..=row_idx, ncols - col_idx - 1]).to_owned();
+            let slice_col = q_arr[farthest_q].slice(s![..=row_idx, ncols - col_idx - 1]);
             farthest_point[1][0] = slice_col.iter().enumerate().max_by(|a, b| a.1.cmp(b.1)).map(|(i, _)| i).unwrap_or(0);
         }
         3 => {
             // max_col for row
-            let slice_row = q_arr[farthest_q].slice(s![row_idx, ..=col_idx]).to_owned();
+            let slice_row = q_arr[farthest_q].slice(s![row_idx, ..=col_idx]);
             farthest_point[0][1] = slice_row.iter().enumerate().max_by(|a, b| a.1.cmp(b.1)).map(|(i, _)| i).unwrap_or(0);
 
             // max_row for col
-            let slice_col = q_arr[farthest_q].slice(s![..=row_idx, col_idx]).to_owned();
+            let slice_col = q_arr[farthest_q].slice(s![..=row_idx, col_idx]);
             farthest_point[1][0] = slice_col.iter().enumerate().max_by(|a, b| a.1.cmp(b.1)).map(|(i, _)| i).unwrap_or(0);
         }
         _ => {}
@@ -847,7 +830,7 @@ pub fn farthest_q(q_arr: [U16Array; 4], white_ts: f32) -> (usize, [[usize; 2]; 2
     (farthest_q, farthest_point)
 }
 
-fn find_farthest_white(arr: U16Array, first_row: bool, first_col: bool, white_ts: u16) -> (i32, i32) {
+fn find_farthest_white(arr: U16View, first_row: bool, first_col: bool, white_ts: u16) -> (i32, i32) {
     // find find_farthest_white return (row, col)
     let nrows = arr.nrows();
     let ncols = arr.ncols();
@@ -905,36 +888,75 @@ pub fn center_point(farthest_point: [[usize; 2]; 2], q: usize, xc: i32, yc: i32)
     (x as usize, y as usize)
 }
 
-pub fn find_edge_tool(vector: Vec<u128>, n: usize, offset: usize, ts: u128) -> usize{
-    let mut start_vals = vec![];
-    let mut v = 0;
-    for i in 0..offset {
-        if ts >= vector[i] {
-            v = 1;
-        } else {
-            v = 0;
+/// run lengths(pixel) for finding the test-tool edges
+pub struct ToolSearch {
+    /// skipped border and length of the start region
+    pub offset: usize,
+    /// number of pixels that must change to be an edge
+    pub edge_run: usize,
+}
+
+// tuned as 30 and 100 pixels on a 3000 pixel, 0.143 mm detector
+const TOOL_OFFSET_MM: f64 = 30.0 * 0.143;
+const TOOL_EDGE_RUN_MM: f64 = 100.0 * 0.143;
+const TOOL_REF_SIZE: f64 = 3000.0;
+
+/// scale the tool search to the detector
+/// 
+/// by pixel spacing(mm) if known, otherwise by image size
+pub fn tool_search_scale(h: usize, w: usize, pixel_spacing: Option<f64>) -> ToolSearch {
+    let (offset, edge_run) = match pixel_spacing {
+        Some(spacing) if spacing > 0.0 => (TOOL_OFFSET_MM / spacing, TOOL_EDGE_RUN_MM / spacing),
+        _ => {
+            let scale = h.min(w) as f64 / TOOL_REF_SIZE;
+            (30.0 * scale, 100.0 * scale)
         }
-        start_vals.push(v);
+    };
+    // keep the runs inside the searched half of the image
+    let max_run = h.min(w) / 8;
+    ToolSearch {
+        offset: (offset.round() as usize).clamp(4, max_run.max(4)),
+        edge_run: (edge_run.round() as usize).clamp(8, max_run.max(8)),
     }
+}
 
-    let start_val = find_mean(start_vals.clone(), start_vals.len());
-    let edge_ts = 100;
+/// mean of each column(axis 0) or each row(axis 1)
+/// 
+/// summed row by row in u32 (exact up to 65537 pixels per mean)
+pub fn mean_profile(arr: U16View, axis: u8) -> Vec<f64> {
+    if axis == 0 {
+        let mut sums = vec![0u32; arr.ncols()];
+        for row in arr.rows() {
+            for (sum, &v) in sums.iter_mut().zip(row.iter()) {
+                *sum += v as u32;
+            }
+        }
+        let n = arr.nrows() as f64;
+        sums.into_iter().map(|sum| sum as f64 / n).collect()
+    } else {
+        let n = arr.ncols() as f64;
+        arr.rows()
+            .into_iter()
+            .map(|row| row.iter().map(|&v| v as u32).sum::<u32>() as f64 / n)
+            .collect()
+    }
+}
+
+/// first position(after offset) where the profile crosses ts for edge_run pixels
+pub fn find_edge_tool(vector: &[f64], offset: usize, ts: f64, edge_run: usize) -> usize{
+    // start region is below ts only if all of it is
+    let start_val = vector[..offset].iter().all(|&v| ts >= v);
     let mut cur_edge = 0;
     let mut edge_pos = 0;
-    let mut p_val = 0;
-    for i in offset..n {
-        if ts >= vector[i] {
-            p_val = 1;
-        } else {
-            p_val = 0;
-        }
+    for i in offset..vector.len() {
+        let p_val = ts >= vector[i];
         if p_val != start_val {
             cur_edge += 1;
         } else {
             cur_edge = 0;
         }
-        if cur_edge >= edge_ts {
-            edge_pos = i - edge_ts;
+        if cur_edge >= edge_run {
+            edge_pos = i - edge_run;
             break;
         }
     }
@@ -942,37 +964,55 @@ pub fn find_edge_tool(vector: Vec<u128>, n: usize, offset: usize, ts: u128) -> u
     edge_pos
 }
 
-pub fn find_mean(vector: Vec<u128>, n: usize) -> u16{
-    let sum: u128 = vector.iter().map(|&x| x as u128).sum();
-    let mean = sum as f64 / n as f64;
-    mean as u16
+pub fn find_mean(vector: &[f64]) -> f64{
+    vector.iter().sum::<f64>() / vector.len() as f64
 }
 
-pub fn cast_type_arr(arr: U16Array) -> U128Array{
-    let h = arr.nrows();
-    let w = arr.ncols();
-    let arr_u128_vec = arr.iter()
-        .map(|&x| x as u128)
-        .collect::<Vec<_>>();
-    let arr_u128 = Array::from_shape_vec((h, w), arr_u128_vec).unwrap();
-    arr_u128
+/// pixel spacing(mm) from Pixel Spacing or Imager Pixel Spacing
+pub fn get_pixel_spacing(obj: &Obj) -> Option<f64> {
+    [tags::PIXEL_SPACING, tags::IMAGER_PIXEL_SPACING].iter()
+        .filter_map(|&tag| obj.element(tag).ok())
+        .filter_map(|elem| elem.to_multi_float64().ok())
+        .filter_map(|values| values.first().copied())
+        .find(|&spacing| spacing > 0.0)
 }
 
-pub fn inv_lut(arr: U16Array) -> U16Array{
-    let max_pixel = arr.max().unwrap();
-    let min_pixel = arr.min().unwrap();
-    let ncols = arr.ncols();
-    let nrows = arr.nrows();
-    let mut inv_arr = arr.clone();
-    for i in 0..nrows{
-        for j in 0..ncols {
-            let val = arr[(i, j)];
-            let new_val = max_pixel - val + min_pixel;
-            inv_arr[(i, j)] = new_val;
-        }
-    }
+/// numeric value of a tag (DS/IS), None if missing
+pub fn get_value(obj: &Obj, tag: Tag) -> Option<f64> {
+    obj.element(tag).ok().and_then(|e| e.to_f64().ok())
+}
+
+/// (slope, intercept) of the modality LUT, (1, 0) if not set
+pub fn get_rescale(obj: &Obj) -> (f64, f64) {
+    let value = |tag| obj.element(tag).ok().and_then(|e| e.to_f64().ok());
+    let slope = value(tags::RESCALE_SLOPE).filter(|&s| s != 0.0).unwrap_or(1.0);
+    (slope, value(tags::RESCALE_INTERCEPT).unwrap_or(0.0))
+}
+
+/// in-memory header of (tag, VR, value) elements for the unit tests
+#[cfg(test)]
+pub fn test_header(elements: &[(Tag, dicom::core::VR, &str)]) -> DcmObj {
+    use dicom::core::{DataElement, PrimitiveValue};
+    let meta = dicom::object::FileMetaTableBuilder::new()
+        .media_storage_sop_class_uid("1.2.840.10008.5.1.4.1.1.1.1")
+        .media_storage_sop_instance_uid("1.2.3.4")
+        .transfer_syntax("1.2.840.10008.1.2.1")
+        .build()
+        .unwrap();
+    InMemDicomObject::from_element_iter(elements.iter().map(|&(tag, vr, value)| DataElement::new(tag, vr, PrimitiveValue::from(value))))
+        .with_exact_meta(meta)
+}
+
+/// stored pixel values to output values (rescale slope/intercept)
+pub fn rescaled(arr: U16View, rescale: (f64, f64)) -> Array2<f64> {
+    let (slope, intercept) = rescale;
+    arr.mapv(|v| v as f64 * slope + intercept)
+}
 
-    inv_arr
+pub fn inv_lut(arr: U16View) -> U16Array{
+    let max_pixel = *arr.max().unwrap();
+    let min_pixel = *arr.min().unwrap();
+    arr.mapv(|val| max_pixel - val + min_pixel)
 }
 
 fn linear_equation(x1: i32, y1: i32, x2: i32, y2: i32) -> [f32; 2] {
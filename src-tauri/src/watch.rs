@@ -0,0 +1,285 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+use serde::{Deserialize, Serialize};
+use crate::analysis::{evaluate, run_collimator, CollimatorResult};
+use crate::cache::ImageCache;
+use crate::deident::{deidentify_result, DeidentOptions};
+use crate::pairing::{read_qa_image, Pair, Pairer};
+use crate::report::collimator_report;
+
+// time between two scans of the watched folder
+const SCAN_INTERVAL: Duration = Duration::from_secs(2);
+// a file is complete when its size and mtime did not change for this many scans
+const STABLE_SCANS: u32 = 2;
+
+/// watch-folder settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WatchConfig {
+    /// folder the rooms export to
+    pub input: PathBuf,
+    /// one result folder per analysed pair is written here
+    pub output: PathBuf,
+    /// processed files are moved here, default <input>/archive
+    pub archive: Option<PathBuf>,
+    /// the large field is the earlier exposure
+    pub large_first: bool,
+    /// applied to the written result.json and report.pdf
+    #[serde(default)]
+    pub deident: DeidentOptions,
+    /// SID and criteria of the report evaluation
+    #[serde(default = "default_sid_cm")]
+    pub sid_cm: f32,
+    #[serde(default = "default_criteria")]
+    pub criteria: f32,
+}
+
+fn default_sid_cm() -> f32 {
+    100.0
+}
+
+fn default_criteria() -> f32 {
+    1.0
+}
+
+impl WatchConfig {
+    pub fn archive_dir(&self) -> PathBuf {
+        self.archive.clone().unwrap_or_else(|| self.input.join("archive"))
+    }
+}
+
+/// result of one pair
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum WatchEvent {
+    Analysed { pair: Pair, output: String, result: Box<CollimatorResult> },
+    Failed { pair: Pair, error: String },
+}
+
+/// running watch-folder service, stopped on `stop` or drop
+pub struct FolderWatcher {
+    pub config: WatchConfig,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl FolderWatcher {
+    /// watch `config.input` on a worker thread
+    pub fn start<F>(config: WatchConfig, cache: ImageCache, on_event: F) -> Result<FolderWatcher, String>
+    where
+        F: Fn(WatchEvent) + Send + 'static,
+    {
+        if !config.input.is_dir() {
+            return Err(format!("{} is not a folder", config.input.display()));
+        }
+        for dir in [config.output.clone(), config.archive_dir(), config.archive_dir().join("failed")] {
+            fs::create_dir_all(&dir).map_err(|err| format!("{}: {}", dir.display(), err))?;
+        }
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let stop = stop.clone();
+            let config = config.clone();
+            thread::spawn(move || watch(&config, &cache, &stop, &on_event))
+        };
+        println!("WATCH: {}", config.input.display());
+        Ok(FolderWatcher { config, stop, handle: Some(handle) })
+    }
+
+    /// stop watching, a running analysis is cancelled
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    /// wait until the service stops (CLI daemon)
+    pub fn join(mut self) {
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for FolderWatcher {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn watch(config: &WatchConfig, cache: &ImageCache, stop: &AtomicBool, on_event: &dyn Fn(WatchEvent)) {
+    let mut pairer = Pairer::new(config.large_first);
+    // size, mtime and unchanged scans of files being written
+    let mut growing: HashMap<PathBuf, (u64, SystemTime, u32)> = HashMap::new();
+    let mut seen: HashSet<PathBuf> = HashSet::new();
+
+    while !stop.load(Ordering::Relaxed) {
+        for path in complete_files(&config.input, &mut growing, &seen) {
+            // not a DICOM file: set aside, it would be read again on every start
+            let Some(image) = read_qa_image(&path.to_string_lossy()) else {
+                println!("WATCH: {} is not a DICOM file", path.display());
+                move_into(&config.archive_dir().join("failed"), &path);
+                continue;
+            };
+            seen.insert(path.clone());
+            let (pair, evicted) = pairer.push_evicting(image);
+            // waited too long for its pair, offered again it would only evict another image
+            if let Some(evicted) = evicted {
+                println!("WATCH: no pair for {}", evicted.path);
+                seen.remove(Path::new(&evicted.path));
+                move_into(&config.archive_dir().join("failed"), Path::new(&evicted.path));
+            }
+            if let Some(pair) = pair {
+                let event = analyse(config, cache, &pair, stop);
+                // cancelled by stop, the files stay for the next start
+                if stop.load(Ordering::Relaxed) {
+                    return;
+                }
+                let failed = matches!(event, WatchEvent::Failed { .. });
+                archive(config, &pair, failed);
+                seen.remove(Path::new(&pair.large.path));
+                seen.remove(Path::new(&pair.small.path));
+                on_event(event);
+            }
+        }
+        thread::sleep(SCAN_INTERVAL);
+    }
+}
+
+/// files of `dir` whose size and mtime stopped changing
+fn complete_files(dir: &Path, growing: &mut HashMap<PathBuf, (u64, SystemTime, u32)>, seen: &HashSet<PathBuf>) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else { return Vec::new() };
+    let mut complete = Vec::new();
+    let mut present = HashSet::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().to_string();
+        // partial uploads and hidden files
+        if name.starts_with('.') || name.ends_with(".part") || name.ends_with(".tmp") || seen.contains(&path) {
+            continue;
+        }
+        let Ok(meta) = entry.metadata() else { continue };
+        if !meta.is_file() {
+            continue;
+        }
+        let mtime = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        present.insert(path.clone());
+        let state = growing.entry(path.clone()).or_insert((meta.len(), mtime, 0));
+        if state.0 == meta.len() && state.1 == mtime {
+            state.2 += 1;
+        } else {
+            *state = (meta.len(), mtime, 0);
+        }
+        if state.2 >= STABLE_SCANS {
+            growing.remove(&path);
+            complete.push(path);
+        }
+    }
+    growing.retain(|path, _| present.contains(path));
+    complete.sort();
+    complete
+}
+
+fn analyse(config: &WatchConfig, cache: &ImageCache, pair: &Pair, stop: &AtomicBool) -> WatchEvent {
+    let stem = Path::new(&pair.large.path).file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let name = format!("{}_{}", pair.large.detector, stem)
+        .replace(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.'), "_");
+    let out_dir = config.output.join(name);
+    if let Err(err) = fs::create_dir_all(&out_dir) {
+        return WatchEvent::Failed { pair: pair.clone(), error: err.to_string() };
+    }
+    let save_path = [
+        out_dir.join("overlay.jpg").to_string_lossy().to_string(),
+        out_dir.join("circle.jpg").to_string_lossy().to_string(),
+        out_dir.join("flatness.png").to_string_lossy().to_string(),
+    ];
+    let file_paths = [pair.large.path.clone(), pair.small.path.clone()];
+    println!("WATCH: analysing {} + {}", file_paths[0], file_paths[1]);
+
+    // a panic in the pipeline must not stop the service
+    let res = panic::catch_unwind(AssertUnwindSafe(|| {
+        run_collimator(&file_paths, &save_path, cache, None, &mut |_| !stop.load(Ordering::Relaxed))
+    }));
+    let Ok(res) = res else {
+        return WatchEvent::Failed { pair: pair.clone(), error: "analysis failed: images could not be analysed".to_string() };
+    };
+    match res {
+        Ok(result) => {
+            let deidentified = deidentify_result(&result, &config.deident);
+            let json = serde_json::to_string_pretty(&deidentified).unwrap_or_default();
+            if let Err(err) = fs::write(out_dir.join("result.json"), json) {
+                return WatchEvent::Failed { pair: pair.clone(), error: err.to_string() };
+            }
+            let evaluation = evaluate(&deidentified, config.sid_cm, config.criteria);
+            let report = collimator_report(&deidentified, &evaluation, Path::new(&save_path[0]), Path::new(&save_path[1]), Path::new(&save_path[2]));
+            if let Err(err) = report.save(&out_dir.join("report.pdf")) {
+                return WatchEvent::Failed { pair: pair.clone(), error: err.to_string() };
+            }
+            WatchEvent::Analysed { pair: pair.clone(), output: out_dir.to_string_lossy().to_string(), result: Box::new(result) }
+        }
+        Err(err) => WatchEvent::Failed { pair: pair.clone(), error: err.to_string() },
+    }
+}
+
+/// move the pair into the archive (failed pairs into archive/failed)
+fn archive(config: &WatchConfig, pair: &Pair, failed: bool) {
+    let mut dir = config.archive_dir();
+    if failed {
+        dir = dir.join("failed");
+    }
+    for path in [&pair.large.path, &pair.small.path] {
+        move_into(&dir, Path::new(path));
+    }
+}
+
+fn move_into(dir: &Path, path: &Path) {
+    let Some(name) = path.file_name() else { return };
+    if let Err(err) = fs::rename(path, dir.join(name)) {
+        println!("WATCH: cannot archive {}: {}", path.display(), err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    #[test]
+    fn growing_file_waits() {
+        let dir = std::env::temp_dir().join(format!("lightbeam-watch-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (mut growing, mut seen) = (HashMap::new(), HashSet::new());
+        let file = dir.join("image.dcm");
+        fs::write(&file, b"DICM").unwrap();
+        fs::write(dir.join("upload.part"), b"DICM").unwrap();
+
+        // first scan: unchanged once
+        assert!(complete_files(&dir, &mut growing, &seen).is_empty());
+        // still being written: counted again from 0
+        OpenOptions::new().append(true).open(&file).unwrap().write_all(b"more").unwrap();
+        assert!(complete_files(&dir, &mut growing, &seen).is_empty());
+        for _ in 1..STABLE_SCANS {
+            assert!(complete_files(&dir, &mut growing, &seen).is_empty());
+        }
+        assert_eq!(complete_files(&dir, &mut growing, &seen), vec![file.clone()]);
+        assert!(growing.is_empty());
+
+        // offered once while it waits for its pair
+        seen.insert(file.clone());
+        for _ in 0..=STABLE_SCANS {
+            assert!(complete_files(&dir, &mut growing, &seen).is_empty());
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,183 @@
+//! HTTP API on a loopback port, a plain HTTP/1.1 client stands in for the caller.
+//!
+//! The synthetic images cannot be analysed, the walk then ends in `failed`. Set
+//! LIGHTBEAM_API_LARGE and LIGHTBEAM_API_SMALL to a real pair to check the result
+//! and the report as well.
+mod common;
+
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+use serde_json::Value;
+use lightbeam_lib::api::ApiServer;
+use lightbeam_lib::cache::ImageCache;
+use common::{temp_dir, write_image};
+
+/// send one request, returns the status code and the body
+fn request(port: u16, method: &str, path: &str, body: &[u8]) -> (u16, Vec<u8>) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(30))).unwrap();
+    write!(stream, "{} {} HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\nContent-Length: {}\r\n\r\n", method, path, body.len()).unwrap();
+    stream.write_all(body).unwrap();
+    read_response(stream)
+}
+
+fn read_response(mut stream: TcpStream) -> (u16, Vec<u8>) {
+    let mut data = Vec::new();
+    stream.read_to_end(&mut data).unwrap();
+    let end = data.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let head = String::from_utf8_lossy(&data[..end]).to_string();
+    let code = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (code, data[end + 4..].to_vec())
+}
+
+fn json(body: &[u8]) -> Value {
+    serde_json::from_slice(body).unwrap()
+}
+
+/// poll until the analysis leaves `running`
+fn wait_for(port: u16, id: u64) -> Value {
+    let start = Instant::now();
+    loop {
+        let (code, body) = request(port, "GET", &format!("/analyses/{}", id), b"");
+        assert_eq!(code, 200);
+        let analysis = json(&body);
+        if analysis["status"] != "running" {
+            return analysis;
+        }
+        assert!(start.elapsed() < Duration::from_secs(120), "analysis still running");
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// the real pair from the environment, or two synthetic images in `dir`
+fn image_pair(dir: &std::path::Path) -> (PathBuf, PathBuf, bool) {
+    match (env::var("LIGHTBEAM_API_LARGE"), env::var("LIGHTBEAM_API_SMALL")) {
+        (Ok(large), Ok(small)) => (PathBuf::from(large), PathBuf::from(small), true),
+        _ => {
+            let (large, small) = (dir.join("large.dcm"), dir.join("small.dcm"));
+            write_image(&large, "1.2.3.4.1", 64);
+            write_image(&small, "1.2.3.4.2", 64);
+            (large, small, false)
+        }
+    }
+}
+
+#[test]
+fn analysis_walk() {
+    let dir = temp_dir("api-walk");
+    let server = ApiServer::start("127.0.0.1:0", dir.join("api"), ImageCache::default()).unwrap();
+    let port = server.port;
+    let (large, small, real) = image_pair(&dir);
+
+    let (code, body) = request(port, "POST", "/analyses", b"");
+    assert_eq!(code, 201);
+    let created = json(&body);
+    assert_eq!(created["status"], "waiting_for_files");
+    let id = created["id"].as_u64().unwrap();
+    let url = |rest: &str| format!("/analyses/{}{}", id, rest);
+
+    // files first
+    assert_eq!(request(port, "POST", &url("/start"), b"").0, 409);
+    assert_eq!(request(port, "PUT", &url("/large"), &fs::read(&large).unwrap()).0, 200);
+    assert_eq!(request(port, "POST", &url("/start"), b"").0, 409);
+    assert_eq!(request(port, "PUT", &url("/small"), &fs::read(&small).unwrap()).0, 200);
+    assert_eq!(request(port, "GET", &url("/result"), b"").0, 409);
+
+    let (code, body) = request(port, "POST", &url("/start?sid=100&criteria=1"), b"");
+    assert_eq!(code, 202);
+    assert_eq!(json(&body)["sid_cm"], 100.0);
+    // started once only, the files are fixed
+    assert_eq!(request(port, "POST", &url("/start"), b"").0, 409);
+    assert_eq!(request(port, "PUT", &url("/large"), &fs::read(&large).unwrap()).0, 409);
+
+    let analysis = wait_for(port, id);
+    if real {
+        assert_eq!(analysis["status"], "done");
+        assert_eq!(analysis["percent"], 100);
+        let (code, body) = request(port, "GET", &url("/result"), b"");
+        assert_eq!(code, 200);
+        let result = json(&body);
+        assert!(result["result"].is_object());
+        assert!(result["evaluation"].is_object());
+        let (code, png) = request(port, "GET", &url("/overlay.png"), b"");
+        assert_eq!(code, 200);
+        assert!(png.starts_with(b"\x89PNG"));
+        let (code, pdf) = request(port, "GET", &url("/report.pdf"), b"");
+        assert_eq!(code, 200);
+        assert!(pdf.starts_with(b"%PDF"));
+    } else {
+        assert_eq!(analysis["status"], "failed");
+        assert!(analysis["error"].is_string());
+        assert_eq!(request(port, "GET", &url("/result"), b"").0, 409);
+        assert_eq!(request(port, "GET", &url("/overlay.png"), b"").0, 409);
+        assert_eq!(request(port, "GET", &url("/report.pdf"), b"").0, 409);
+    }
+
+    assert_eq!(request(port, "DELETE", &url(""), b"").0, 200);
+    assert_eq!(request(port, "GET", &url(""), b"").0, 404);
+    assert!(!dir.join("api").join(id.to_string()).exists());
+
+    server.stop();
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn rejected_requests() {
+    let dir = temp_dir("api-errors");
+    let server = ApiServer::start("127.0.0.1:0", dir.join("api"), ImageCache::default()).unwrap();
+    let port = server.port;
+
+    assert_eq!(request(port, "GET", "/analyses/1", b"").0, 404);
+    assert_eq!(request(port, "GET", "/analyses/one", b"").0, 404);
+    assert_eq!(request(port, "GET", "/nothing", b"").0, 404);
+    assert_eq!(request(port, "PUT", "/analyses/1/large", b"").0, 404);
+
+    let id = json(&request(port, "POST", "/analyses", b"").1)["id"].as_u64().unwrap();
+    let upload = format!("/analyses/{}/large", id);
+    assert_eq!(request(port, "PUT", &format!("/analyses/{}/medium", id), b"").0, 404);
+
+    // no DICM marker after the 128 byte preamble
+    assert_eq!(request(port, "PUT", &upload, b"not a dicom file").0, 415);
+    assert_eq!(request(port, "PUT", &upload, &[0; 200]).0, 415);
+
+    // refused from the header, the body is never sent
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(30))).unwrap();
+    write!(stream, "PUT {} HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\nContent-Length: {}\r\n\r\n", upload, 256 * 1024 * 1024 + 1).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(read_response(stream).0, 413);
+
+    // nothing was stored
+    assert!(!dir.join("api").join(id.to_string()).join("large.dcm").exists());
+
+    server.stop();
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn status_during_an_upload() {
+    let dir = temp_dir("api-slow");
+    let server = ApiServer::start("127.0.0.1:0", dir.join("api"), ImageCache::default()).unwrap();
+    let port = server.port;
+    let id = json(&request(port, "POST", "/analyses", b"").1)["id"].as_u64().unwrap();
+
+    // an upload that sends its header and then stalls
+    let mut slow = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(slow, "PUT /analyses/{}/large HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\nContent-Length: 1000\r\n\r\n", id).unwrap();
+    thread::sleep(Duration::from_millis(500));
+
+    let (code, body) = request(port, "GET", &format!("/analyses/{}", id), b"");
+    assert_eq!(code, 200);
+    assert_eq!(json(&body)["status"], "waiting_for_files");
+
+    slow.write_all(&[0; 1000]).unwrap();
+    assert_eq!(read_response(slow).0, 415);
+
+    server.stop();
+    let _ = fs::remove_dir_all(&dir);
+}
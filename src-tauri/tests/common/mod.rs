@@ -0,0 +1,44 @@
+//! Helpers shared by the DICOM network tests.
+#![allow(dead_code)]
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use dicom::core::{DataElement, PrimitiveValue, VR};
+use dicom::dictionary_std::tags;
+use dicom::object::{FileMetaTableBuilder, InMemDicomObject};
+use lightbeam_lib::dimse;
+
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("lightbeam-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// CR image of `size` x `size` pixels as a Part 10 file
+pub fn write_image(path: &Path, sop_instance_uid: &str, size: u16) {
+    let pixels: Vec<u16> = (0..size as u32 * size as u32).map(|i| (i % 4096) as u16).collect();
+    let obj = InMemDicomObject::from_element_iter([
+        DataElement::new(tags::SOP_CLASS_UID, VR::UI, PrimitiveValue::from(dimse::CR_IMAGE_STORAGE)),
+        DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, PrimitiveValue::from(sop_instance_uid)),
+        DataElement::new(tags::MODALITY, VR::CS, PrimitiveValue::from("CR")),
+        DataElement::new(tags::SAMPLES_PER_PIXEL, VR::US, PrimitiveValue::from(1u16)),
+        DataElement::new(tags::PHOTOMETRIC_INTERPRETATION, VR::CS, PrimitiveValue::from("MONOCHROME2")),
+        DataElement::new(tags::ROWS, VR::US, PrimitiveValue::from(size)),
+        DataElement::new(tags::COLUMNS, VR::US, PrimitiveValue::from(size)),
+        DataElement::new(tags::BITS_ALLOCATED, VR::US, PrimitiveValue::from(16u16)),
+        DataElement::new(tags::BITS_STORED, VR::US, PrimitiveValue::from(12u16)),
+        DataElement::new(tags::HIGH_BIT, VR::US, PrimitiveValue::from(11u16)),
+        DataElement::new(tags::PIXEL_REPRESENTATION, VR::US, PrimitiveValue::from(0u16)),
+        DataElement::new(tags::PIXEL_DATA, VR::OW, PrimitiveValue::U16(pixels.into())),
+    ]);
+    let meta = FileMetaTableBuilder::new()
+        .media_storage_sop_class_uid(dimse::CR_IMAGE_STORAGE)
+        .media_storage_sop_instance_uid(sop_instance_uid)
+        .transfer_syntax(dimse::EXPLICIT_VR_LE)
+        .build()
+        .unwrap();
+    obj.with_exact_meta(meta).write_to_file(path).unwrap();
+}
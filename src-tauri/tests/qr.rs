@@ -0,0 +1,197 @@
+//! Query/retrieve against a local Orthanc-style stand-in: study root C-FIND that
+//! ignores the Station Name key, C-MOVE to the AE titles it knows.
+mod common;
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use dicom::core::VR;
+use dicom::dictionary_std::tags;
+use dicom::object::{InMemDicomObject, Tag};
+use dicom::ul::association::{ServerAssociation, ServerAssociationOptions};
+use lightbeam_lib::dimse::{self, get_str, Association, Message};
+use lightbeam_lib::qr::{find, retrieve, QrConfig, QrQuery};
+use lightbeam_lib::storescp::{ScpConfig, StoreScp};
+use lightbeam_lib::storescu::store_files;
+use common::{temp_dir, write_image};
+
+const PACS_AE: &str = "ORTHANC";
+
+/// instance held by the stand-in
+#[derive(Clone)]
+struct Instance {
+    study: &'static str,
+    series: &'static str,
+    sop: &'static str,
+    modality: &'static str,
+    station: &'static str,
+    file: PathBuf,
+}
+
+/// serve associations on a loopback port, `modalities`: C-MOVE destinations (AE title -> port)
+fn start_pacs(instances: Vec<Instance>, modalities: HashMap<String, u16>) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            serve(stream, &instances, &modalities);
+        }
+    });
+    port
+}
+
+fn serve(stream: TcpStream, instances: &[Instance], modalities: &HashMap<String, u16>) {
+    let mut assoc = ServerAssociationOptions::new()
+        .accept_any()
+        .ae_title(PACS_AE)
+        .with_abstract_syntax(dimse::STUDY_ROOT_FIND)
+        .with_abstract_syntax(dimse::STUDY_ROOT_MOVE)
+        .with_transfer_syntax(dimse::IMPLICIT_VR_LE)
+        .with_transfer_syntax(dimse::EXPLICIT_VR_LE)
+        .establish(stream)
+        .unwrap();
+    while let Ok(Some(msg)) = dimse::receive_message(&mut assoc) {
+        let ts = assoc.transfer_syntax(msg.pc_id).unwrap();
+        let identifier = dimse::read_dataset(msg.data.as_deref().unwrap(), &ts).unwrap();
+        // only the uid keys are matched, like an SCP without Station Name support
+        let key = |tag: Tag| get_str(&identifier, tag);
+        let matches: Vec<&Instance> = instances.iter()
+            .filter(|i| key(tags::STUDY_INSTANCE_UID).is_empty() || key(tags::STUDY_INSTANCE_UID) == i.study)
+            .filter(|i| key(tags::SERIES_INSTANCE_UID).is_empty() || key(tags::SERIES_INSTANCE_UID) == i.series)
+            .filter(|i| key(tags::SOP_INSTANCE_UID).is_empty() || key(tags::SOP_INSTANCE_UID) == i.sop)
+            .collect();
+
+        match msg.command_field() {
+            dimse::C_FIND_RQ => {
+                let level = key(tags::QUERY_RETRIEVE_LEVEL);
+                let mut sent = Vec::new();
+                for i in matches {
+                    let uid = match level.as_str() {
+                        "STUDY" => i.study,
+                        "SERIES" => i.series,
+                        _ => i.sop,
+                    };
+                    if sent.contains(&uid) {
+                        continue;
+                    }
+                    sent.push(uid);
+                    let obj = InMemDicomObject::from_element_iter([
+                        dimse::element_str(tags::QUERY_RETRIEVE_LEVEL, VR::CS, &level),
+                        dimse::element_str(tags::STUDY_INSTANCE_UID, VR::UI, i.study),
+                        dimse::element_str(tags::SERIES_INSTANCE_UID, VR::UI, i.series),
+                        dimse::element_str(tags::SOP_INSTANCE_UID, VR::UI, i.sop),
+                        dimse::element_str(tags::MODALITY, VR::CS, i.modality),
+                        dimse::element_str(tags::STATION_NAME, VR::SH, i.station),
+                    ]);
+                    let data = dimse::write_dataset(&obj, &ts).unwrap();
+                    respond(&mut assoc, &msg, dimse::C_FIND_RSP, dimse::STATUS_PENDING, Some(&data), vec![]);
+                }
+                respond(&mut assoc, &msg, dimse::C_FIND_RSP, dimse::STATUS_SUCCESS, None, vec![]);
+            }
+            dimse::C_MOVE_RQ => {
+                let destination = msg.command_str(tags::MOVE_DESTINATION);
+                let files: Vec<String> = matches.iter().map(|i| i.file.to_string_lossy().to_string()).collect();
+                let completed = match modalities.get(&destination) {
+                    Some(port) => store_files(&format!("127.0.0.1:{}", port), PACS_AE, &destination, &files)
+                        .map(|statuses| statuses.iter().filter(|&&s| s == dimse::STATUS_SUCCESS).count())
+                        .unwrap_or(0),
+                    None => 0,
+                };
+                let failed = files.len() - completed;
+                respond(&mut assoc, &msg, dimse::C_MOVE_RSP, dimse::STATUS_SUCCESS, None, vec![
+                    dimse::element_u16(tags::NUMBER_OF_COMPLETED_SUBOPERATIONS, completed as u16),
+                    dimse::element_u16(tags::NUMBER_OF_FAILED_SUBOPERATIONS, failed as u16),
+                ]);
+            }
+            _ => respond(&mut assoc, &msg, msg.command_field() | dimse::RESPONSE, dimse::STATUS_UNRECOGNIZED_OPERATION, None, vec![]),
+        }
+    }
+}
+
+fn respond(assoc: &mut ServerAssociation, msg: &Message, command_field: u16, status: u16, data: Option<&[u8]>, mut elements: Vec<dicom::object::mem::InMemElement>) {
+    let message_id = msg.command.element(tags::MESSAGE_ID).ok().and_then(|e| e.to_int::<u16>().ok()).unwrap_or(0);
+    elements.extend([
+        dimse::element_u16(tags::COMMAND_FIELD, command_field),
+        dimse::element_u16(tags::MESSAGE_ID_BEING_RESPONDED_TO, message_id),
+        dimse::element_u16(tags::COMMAND_DATA_SET_TYPE, if data.is_some() { dimse::DATA_SET } else { dimse::NO_DATA_SET }),
+        dimse::element_u16(tags::STATUS, status),
+        dimse::element_str(tags::AFFECTED_SOP_CLASS_UID, VR::UI, &msg.command_str(tags::AFFECTED_SOP_CLASS_UID)),
+    ]);
+    dimse::send_message(assoc, msg.pc_id, &dimse::command(elements), data).unwrap();
+}
+
+/// three QA series in two studies, written to `dir`
+fn instances(dir: &std::path::Path) -> Vec<Instance> {
+    let instance = |study, series, sop, modality, station| {
+        let file = dir.join(format!("{}.dcm", sop));
+        write_image(&file, sop, 16);
+        Instance { study, series, sop, modality, station, file }
+    };
+    vec![
+        instance("1.2.3.1", "1.2.3.1.1", "1.2.3.1.1.1", "CR", "ROOM1"),
+        instance("1.2.3.1", "1.2.3.1.2", "1.2.3.1.2.1", "CR", "ROOM2"),
+        instance("1.2.3.2", "1.2.3.2.1", "1.2.3.2.1.1", "DX", "ROOM10"),
+    ]
+}
+
+fn config(port: u16) -> QrConfig {
+    QrConfig {
+        ae_title: "LIGHTBEAM".to_string(),
+        pacs_ae_title: PACS_AE.to_string(),
+        host: "127.0.0.1".to_string(),
+        port,
+    }
+}
+
+fn sop_uids(config: &QrConfig, query: &QrQuery) -> Vec<String> {
+    let mut uids: Vec<String> = find(config, query).unwrap().into_iter().map(|i| i.sop_instance_uid).collect();
+    uids.sort();
+    uids
+}
+
+#[test]
+fn find_filters_station_name() {
+    let dir = temp_dir("qr-find");
+    let config = config(start_pacs(instances(&dir), HashMap::new()));
+
+    let all = QrQuery::default();
+    assert_eq!(sop_uids(&config, &all), ["1.2.3.1.1.1", "1.2.3.1.2.1", "1.2.3.2.1.1"]);
+    let exact = QrQuery { station_name: "ROOM1".to_string(), ..Default::default() };
+    assert_eq!(sop_uids(&config, &exact), ["1.2.3.1.1.1"]);
+    let wildcard = QrQuery { station_name: "ROOM1*".to_string(), ..Default::default() };
+    assert_eq!(sop_uids(&config, &wildcard), ["1.2.3.1.1.1", "1.2.3.2.1.1"]);
+    let cr = QrQuery { station_name: "ROOM?".to_string(), modality: "CR".to_string(), ..Default::default() };
+    assert_eq!(sop_uids(&config, &cr), ["1.2.3.1.1.1", "1.2.3.1.2.1"]);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn retrieve_moves_into_the_scp() {
+    let dir = temp_dir("qr-move");
+    let (tx, received) = mpsc::channel();
+    let scp = StoreScp::start(ScpConfig { ae_title: "LIGHTBEAM".to_string(), port: 0, inbox: dir.join("inbox") }, move |file| {
+        let _ = tx.send(file);
+    }).unwrap();
+    let modalities = HashMap::from([("LIGHTBEAM".to_string(), scp.port())]);
+    let config = config(start_pacs(instances(&dir), modalities));
+
+    let query = QrQuery { station_name: "ROOM2".to_string(), ..Default::default() };
+    let found = find(&config, &query).unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].station_name, "ROOM2");
+    assert_eq!(retrieve(&config, "LIGHTBEAM", &found).unwrap(), (1, 0));
+    let stored = received.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(stored.sop_instance_uid, "1.2.3.1.2.1");
+    assert_eq!(stored.calling_ae, PACS_AE);
+
+    // unknown destination: the PACS reports a failed sub-operation
+    assert_eq!(retrieve(&config, "NOBODY", &found).unwrap(), (0, 1));
+
+    scp.stop();
+    let _ = fs::remove_dir_all(&dir);
+}
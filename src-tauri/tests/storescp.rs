@@ -0,0 +1,99 @@
+//! Storage SCP on a loopback port, `storescu::store_files` stands in for the modality.
+mod common;
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::Duration;
+use dicom::core::{DataElement, PrimitiveValue, VR};
+use dicom::dictionary_std::tags;
+use dicom::object::{open_file, InMemDicomObject};
+use dicom::ul::association::{ClientAssociation, ClientAssociationOptions};
+use lightbeam_lib::dimse::{self, Message};
+use lightbeam_lib::storescp::{inbox_path, ReceivedFile, ScpConfig, StoreScp};
+use lightbeam_lib::storescu::store_files;
+use common::{temp_dir, write_image};
+
+const AE_TITLE: &str = "LIGHTBEAM";
+
+fn start_scp(inbox: PathBuf) -> (StoreScp, mpsc::Receiver<ReceivedFile>) {
+    let (tx, rx) = mpsc::channel();
+    let config = ScpConfig { ae_title: AE_TITLE.to_string(), port: 0, inbox };
+    let scp = StoreScp::start(config, move |file| {
+        let _ = tx.send(file);
+    }).unwrap();
+    (scp, rx)
+}
+
+fn connect(port: u16) -> ClientAssociation {
+    ClientAssociationOptions::new()
+        .calling_ae_title("STORESCU")
+        .called_ae_title(AE_TITLE)
+        .with_presentation_context(dimse::VERIFICATION, vec![dimse::IMPLICIT_VR_LE])
+        .establish(("127.0.0.1", port))
+        .unwrap()
+}
+
+/// send a command on the verification context and wait for the response
+fn request(assoc: &mut ClientAssociation, command_field: u16, data: Option<&[u8]>) -> Message {
+    let command = dimse::command(vec![
+        dimse::element_u16(tags::COMMAND_FIELD, command_field),
+        dimse::element_u16(tags::MESSAGE_ID, 1),
+        dimse::element_u16(tags::COMMAND_DATA_SET_TYPE, if data.is_some() { dimse::DATA_SET } else { dimse::NO_DATA_SET }),
+        dimse::element_str(tags::AFFECTED_SOP_CLASS_UID, VR::UI, dimse::VERIFICATION),
+    ]);
+    dimse::send_message(assoc, 1, &command, data).unwrap();
+    dimse::receive_message(assoc).unwrap().expect("response")
+}
+
+#[test]
+fn echo_and_unsupported_command() {
+    let dir = temp_dir("scp-echo");
+    let (scp, _) = start_scp(dir.join("inbox"));
+    let mut assoc = connect(scp.port());
+
+    let rsp = request(&mut assoc, dimse::C_ECHO_RQ, None);
+    assert_eq!(rsp.command_field(), dimse::C_ECHO_RSP);
+    assert_eq!(rsp.status(), dimse::STATUS_SUCCESS);
+
+    // a query sent to the SCP by mistake is answered, not left to time out
+    let identifier = InMemDicomObject::from_element_iter([
+        DataElement::new(tags::QUERY_RETRIEVE_LEVEL, VR::CS, PrimitiveValue::from("STUDY")),
+    ]);
+    let data = dimse::write_dataset(&identifier, dimse::IMPLICIT_VR_LE).unwrap();
+    let rsp = request(&mut assoc, dimse::C_FIND_RQ, Some(&data));
+    assert_eq!(rsp.command_field(), dimse::C_FIND_RSP);
+    assert_eq!(rsp.status(), dimse::STATUS_UNRECOGNIZED_OPERATION);
+
+    let _ = assoc.release();
+    scp.stop();
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn store_writes_the_inbox() {
+    let dir = temp_dir("scp-store");
+    let inbox = dir.join("inbox");
+    let (scp, received) = start_scp(inbox.clone());
+    // 128 KB of pixel data, sent in several PDVs
+    let uid = "1.2.826.0.1.3680043.2.1143.4242";
+    let file = dir.join("image.dcm");
+    write_image(&file, uid, 256);
+
+    let addr = format!("127.0.0.1:{}", scp.port());
+    let statuses = store_files(&addr, "STORESCU", AE_TITLE, &[file.to_string_lossy().to_string()]).unwrap();
+    assert_eq!(statuses, vec![dimse::STATUS_SUCCESS]);
+
+    let stored = received.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(stored.sop_instance_uid, uid);
+    assert_eq!(stored.calling_ae, "STORESCU");
+    let config = ScpConfig { ae_title: AE_TITLE.to_string(), port: 0, inbox };
+    assert_eq!(PathBuf::from(&stored.path), inbox_path(&config, uid));
+
+    let obj = open_file(&stored.path).unwrap();
+    assert_eq!(obj.element(tags::ROWS).unwrap().to_int::<u16>().unwrap(), 256);
+    assert_eq!(obj.element(tags::PIXEL_DATA).unwrap().to_multi_int::<u16>().unwrap().len(), 256 * 256);
+
+    scp.stop();
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -1,9 +1,10 @@
 const { invoke } = window.__TAURI__.tauri;
 const { tempdir } = window.__TAURI__.os;
 const { convertFileSrc } = window.__TAURI__.tauri;
-const { open, message, save } = window.__TAURI__.dialog;
-const { appDataDir } = window.__TAURI__.path;
-const { createDir, exists, writeBinaryFile } = window.__TAURI__.fs;
+const { open, ask, message, save } = window.__TAURI__.dialog;
+const { appDataDir, join } = window.__TAURI__.path;
+const { copyFile, createDir, exists, writeBinaryFile } = window.__TAURI__.fs;
+const { listen } = window.__TAURI__.event;
 
 // load image
 const inputDiv = document.querySelector(".file-input-container");
@@ -17,6 +18,19 @@ const processBtn = document.getElementById("processBtn");
 
 // loading process
 const loadingDiv = document.querySelector(".loading");
+const loadingText = document.getElementById("loadingText");
+const loadingBar = document.getElementById("loadingBar");
+const cancelBtn = document.getElementById("cancelBtn");
+let currentJobId = null;
+const stageNames = {
+  load: "Loading images",
+  tool_detection: "Detecting test tool",
+  rotation: "Rotating images",
+  line_detection: "Detecting lines",
+  edge_detection: "Detecting edges",
+  circle_analysis: "Analysing beam alignment",
+  rendering: "Rendering result",
+};
 
 // show result
 const resultDiv = document.querySelector(".result");
@@ -41,6 +55,7 @@ let fileCheckInfoF = [0, 0, 0, 0];
 let largeCheck = false;
 let smallCheck = false;
 let contentCsvList = [0, 0, 0, 0, 0, 0, 0, 0, 0];
+let lastResult = null;
 
 //Trial Version 14day
 checkTrialVersion();
@@ -81,16 +96,32 @@ async function process() {
   const savePath = [
     `${tempDir}${formattedDateTime}.jpg`,
     `${tempDir}${formattedDateTime}+cir.jpg`,
+    `${tempDir}${formattedDateTime}+flat.png`,
   ];
 
-  const res = await invoke("processing", {
-    filePaths: filePathsImage,
-    savePath: savePath,
-  });
+  let res;
+  try {
+    res = await runAnalysis(filePathsImage, savePath);
+  } catch (err) {
+    loadingDiv.style.display = "none";
+    inputDiv.style.display = "grid";
+    if (err !== "cancelled") {
+      await message(`${err}`, { title: "LightBeamKKU", type: "error" });
+    }
+    return;
+  }
 
+  lastResult = res;
+  // QA history of the detector, with the current SID and criteria
+  invoke("record_collimator", {
+    filePath: filePathsImage[0],
+    result: res,
+    sid: parseFloat(sid),
+    criteria: criteria,
+  }).catch((err) => console.log("history:", err));
   // get results
-  const [x, y, h, k] = res[0];
-  let [cir_distance, cir_angle] = res[1];
+  const [x, y, h, k] = res.circle_points;
+  let [cir_distance, cir_angle] = res.beam_alignment;
   contentCsvList[6] = cir_distance.toFixed(3);
   contentCsvList[7] = cir_angle.toFixed(3);
   let cir_status = "passed";
@@ -100,8 +131,8 @@ async function process() {
     cir_color = "red";
   }
   contentCsvList[8] = cir_status;
-  const points = res[2];
-  const length = res[3];
+  const points = res.points;
+  const length = res.lengths;
   lengthCm = [
     length[0][0][0].toFixed(3),
     length[1][0][0].toFixed(3),
@@ -131,12 +162,11 @@ async function process() {
     errPercentage[3][1],
   ];
   contentCsvList[5] = errStatusVal;
-  // const pos = res[4];
-  const max_err_pos = res[5];
+  const max_err_pos = res.most_error;
   contentCsvList[4] = max_err_pos;
-  const xpoints = res[6];
-  const ypoints = res[7];
-  const info = res[8];
+  const xpoints = res.xpoints;
+  const ypoints = res.ypoints;
+  const info = res.details;
 
   // details
   let pixel_size_sup = "-";
@@ -172,12 +202,20 @@ async function process() {
     }
     event.target.value = input;
     sid = input;
+    updateSidCheck(res);
     updateErr(errCm, sid, criteria);
     // updateCir(cir_distance, parseFloat(sid));
     updateRowBackground();
     updateCircleRowBackground();
     contentCsv(sid, criteria);
   });
+  // SID from the tool magnification
+  document.getElementById("toolHeightCm").addEventListener("input", (event) => {
+    localStorage.setItem("toolHeight", event.target.value);
+    updateSidCheck(res);
+  });
+  updateSidCheck(res);
+
   // criteria
   document.getElementById("radioForm").addEventListener("change", function () {
     const selectedOption = document.querySelector(
@@ -218,6 +256,73 @@ async function process() {
 // inputDiv.style.display = "none";
 // resultDiv.style.display = "grid";
 
+// run the analysis job, resolve with the result
+async function runAnalysis(filePaths, savePath) {
+  setProgress("load", 0);
+  currentJobId = null;
+  const unlisten = [];
+  const stopListen = () => unlisten.forEach((fn) => fn());
+  // events can arrive before start_processing returns the job id
+  const isCurrent = (payload) =>
+    currentJobId === null || payload.job_id === currentJobId;
+
+  const result = new Promise(async (resolve, reject) => {
+    unlisten.push(
+      await listen("analysis-progress", (event) => {
+        if (isCurrent(event.payload)) {
+          setProgress(event.payload.stage, event.payload.percent);
+        }
+      })
+    );
+    unlisten.push(
+      await listen("analysis-done", (event) => {
+        if (isCurrent(event.payload)) {
+          setProgress("rendering", 100);
+          resolve(event.payload.result);
+        }
+      })
+    );
+    unlisten.push(
+      await listen("analysis-error", (event) => {
+        if (isCurrent(event.payload)) {
+          reject(event.payload.error);
+        }
+      })
+    );
+    unlisten.push(
+      await listen("analysis-cancelled", (event) => {
+        if (isCurrent(event.payload)) {
+          reject("cancelled");
+        }
+      })
+    );
+    currentJobId = await invoke("start_processing", {
+      filePaths: filePaths,
+      savePath: savePath,
+      defects: defectMask.checked ? defectMaskSource() : null,
+    });
+  });
+
+  try {
+    return await result;
+  } finally {
+    stopListen();
+    currentJobId = null;
+  }
+}
+
+function setProgress(stage, percent) {
+  loadingText.textContent = `${stageNames[stage] || stage}... ${percent}%`;
+  loadingBar.style.width = `${percent}%`;
+}
+
+cancelBtn.addEventListener("click", async (event) => {
+  event.preventDefault();
+  if (currentJobId !== null) {
+    await invoke("cancel_processing", { jobId: currentJobId });
+  }
+});
+
 async function savePreviewImage(filePath, savePath, isLarge) {
   const res = await invoke("preview", {
     filePath: filePath,
@@ -257,6 +362,66 @@ function openFilefn() {
   });
 }
 
+// SID measured from the tool magnification against the header and typed-in SID,
+// a mismatch makes the % of SID errors unreliable
+async function updateSidCheck(res) {
+  const text = document.getElementById("sidCheckText");
+  const height = parseFloat(document.getElementById("toolHeightCm").value);
+  if (!(height > 0) || !(parseFloat(sid) > 0)) {
+    text.textContent = "";
+    return;
+  }
+  try {
+    const check = await invoke("sid_check", {
+      filePath: filePathsImage[0],
+      result: res,
+      toolHeight: height,
+      sid: parseFloat(sid),
+      profile: qaProfile.value,
+    });
+    const header =
+      check.header_sid_cm === null
+        ? ""
+        : `, header ${check.header_sid_cm.toFixed(1)} cm (${check.header_deviation_percent.toFixed(1)}%)`;
+    text.textContent =
+      `— measured SID ${check.measured_sid_cm.toFixed(1)} cm: entered ${check.entered_deviation_percent.toFixed(1)}%${header}` +
+      (check.passed ? "" : " — SID mismatch");
+    text.style.color = check.passed ? "blue" : "red";
+  } catch (err) {
+    text.textContent = `— ${err}`;
+    text.style.color = "red";
+  }
+}
+
+// heel effect and flatness of the large field, empty if not measured
+function flatnessSection(flatness, plotPath) {
+  if (!flatness) {
+    return "";
+  }
+  const heel = flatness.heel_axis === "x" ? flatness.x : flatness.y;
+  const transverse = flatness.heel_axis === "x" ? flatness.y : flatness.x;
+  const row = (name, p) => `
+                <tr>
+                  <td>${name}</td>
+                  <td>${p.gradient_percent.toFixed(2)}</td>
+                  <td>${p.flatness_percent.toFixed(2)}</td>
+                  <td>${p.symmetry_percent.toFixed(2)}</td>
+                </tr>`;
+  return `
+              <span id="flatRes">Field Flatness</span>
+              <table>
+                <tr>
+                  <th>Axis</th>
+                  <th>Gradient (%)</th>
+                  <th>Flatness (%)</th>
+                  <th>Symmetry (%)</th>
+                </tr>${row(`Anode-cathode (${flatness.heel_axis})`, heel)}${row("Transverse", transverse)}
+              </table>
+              <div class="flatnessImage">
+                <img src="${convertFileSrc(plotPath)}" />
+              </div>`;
+}
+
 async function updateTable(
   lengthCm,
   errCm,
@@ -295,6 +460,12 @@ async function updateTable(
             </form>
             <p>criteria</p></span
           >
+          <span id="sidVerify"
+            ><p>Tool height above detector:</p>
+            <input type="number" id="toolHeightCm" value="${localStorage.getItem("toolHeight") || ""}" />
+            <p>cm</p>
+            <p id="sidCheckText"></p></span
+          >
           <table>
             <tr>
               <th>Position</th>
@@ -357,6 +528,7 @@ async function updateTable(
                 <img id="resultImageCir" src="${convertFileSrc(savePath[1])}" />
                 <canvas id="canvasCir"></canvas>
               </div>
+              ${flatnessSection(lastResult.flatness, savePath[2])}
             </div>
 
             <div class="right-res">
@@ -391,6 +563,10 @@ async function updateTable(
 
 async function readFile(size) {
   const filePath = await openFilefn();
+  await loadFile(size, filePath);
+}
+
+async function loadFile(size, filePath) {
   if (filePath) {
     const lowerCasePath = filePath.toLowerCase();
     const split_ = lowerCasePath.split("\\");
@@ -536,6 +712,1133 @@ backBtn.addEventListener("click", (event) => {
   criteria = 1;
 });
 
+// DICOM receiver
+const scpBtn = document.getElementById("scpBtn");
+const scpPopup = document.getElementById("scpPopup");
+const scpAeTitle = document.getElementById("scpAeTitle");
+const scpPort = document.getElementById("scpPort");
+const scpLargeFirst = document.getElementById("scpLargeFirst");
+const scpToggle = document.getElementById("scpToggle");
+const scpStatus = document.getElementById("scpStatus");
+const scpLog = document.getElementById("scpLog");
+const scpCloseBtn = document.getElementById("scpCloseBtn");
+let scpRunning = false;
+
+scpAeTitle.value = localStorage.getItem("scpAeTitle") || "LIGHTBEAM";
+scpPort.value = localStorage.getItem("scpPort") || "11112";
+scpLargeFirst.checked = localStorage.getItem("scpLargeFirst") !== "false";
+
+scpBtn.addEventListener("click", async (event) => {
+  event.preventDefault();
+  const config = await invoke("scp_status");
+  scpRunning = config !== null;
+  updateScpStatus(config ? config.port : null);
+  const port = await invoke("api_status");
+  apiRunning = port !== null;
+  updateApiStatus(port);
+  scpPopup.style.display = "block";
+  overlay.style.display = "block";
+});
+
+scpCloseBtn.addEventListener("click", () => {
+  scpPopup.style.display = "none";
+  overlay.style.display = "none";
+});
+
+scpToggle.addEventListener("click", async () => {
+  if (scpRunning) {
+    await invoke("stop_scp");
+    scpRunning = false;
+    updateScpStatus(null);
+    return;
+  }
+  const port = parseInt(scpPort.value, 10);
+  const aeTitle = scpAeTitle.value.trim().toUpperCase();
+  if (!aeTitle || aeTitle.length > 16 || !(port > 0 && port < 65536)) {
+    await message("AE title (max 16 characters) and port are required", {
+      title: "LightBeamKKU",
+      type: "error",
+    });
+    return;
+  }
+  localStorage.setItem("scpAeTitle", aeTitle);
+  localStorage.setItem("scpPort", `${port}`);
+  localStorage.setItem("scpLargeFirst", `${scpLargeFirst.checked}`);
+  const inbox = await join(await appDataDir(), "inbox");
+  try {
+    const listenPort = await invoke("start_scp", {
+      config: { ae_title: aeTitle, port: port, inbox: inbox },
+      largeFirst: scpLargeFirst.checked,
+    });
+    scpRunning = true;
+    updateScpStatus(listenPort);
+  } catch (err) {
+    await message(`${err}`, { title: "LightBeamKKU", type: "error" });
+  }
+});
+
+function updateScpStatus(port) {
+  scpStatus.textContent = scpRunning ? `Listening on port ${port}` : "Stopped";
+  scpToggle.textContent = scpRunning ? "Stop" : "Start";
+  scpBtn.textContent = scpRunning ? "Receiver (on)" : "Receiver";
+}
+
+const apiPort = document.getElementById("apiPort");
+const apiToggle = document.getElementById("apiToggle");
+const apiStatus = document.getElementById("apiStatus");
+let apiRunning = false;
+
+apiPort.value = localStorage.getItem("apiPort") || "8787";
+
+apiToggle.addEventListener("click", async () => {
+  if (apiRunning) {
+    await invoke("stop_api");
+    apiRunning = false;
+    updateApiStatus(null);
+    return;
+  }
+  const port = parseInt(apiPort.value, 10);
+  if (!(port > 0 && port < 65536)) {
+    await message("Port is required", { title: "LightBeamKKU", type: "error" });
+    return;
+  }
+  localStorage.setItem("apiPort", `${port}`);
+  try {
+    const listenPort = await invoke("start_api", { port: port });
+    apiRunning = true;
+    updateApiStatus(listenPort);
+  } catch (err) {
+    await message(`${err}`, { title: "LightBeamKKU", type: "error" });
+  }
+});
+
+function updateApiStatus(port) {
+  apiStatus.textContent = apiRunning ? `http://127.0.0.1:${port}/analyses` : "Stopped";
+  apiToggle.textContent = apiRunning ? "Stop" : "Start";
+}
+
+listen("scp-received", (event) => {
+  const item = document.createElement("p");
+  item.textContent = `${event.payload.calling_ae}: ${event.payload.path}`;
+  scpLog.prepend(item);
+});
+
+// received pair: load both fields and analyse, unless busy with another one
+listen("scp-pair", async (event) => {
+  if (inputDiv.style.display === "none") {
+    return;
+  }
+  await loadFile("large", event.payload.large.path);
+  await loadFile("small", event.payload.small.path);
+  if (processBtn.style.cursor === "pointer") {
+    scpPopup.style.display = "none";
+    overlay.style.display = "none";
+    await process();
+  }
+});
+
+// PACS query/retrieve
+const pacsBtn = document.getElementById("pacsBtn");
+const pacsPopup = document.getElementById("pacsPopup");
+const pacsCloseBtn = document.getElementById("pacsCloseBtn");
+const pacsHost = document.getElementById("pacsHost");
+const pacsPort = document.getElementById("pacsPort");
+const pacsAeTitle = document.getElementById("pacsAeTitle");
+const qrSearch = document.getElementById("qrSearch");
+const qrResults = document.getElementById("qrResults");
+
+pacsHost.value = localStorage.getItem("pacsHost") || "127.0.0.1";
+pacsPort.value = localStorage.getItem("pacsPort") || "4242";
+pacsAeTitle.value = localStorage.getItem("pacsAeTitle") || "ORTHANC";
+
+pacsBtn.addEventListener("click", (event) => {
+  event.preventDefault();
+  pacsPopup.style.display = "block";
+  overlay.style.display = "block";
+});
+
+pacsCloseBtn.addEventListener("click", () => {
+  pacsPopup.style.display = "none";
+  overlay.style.display = "none";
+});
+
+function qrConfig() {
+  localStorage.setItem("pacsHost", pacsHost.value.trim());
+  localStorage.setItem("pacsPort", pacsPort.value);
+  localStorage.setItem("pacsAeTitle", pacsAeTitle.value.trim());
+  return {
+    ae_title: scpAeTitle.value.trim().toUpperCase(),
+    pacs_ae_title: pacsAeTitle.value.trim(),
+    host: pacsHost.value.trim(),
+    port: parseInt(pacsPort.value, 10),
+  };
+}
+
+qrSearch.addEventListener("click", async () => {
+  const query = {
+    station_name: document.getElementById("qrStation").value.trim(),
+    detector_id: document.getElementById("qrDetector").value.trim(),
+    date_from: document.getElementById("qrFrom").value.replaceAll("-", ""),
+    date_to: document.getElementById("qrTo").value.replaceAll("-", ""),
+    modality: document.getElementById("qrModality").value,
+  };
+  qrResults.innerHTML = "<p>searching...</p>";
+  let instances;
+  try {
+    instances = await invoke("qr_find", { config: qrConfig(), query: query });
+  } catch (err) {
+    qrResults.innerHTML = "";
+    await message(`${err}`, { title: "LightBeamKKU", type: "error" });
+    return;
+  }
+  qrResults.innerHTML = instances.length ? "" : "<p>no images found</p>";
+  instances.forEach((instance) => {
+    const row = document.createElement("span");
+    const text = document.createElement("p");
+    text.textContent = `${instance.acquisition_date} ${instance.acquisition_time.slice(0, 6)} ${instance.modality} ${instance.station_name} [${instance.detector_id}]`;
+    row.appendChild(text);
+    ["large", "small"].forEach((size) => {
+      const btn = document.createElement("button");
+      btn.textContent = size;
+      btn.addEventListener("click", () => retrieveInstance(size, instance, btn));
+      row.appendChild(btn);
+    });
+    qrResults.appendChild(row);
+  });
+});
+
+async function retrieveInstance(size, instance, btn) {
+  btn.disabled = true;
+  const inbox = await join(await appDataDir(), "inbox");
+  try {
+    const paths = await invoke("qr_retrieve", {
+      config: qrConfig(),
+      scpConfig: {
+        ae_title: scpAeTitle.value.trim().toUpperCase(),
+        port: parseInt(scpPort.value, 10),
+        inbox: inbox,
+      },
+      instances: [instance],
+    });
+    await loadFile(size, paths[0]);
+  } catch (err) {
+    await message(`${err}`, { title: "LightBeamKKU", type: "error" });
+  }
+  btn.disabled = false;
+}
+
+// CD/USB import (DICOMDIR or folder scan)
+const mediaBtn = document.getElementById("mediaBtn");
+const mediaPopup = document.getElementById("mediaPopup");
+const mediaCloseBtn = document.getElementById("mediaCloseBtn");
+const mediaSource = document.getElementById("mediaSource");
+const mediaResults = document.getElementById("mediaResults");
+
+mediaBtn.addEventListener("click", (event) => {
+  event.preventDefault();
+  mediaPopup.style.display = "block";
+  overlay.style.display = "block";
+});
+
+mediaCloseBtn.addEventListener("click", () => {
+  mediaPopup.style.display = "none";
+  overlay.style.display = "none";
+});
+
+document.getElementById("mediaFolderBtn").addEventListener("click", async () => {
+  const folder = await open({ directory: true, multiple: false, title: "CD/USB folder" });
+  if (folder) {
+    await scanMedia(folder);
+  }
+});
+
+document.getElementById("mediaDicomdirBtn").addEventListener("click", async () => {
+  const file = await open({ multiple: false, title: "DICOMDIR" });
+  if (file) {
+    await scanMedia(file);
+  }
+});
+
+async function scanMedia(path) {
+  mediaSource.textContent = path;
+  mediaResults.innerHTML = "<p>scanning...</p>";
+  let instances;
+  try {
+    instances = await invoke("scan_media", { path: path });
+  } catch (err) {
+    mediaResults.innerHTML = "";
+    await message(`${err}`, { title: "LightBeamKKU", type: "error" });
+    return;
+  }
+  mediaResults.innerHTML = instances.length ? "" : "<p>no images found</p>";
+  const thumbs = instances.map((instance) => {
+    const row = document.createElement("span");
+    const thumb = document.createElement("img");
+    row.appendChild(thumb);
+    const text = document.createElement("p");
+    text.textContent = `${instance.acquisition_date} ${instance.acquisition_time.slice(0, 6)} ${instance.modality} ${instance.station_name} [${instance.detector_id}] ${instance.patient_name} ${instance.series_description}`;
+    row.appendChild(text);
+    ["large", "small"].forEach((size) => {
+      const btn = document.createElement("button");
+      btn.textContent = size;
+      btn.addEventListener("click", async () => {
+        btn.disabled = true;
+        await loadFile(size, instance.path);
+        btn.disabled = false;
+      });
+      row.appendChild(btn);
+    });
+    mediaResults.appendChild(row);
+    return thumb;
+  });
+
+  // thumbnails one by one through the preview, the list is usable meanwhile
+  const tempDir = await tempdir();
+  const stamp = Date.now();
+  for (let i = 0; i < instances.length; i++) {
+    if (mediaSource.textContent !== path) {
+      return;
+    }
+    const savePath = `${tempDir}media${stamp}_${i}.jpg`;
+    await invoke("preview", { filePath: instances[i].path, savePath: savePath });
+    thumbs[i].src = convertFileSrc(savePath);
+  }
+}
+
+// Detector QA tests, each test: command, image inputs and result images
+// (optional inputs come last and may stay empty)
+const qaTests = {
+  uniformity: {
+    name: "Uniformity / SNR",
+    files: ["Flood image"],
+    outputs: ["map.png"],
+    run: (files, savePaths, profile) =>
+      invoke("uniformity", { filePath: files[0], savePath: savePaths[0], profile: profile }),
+    summary: (res) => [
+      ["ROI grid", `${res.grid[0]} x ${res.grid[1]} (${res.roi_size_px} px)`],
+      ["Mean", res.mean.toFixed(2)],
+      ["SD", res.sd.toFixed(2)],
+      ["SNR", res.snr.toFixed(2)],
+    ],
+  },
+  mtf: {
+    name: "MTF (slanted edge)",
+    files: ["Edge image", "Second edge image (optional)"],
+    required: 1,
+    outputs: ["curves.csv", "plot.png"],
+    run: (files, savePaths, profile) =>
+      invoke("mtf", { filePaths: files.filter((f) => f), savePath: savePaths, profile: profile }),
+    summary: (res) =>
+      res.curves.map((curve, i) => [
+        `${curve.direction} (${["blue", "red"][i]})`,
+        `MTF50 ${curve.mtf50 === null ? "-" : curve.mtf50.toFixed(3)}, MTF10 ${curve.mtf10 === null ? "-" : curve.mtf10.toFixed(3)} ${res.pixel_spacing_mm === null ? "cycles/pixel" : "lp/mm"}`,
+      ]),
+  },
+  nps: {
+    name: "Noise power spectrum",
+    files: ["Flat-field image"],
+    outputs: ["nnps.csv", "plot.png"],
+    run: (files, savePaths, profile) =>
+      invoke("nps", { filePath: files[0], savePath: savePaths, profile: profile }),
+    summary: (res) => [
+      ["ROIs", `${res.roi_count} (${res.roi_size_px} px)`],
+      ["Mean", res.mean.toFixed(2)],
+      ...res.nnps.map(([f, value]) => [`NNPS at ${f} lp/mm`, `${value.toExponential(3)} mm²`]),
+      ["Plot", "log10 NNPS: horizontal (blue), vertical (red), radial (green)"],
+    ],
+  },
+  ei: {
+    name: "Exposure index (IEC 62494-1)",
+    files: [],
+    // any number of images, each with a value
+    series: { label: "Flat exposures", value: "Air kerma (µGy)", required: 2 },
+    outputs: ["exposures.csv", "plot.png"],
+    run: (files, savePaths, profile, values) =>
+      invoke("exposure_index", { filePaths: files, kerma: values, savePath: savePaths, profile: profile }),
+    summary: (res) => [
+      ["Fit", `EI = ${res.slope.toFixed(2)} x kerma + ${res.intercept.toFixed(1)}`],
+      ...res.exposures.map((e) => [
+        `${e.kerma_ugy} µGy`,
+        `EI ${e.exposure_index.toFixed(0)}, DI ${e.deviation_index === null ? "-" : e.deviation_index.toFixed(2)} (expected ${e.expected_deviation_index === null ? "-" : e.expected_deviation_index.toFixed(2)}), ${e.calibration_error.toFixed(1)}%`,
+      ]),
+      ["Plot", "EI (blue), fit (red), 100 x kerma (green)"],
+    ],
+  },
+  linepair: {
+    name: "Limiting resolution (line pairs)",
+    files: ["Phantom image", "Second phantom image, other axis (optional)"],
+    required: 1,
+    phantom: "linepair",
+    outputs: ["groups.csv", "plot.png"],
+    run: (files, savePaths, profile) =>
+      invoke("linepair", {
+        filePaths: files.filter((f) => f),
+        savePath: savePaths,
+        phantom: qaPhantom.value,
+        profile: profile,
+      }),
+    summary: (res) => [
+      ["Phantom", res.phantom],
+      ...res.axes.map((axis, i) => [
+        `${axis.direction} (${["blue", "red"][i]})`,
+        `${axis.limiting_resolution} lp/mm, tilt ${axis.angle.toFixed(2)}°`,
+      ]),
+    ],
+  },
+  contrast: {
+    name: "Contrast-detail (CNR)",
+    files: ["Phantom image"],
+    phantom: "contrast",
+    outputs: ["details.csv", "plot.png", "overlay.png"],
+    run: (files, savePaths, profile) =>
+      invoke("contrast", { filePath: files[0], savePath: savePaths, phantom: qaPhantom.value, profile: profile }),
+    summary: (res) => [
+      ["Phantom", res.phantom],
+      ["Registration", `offset ${res.offset_mm.map((v) => v.toFixed(1)).join(", ")} mm, angle ${res.angle.toFixed(1)}°`],
+      ["Visible", `${res.visible} of ${res.measurements.length}`],
+      ...res.threshold_contrast.map((t) => [
+        `Threshold contrast ${t.diameter_mm} mm`,
+        t.contrast === null ? "-" : `${t.contrast.toFixed(2)}%`,
+      ]),
+      ["Overlay", "visible (green), not visible (red)"],
+    ],
+  },
+  defects: {
+    name: "Defect map (bad pixels/lines)",
+    files: ["Flat-field image"],
+    defects: true,
+    outputs: ["defects.png"],
+    run: async (files, savePaths, profile) => {
+      const res = await invoke("defects", { filePath: files[0], savePath: savePaths[0], thresholds: defectThresholds(), profile: profile });
+      // its defect map is masked in the collimator analysis
+      localStorage.setItem("defectFlatField", files[0]);
+      return res;
+    },
+    summary: (res) => [
+      ["Defect pixels", `${res.pixel_count}`],
+      ["Defect rows", res.rows.length ? res.rows.join(", ") : "-"],
+      ["Defect columns", res.columns.length ? res.columns.join(", ") : "-"],
+      ...res.clusters.map((c) => [`Clusters of ${c.size} px`, `${c.count}`]),
+      ["Map", "defect pixels (black), defect lines (red)"],
+    ],
+  },
+  lag: {
+    name: "Lag / ghosting",
+    files: ["Block exposure", "Following flat exposure"],
+    outputs: ["ghost.png"],
+    run: (files, savePaths, profile) =>
+      invoke("lag", { filePaths: files, savePath: savePaths[0], profile: profile }),
+    summary: (res) => [
+      ["Interval", res.interval_s === null ? "-" : `${res.interval_s} s`],
+      ["Block", `${res.block.ncols} x ${res.block.nrows} px at (${res.block.col}, ${res.block.row})`],
+      ["Ghost", `${res.ghost_percent.toFixed(3)}% (CNR ${res.ghost_cnr.toFixed(2)})`],
+      ["Lag factor", formatValue(res.lag_factor)],
+      ["Image", "block (yellow), inside (red) and outside (green) ROIs"],
+    ],
+  },
+  focal: {
+    name: "Focal spot size",
+    files: ["Star pattern or pinhole image"],
+    // test settings, entered above the images
+    params: [
+      { key: "method", label: "Test object", options: { star: "Star pattern", pinhole: "Pinhole" } },
+      { key: "angle", label: "Star spoke angle (degree)", value: "2" },
+      { key: "magnification", label: "Magnification (empty: SID/SOD)", value: "" },
+      { key: "nominal", label: "Nominal focal spot (mm)", value: "" },
+    ],
+    outputs: ["plot.png", "report.pdf"],
+    run: (files, savePaths, profile, values, params) => {
+      const number = (value) => (value.trim() === "" ? null : parseFloat(value));
+      const method = params.method === "star" ? { kind: "star", angle: parseFloat(params.angle) } : { kind: "pinhole" };
+      return invoke("focal_spot", {
+        filePath: files[0],
+        savePath: savePaths,
+        method: method,
+        magnification: number(params.magnification),
+        nominal: number(params.nominal),
+        profile: profile,
+      });
+    },
+    summary: (res) => [
+      ["Magnification", `${res.magnification.toFixed(3)} (${res.magnification_source})`],
+      [res.method.kind === "star" ? "Blur diameter" : "Spot width", res.measured_mm.map((v) => `${v.toFixed(2)} mm`).join(" x ")],
+      ["Focal spot", res.size_mm.map((v) => `${v.toFixed(2)} mm`).join(" x ")],
+      [
+        "Plot",
+        res.method.kind === "star"
+          ? "modulation against radius: +x (blue), +y (red), -x (green), -y (orange)"
+          : "profiles along x (blue) and y (red)",
+      ],
+    ],
+  },
+  aec: {
+    name: "AEC consistency",
+    files: [],
+    // one exposure per phantom thickness / kV
+    series: { label: "AEC exposures", value: "Phantom thickness (mm)", required: 2 },
+    outputs: ["aec.csv", "plot.png"],
+    run: (files, savePaths, profile, values) =>
+      invoke("aec", { filePaths: files, thickness: values, savePath: savePaths, profile: profile }),
+    summary: (res) => [
+      ["Room", res.room],
+      ["Mean deviation", `${res.mean_deviation.toFixed(2)}% (CV ${res.mean_cv.toFixed(2)}%)`],
+      ["SNR deviation", `${res.snr_deviation.toFixed(2)}%`],
+      ["EI deviation", res.ei_deviation === null ? "-" : `${res.ei_deviation.toFixed(2)}%`],
+      ...res.exposures.map((e) => [
+        `${e.thickness} mm`,
+        `${e.kvp === null ? "-" : e.kvp.toFixed(0)} kV, ${e.mas === null ? "-" : e.mas.toFixed(2)} mAs: mean ${e.mean.toFixed(1)}, SNR ${e.snr.toFixed(1)}, EI ${e.exposure_index === null ? "-" : e.exposure_index.toFixed(0)}`,
+      ]),
+      ["Plot", "% of the series mean: mean (blue), SNR (red), EI (green)"],
+    ],
+  },
+  grid: {
+    name: "Grid alignment",
+    files: ["Flood image with the grid"],
+    outputs: ["profile.png", "spectrum.png"],
+    run: (files, savePaths, profile) =>
+      invoke("grid", { filePath: files[0], savePath: savePaths, profile: profile }),
+    summary: (res) => {
+      const peak = res.line_direction === "vertical" ? res.rows : res.columns;
+      return [
+        ["Grid lines", res.line_direction],
+        ["Cut-off (start / end)", `${res.cutoff_start_percent.toFixed(2)}% / ${res.cutoff_end_percent.toFixed(2)}%`],
+        ["Asymmetry", `${res.asymmetry_percent.toFixed(2)}%`],
+        ["Decentering", res.decentering_mm === null ? "-" : `${res.decentering_mm.toFixed(1)} mm`],
+        ["Grid line peak", `${peak.peak_frequency.toFixed(3)} lp/mm (${peak.peak_ratio.toFixed(1)} x median)`],
+        ["Plots", "profile across the grid lines (50 - 110%); line spectra of rows (blue) and columns (red)"],
+      ];
+    },
+  },
+};
+const qaBtn = document.getElementById("qaBtn");
+const qaPopup = document.getElementById("qaPopup");
+const qaTest = document.getElementById("qaTest");
+const qaProfile = document.getElementById("qaProfile");
+const qaFiles = document.getElementById("qaFiles");
+const qaStatus = document.getElementById("qaStatus");
+const qaResults = document.getElementById("qaResults");
+const qaDetector = document.getElementById("qaDetector");
+const qaDetectorLabel = document.getElementById("qaDetectorLabel");
+const qaPhantom = document.getElementById("qaPhantom");
+let qaFilePaths = [];
+let qaValues = [];
+let qaParams = {};
+
+// small values (e.g. NNPS) in exponent notation
+function formatValue(value) {
+  return value !== 0 && Math.abs(value) < 0.01 ? value.toExponential(3) : value.toFixed(3);
+}
+
+Object.entries(qaTests).forEach(([key, test]) => {
+  const option = document.createElement("option");
+  option.value = key;
+  option.textContent = test.name;
+  qaTest.appendChild(option);
+});
+
+qaBtn.addEventListener("click", async (event) => {
+  event.preventDefault();
+  await loadProfiles(qaProfile);
+  await loadDetectors();
+  await showQaFiles();
+  qaPopup.style.display = "block";
+  overlay.style.display = "block";
+});
+
+document.getElementById("qaCloseBtn").addEventListener("click", () => {
+  qaPopup.style.display = "none";
+  overlay.style.display = "none";
+});
+
+qaTest.addEventListener("change", async () => {
+  await loadDetectors();
+  await showQaFiles();
+});
+
+async function showQaFiles() {
+  const test = qaTests[qaTest.value];
+  qaFilePaths = test.files.map(() => "");
+  qaValues = [];
+  qaFiles.innerHTML = "";
+  qaResults.innerHTML = "";
+  qaStatus.textContent = "";
+  document.getElementById("qaPhantomRow").style.display = test.phantom ? "flex" : "none";
+  document.getElementById("qaDefectRow").style.display = test.defects ? "flex" : "none";
+  if (test.phantom) {
+    phantomKind = test.phantom;
+    await loadPhantoms(qaPhantom);
+  }
+  qaParams = {};
+  (test.params || []).forEach((param) => {
+    const row = document.createElement("span");
+    const label = document.createElement("label");
+    let input;
+    if (param.options) {
+      input = document.createElement("select");
+      Object.entries(param.options).forEach(([value, text]) => {
+        const option = document.createElement("option");
+        option.value = value;
+        option.textContent = text;
+        input.appendChild(option);
+      });
+    } else {
+      input = document.createElement("input");
+      input.type = "text";
+      input.value = param.value;
+    }
+    qaParams[param.key] = input.value;
+    input.addEventListener("change", () => (qaParams[param.key] = input.value));
+    label.textContent = `${param.label} `;
+    label.appendChild(input);
+    row.appendChild(label);
+    qaFiles.appendChild(row);
+  });
+  test.files.forEach((label, i) => {
+    const row = document.createElement("span");
+    const btn = document.createElement("button");
+    const text = document.createElement("p");
+    btn.textContent = label;
+    text.textContent = "-";
+    btn.addEventListener("click", async () => {
+      const file = await open({ multiple: false, title: label });
+      if (file) {
+        qaFilePaths[i] = file;
+        text.textContent = file;
+      }
+    });
+    row.appendChild(btn);
+    row.appendChild(text);
+    qaFiles.appendChild(row);
+  });
+  if (test.series) {
+    const btn = document.createElement("button");
+    btn.textContent = `Add ${test.series.label.toLowerCase()}`;
+    btn.addEventListener("click", async () => {
+      const files = await open({ multiple: true, title: test.series.label });
+      (files || []).forEach((file) => addSeriesRow(test, file));
+    });
+    qaFiles.appendChild(btn);
+  }
+}
+
+// one image of a series with its value (e.g. air kerma)
+function addSeriesRow(test, file) {
+  const index = qaFilePaths.length;
+  qaFilePaths.push(file);
+  qaValues.push(NaN);
+  const row = document.createElement("span");
+  const input = document.createElement("input");
+  const text = document.createElement("p");
+  const remove = document.createElement("button");
+  input.type = "number";
+  input.step = "any";
+  input.placeholder = test.series.value;
+  input.addEventListener("input", () => (qaValues[index] = parseFloat(input.value)));
+  text.textContent = file;
+  remove.textContent = "Remove";
+  remove.addEventListener("click", () => {
+    // keep the indices of the other rows
+    qaFilePaths[index] = "";
+    row.remove();
+  });
+  row.appendChild(input);
+  row.appendChild(text);
+  row.appendChild(remove);
+  qaFiles.insertBefore(row, qaFiles.lastChild);
+}
+
+document.getElementById("qaRun").addEventListener("click", async () => {
+  const test = qaTests[qaTest.value];
+  const required = test.required === undefined ? test.files.length : test.required;
+  if (qaFilePaths.slice(0, required).some((path) => !path)) {
+    await message("Choose the images first", { title: "LightBeamKKU", type: "error" });
+    return;
+  }
+  let files = qaFilePaths;
+  let values = [];
+  if (test.series) {
+    const rows = qaFilePaths.map((path, i) => [path, qaValues[i]]).filter(([path]) => path);
+    if (rows.length < test.series.required) {
+      await message(`Add at least ${test.series.required} images`, { title: "LightBeamKKU", type: "error" });
+      return;
+    }
+    if (rows.some(([, value]) => isNaN(value))) {
+      await message(`Enter the ${test.series.value} of every image`, { title: "LightBeamKKU", type: "error" });
+      return;
+    }
+    files = rows.map(([path]) => path);
+    values = rows.map(([, value]) => value);
+  }
+  const tempDir = await tempdir();
+  const stamp = Date.now();
+  const savePaths = test.outputs.map((name) => `${tempDir}${qaTest.value}${stamp}_${name}`);
+  qaStatus.textContent = "running...";
+  qaResults.innerHTML = "";
+  try {
+    const res = await test.run(files, savePaths, qaProfile.value, values, qaParams);
+    qaStatus.textContent = res.passed ? "passed" : "failed";
+    showQaResult(test, res, savePaths);
+    await loadDetectors();
+  } catch (err) {
+    qaStatus.textContent = "";
+    await message(`${err}`, { title: "LightBeamKKU", type: "error" });
+  }
+});
+
+function showQaResult(test, res, savePaths) {
+  const table = document.createElement("table");
+  table.innerHTML = "<tr><th>Check</th><th>Value</th><th>Min</th><th>Max</th><th>Status</th></tr>";
+  const limit = (value) => (value === null || value === undefined ? "-" : value);
+  res.checks.forEach((check) => {
+    const row = document.createElement("tr");
+    const status = check.passed === null ? "-" : check.passed ? "passed" : "failed";
+    [check.key, formatValue(check.value), limit(check.tolerance.min), limit(check.tolerance.max), status].forEach(
+      (value) => {
+        const cell = document.createElement("td");
+        cell.textContent = value;
+        row.appendChild(cell);
+      }
+    );
+    if (check.passed === false) {
+      row.classList.add("qa-failed");
+    }
+    table.appendChild(row);
+  });
+  test.summary(res).forEach(([label, value]) => {
+    const row = document.createElement("tr");
+    row.innerHTML = `<td>${label}</td><td colspan="4">${value}</td>`;
+    table.appendChild(row);
+  });
+  qaResults.appendChild(table);
+  savePaths.forEach((path, i) => {
+    const name = test.outputs[i];
+    if (name.endsWith(".png")) {
+      const img = document.createElement("img");
+      img.src = convertFileSrc(path);
+      qaResults.appendChild(img);
+      return;
+    }
+    // data files are kept in the temp folder until saved
+    const btn = document.createElement("button");
+    btn.textContent = `Save ${name}`;
+    btn.addEventListener("click", async () => {
+      const extension = name.split(".").pop();
+      const target = await save({
+        filters: [{ name: extension, extensions: [extension] }],
+        defaultPath: `${qaTest.value}-${name}`,
+      });
+      if (target) {
+        await copyFile(path, target);
+      }
+    });
+    qaResults.appendChild(btn);
+  });
+}
+
+async function loadDetectors() {
+  const selected = qaDetector.value;
+  // AEC results are stored per room
+  qaDetectorLabel.textContent = qaTest.value === "aec" ? "Room" : "Detector";
+  const detectors = await invoke("history_detectors", { test: qaTest.value });
+  qaDetector.innerHTML = "";
+  detectors.forEach((detector) => {
+    const option = document.createElement("option");
+    option.value = detector;
+    option.textContent = detector;
+    qaDetector.appendChild(option);
+  });
+  if (detectors.includes(selected)) {
+    qaDetector.value = selected;
+  }
+}
+
+// stored results of the selected test and detector, oldest first
+document.getElementById("qaHistory").addEventListener("click", async () => {
+  if (!qaDetector.value) {
+    await message("No QA history yet", { title: "LightBeamKKU", type: "info" });
+    return;
+  }
+  const entries = await invoke("qa_history", { detector: qaDetector.value, test: qaTest.value });
+  const keys = [...new Set(entries.flatMap((entry) => Object.keys(entry.values)))];
+  const table = document.createElement("table");
+  const header = document.createElement("tr");
+  ["Acquired", ...keys, "Status"].forEach((key) => {
+    const cell = document.createElement("th");
+    cell.textContent = key;
+    header.appendChild(cell);
+  });
+  table.appendChild(header);
+  entries.forEach((entry) => {
+    const row = document.createElement("tr");
+    const status = entry.passed === null ? "-" : entry.passed ? "passed" : "failed";
+    const values = keys.map((key) => (key in entry.values ? formatValue(entry.values[key]) : "-"));
+    [entry.acquired || "-", ...values, status].forEach((value) => {
+      const cell = document.createElement("td");
+      cell.textContent = value;
+      row.appendChild(cell);
+    });
+    if (entry.passed === false) {
+      row.classList.add("qa-failed");
+    }
+    table.appendChild(row);
+  });
+  qaStatus.textContent = `${entries.length} results of ${qaDetector.value}`;
+  qaResults.innerHTML = "";
+  qaResults.appendChild(table);
+});
+
+// tolerance profiles
+const tolerancePopup = document.getElementById("tolerancePopup");
+const toleranceProfile = document.getElementById("toleranceProfile");
+const toleranceName = document.getElementById("toleranceName");
+const toleranceLimits = document.getElementById("toleranceLimits");
+let profiles = [];
+
+async function loadProfiles(select) {
+  const selected = select.value;
+  profiles = await invoke("tolerance_profiles");
+  select.innerHTML = "";
+  profiles.forEach((profile) => {
+    const option = document.createElement("option");
+    option.value = profile.name;
+    option.textContent = profile.name;
+    select.appendChild(option);
+  });
+  if (profiles.some((profile) => profile.name === selected)) {
+    select.value = selected;
+  }
+}
+
+document.getElementById("qaToleranceBtn").addEventListener("click", async () => {
+  await loadProfiles(toleranceProfile);
+  toleranceProfile.value = qaProfile.value;
+  showLimits();
+  qaPopup.style.display = "none";
+  tolerancePopup.style.display = "block";
+});
+
+document.getElementById("toleranceCloseBtn").addEventListener("click", async () => {
+  tolerancePopup.style.display = "none";
+  await loadProfiles(qaProfile);
+  qaPopup.style.display = "block";
+});
+
+toleranceProfile.addEventListener("change", showLimits);
+
+function showLimits() {
+  const profile = profiles.find((p) => p.name === toleranceProfile.value) || profiles[0];
+  toleranceName.value = profile.name;
+  const table = document.createElement("table");
+  table.innerHTML = "<tr><th>Value</th><th>Min</th><th>Max</th></tr>";
+  Object.entries(profile.limits).forEach(([key, tolerance]) => {
+    const row = document.createElement("tr");
+    row.innerHTML = `<td>${key}</td>`;
+    ["min", "max"].forEach((bound) => {
+      const cell = document.createElement("td");
+      const input = document.createElement("input");
+      input.type = "number";
+      input.step = "any";
+      input.value = tolerance[bound] === null ? "" : tolerance[bound];
+      input.dataset.key = key;
+      input.dataset.bound = bound;
+      cell.appendChild(input);
+      row.appendChild(cell);
+    });
+    table.appendChild(row);
+  });
+  toleranceLimits.innerHTML = "";
+  toleranceLimits.appendChild(table);
+}
+
+document.getElementById("toleranceNew").addEventListener("click", () => {
+  const base = profiles.find((p) => p.name === toleranceProfile.value) || profiles[0];
+  const profile = JSON.parse(JSON.stringify(base));
+  profile.name = `${base.name} copy`;
+  profiles.push(profile);
+  const option = document.createElement("option");
+  option.value = profile.name;
+  option.textContent = profile.name;
+  toleranceProfile.appendChild(option);
+  toleranceProfile.value = profile.name;
+  showLimits();
+});
+
+document.getElementById("toleranceSave").addEventListener("click", async () => {
+  const profile = profiles.find((p) => p.name === toleranceProfile.value);
+  const name = toleranceName.value.trim();
+  if (!name || profiles.some((p) => p !== profile && p.name === name)) {
+    await message("Profile names must be unique", { title: "LightBeamKKU", type: "error" });
+    return;
+  }
+  profile.name = name;
+  toleranceLimits.querySelectorAll("input").forEach((input) => {
+    const value = input.value === "" ? null : parseFloat(input.value);
+    profile.limits[input.dataset.key][input.dataset.bound] = value;
+  });
+  try {
+    await invoke("save_tolerance_profiles", { profiles: profiles });
+    await loadProfiles(toleranceProfile);
+    toleranceProfile.value = name;
+    showLimits();
+  } catch (err) {
+    await message(`${err}`, { title: "LightBeamKKU", type: "error" });
+  }
+});
+
+// defect thresholds, also used for the defect mask of the collimator analysis
+const defectPixel = document.getElementById("defectPixel");
+const defectLine = document.getElementById("defectLine");
+const defectMask = document.getElementById("defectMask");
+defectPixel.value = localStorage.getItem("defectPixel") || "20";
+defectLine.value = localStorage.getItem("defectLine") || "5";
+defectMask.checked = localStorage.getItem("defectMask") === "true";
+
+function defectThresholds() {
+  return { pixel: parseFloat(defectPixel.value) / 100, line: parseFloat(defectLine.value) / 100 };
+}
+
+// flat field of the last defect map, null if none was run yet
+function defectMaskSource() {
+  const flatField = localStorage.getItem("defectFlatField");
+  return flatField ? { flat_field: flatField, thresholds: defectThresholds() } : null;
+}
+
+[defectPixel, defectLine].forEach((input) =>
+  input.addEventListener("change", () => localStorage.setItem(input.id, input.value))
+);
+defectMask.addEventListener("change", () => localStorage.setItem("defectMask", `${defectMask.checked}`));
+
+// phantom layouts of the line-pair and contrast-detail tests
+const phantomPopup = document.getElementById("phantomPopup");
+const phantomSelect = document.getElementById("phantomSelect");
+const phantomName = document.getElementById("phantomName");
+const phantomGroups = document.getElementById("phantomGroups");
+const phantomBars = document.getElementById("phantomBars");
+const phantomThreshold = document.getElementById("phantomThreshold");
+const phantomMagnification = document.getElementById("phantomMagnification");
+const phantomRose = document.getElementById("phantomRose");
+const phantomDetails = document.getElementById("phantomDetails");
+const phantomTitles = { linepair: "Line-pair Phantoms", contrast: "Contrast-detail Phantoms" };
+let phantomKind = "linepair";
+let phantoms = [];
+
+async function loadPhantoms(select) {
+  const selected = select.value;
+  phantoms = await invoke(`${phantomKind}_phantoms`);
+  select.innerHTML = "";
+  phantoms.forEach((phantom) => {
+    const option = document.createElement("option");
+    option.value = phantom.name;
+    option.textContent = phantom.name;
+    select.appendChild(option);
+  });
+  if (phantoms.some((phantom) => phantom.name === selected)) {
+    select.value = selected;
+  }
+}
+
+document.getElementById("qaPhantomBtn").addEventListener("click", async () => {
+  await loadPhantoms(phantomSelect);
+  phantomSelect.value = qaPhantom.value;
+  document.getElementById("phantomTitle").textContent = phantomTitles[phantomKind];
+  document.getElementById("phantomLinepair").style.display = phantomKind === "linepair" ? "block" : "none";
+  document.getElementById("phantomContrast").style.display = phantomKind === "contrast" ? "block" : "none";
+  showPhantom();
+  qaPopup.style.display = "none";
+  phantomPopup.style.display = "block";
+});
+
+document.getElementById("phantomCloseBtn").addEventListener("click", async () => {
+  phantomPopup.style.display = "none";
+  await loadPhantoms(qaPhantom);
+  qaPopup.style.display = "block";
+});
+
+phantomSelect.addEventListener("change", showPhantom);
+
+function showPhantom() {
+  const phantom = phantoms.find((p) => p.name === phantomSelect.value) || phantoms[0];
+  phantomName.value = phantom.name;
+  if (phantomKind === "linepair") {
+    phantomGroups.value = phantom.groups.join(", ");
+    phantomBars.value = phantom.bars;
+    phantomThreshold.value = phantom.threshold;
+  } else {
+    phantomMagnification.value = phantom.magnification;
+    phantomRose.value = phantom.threshold;
+    phantomDetails.value = phantom.details
+      .map((d) => [d.x_mm, d.y_mm, d.diameter_mm, d.contrast].join(", "))
+      .join("\n");
+  }
+}
+
+document.getElementById("phantomNew").addEventListener("click", () => {
+  const base = phantoms.find((p) => p.name === phantomSelect.value) || phantoms[0];
+  const phantom = JSON.parse(JSON.stringify(base));
+  phantom.name = `${base.name} copy`;
+  phantoms.push(phantom);
+  const option = document.createElement("option");
+  option.value = phantom.name;
+  option.textContent = phantom.name;
+  phantomSelect.appendChild(option);
+  phantomSelect.value = phantom.name;
+  showPhantom();
+});
+
+// layout fields of the editor, null if not valid
+function readPhantom() {
+  if (phantomKind === "linepair") {
+    const groups = phantomGroups.value.split(/[\s,;]+/).filter((v) => v).map(parseFloat);
+    const bars = parseInt(phantomBars.value);
+    const threshold = parseFloat(phantomThreshold.value);
+    if (groups.length === 0 || groups.some((f) => isNaN(f) || f <= 0) || isNaN(bars) || bars < 2 || isNaN(threshold)) {
+      return null;
+    }
+    return { groups, bars, threshold };
+  }
+  const details = phantomDetails.value
+    .split("\n")
+    .filter((line) => line.trim())
+    .map((line) => line.split(/[\s,;]+/).filter((v) => v).map(parseFloat));
+  const magnification = parseFloat(phantomMagnification.value);
+  const threshold = parseFloat(phantomRose.value);
+  if (
+    details.length === 0 ||
+    details.some((d) => d.length !== 4 || d.some(isNaN) || d[2] <= 0) ||
+    isNaN(magnification) ||
+    magnification <= 0 ||
+    isNaN(threshold)
+  ) {
+    return null;
+  }
+  return {
+    details: details.map(([x_mm, y_mm, diameter_mm, contrast]) => ({ x_mm, y_mm, diameter_mm, contrast })),
+    magnification,
+    threshold,
+  };
+}
+
+document.getElementById("phantomSave").addEventListener("click", async () => {
+  const phantom = phantoms.find((p) => p.name === phantomSelect.value);
+  const name = phantomName.value.trim();
+  if (!name || phantoms.some((p) => p !== phantom && p.name === name)) {
+    await message("Phantom names must be unique", { title: "LightBeamKKU", type: "error" });
+    return;
+  }
+  const layout = readPhantom();
+  if (!layout) {
+    const fields = phantomKind === "linepair" ? "the group frequencies, bars and threshold" : "the details, magnification and SNR";
+    await message(`Enter ${fields}`, { title: "LightBeamKKU", type: "error" });
+    return;
+  }
+  Object.assign(phantom, { name }, layout);
+  try {
+    await invoke(`save_${phantomKind}_phantoms`, { phantoms: phantoms });
+    await loadPhantoms(phantomSelect);
+    phantomSelect.value = name;
+    showPhantom();
+  } catch (err) {
+    await message(`${err}`, { title: "LightBeamKKU", type: "error" });
+  }
+});
+
+// Watch folder
+const watchBtn = document.getElementById("watchBtn");
+const watchPopup = document.getElementById("watchPopup");
+const watchCloseBtn = document.getElementById("watchCloseBtn");
+const watchInput = document.getElementById("watchInput");
+const watchOutput = document.getElementById("watchOutput");
+const watchToggle = document.getElementById("watchToggle");
+const watchStatus = document.getElementById("watchStatus");
+const watchLog = document.getElementById("watchLog");
+let watchRunning = false;
+
+watchInput.textContent = localStorage.getItem("watchInput") || "-";
+watchOutput.textContent = localStorage.getItem("watchOutput") || "-";
+
+watchBtn.addEventListener("click", async (event) => {
+  event.preventDefault();
+  watchRunning = (await invoke("watch_status")) !== null;
+  updateWatchStatus();
+  watchPopup.style.display = "block";
+  overlay.style.display = "block";
+});
+
+watchCloseBtn.addEventListener("click", () => {
+  watchPopup.style.display = "none";
+  overlay.style.display = "none";
+});
+
+async function chooseFolder(title, target, key) {
+  const folder = await open({ directory: true, multiple: false, title: title });
+  if (folder) {
+    target.textContent = folder;
+    localStorage.setItem(key, folder);
+  }
+}
+
+document.getElementById("watchInputBtn").addEventListener("click", () =>
+  chooseFolder("Folder to watch", watchInput, "watchInput")
+);
+document.getElementById("watchOutputBtn").addEventListener("click", () =>
+  chooseFolder("Folder for results", watchOutput, "watchOutput")
+);
+
+const watchRetainDevice = document.getElementById("watchRetainDevice");
+watchRetainDevice.checked = localStorage.getItem("watchRetainDevice") !== "false";
+watchRetainDevice.addEventListener("change", () =>
+  localStorage.setItem("watchRetainDevice", `${watchRetainDevice.checked}`)
+);
+
+watchToggle.addEventListener("click", async () => {
+  if (watchRunning) {
+    await invoke("stop_watch");
+    watchRunning = false;
+    updateWatchStatus();
+    return;
+  }
+  if (watchInput.textContent === "-" || watchOutput.textContent === "-") {
+    await message("Choose the input and output folders", {
+      title: "LightBeamKKU",
+      type: "error",
+    });
+    return;
+  }
+  try {
+    await invoke("start_watch", {
+      config: {
+        input: watchInput.textContent,
+        output: watchOutput.textContent,
+        archive: null,
+        large_first: scpLargeFirst.checked,
+        deident: { retain_device_identity: watchRetainDevice.checked },
+        sid_cm: parseFloat(sid),
+        criteria: criteria,
+      },
+    });
+    watchRunning = true;
+    updateWatchStatus();
+  } catch (err) {
+    await message(`${err}`, { title: "LightBeamKKU", type: "error" });
+  }
+});
+
+function updateWatchStatus() {
+  watchStatus.textContent = watchRunning ? "Watching" : "Stopped";
+  watchToggle.textContent = watchRunning ? "Stop" : "Start";
+  watchBtn.textContent = watchRunning ? "Watch Folder (on)" : "Watch Folder";
+}
+
+listen("watch-result", (event) => {
+  const { kind, pair } = event.payload;
+  const item = document.createElement("p");
+  item.textContent =
+    kind === "analysed"
+      ? `[${pair.large.detector}] analysed -> ${event.payload.output}`
+      : `[${pair.large.detector}] failed: ${event.payload.error}`;
+  watchLog.prepend(item);
+});
+
 // Database Pop-Up
 // openDb.addEventListener("click", (event) => {
 //   event.preventDefault();
@@ -589,6 +1892,14 @@ closeBtn.addEventListener("click", () => {
 // Close when clicking outside the popup
 overlay.addEventListener("click", () => {
   popup.style.display = "none";
+  scpPopup.style.display = "none";
+  pacsPopup.style.display = "none";
+  mediaPopup.style.display = "none";
+  qaPopup.style.display = "none";
+  tolerancePopup.style.display = "none";
+  phantomPopup.style.display = "none";
+  watchPopup.style.display = "none";
+  qatrackPopup.style.display = "none";
   overlay.style.display = "none";
 });
 
@@ -987,6 +2298,109 @@ exportBtn.addEventListener("click", () => {
   save2Csv(savePath, contenCsv);
 });
 
+// de-identified copies of the analysed pair (PS3.15 Basic Profile)
+document.getElementById("dicomExportBtn").addEventListener("click", async () => {
+  const outDir = await open({ directory: true, multiple: false, title: "Export DICOM to" });
+  if (!outDir) {
+    return;
+  }
+  const retain = await ask("Keep Detector ID and Station Name?", {
+    title: "LightBeamKKU",
+    type: "info",
+  });
+  try {
+    const files = await invoke("export_dicom", {
+      filePaths: filePathsImage,
+      outDir: outDir,
+      options: { retain_device_identity: retain },
+    });
+    alert(`Saved:\n${files.join("\n")}`);
+  } catch (err) {
+    await message(`${err}`, { title: "LightBeamKKU", type: "error" });
+  }
+});
+
+// QATrack+ export, macro names are kept per site in localStorage
+const qatrackBtn = document.getElementById("qatrackBtn");
+const qatrackPopup = document.getElementById("qatrackPopup");
+const qatrackCloseBtn = document.getElementById("qatrackCloseBtn");
+const qatrackUtc = document.getElementById("qatrackUtc");
+const qatrackSave = document.getElementById("qatrackSave");
+const qatrackMacroInputs = qatrackPopup.querySelectorAll("[data-macro]");
+const qatrackDefaults = {
+  edge_error: ["x1_error", "x2_error", "y1_error", "y2_error"],
+  field_size: ["field_size_x", "field_size_y"],
+  collimator_passed: "collimator_pass",
+  beam_distance: "beam_distance",
+  beam_angle: "beam_angle",
+  beam_passed: "beam_pass",
+};
+
+function loadQatrackConfig() {
+  const saved = JSON.parse(localStorage.getItem("qatrackConfig") || "null");
+  return saved || { unit_test_collection: "", macros: qatrackDefaults };
+}
+
+qatrackBtn.addEventListener("click", () => {
+  const config = loadQatrackConfig();
+  qatrackUtc.value = config.unit_test_collection;
+  qatrackMacroInputs.forEach((input) => {
+    const [key, index] = input.dataset.macro.split(".");
+    const value = config.macros[key];
+    input.value = index === undefined ? value : value[index];
+  });
+  qatrackPopup.style.display = "block";
+  overlay.style.display = "block";
+});
+
+qatrackCloseBtn.addEventListener("click", () => {
+  qatrackPopup.style.display = "none";
+  overlay.style.display = "none";
+});
+
+qatrackSave.addEventListener("click", async () => {
+  if (lastResult === null) {
+    return;
+  }
+  const macros = JSON.parse(JSON.stringify(qatrackDefaults));
+  qatrackMacroInputs.forEach((input) => {
+    const [key, index] = input.dataset.macro.split(".");
+    if (index === undefined) {
+      macros[key] = input.value.trim();
+    } else {
+      macros[key][index] = input.value.trim();
+    }
+  });
+  const config = { unit_test_collection: qatrackUtc.value.trim(), macros: macros };
+  localStorage.setItem("qatrackConfig", JSON.stringify(config));
+
+  const filePath = await save({
+    filters: [{ name: "json", extensions: ["json"] }],
+    defaultPath: fileCheckInfoL.join("-") + "-qatrack.json",
+  });
+  if (!filePath) {
+    return;
+  }
+  const now = new Date();
+  const pad = (n) => String(n).padStart(2, "0");
+  const workStarted = `${now.getFullYear()}-${pad(now.getMonth() + 1)}-${pad(now.getDate())} ${pad(now.getHours())}:${pad(now.getMinutes())}`;
+  try {
+    await invoke("export_qatrack", {
+      savePath: filePath,
+      result: lastResult,
+      sid: parseFloat(sid),
+      criteria: criteria,
+      config: config,
+      workStarted: workStarted,
+    });
+    qatrackPopup.style.display = "none";
+    overlay.style.display = "none";
+    alert(`Saved: ${filePath}`);
+  } catch (err) {
+    await message(`${err}`, { title: "LightBeamKKU", type: "error" });
+  }
+});
+
 async function save2Csv(savePath, contentCsv) {
   const filePath = await save({
     filters: [